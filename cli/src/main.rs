@@ -1,9 +1,12 @@
 use color_eyre::owo_colors::OwoColorize;
 use promkit::preset::{listbox::Listbox, password::Password, readline::Readline};
-use std::{panic, str::FromStr};
+use std::{panic, str::FromStr, time::SystemTime};
+use tcp_chat::proto::room_subscription_request::ResumeAfter;
+use tcp_chat::proto::serverside_user_event::Event as UserEvent;
 use tcp_chat::proto::{chat_client::ChatClient, registry_client::RegistryClient};
 use tcp_chat::proto::{serverside_room_event::Event, user_lookup_request::Identifier};
-use tcp_chat::proto::{AuthPair, ClientsideMessage, ServersideMessage};
+use tcp_chat::proto::{AuthPair, ClientsideMessage, ReadMarkerRequest, ServersideMessage};
+use tcp_chat::proto::{MessageSearchRequest, RoomSubscriptionRequest};
 use tcp_chat::proto::{RoomWithUserCreationRequest, UserCredentials, UserLookupRequest};
 use tcp_chat::{auth::AuthenticatedRequest, proto};
 use tokio_stream::StreamExt;
@@ -14,6 +17,7 @@ use uuid::Uuid;
 
 const CERT: &str = include_str!("../../tls/ca.pem");
 const URL: &str = "https://localhost:9001";
+const SEND_ATTEMPTS: usize = 3;
 
 #[tokio::main]
 async fn main() {
@@ -96,12 +100,17 @@ async fn list_rooms(auth_pair: AuthPair) {
         Ok(request)
     });
 
-    let room_strategy = Listbox::new(["Focus existing room", "Create new private room"])
-        .title("What would you like to do?")
-        .prompt()
-        .unwrap()
-        .run()
-        .unwrap();
+    let room_strategy = Listbox::new([
+        "Focus existing room",
+        "Create new private room",
+        "Search messages",
+        "Wait for mentions",
+    ])
+    .title("What would you like to do?")
+    .prompt()
+    .unwrap()
+    .run()
+    .unwrap();
 
     match room_strategy.as_str() {
         "Focus existing room" => existing_room(chat).await,
@@ -136,6 +145,52 @@ async fn list_rooms(auth_pair: AuthPair) {
             existing_room(chat).await;
         }
 
+        "Search messages" => {
+            let query = Readline::default()
+                .title("What are you looking for?")
+                .prompt()
+                .unwrap()
+                .run()
+                .unwrap();
+
+            let hits = chat
+                .search_messages(MessageSearchRequest {
+                    query,
+                    ..Default::default()
+                })
+                .await
+                .unwrap()
+                .into_inner()
+                .hits;
+
+            if hits.is_empty() {
+                println!("{}", "Nothing found.".bright_black());
+            }
+
+            for hit in hits {
+                let msg = hit.message.unwrap();
+                println!(
+                    "{} | {} | {}: {}",
+                    msg.room_uuid.unwrap().uuid.purple(),
+                    msg.timestamp.unwrap().blue(),
+                    msg.sender_uuid.unwrap().uuid.green(),
+                    hit.snippet
+                );
+            }
+        }
+
+        "Wait for mentions" => {
+            let mut user_stream = chat.subscribe_to_user(()).await.unwrap().into_inner();
+
+            while let Some(event) = user_stream.next().await {
+                if let Some(UserEvent::Mentioned(msg)) = event.unwrap().event {
+                    let room = msg.room_uuid.clone().unwrap().uuid;
+                    println!("{}", format!("You were mentioned in {room}:").yellow());
+                    print_message(&msg);
+                }
+            }
+        }
+
         _ => unreachable!(),
     }
 }
@@ -175,15 +230,27 @@ async fn existing_room(
                 .run()
                 .unwrap();
 
-            let _ = chat
-                .send_message(ClientsideMessage {
-                    room_uuid: Some(chosen_room.into()),
-                    text,
-                })
-                .await
-                .unwrap();
+            // Retries carry the same key, so the server never stores the message twice.
+            let message = ClientsideMessage {
+                room_uuid: Some(chosen_room.into()),
+                text,
+                attachment_uuids: vec![],
+                deliver_at: None,
+                ttl_seconds: 0,
+                idempotency_key: Uuid::new_v4().to_string(),
+            };
+
+            let mut attempts = 0;
+            let message_uuid = loop {
+                attempts += 1;
+                match chat.send_message(message.clone()).await {
+                    Ok(response) => break response.into_inner().uuid,
+                    Err(status) if attempts < SEND_ATTEMPTS && is_transient(&status) => continue,
+                    Err(status) => panic!("Could not send the message: {status}"),
+                }
+            };
 
-            println!("{}", "Message sent!".bright_black());
+            println!("{}", format!("Message {message_uuid} sent!").bright_black());
         },
 
         "Listen to messages" => {
@@ -195,13 +262,29 @@ async fn existing_room(
                 .into_inner()
                 .messages;
 
-            for msg in messages.into_iter() {
-                print_message(&msg);
+            for msg in messages.iter() {
+                print_message(msg);
             }
 
-            // Print live messages.
+            // Everything that's been printed counts as read.
+            if let Some(last_message) = messages.last() {
+                let _ = chat
+                    .mark_read(ReadMarkerRequest {
+                        room_uuid: Some(chosen_room.into()),
+                        message_uuid: last_message.uuid.clone(),
+                    })
+                    .await
+                    .unwrap();
+            }
+
+            // Print live messages, starting right after the listed ones, so that
+            // nothing sent in between gets lost. Backfill any that slip through.
+            let mut last_sequence = messages.last().map_or(0, |msg| msg.sequence);
             let mut message_stream = chat
-                .subscribe_to_room(proto::Uuid::from(chosen_room))
+                .resume_room_subscription(RoomSubscriptionRequest {
+                    room_uuid: Some(chosen_room.into()),
+                    resume_after: Some(ResumeAfter::Sequence(last_sequence)),
+                })
                 .await
                 .unwrap()
                 .into_inner();
@@ -209,11 +292,78 @@ async fn existing_room(
             'message_listener: while let Ok(event) = message_stream.next().await.unwrap() {
                 match event.event.unwrap() {
                     Event::NewMessage(msg) => {
+                        if msg.sequence > last_sequence + 1 {
+                            let missed = chat
+                                .list_messages(Into::<proto::Uuid>::into(chosen_room))
+                                .await
+                                .unwrap()
+                                .into_inner()
+                                .messages;
+                            missed
+                                .iter()
+                                .filter(|m| (last_sequence + 1..msg.sequence).contains(&m.sequence))
+                                .for_each(print_message);
+                        }
+                        last_sequence = last_sequence.max(msg.sequence);
+
                         print_message(&msg);
+                        let _ = chat
+                            .mark_read(ReadMarkerRequest {
+                                room_uuid: Some(chosen_room.into()),
+                                message_uuid: msg.uuid.clone(),
+                            })
+                            .await
+                            .unwrap();
                         if msg.text.as_str() == "exit" {
                             break 'message_listener;
                         }
                     }
+                    Event::ReadReceipt(_) => {}
+                    Event::ResyncRequired(resync) => {
+                        println!(
+                            "{}",
+                            format!("Missed {} events, catching up...", resync.skipped_events)
+                                .bright_black()
+                        );
+                        let missed = chat
+                            .list_messages(Into::<proto::Uuid>::into(chosen_room))
+                            .await
+                            .unwrap()
+                            .into_inner()
+                            .messages;
+                        missed
+                            .iter()
+                            .filter(|m| m.sequence > last_sequence)
+                            .for_each(print_message);
+                        last_sequence = missed.last().map_or(last_sequence, |m| m.sequence);
+                    }
+                    Event::MessagePinned(pin) => {
+                        let pinner = pin.pinner_uuid.unwrap_or_default().uuid;
+                        println!("{}", format!("{pinner} pinned a message:").bright_black());
+                        if let Some(msg) = pin.message {
+                            print_message(&msg);
+                        }
+                    }
+                    Event::MessageDeleted(deleted) => {
+                        let message = deleted.message_uuid.unwrap_or_default().uuid;
+                        println!(
+                            "{}",
+                            format!("Message {message} was deleted").bright_black()
+                        );
+                    }
+                    Event::MessageUnpinned(unpin) => {
+                        let unpinner = unpin.user_uuid.unwrap_or_default().uuid;
+                        println!(
+                            "{}",
+                            format!("{unpinner} unpinned a message").bright_black()
+                        );
+                    }
+                    Event::Typing(indicator) => {
+                        if indicator.typing {
+                            let typist = indicator.user_uuid.unwrap_or_default().uuid;
+                            println!("{}", format!("{typist} is typing...").bright_black());
+                        }
+                    }
                 }
             }
         }
@@ -234,6 +384,13 @@ async fn existing_room(
     }
 }
 
+fn is_transient(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        tonic::Code::Unavailable | tonic::Code::DeadlineExceeded
+    )
+}
+
 fn print_message(msg: &ServersideMessage) {
     println!(
         "{} | {}: {}",
@@ -241,4 +398,23 @@ fn print_message(msg: &ServersideMessage) {
         msg.sender_uuid.clone().unwrap().uuid.green(),
         msg.text
     );
+    if let Some(expires_at) = msg.expires_at.clone() {
+        let left = SystemTime::try_from(expires_at)
+            .ok()
+            .and_then(|at| at.duration_since(SystemTime::now()).ok())
+            .unwrap_or_default();
+        println!(
+            "    {}",
+            format!("Self-destructs in {}s", left.as_secs()).bright_black()
+        );
+    }
+    for attachment in msg.attachments.iter() {
+        println!(
+            "    {} {} ({}, {} bytes)",
+            "Attachment:".bright_black(),
+            attachment.filename,
+            attachment.mime_type,
+            attachment.size
+        );
+    }
 }
@@ -0,0 +1 @@
+-- SQLite has no stored functions, and nothing uses the Postgres helpers anyway.
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE read_markers (
+    user_uuid UUID NOT NULL REFERENCES users(uuid),
+    room_uuid UUID NOT NULL REFERENCES rooms(uuid),
+    message_uuid UUID NOT NULL REFERENCES messages(uuid),
+    timestamp TIMESTAMP NOT NULL,
+    PRIMARY KEY(user_uuid, room_uuid)
+);
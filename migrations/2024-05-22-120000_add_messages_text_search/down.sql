@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX messages_text_search_idx;
+ALTER TABLE messages DROP COLUMN text_search;
@@ -0,0 +1 @@
+-- SQLite has no full text search index, messages are searched with `LIKE` instead.
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE messages ADD COLUMN text_search TSVECTOR NOT NULL
+    GENERATED ALWAYS AS (to_tsvector('simple', text)) STORED;
+
+CREATE INDEX messages_text_search_idx ON messages USING GIN (text_search);
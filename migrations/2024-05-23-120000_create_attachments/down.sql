@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE messages_attachments;
+DROP TABLE attachments;
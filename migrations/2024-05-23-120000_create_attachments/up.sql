@@ -0,0 +1,19 @@
+-- Your SQL goes here
+CREATE TABLE attachments (
+    uuid UUID NOT NULL PRIMARY KEY,
+    room_uuid UUID NOT NULL REFERENCES rooms(uuid),
+    uploader_uuid UUID NOT NULL REFERENCES users(uuid),
+    hash CHAR(64) NOT NULL,
+    filename VARCHAR(256) NOT NULL,
+    mime_type VARCHAR(128) NOT NULL,
+    size BIGINT NOT NULL,
+    timestamp TIMESTAMP NOT NULL
+);
+
+CREATE INDEX attachments_hash_idx ON attachments (hash);
+
+CREATE TABLE messages_attachments (
+    message_uuid UUID NOT NULL REFERENCES messages(uuid) ON DELETE CASCADE,
+    attachment_uuid UUID NOT NULL REFERENCES attachments(uuid),
+    PRIMARY KEY(message_uuid, attachment_uuid)
+);
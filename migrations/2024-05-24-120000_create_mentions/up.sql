@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE mentions (
+    message_uuid UUID NOT NULL REFERENCES messages(uuid) ON DELETE CASCADE,
+    user_uuid UUID NOT NULL REFERENCES users(uuid),
+    byte_offset INTEGER NOT NULL,
+    byte_length INTEGER NOT NULL,
+    PRIMARY KEY(message_uuid, byte_offset)
+);
+
+CREATE INDEX mentions_user_uuid_idx ON mentions (user_uuid);
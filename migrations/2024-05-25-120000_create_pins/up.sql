@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE pins (
+    message_uuid UUID NOT NULL PRIMARY KEY REFERENCES messages(uuid) ON DELETE CASCADE,
+    room_uuid UUID NOT NULL REFERENCES rooms(uuid),
+    pinner_uuid UUID NOT NULL REFERENCES users(uuid),
+    timestamp TIMESTAMP NOT NULL
+);
+
+CREATE INDEX pins_room_uuid_idx ON pins (room_uuid);
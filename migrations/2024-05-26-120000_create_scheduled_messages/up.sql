@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE scheduled_messages (
+    uuid UUID NOT NULL PRIMARY KEY,
+    sender_uuid UUID NOT NULL REFERENCES users(uuid),
+    room_uuid UUID NOT NULL REFERENCES rooms(uuid),
+    text TEXT NOT NULL,
+    attachment_uuids UUID[] NOT NULL,
+    deliver_at TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX scheduled_messages_deliver_at_idx ON scheduled_messages (deliver_at);
+CREATE INDEX scheduled_messages_sender_uuid_idx ON scheduled_messages (sender_uuid);
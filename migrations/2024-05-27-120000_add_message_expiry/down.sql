@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE scheduled_messages DROP COLUMN ttl_seconds;
+ALTER TABLE rooms DROP COLUMN message_ttl_seconds;
+
+DROP INDEX messages_expires_at_idx;
+ALTER TABLE messages DROP COLUMN expires_at;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE idempotency_keys (
+    sender_uuid UUID NOT NULL REFERENCES users(uuid),
+    key VARCHAR(64) NOT NULL,
+    message_uuid UUID NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    PRIMARY KEY(sender_uuid, key)
+);
+
+CREATE INDEX idempotency_keys_created_at_idx ON idempotency_keys (created_at);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX messages_room_uuid_sequence_idx;
+ALTER TABLE messages DROP COLUMN sequence;
+ALTER TABLE rooms DROP COLUMN last_sequence;
@@ -0,0 +1,17 @@
+-- SQLite can't make a column NOT NULL after adding it, so it's added with a default instead.
+ALTER TABLE rooms ADD COLUMN last_sequence BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE messages ADD COLUMN sequence BIGINT NOT NULL DEFAULT 0;
+
+-- Number the existing messages in the order they were sent.
+UPDATE messages
+SET sequence = numbered.sequence
+FROM (
+    SELECT uuid, ROW_NUMBER() OVER (PARTITION BY room_uuid ORDER BY timestamp, uuid) AS sequence
+    FROM messages
+) AS numbered
+WHERE messages.uuid = numbered.uuid;
+
+UPDATE rooms
+SET last_sequence = COALESCE((SELECT MAX(sequence) FROM messages WHERE messages.room_uuid = rooms.uuid), 0);
+
+CREATE UNIQUE INDEX messages_room_uuid_sequence_idx ON messages (room_uuid, sequence);
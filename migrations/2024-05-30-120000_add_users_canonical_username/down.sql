@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX users_canonical_username_idx;
+ALTER TABLE users DROP COLUMN canonical_username;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+-- Usernames are compared in a canonical form (NFKC, case folded, lookalike letters
+-- replaced), which only the server can compute, so it's filled in for existing users
+-- (and made NOT NULL) by the next migration.
+ALTER TABLE users ADD COLUMN canonical_username TEXT;
+CREATE UNIQUE INDEX users_canonical_username_idx ON users (canonical_username);
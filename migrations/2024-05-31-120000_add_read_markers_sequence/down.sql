@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE read_markers DROP COLUMN message_sequence;
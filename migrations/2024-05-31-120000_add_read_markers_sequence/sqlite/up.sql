@@ -0,0 +1,8 @@
+-- SQLite can't make a column NOT NULL after adding it, so it's added with a default instead.
+ALTER TABLE read_markers ADD COLUMN message_sequence BIGINT NOT NULL DEFAULT 0;
+
+-- Markers are compared by the sequence number of the message they point to.
+UPDATE read_markers
+SET message_sequence = messages.sequence
+FROM messages
+WHERE read_markers.message_uuid = messages.uuid;
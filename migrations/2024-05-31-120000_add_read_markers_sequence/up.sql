@@ -0,0 +1,10 @@
+-- Your SQL goes here
+ALTER TABLE read_markers ADD COLUMN message_sequence BIGINT;
+
+-- Markers are compared by the sequence number of the message they point to.
+UPDATE read_markers
+SET message_sequence = messages.sequence
+FROM messages
+WHERE read_markers.message_uuid = messages.uuid;
+
+ALTER TABLE read_markers ALTER COLUMN message_sequence SET NOT NULL;
@@ -0,0 +1,2 @@
+DROP TRIGGER users_canonical_username_update;
+DROP TRIGGER users_canonical_username_insert;
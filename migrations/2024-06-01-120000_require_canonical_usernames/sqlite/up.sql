@@ -0,0 +1,13 @@
+-- SQLite can't add a NOT NULL constraint to an existing column without rebuilding the
+-- table, so it's enforced by triggers instead.
+CREATE TRIGGER users_canonical_username_insert BEFORE INSERT ON users
+WHEN NEW.canonical_username IS NULL
+BEGIN
+    SELECT RAISE(ABORT, 'NOT NULL constraint failed: users.canonical_username');
+END;
+
+CREATE TRIGGER users_canonical_username_update BEFORE UPDATE OF canonical_username ON users
+WHEN NEW.canonical_username IS NULL
+BEGIN
+    SELECT RAISE(ABORT, 'NOT NULL constraint failed: users.canonical_username');
+END;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- `server migrate up` fills in the canonical usernames of existing users right before
+-- running this, refusing to if any of their names collide in the canonical form. Run by
+-- the `diesel` CLI alone, it fails if there are any users left without one.
+ALTER TABLE users ALTER COLUMN canonical_username SET NOT NULL;
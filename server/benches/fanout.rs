@@ -0,0 +1,92 @@
+//! Fan-out throughput with thousands of room subscribers.
+//!
+//! Every subscriber is in a room of [`ROOM_SIZE`] members, and every room gets one event
+//! per round. The `per-room` case publishes through a [`RoomChannels`] registry, while the
+//! `single channel` case mirrors the old approach, where every subscriber receives every
+//! event on the server and drops the ones from other rooms.
+//!
+//! Run with `cargo bench --bench fanout`.
+
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tcp_chat::fanout::RoomChannels;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+const ROOM_SIZE: usize = 10;
+const SUBSCRIBER_COUNTS: [usize; 4] = [1_000, 2_500, 5_000, 10_000];
+const ROUNDS: u32 = 20;
+
+fn main() {
+    println!(
+        "{:>12} {:>20} {:>20}",
+        "subscribers", "per-room (ev/s)", "single channel (ev/s)"
+    );
+
+    for subscribers in SUBSCRIBER_COUNTS {
+        let rooms: Vec<Uuid> = (0..subscribers / ROOM_SIZE)
+            .map(|_| Uuid::new_v4())
+            .collect();
+
+        let per_room = per_room(&rooms);
+        let single_channel = single_channel(&rooms);
+
+        // Each round delivers one event to each subscriber.
+        let throughput = |elapsed: Duration| {
+            (subscribers as f64 * f64::from(ROUNDS) / elapsed.as_secs_f64()) as u64
+        };
+        println!(
+            "{subscribers:>12} {:>20} {:>20}",
+            throughput(per_room),
+            throughput(single_channel)
+        );
+    }
+}
+
+fn per_room(rooms: &[Uuid]) -> Duration {
+    let channels = Arc::new(RoomChannels::new(rooms.len()));
+    let mut receivers: Vec<_> = members(rooms)
+        .map(|room| channels.subscribe(room))
+        .collect();
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for &room in rooms {
+            channels.send(room, room);
+        }
+        for receiver in &mut receivers {
+            while let Ok(event) = receiver.try_recv() {
+                black_box(event);
+            }
+        }
+    }
+    start.elapsed()
+}
+
+fn single_channel(rooms: &[Uuid]) -> Duration {
+    let (tx, _) = broadcast::channel(rooms.len());
+    let mut receivers: Vec<_> = members(rooms).map(|room| (room, tx.subscribe())).collect();
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for &room in rooms {
+            let _ = tx.send(room);
+        }
+        for (room, receiver) in &mut receivers {
+            while let Ok(event) = receiver.try_recv() {
+                if event == *room {
+                    black_box(event);
+                }
+            }
+        }
+    }
+    start.elapsed()
+}
+
+/// The room of every subscriber.
+fn members(rooms: &[Uuid]) -> impl Iterator<Item = Uuid> + '_ {
+    rooms
+        .iter()
+        .flat_map(|&room| std::iter::repeat(room).take(ROOM_SIZE))
+}
@@ -1,5 +1,7 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::configure().compile(
+    // Clients are connected over a `Channel` built by hand, and the generated `connect`
+    // constructor would clash with the client method of the `Connect` RPC.
+    tonic_build::configure().build_transport(false).compile(
         &[
             "../proto/entities.proto",
             "../proto/requests.proto",
@@ -8,5 +10,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         ],
         &["../proto"],
     )?;
+
+    // `diesel_migrations::embed_migrations!` doesn't notice new migrations by itself.
+    println!("cargo:rerun-if-changed=../migrations");
     Ok(())
 }
@@ -1,9 +1,19 @@
+use crate::entities::token::AuthToken;
 use crate::entities::User;
 use crate::proto::AuthPair;
-use crate::services::acquire_connection_error_status;
-use crate::{entities::token::AuthToken, persistence::ConnectionPool};
+use crate::repositories::UserRepository;
+use futures::Future;
+use std::pin::Pin;
 use std::str::FromStr;
-use tonic::{service::Interceptor, Request, Status};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use subtle::ConstantTimeEq;
+use tonic::body::BoxBody;
+use tonic::metadata::MetadataMap;
+use tonic::server::NamedService;
+use tonic::transport::Body;
+use tonic::{Extensions, Request, Status};
+use tower_service::Service;
 use uuid::Uuid;
 
 #[allow(clippy::missing_errors_doc)]
@@ -73,23 +83,37 @@ impl<T> AuthenticatedRequest for Request<T> {
     }
 }
 
+/// Checks the credentials of every request to the services it [wraps](Self::wrap).
+///
+/// Since the check needs the database, this is a service wrapper rather than a
+/// [`tonic::service::Interceptor`], which can't be async.
 #[derive(Debug, Clone)]
 pub struct Authenticator {
-    persistence_pool: ConnectionPool,
+    users: Arc<dyn UserRepository>,
 }
 
 impl Authenticator {
-    pub fn new(persistence_pool: ConnectionPool) -> Self {
-        Self { persistence_pool }
+    pub fn new(users: Arc<dyn UserRepository>) -> Self {
+        Self { users }
     }
 
     pub const USER_UUID_KEY: &'static str = "user_uuid";
     pub const AUTH_TOKEN_KEY: &'static str = "auth_token";
-}
 
-impl Interceptor for Authenticator {
-    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
-        dbg!(&request);
+    /// Only let authenticated requests through to a service.
+    pub const fn wrap<S>(self, service: S) -> Authenticated<S> {
+        Authenticated {
+            inner: service,
+            authenticator: self,
+        }
+    }
+
+    /// Check the UUID+token pair in the request's metadata.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `UNAUTHENTICATED` status if the pair is missing or doesn't match any user.
+    pub async fn authenticate(&self, mut request: Request<()>) -> Result<Request<()>, Status> {
         let auth_pair: AuthPair = request.get_auth_pair().map_err(|_| unauthenticated())?;
         let user_uuid: Uuid = auth_pair
             .user_uuid
@@ -104,47 +128,93 @@ impl Interceptor for Authenticator {
         // so it doesn't accidently appear anywhere else (i.e. logs).
         let _ = request.metadata_mut().remove(Self::AUTH_TOKEN_KEY);
 
-        let mut connection = self
-            .persistence_pool
-            .get()
-            .map_err(acquire_connection_error_status)?;
-
-        // Import some traits and methods to interact with the ORM.
-        use crate::entities::schema::users::dsl::*;
-        use diesel::query_dsl::methods::{FilterDsl, SelectDsl};
-        use diesel::{ExpressionMethods, OptionalExtension, RunQueryDsl, SelectableHelper};
-
-        let user_with_matching_credentials = users
-            .filter(uuid.eq(user_uuid))
-            .filter(auth_token.eq(proto_auth_token))
-            .select(User::as_select())
-            .first(&mut connection)
-            .optional()
-            .map_err(|err| Status::internal(err.to_string()))?;
-
-        match user_with_matching_credentials {
-            Some(user) => {
+        let user = self.users.find(user_uuid).await.map_err(|error| {
+            let msg = "Couldn't fetch the user from database";
+            tracing::error!(message = msg, ?error);
+            Status::internal(msg)
+        })?;
+
+        // NOTE: Compare tokens in constant time, so that response times
+        // don't leak how much of a guessed token is right.
+        let token_matches = |user: &User| {
+            bool::from(
+                user.auth_token
+                    .as_bytes()
+                    .ct_eq(proto_auth_token.as_bytes()),
+            )
+        };
+        match user {
+            Some(user) if token_matches(&user) => {
                 tracing::trace!(message = "Authenticated request", username = ?user.username);
                 Ok(request)
             }
-            None => Err(unauthenticated()),
+            _ => Err(unauthenticated()),
         }
     }
 }
 
+/// A service that only lets requests through if the [`Authenticator`] accepts them.
+#[derive(Debug, Clone)]
+pub struct Authenticated<S> {
+    inner: S,
+    authenticator: Authenticator,
+}
+
+impl<S> Service<http::Request<Body>> for Authenticated<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        // The service that was polled ready is the one that must handle the request,
+        // so take it and leave a fresh clone behind for the next one.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let authenticator = self.authenticator.clone();
+
+        Box::pin(async move {
+            let (mut parts, body) = request.into_parts();
+            let metadata = MetadataMap::from_headers(std::mem::take(&mut parts.headers));
+            let metadata_only = Request::from_parts(metadata, Extensions::default(), ());
+
+            match authenticator.authenticate(metadata_only).await {
+                Ok(authenticated) => {
+                    parts.headers = authenticated.into_parts().0.into_headers();
+                    inner.call(http::Request::from_parts(parts, body)).await
+                }
+                Err(status) => Ok(status.to_http()),
+            }
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for Authenticated<S> {
+    const NAME: &'static str = S::NAME;
+}
+
 fn unauthenticated() -> Status {
-    tracing::warn!(message = "Interceptor caught an unauthenticated request!");
+    tracing::warn!(message = "Authenticator caught an unauthenticated request!");
     Status::unauthenticated("The UUID+token pair was invalid or not provided in request metadata")
 }
 
 #[cfg(test)]
 #[allow(clippy::unwrap_used)]
 mod tests {
-    use super::AuthenticatedRequest;
+    use super::{AuthenticatedRequest, Authenticator};
+    use crate::repositories::{InMemoryRepository, UserRepository};
     use crate::{entities::User, proto::AuthPair};
     use rand_chacha::ChaCha20Rng;
     use rand_core::{OsRng, RngCore, SeedableRng};
-    use tonic::Request;
+    use std::sync::Arc;
+    use tonic::{Code, Request};
 
     #[test]
     fn conversion() {
@@ -159,4 +229,38 @@ mod tests {
         assert!(request.add_auth_pair(auth_pair.clone()).is_ok());
         assert_eq!(request.get_auth_pair().unwrap(), auth_pair);
     }
+
+    // NOTE: `#[tokio::test]` runs on a current-thread runtime, which blocking
+    // inside of the runtime (e.g. with `block_in_place`) would panic on.
+    #[tokio::test]
+    async fn authentication() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let authenticator = Authenticator::new(repository.clone());
+        let mut rng = ChaCha20Rng::seed_from_u64(OsRng.next_u64());
+        let user = User::new("user_1".into(), "pass_1".into(), &mut rng);
+        let impostor = User::new("user_1".into(), "pass_1".into(), &mut rng);
+        repository.create(user.clone()).await.unwrap();
+
+        let mut request = Request::new(());
+        request.add_auth_pair(user.auth_pair()).unwrap();
+        let request = authenticator.authenticate(request).await.unwrap();
+        assert_eq!(request.get_originator_uuid().unwrap(), user.uuid);
+        assert!(request.get_token().is_err());
+
+        let mut request = Request::new(());
+        request
+            .add_auth_pair(AuthPair {
+                user_uuid: Some(user.uuid.into()),
+                token: Some(impostor.proto_token()),
+            })
+            .unwrap();
+        let status = authenticator.authenticate(request).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let status = authenticator
+            .authenticate(Request::new(()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+    }
 }
@@ -0,0 +1,99 @@
+//! # Command line
+//!
+//! ```text
+//! server [--apply-migrations]     Serve, applying pending migrations first if asked to
+//! server migrate up               Apply all pending migrations
+//! server migrate down             Revert the latest applied migration
+//! server migrate status           List migrations and whether they've been applied
+//! ```
+//!
+//! Without `--apply-migrations`, the server refuses to start on an outdated schema.
+
+/// What the `server` binary was asked to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Serve { apply_migrations: bool },
+    Migrate(MigrateCommand),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub enum MigrateCommand {
+    Up,
+    Down,
+    Status,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+pub enum CommandError {
+    #[error("Unexpected argument: {0}")]
+    Unexpected(String),
+    #[error("Missing a migrate subcommand")]
+    MissingSubcommand,
+}
+
+impl Command {
+    pub const USAGE: &'static str = "\
+Usage: server [--apply-migrations]
+       server migrate <up|down|status>";
+
+    /// Parse the arguments the binary was run with, not including its name.
+    ///
+    /// # Errors
+    ///
+    /// Fails on unknown or missing arguments.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, CommandError> {
+        let mut args = args.into_iter();
+        let command = match args.next().as_deref() {
+            None => Self::Serve {
+                apply_migrations: false,
+            },
+            Some("--apply-migrations") => Self::Serve {
+                apply_migrations: true,
+            },
+            Some("migrate") => Self::Migrate(match args.next().as_deref() {
+                Some("up") => MigrateCommand::Up,
+                Some("down") => MigrateCommand::Down,
+                Some("status") => MigrateCommand::Status,
+                Some(other) => return Err(CommandError::Unexpected(other.to_string())),
+                None => return Err(CommandError::MissingSubcommand),
+            }),
+            Some(other) => return Err(CommandError::Unexpected(other.to_string())),
+        };
+
+        match args.next() {
+            Some(extra) => Err(CommandError::Unexpected(extra)),
+            None => Ok(command),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, CommandError, MigrateCommand};
+    use rstest::rstest;
+
+    fn parse(args: &[&str]) -> Result<Command, CommandError> {
+        Command::parse(args.iter().map(ToString::to_string))
+    }
+
+    #[rstest]
+    #[case::serve(&[], Command::Serve { apply_migrations: false })]
+    #[case::serve_and_apply(&["--apply-migrations"], Command::Serve { apply_migrations: true })]
+    #[case::up(&["migrate", "up"], Command::Migrate(MigrateCommand::Up))]
+    #[case::down(&["migrate", "down"], Command::Migrate(MigrateCommand::Down))]
+    #[case::status(&["migrate", "status"], Command::Migrate(MigrateCommand::Status))]
+    fn valid(#[case] args: &[&str], #[case] expected: Command) {
+        assert_eq!(parse(args), Ok(expected));
+    }
+
+    #[rstest]
+    #[case::unknown(&["serve"], CommandError::Unexpected("serve".into()))]
+    #[case::unknown_subcommand(&["migrate", "redo"], CommandError::Unexpected("redo".into()))]
+    #[case::missing_subcommand(&["migrate"], CommandError::MissingSubcommand)]
+    #[case::extra(&["migrate", "up", "now"], CommandError::Unexpected("now".into()))]
+    fn invalid(#[case] args: &[&str], #[case] expected: CommandError) {
+        assert_eq!(parse(args), Err(expected));
+    }
+}
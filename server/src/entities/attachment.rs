@@ -0,0 +1,95 @@
+use super::{Room, User};
+use crate::persistence::sql_types::{SqlTime, SqlUuid};
+use crate::proto;
+use diesel::prelude::*;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// A file uploaded to a room. The contents live in a [`BlobStore`](crate::storage::BlobStore).
+#[derive(Queryable, Identifiable, Selectable, Insertable, Associations, Debug, Clone)]
+#[diesel(table_name = crate::entities::schema::attachments)]
+#[diesel(check_for_backend(crate::persistence::MultiBackend))]
+#[diesel(belongs_to(Room, foreign_key = room_uuid))]
+#[diesel(belongs_to(User, foreign_key = uploader_uuid))]
+#[diesel(primary_key(uuid))]
+pub struct Attachment {
+    #[diesel(serialize_as = SqlUuid)]
+    pub uuid: Uuid,
+    #[diesel(serialize_as = SqlUuid)]
+    pub room_uuid: Uuid,
+    #[diesel(serialize_as = SqlUuid)]
+    pub uploader_uuid: Uuid,
+    pub hash: String,
+    pub filename: String,
+    pub mime_type: String,
+    pub size: i64,
+    #[diesel(serialize_as = SqlTime)]
+    pub timestamp: SystemTime,
+}
+
+impl Attachment {
+    pub const MAX_FILENAME_LENGTH: usize = 256;
+
+    pub fn new(
+        room_uuid: Uuid,
+        uploader_uuid: Uuid,
+        filename: String,
+        mime_type: String,
+        hash: blake3::Hash,
+        size: usize,
+    ) -> Self {
+        Self {
+            uuid: Uuid::new_v4(),
+            room_uuid,
+            uploader_uuid,
+            hash: hash.to_hex().to_string(),
+            filename,
+            mime_type,
+            size: size.try_into().unwrap_or(i64::MAX),
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    /// The content address of this attachment in a blob store.
+    pub fn blake3_hash(&self) -> Result<blake3::Hash, blake3::HexError> {
+        blake3::Hash::from_hex(&self.hash)
+    }
+}
+
+impl From<Attachment> for proto::Attachment {
+    fn from(attachment: Attachment) -> Self {
+        Self {
+            uuid: Some(attachment.uuid.into()),
+            room_uuid: Some(attachment.room_uuid.into()),
+            uploader_uuid: Some(attachment.uploader_uuid.into()),
+            filename: attachment.filename,
+            mime_type: attachment.mime_type,
+            size: attachment.size.try_into().unwrap_or_default(),
+            hash: attachment.hash,
+            timestamp: Some(attachment.timestamp.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::Attachment;
+    use uuid::Uuid;
+
+    #[test]
+    fn content_address() {
+        let content = b"deploy checklist";
+        let attachment = Attachment::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            "checklist.txt".into(),
+            "text/plain".into(),
+            blake3::hash(content),
+            content.len(),
+        );
+
+        assert_eq!(attachment.size, 16);
+        assert_eq!(attachment.blake3_hash().unwrap(), blake3::hash(content));
+    }
+}
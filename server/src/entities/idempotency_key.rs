@@ -0,0 +1,116 @@
+use super::User;
+use crate::persistence::sql_types::{SqlTime, SqlUuid};
+use crate::persistence::Connection;
+use diesel::prelude::*;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// A client-generated key that a message was sent with, used to deduplicate retries.
+#[derive(
+    Queryable, Identifiable, Selectable, Insertable, AsChangeset, Associations, Debug, Clone,
+)]
+#[diesel(table_name = crate::entities::schema::idempotency_keys)]
+#[diesel(check_for_backend(crate::persistence::MultiBackend))]
+#[diesel(belongs_to(User, foreign_key = sender_uuid))]
+#[diesel(primary_key(sender_uuid, key))]
+pub struct IdempotencyKey {
+    #[diesel(serialize_as = SqlUuid)]
+    pub sender_uuid: Uuid,
+    pub key: String,
+    #[diesel(serialize_as = SqlUuid)]
+    pub message_uuid: Uuid,
+    #[diesel(serialize_as = SqlTime)]
+    pub created_at: SystemTime,
+}
+
+impl IdempotencyKey {
+    pub const MAX_LENGTH: usize = 64;
+
+    /// For how long a key keeps deduplicating messages after it's been used.
+    pub const WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+    pub fn new(sender_uuid: Uuid, key: String, message_uuid: Uuid) -> Self {
+        Self {
+            sender_uuid,
+            key,
+            message_uuid,
+            created_at: SystemTime::now(),
+        }
+    }
+
+    /// Whether the key still deduplicates messages at the provided moment.
+    pub fn is_fresh(&self, now: SystemTime) -> bool {
+        // A key from the future (i.e. after a clock adjustment) is better kept.
+        now.duration_since(self.created_at)
+            .map_or(true, |age| age < Self::WINDOW)
+    }
+
+    /// Claim the key for a new message, unless it's been used for another one within the window.
+    ///
+    /// Returns the UUID of that other message if so. Should be called in the same
+    /// transaction that stores the new message, so that a failure releases the key.
+    pub fn claim(&self, db_connection: &mut Connection) -> QueryResult<Option<Uuid>> {
+        use crate::entities::schema::idempotency_keys;
+
+        let inserted = db_connection.insert_or_ignore(|db| {
+            diesel::insert_into(idempotency_keys::table)
+                .values(self.clone())
+                .execute(db)
+        })?;
+        if inserted > 0 {
+            return Ok(None);
+        }
+
+        // NOTE: SQLite has locked the whole database already, if this is in a write transaction.
+        let existing_query = idempotency_keys::table.find((SqlUuid(self.sender_uuid), &self.key));
+        let existing: Self = match db_connection.as_postgres() {
+            Some(db) => existing_query
+                .select(Self::as_select())
+                .for_update()
+                .first(db)?,
+            None => existing_query
+                .select(Self::as_select())
+                .first(db_connection)?,
+        };
+        if existing.is_fresh(self.created_at) {
+            return Ok(Some(existing.message_uuid));
+        }
+
+        // The key has outlived its window, so it may be reused.
+        let _ = diesel::update(existing_query)
+            .set(self.clone())
+            .execute(db_connection)?;
+        Ok(None)
+    }
+
+    /// Forget keys that have outlived their window.
+    pub fn prune(now: SystemTime, db_connection: &mut Connection) -> QueryResult<usize> {
+        use crate::entities::schema::idempotency_keys;
+
+        let Some(cutoff) = now.checked_sub(Self::WINDOW) else {
+            return Ok(0);
+        };
+
+        diesel::delete(
+            idempotency_keys::table.filter(idempotency_keys::created_at.lt(SqlTime(cutoff))),
+        )
+        .execute(db_connection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdempotencyKey;
+    use rstest::rstest;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    #[rstest]
+    #[case::just_used(Duration::ZERO, true)]
+    #[case::retried(Duration::from_secs(30), true)]
+    #[case::stale(IdempotencyKey::WINDOW, false)]
+    fn window(#[case] age: Duration, #[case] fresh: bool) {
+        let key = IdempotencyKey::new(Uuid::new_v4(), "retry-me".into(), Uuid::new_v4());
+        assert_eq!(key.is_fresh(key.created_at + age), fresh);
+    }
+}
@@ -0,0 +1,171 @@
+use super::{username, Message, User};
+use crate::persistence::sql_types::SqlUuid;
+use crate::persistence::Connection;
+use crate::proto;
+use diesel::prelude::*;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// A room member that was mentioned in a message with a `@username`.
+#[derive(Queryable, Identifiable, Selectable, Insertable, Associations, Debug, Clone)]
+#[diesel(table_name = crate::entities::schema::mentions)]
+#[diesel(check_for_backend(crate::persistence::MultiBackend))]
+#[diesel(belongs_to(Message, foreign_key = message_uuid))]
+#[diesel(belongs_to(User, foreign_key = user_uuid))]
+#[diesel(primary_key(message_uuid, byte_offset))]
+pub struct Mention {
+    #[diesel(serialize_as = SqlUuid)]
+    pub message_uuid: Uuid,
+    #[diesel(serialize_as = SqlUuid)]
+    pub user_uuid: Uuid,
+    pub byte_offset: i32,
+    pub byte_length: i32,
+}
+
+impl Mention {
+    /// Resolve `@username` mentions in the text of a message against the members of its room.
+    ///
+    /// Usernames are compared in their [canonical form](username::canonicalize), like on login.
+    /// Usernames that don't belong to any member of the room are ignored.
+    pub fn resolve(message: &Message, db_connection: &mut Connection) -> QueryResult<Vec<Self>> {
+        use crate::entities::schema::{rooms_users, users};
+
+        let parsed_mentions = parse_mentions(&message.text);
+        if parsed_mentions.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let canonical_usernames: Vec<String> = parsed_mentions
+            .iter()
+            .map(|m| username::canonicalize(m.username))
+            .collect();
+        let members: HashMap<String, Uuid> = users::table
+            .inner_join(rooms_users::table)
+            .filter(rooms_users::room_uuid.eq(SqlUuid(message.room_uuid)))
+            .filter(users::canonical_username.eq_any(&canonical_usernames))
+            .select((users::canonical_username, users::uuid))
+            .load::<(String, Uuid)>(db_connection)?
+            .into_iter()
+            .collect();
+
+        Ok(Self::resolve_among(message, &members))
+    }
+
+    /// Resolve `@username` mentions in the text of a message against the
+    /// canonical usernames of the members of its room, mapped to their UUIDs.
+    pub fn resolve_among(message: &Message, members: &HashMap<String, Uuid>) -> Vec<Self> {
+        parse_mentions(&message.text)
+            .into_iter()
+            .filter_map(|parsed| {
+                Some(Self {
+                    message_uuid: message.uuid,
+                    user_uuid: *members.get(&username::canonicalize(parsed.username))?,
+                    byte_offset: parsed.offset.try_into().ok()?,
+                    byte_length: parsed.length.try_into().ok()?,
+                })
+            })
+            .collect()
+    }
+}
+
+impl From<Mention> for proto::Mention {
+    fn from(mention: Mention) -> Self {
+        Self {
+            user_uuid: Some(mention.user_uuid.into()),
+            offset: mention.byte_offset.try_into().unwrap_or_default(),
+            length: mention.byte_length.try_into().unwrap_or_default(),
+        }
+    }
+}
+
+/// A `@username` found in the text of a message, not yet resolved to a user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParsedMention<'text> {
+    pub username: &'text str,
+    /// In bytes, pointing at the `@`.
+    pub offset: usize,
+    /// In bytes, including the `@`.
+    pub length: usize,
+}
+
+/// Find all `@username` mentions in a text.
+///
+/// A mention must not be glued to a preceding word (so e-mail addresses don't count),
+/// and trailing punctuation (as in "thanks, @alice.") is not a part of the username.
+pub fn parse_mentions(text: &str) -> Vec<ParsedMention<'_>> {
+    let is_username_char = |c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '.');
+
+    let mut mentions = vec![];
+    let mut previous: Option<char> = None;
+    for (offset, c) in text.char_indices() {
+        let glued = previous.is_some_and(is_username_char);
+        previous = Some(c);
+        if c != '@' || glued {
+            continue;
+        }
+
+        let rest = &text[offset + 1..];
+        let end = rest.find(|c| !is_username_char(c)).unwrap_or(rest.len());
+        let username = rest[..end].trim_end_matches(['.', '-']);
+        if !username.is_empty() {
+            mentions.push(ParsedMention {
+                username,
+                offset,
+                length: username.len() + 1,
+            });
+        }
+    }
+
+    mentions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_mentions, Mention, ParsedMention};
+    use crate::entities::{username, Message};
+    use rstest::rstest;
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    #[rstest]
+    #[case::none("no mentions here", &[])]
+    #[case::single("@alice hi", &[("alice", 0)])]
+    #[case::multiple("@alice and @bob_2", &[("alice", 0), ("bob_2", 11)])]
+    #[case::punctuation("thanks, @alice.", &[("alice", 8)])]
+    #[case::email("mail me at alice@example.com", &[])]
+    #[case::lone_at("@ @ @", &[])]
+    #[case::unicode("привет, @мария!", &[("мария", 14)])]
+    fn parsing(#[case] text: &str, #[case] expected: &[(&str, usize)]) {
+        let expected: Vec<ParsedMention> = expected
+            .iter()
+            .map(|&(username, offset)| ParsedMention {
+                username,
+                offset,
+                length: username.len() + 1,
+            })
+            .collect();
+
+        let mentions = parse_mentions(text);
+        assert_eq!(mentions, expected);
+        for mention in mentions {
+            let slice = &text[mention.offset..mention.offset + mention.length];
+            assert_eq!(slice, format!("@{}", mention.username));
+        }
+    }
+
+    #[test]
+    fn resolving_compares_canonical_usernames() {
+        let (alice, maria) = (Uuid::new_v4(), Uuid::new_v4());
+        let members = HashMap::from([
+            (username::canonicalize("alice"), alice),
+            (username::canonicalize("мария"), maria),
+        ]);
+        let message = Message::new("@ALICE, @Мария and @bob", alice, Uuid::new_v4());
+
+        let mentioned: Vec<Uuid> = Mention::resolve_among(&message, &members)
+            .into_iter()
+            .map(|mention| mention.user_uuid)
+            .collect();
+        assert_eq!(mentioned, [alice, maria]);
+    }
+}
@@ -1,26 +1,40 @@
-use super::{ConversionError, Room, User};
+use super::{Attachment, ConversionError, Mention, MessageAttachment, ReadMarker, Room, User};
 use crate::auth::Authenticator;
+use crate::persistence::sql_types::{SqlOptionalTime, SqlTime, SqlUuid};
+use crate::persistence::Connection;
 use crate::proto::{ClientsideMessage, ServersideMessage};
 use diesel::prelude::*;
-use std::{fmt, str::FromStr, time::SystemTime};
+use std::time::{Duration, SystemTime};
+use std::{fmt, str::FromStr};
 use tonic::{Request, Status};
 use uuid::Uuid;
 
 #[derive(Queryable, Identifiable, Selectable, Insertable, Debug, Clone, Associations)]
 #[diesel(table_name = crate::entities::schema::messages)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(check_for_backend(crate::persistence::MultiBackend))]
 #[diesel(belongs_to(User, foreign_key = sender_uuid))]
 #[diesel(belongs_to(Room, foreign_key = room_uuid))]
 #[diesel(primary_key(uuid))]
 pub struct Message {
+    #[diesel(serialize_as = SqlUuid)]
     pub uuid: Uuid,
+    #[diesel(serialize_as = SqlUuid)]
     pub sender_uuid: Uuid,
+    #[diesel(serialize_as = SqlUuid)]
     pub room_uuid: Uuid,
     pub text: String,
+    #[diesel(serialize_as = SqlTime)]
     pub timestamp: SystemTime,
+    #[diesel(serialize_as = SqlOptionalTime)]
+    pub expires_at: Option<SystemTime>,
+    /// Assigned when the message is stored, see [`Message::store`].
+    pub sequence: i64,
 }
 
 impl Message {
+    /// The longest a self-destructing message may live.
+    pub const MAX_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
     pub fn new<T: Into<String>>(text: T, sender_uuid: Uuid, room_uuid: Uuid) -> Self {
         Self {
             uuid: Uuid::new_v4(),
@@ -28,6 +42,8 @@ impl Message {
             room_uuid,
             text: text.into(),
             timestamp: SystemTime::now(),
+            expires_at: None,
+            sequence: 0,
         }
     }
 
@@ -44,8 +60,182 @@ impl Message {
                 .ok_or(ConversionError::MissingField)?,
             text: msg.text,
             timestamp: SystemTime::now(),
+            expires_at: None,
+            sequence: 0,
+        })
+    }
+
+    /// Make the message expire after some time since it was sent.
+    #[must_use]
+    pub fn with_ttl(self, ttl: Option<Duration>) -> Self {
+        Self {
+            expires_at: ttl.map(|ttl| self.timestamp + ttl),
+            ..self
+        }
+    }
+
+    /// Delete a batch of messages that have expired by now, returning them.
+    ///
+    /// Read markers that point at deleted messages are moved back to the latest message
+    /// left in the room before them (or removed), so that unread counters stay correct.
+    pub fn delete_expired(
+        now: SystemTime,
+        limit: i64,
+        db_connection: &mut Connection,
+    ) -> QueryResult<Vec<Self>> {
+        use crate::entities::schema::{messages, read_markers};
+
+        db_connection.write_transaction(|conn| {
+            let expired_query = messages::table
+                .filter(messages::expires_at.le(SqlTime(now)))
+                .order_by(messages::expires_at)
+                .limit(limit);
+            // NOTE: Other instances skip the locked messages, and delete the next batch instead.
+            // SQLite has locked the whole database already.
+            let expired: Vec<Self> = match conn.as_postgres() {
+                Some(conn) => expired_query
+                    .select(Self::as_select())
+                    .for_update()
+                    .skip_locked()
+                    .load(conn)?,
+                None => expired_query.select(Self::as_select()).load(conn)?,
+            };
+            if expired.is_empty() {
+                return Ok(expired);
+            }
+
+            let expired_uuids: Vec<SqlUuid> = expired.iter().map(|m| SqlUuid(m.uuid)).collect();
+            let stale_markers: Vec<ReadMarker> = read_markers::table
+                .filter(read_markers::message_uuid.eq_any(&expired_uuids))
+                .select(ReadMarker::as_select())
+                .load(conn)?;
+
+            for marker in stale_markers {
+                let Some(marked) = expired.iter().find(|m| m.uuid == marker.message_uuid) else {
+                    continue;
+                };
+
+                let replacement: Option<(Uuid, i64)> = messages::table
+                    .filter(messages::room_uuid.eq(SqlUuid(marker.room_uuid)))
+                    .filter(messages::uuid.ne_all(&expired_uuids))
+                    .filter(messages::sequence.lt(marked.sequence))
+                    .order_by(messages::sequence.desc())
+                    .select((messages::uuid, messages::sequence))
+                    .first(conn)
+                    .optional()?;
+
+                let marker_row = read_markers::table
+                    .find((SqlUuid(marker.user_uuid), SqlUuid(marker.room_uuid)));
+                let _ = match replacement {
+                    Some((replacement_uuid, replacement_sequence)) => diesel::update(marker_row)
+                        .set((
+                            read_markers::message_uuid.eq(SqlUuid(replacement_uuid)),
+                            read_markers::message_sequence.eq(replacement_sequence),
+                        ))
+                        .execute(conn)?,
+                    None => diesel::delete(marker_row).execute(conn)?,
+                };
+            }
+
+            diesel::delete(messages::table.filter(messages::uuid.eq_any(&expired_uuids)))
+                .execute(conn)?;
+
+            Ok(expired)
+        })
+    }
+
+    /// Store the message along with links to its attachments and its mentions.
+    ///
+    /// The message gets the next sequence number in its room. Since the counter is
+    /// bumped in the same transaction, the room's row stays locked until it's committed,
+    /// so concurrent messages are numbered in the order they are stored, without gaps.
+    pub fn store(
+        &mut self,
+        attachment_uuids: &[Uuid],
+        mentions: &[Mention],
+        db_connection: &mut Connection,
+    ) -> QueryResult<()> {
+        use crate::entities::schema::{mentions, messages, messages_attachments, rooms};
+
+        let links: Vec<MessageAttachment> = attachment_uuids
+            .iter()
+            .map(|attachment_uuid| MessageAttachment {
+                message_uuid: self.uuid,
+                attachment_uuid: *attachment_uuid,
+            })
+            .collect();
+
+        db_connection.write_transaction(|conn| {
+            diesel::update(rooms::table.find(SqlUuid(self.room_uuid)))
+                .set(rooms::last_sequence.eq(rooms::last_sequence + 1))
+                .execute(conn)?;
+            self.sequence = rooms::table
+                .find(SqlUuid(self.room_uuid))
+                .select(rooms::last_sequence)
+                .first(conn)?;
+            diesel::insert_into(messages::table)
+                .values(self.clone())
+                .execute(conn)?;
+            // NOTE: Diesel can't batch inserts for both backends at once.
+            for link in &links {
+                diesel::insert_into(messages_attachments::table)
+                    .values(link.clone())
+                    .execute(conn)?;
+            }
+            for mention in mentions {
+                diesel::insert_into(mentions::table)
+                    .values(mention.clone())
+                    .execute(conn)?;
+            }
+            Ok(())
         })
     }
+
+    /// Load the attachments and mentions of messages.
+    pub fn hydrate(
+        messages: Vec<Self>,
+        db_connection: &mut Connection,
+    ) -> QueryResult<Vec<HydratedMessage>> {
+        use crate::entities::schema::{attachments, mentions, messages_attachments};
+
+        let message_uuids: Vec<SqlUuid> = messages.iter().map(|m| SqlUuid(m.uuid)).collect();
+        let attachments: Vec<(MessageAttachment, Attachment)> = messages_attachments::table
+            .filter(messages_attachments::message_uuid.eq_any(&message_uuids))
+            .inner_join(attachments::table)
+            .select((MessageAttachment::as_select(), Attachment::as_select()))
+            .load(db_connection)?;
+
+        let mentions: Vec<Mention> = mentions::table
+            .filter(mentions::message_uuid.eq_any(&message_uuids))
+            .select(Mention::as_select())
+            .order_by(mentions::byte_offset)
+            .load(db_connection)?;
+
+        let hydrated_messages = attachments
+            .grouped_by(&messages)
+            .into_iter()
+            .zip(mentions.grouped_by(&messages))
+            .zip(messages)
+            .map(|((attachments, mentions), message)| HydratedMessage {
+                message,
+                attachments: attachments
+                    .into_iter()
+                    .map(|(_, attachment)| attachment)
+                    .collect(),
+                mentions,
+            })
+            .collect();
+
+        Ok(hydrated_messages)
+    }
+}
+
+/// A message along with its attachments and mentions, ordered by their offsets.
+#[derive(Debug, Clone)]
+pub struct HydratedMessage {
+    pub message: Message,
+    pub attachments: Vec<Attachment>,
+    pub mentions: Vec<Mention>,
 }
 
 impl fmt::Display for Message {
@@ -81,6 +271,20 @@ impl From<Message> for ServersideMessage {
             room_uuid: Some(msg.room_uuid.into()),
             text: msg.text,
             timestamp: Some(msg.timestamp.into()),
+            attachments: vec![],
+            mentions: vec![],
+            expires_at: msg.expires_at.map(Into::into),
+            sequence: msg.sequence.try_into().unwrap_or_default(),
+        }
+    }
+}
+
+impl From<HydratedMessage> for ServersideMessage {
+    fn from(hydrated: HydratedMessage) -> Self {
+        Self {
+            attachments: hydrated.attachments.into_iter().map(Into::into).collect(),
+            mentions: hydrated.mentions.into_iter().map(Into::into).collect(),
+            ..Self::from(hydrated.message)
         }
     }
 }
@@ -92,6 +296,8 @@ mod tests {
     use crate::auth::Authenticator;
     use crate::proto::{self, ClientsideMessage, ServersideMessage};
     use rstest::rstest;
+    use std::time::Duration;
+    use uuid::Uuid;
 
     /// Clients get their own messages echoed back in orded to be displayed,
     /// So each message essentially goes though being a [`ClientsideMessage`],
@@ -105,6 +311,10 @@ mod tests {
         let clientside_message = ClientsideMessage {
             text: text.clone(),
             room_uuid: Some(proto::Uuid { uuid: uuid.into() }),
+            attachment_uuids: vec![],
+            deliver_at: None,
+            ttl_seconds: 0,
+            idempotency_key: String::new(),
         };
 
         let mut request = tonic::Request::new(clientside_message);
@@ -129,7 +339,23 @@ mod tests {
                 room_uuid: Some(uuid.into()),
                 timestamp: Some(timestamp.into()),
                 text,
+                attachments: vec![],
+                mentions: vec![],
+                expires_at: None,
+                sequence: 0,
             }
         );
     }
+
+    #[rstest]
+    #[case::forever(None)]
+    #[case::minute(Some(Duration::from_secs(60)))]
+    fn expiry(#[case] ttl: Option<Duration>) {
+        let message = Message::new("self-destruct", Uuid::new_v4(), Uuid::new_v4()).with_ttl(ttl);
+        let expires_at = ttl.map(|ttl| message.timestamp + ttl);
+        assert_eq!(message.expires_at, expires_at);
+
+        let serverside_message = ServersideMessage::from(message);
+        assert_eq!(serverside_message.expires_at, expires_at.map(Into::into));
+    }
 }
@@ -1,15 +1,32 @@
 pub mod schema;
 
+pub mod attachment;
+pub mod idempotency_key;
+pub mod mention;
 pub mod message;
+pub mod pin;
+pub mod read_marker;
 pub mod relations;
 pub mod room;
+pub mod scheduled_message;
+pub mod search;
+pub mod subscription;
 pub mod token;
 pub mod user;
+pub mod username;
 pub mod uuid;
 
-pub use message::Message;
-pub use relations::RoomUser;
+pub use attachment::Attachment;
+pub use idempotency_key::IdempotencyKey;
+pub use mention::Mention;
+pub use message::{HydratedMessage, Message};
+pub use pin::MessagePin;
+pub use read_marker::ReadMarker;
+pub use relations::{MessageAttachment, RoomUser};
 pub use room::Room;
+pub use scheduled_message::ScheduledMessage;
+pub use search::MessageSearch;
+pub use subscription::{ResumeCursor, RoomSubscription};
 pub use token::AuthToken;
 pub use user::User;
 
@@ -17,4 +34,6 @@ pub use user::User;
 pub enum ConversionError {
     #[error("The protobuf entity is missing a required field")]
     MissingField,
+    #[error("The protobuf entity has an invalid `{0}` field")]
+    InvalidField(&'static str),
 }
@@ -0,0 +1,47 @@
+use super::{Message, Room, User};
+use crate::persistence::sql_types::{SqlTime, SqlUuid};
+use crate::proto::{PinnedMessage, ServersideMessage};
+use diesel::prelude::*;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// A message pinned in a room by one of its members.
+#[derive(Queryable, Identifiable, Selectable, Insertable, Associations, Debug, Clone)]
+#[diesel(table_name = crate::entities::schema::pins)]
+#[diesel(check_for_backend(crate::persistence::MultiBackend))]
+#[diesel(belongs_to(Message, foreign_key = message_uuid))]
+#[diesel(belongs_to(Room, foreign_key = room_uuid))]
+#[diesel(belongs_to(User, foreign_key = pinner_uuid))]
+#[diesel(primary_key(message_uuid))]
+pub struct MessagePin {
+    #[diesel(serialize_as = SqlUuid)]
+    pub message_uuid: Uuid,
+    #[diesel(serialize_as = SqlUuid)]
+    pub room_uuid: Uuid,
+    #[diesel(serialize_as = SqlUuid)]
+    pub pinner_uuid: Uuid,
+    #[diesel(serialize_as = SqlTime)]
+    pub timestamp: SystemTime,
+}
+
+impl MessagePin {
+    pub const MAX_PINS_PER_ROOM: usize = 50;
+
+    pub fn new(pinner_uuid: Uuid, message: &Message) -> Self {
+        Self {
+            message_uuid: message.uuid,
+            room_uuid: message.room_uuid,
+            pinner_uuid,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    /// Combine the pin with an already converted message it points to.
+    pub fn into_pinned_message(self, message: ServersideMessage) -> PinnedMessage {
+        PinnedMessage {
+            message: Some(message),
+            pinner_uuid: Some(self.pinner_uuid.into()),
+            timestamp: Some(self.timestamp.into()),
+        }
+    }
+}
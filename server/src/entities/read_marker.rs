@@ -0,0 +1,50 @@
+use super::{Message, Room, User};
+use crate::persistence::sql_types::{SqlTime, SqlUuid};
+use crate::proto::ReadReceipt;
+use diesel::prelude::*;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// The last message a user has read in a certain room.
+#[derive(
+    Queryable, Identifiable, Selectable, Insertable, AsChangeset, Associations, Debug, Clone,
+)]
+#[diesel(table_name = crate::entities::schema::read_markers)]
+#[diesel(check_for_backend(crate::persistence::MultiBackend))]
+#[diesel(belongs_to(User, foreign_key = user_uuid))]
+#[diesel(belongs_to(Room, foreign_key = room_uuid))]
+#[diesel(belongs_to(Message, foreign_key = message_uuid))]
+#[diesel(primary_key(user_uuid, room_uuid))]
+pub struct ReadMarker {
+    #[diesel(serialize_as = SqlUuid)]
+    pub user_uuid: Uuid,
+    #[diesel(serialize_as = SqlUuid)]
+    pub room_uuid: Uuid,
+    #[diesel(serialize_as = SqlUuid)]
+    pub message_uuid: Uuid,
+    #[diesel(serialize_as = SqlTime)]
+    pub timestamp: SystemTime,
+    /// The sequence number of the message, which markers are compared by.
+    pub message_sequence: i64,
+}
+
+impl ReadMarker {
+    pub fn new(user_uuid: Uuid, message: &Message) -> Self {
+        Self {
+            user_uuid,
+            room_uuid: message.room_uuid,
+            message_uuid: message.uuid,
+            timestamp: SystemTime::now(),
+            message_sequence: message.sequence,
+        }
+    }
+}
+
+impl From<ReadMarker> for ReadReceipt {
+    fn from(marker: ReadMarker) -> Self {
+        Self {
+            user_uuid: Some(marker.user_uuid.into()),
+            message_uuid: Some(marker.message_uuid.into()),
+        }
+    }
+}
@@ -1,14 +1,57 @@
-use super::{Room, User};
+use super::{Attachment, Message, Room, User};
+use crate::persistence::sql_types::SqlUuid;
+use crate::persistence::Connection;
 use diesel::prelude::*;
 use uuid::Uuid;
 
 #[derive(Queryable, Identifiable, Selectable, Insertable, Associations, Debug, Clone)]
 #[diesel(table_name = crate::entities::schema::rooms_users)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(check_for_backend(crate::persistence::MultiBackend))]
 #[diesel(belongs_to(Room, foreign_key = room_uuid))]
 #[diesel(belongs_to(User, foreign_key = user_uuid))]
 #[diesel(primary_key(room_uuid, user_uuid))]
 pub struct RoomUser {
+    #[diesel(serialize_as = SqlUuid)]
     pub room_uuid: Uuid,
+    #[diesel(serialize_as = SqlUuid)]
     pub user_uuid: Uuid,
 }
+
+impl RoomUser {
+    /// List the rooms a user is a member of.
+    pub fn rooms_of(user_uuid: &Uuid, db_connection: &mut Connection) -> QueryResult<Vec<Uuid>> {
+        use crate::entities::schema::rooms_users;
+
+        rooms_users::table
+            .filter(rooms_users::user_uuid.eq(SqlUuid(*user_uuid)))
+            .select(rooms_users::room_uuid)
+            .load(db_connection)
+    }
+
+    /// Check whether a user is a member of a room.
+    pub fn exists(
+        room_uuid: &Uuid,
+        user_uuid: &Uuid,
+        db_connection: &mut Connection,
+    ) -> QueryResult<bool> {
+        use crate::entities::schema::rooms_users;
+
+        diesel::select(diesel::dsl::exists(
+            rooms_users::table.find((SqlUuid(*room_uuid), SqlUuid(*user_uuid))),
+        ))
+        .get_result(db_connection)
+    }
+}
+
+#[derive(Queryable, Identifiable, Selectable, Insertable, Associations, Debug, Clone)]
+#[diesel(table_name = crate::entities::schema::messages_attachments)]
+#[diesel(check_for_backend(crate::persistence::MultiBackend))]
+#[diesel(belongs_to(Message, foreign_key = message_uuid))]
+#[diesel(belongs_to(Attachment, foreign_key = attachment_uuid))]
+#[diesel(primary_key(message_uuid, attachment_uuid))]
+pub struct MessageAttachment {
+    #[diesel(serialize_as = SqlUuid)]
+    pub message_uuid: Uuid,
+    #[diesel(serialize_as = SqlUuid)]
+    pub attachment_uuid: Uuid,
+}
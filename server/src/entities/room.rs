@@ -1,17 +1,21 @@
-use crate::persistence::Connection;
+use crate::persistence::sql_types::SqlUuid;
 use crate::proto::ServersideRoom;
 use diesel::prelude::*;
-use diesel::r2d2::{ConnectionManager, PooledConnection};
 use std::fmt;
+use std::time::Duration;
 use uuid::Uuid;
 
 #[derive(Queryable, Identifiable, Selectable, Insertable, Debug, Clone)]
 #[diesel(table_name = crate::entities::schema::rooms)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(check_for_backend(crate::persistence::MultiBackend))]
 #[diesel(primary_key(uuid))]
 pub struct Room {
+    #[diesel(serialize_as = SqlUuid)]
     pub uuid: Uuid,
     pub name: String,
+    pub message_ttl_seconds: Option<i32>,
+    /// The sequence number of the last message sent to this room.
+    pub last_sequence: i64,
 }
 
 impl Room {
@@ -19,37 +23,50 @@ impl Room {
         Self {
             uuid: Uuid::new_v4(),
             name: name.into(),
+            message_ttl_seconds: None,
+            last_sequence: 0,
         }
     }
 
-    pub async fn get_members(
-        &self,
-        db_connection: &mut PooledConnection<ConnectionManager<Connection>>,
-    ) -> Vec<Uuid> {
-        use crate::entities::schema::rooms_users::dsl::*;
-        use diesel::prelude::*;
-
-        let members: Vec<Uuid> = rooms_users
-            .filter(room_uuid.eq(self.uuid))
-            .select(user_uuid)
-            .load(db_connection)
-            .unwrap_or_else(|error| {
-                tracing::error!(message = "Couldn't fetch membership from database", ?error);
-                vec![]
-            });
-
-        members
+    /// The default TTL of new messages in this room.
+    pub fn message_ttl(&self) -> Option<Duration> {
+        self.message_ttl_seconds
+            .and_then(|seconds| u64::try_from(seconds).ok())
+            .map(Duration::from_secs)
     }
 }
 
 impl fmt::Display for ServersideRoom {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{} ({})",
-            self.name,
-            self.uuid.clone().unwrap_or_default().uuid
-        )?;
+        write!(f, "{}", self.name)?;
+        if self.unread_count > 0 {
+            write!(f, " [{} unread]", self.unread_count)?;
+        }
+        write!(f, " ({})", self.uuid.clone().unwrap_or_default().uuid)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::ServersideRoom;
+    use rstest::rstest;
+    use uuid::Uuid;
+
+    /// The CLI picks the room's UUID out of the last word of this
+    /// representation, so the unread counter must never come after it.
+    #[rstest]
+    #[case::read(0, "room")]
+    #[case::unread(3, "room [3 unread]")]
+    fn display(#[case] unread_count: u64, #[case] prefix: &str) {
+        let uuid = Uuid::new_v4();
+        let room = ServersideRoom {
+            uuid: Some(uuid.into()),
+            name: "room".into(),
+            unread_count,
+            ..Default::default()
+        };
+
+        assert_eq!(room.to_string(), format!("{prefix} ({uuid})"));
+    }
+}
@@ -0,0 +1,129 @@
+use super::{Message, Room, User};
+use crate::persistence::sql_types::{SqlTime, SqlUuid, SqlUuids};
+use crate::proto;
+use diesel::prelude::*;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// A message that's held back until its delivery time comes.
+///
+/// Once delivered, it becomes a regular [`Message`] with the same UUID.
+#[derive(Queryable, Identifiable, Selectable, Insertable, Associations, Debug, Clone)]
+#[diesel(table_name = crate::entities::schema::scheduled_messages)]
+#[diesel(check_for_backend(crate::persistence::MultiBackend))]
+#[diesel(belongs_to(User, foreign_key = sender_uuid))]
+#[diesel(belongs_to(Room, foreign_key = room_uuid))]
+#[diesel(primary_key(uuid))]
+pub struct ScheduledMessage {
+    #[diesel(serialize_as = SqlUuid)]
+    pub uuid: Uuid,
+    #[diesel(serialize_as = SqlUuid)]
+    pub sender_uuid: Uuid,
+    #[diesel(serialize_as = SqlUuid)]
+    pub room_uuid: Uuid,
+    pub text: String,
+    #[diesel(serialize_as = SqlUuids)]
+    pub attachment_uuids: Vec<Uuid>,
+    #[diesel(serialize_as = SqlTime)]
+    pub deliver_at: SystemTime,
+    #[diesel(serialize_as = SqlTime)]
+    pub created_at: SystemTime,
+    pub ttl_seconds: Option<i32>,
+}
+
+impl ScheduledMessage {
+    /// How many messages a single user may have waiting for delivery.
+    pub const MAX_PENDING_PER_USER: i64 = 100;
+
+    /// How far into the future a message may be scheduled.
+    pub const MAX_DELAY: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+    pub fn new(
+        message: Message,
+        attachment_uuids: Vec<Uuid>,
+        deliver_at: SystemTime,
+        ttl: Option<Duration>,
+    ) -> Self {
+        Self {
+            uuid: message.uuid,
+            sender_uuid: message.sender_uuid,
+            room_uuid: message.room_uuid,
+            text: message.text,
+            attachment_uuids,
+            deliver_at,
+            created_at: message.timestamp,
+            ttl_seconds: ttl.and_then(|ttl| ttl.as_secs().try_into().ok()),
+        }
+    }
+
+    /// Turn into a regular message, timestamped with the actual delivery time.
+    pub fn into_message(self, delivered_at: SystemTime) -> (Message, Vec<Uuid>) {
+        let ttl = self
+            .ttl_seconds
+            .and_then(|seconds| u64::try_from(seconds).ok())
+            .map(Duration::from_secs);
+        let message = Message {
+            uuid: self.uuid,
+            sender_uuid: self.sender_uuid,
+            room_uuid: self.room_uuid,
+            text: self.text,
+            timestamp: delivered_at,
+            expires_at: None,
+            sequence: 0,
+        }
+        .with_ttl(ttl);
+
+        (message, self.attachment_uuids)
+    }
+}
+
+impl From<ScheduledMessage> for proto::ScheduledMessage {
+    fn from(message: ScheduledMessage) -> Self {
+        Self {
+            uuid: Some(message.uuid.into()),
+            room_uuid: Some(message.room_uuid.into()),
+            text: message.text,
+            attachment_uuids: message
+                .attachment_uuids
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            deliver_at: Some(message.deliver_at.into()),
+            created_at: Some(message.created_at.into()),
+            ttl_seconds: message
+                .ttl_seconds
+                .and_then(|seconds| seconds.try_into().ok())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ScheduledMessage;
+    use crate::entities::Message;
+    use std::time::{Duration, SystemTime};
+    use uuid::Uuid;
+
+    #[test]
+    fn delivery_keeps_identity() {
+        let message = Message::new("deploy at noon", Uuid::new_v4(), Uuid::new_v4());
+        let (message_uuid, text) = (message.uuid, message.text.clone());
+        let attachment_uuids = vec![Uuid::new_v4()];
+        let deliver_at = SystemTime::now() + Duration::from_secs(60);
+
+        let ttl = Duration::from_secs(30);
+
+        let scheduled =
+            ScheduledMessage::new(message, attachment_uuids.clone(), deliver_at, Some(ttl));
+        let delivered_at = deliver_at + Duration::from_secs(1);
+        let (delivered, delivered_attachments) = scheduled.into_message(delivered_at);
+
+        assert_eq!(delivered.uuid, message_uuid);
+        assert_eq!(delivered.text, text);
+        assert_eq!(delivered.timestamp, delivered_at);
+        // The countdown only starts once the message is delivered.
+        assert_eq!(delivered.expires_at, Some(delivered_at + ttl));
+        assert_eq!(delivered_attachments, attachment_uuids);
+    }
+}
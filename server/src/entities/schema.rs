@@ -1,24 +1,117 @@
 // @generated automatically by Diesel CLI.
 
 diesel::table! {
+    use crate::persistence::sql_types::*;
+    use diesel_full_text_search::Tsvector;
+
+    attachments (uuid) {
+        uuid -> Uuid,
+        room_uuid -> Uuid,
+        uploader_uuid -> Uuid,
+        #[max_length = 64]
+        hash -> Bpchar,
+        #[max_length = 256]
+        filename -> Varchar,
+        #[max_length = 128]
+        mime_type -> Varchar,
+        size -> Int8,
+        timestamp -> Timestamp,
+    }
+}
+
+diesel::table! {
+    use crate::persistence::sql_types::*;
+    use diesel_full_text_search::Tsvector;
+
+    idempotency_keys (sender_uuid, key) {
+        sender_uuid -> Uuid,
+        #[max_length = 64]
+        key -> Varchar,
+        message_uuid -> Uuid,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    use crate::persistence::sql_types::*;
+    use diesel_full_text_search::Tsvector;
+
+    mentions (message_uuid, byte_offset) {
+        message_uuid -> Uuid,
+        user_uuid -> Uuid,
+        byte_offset -> Int4,
+        byte_length -> Int4,
+    }
+}
+
+diesel::table! {
+    use crate::persistence::sql_types::*;
+    use diesel_full_text_search::Tsvector;
+
     messages (uuid) {
         uuid -> Uuid,
         sender_uuid -> Uuid,
         room_uuid -> Uuid,
         text -> Text,
         timestamp -> Timestamp,
+        text_search -> Tsvector,
+        expires_at -> Nullable<Timestamp>,
+        sequence -> Int8,
+    }
+}
+
+diesel::table! {
+    use crate::persistence::sql_types::*;
+    use diesel_full_text_search::Tsvector;
+
+    messages_attachments (message_uuid, attachment_uuid) {
+        message_uuid -> Uuid,
+        attachment_uuid -> Uuid,
+    }
+}
+
+diesel::table! {
+    use crate::persistence::sql_types::*;
+    use diesel_full_text_search::Tsvector;
+
+    pins (message_uuid) {
+        message_uuid -> Uuid,
+        room_uuid -> Uuid,
+        pinner_uuid -> Uuid,
+        timestamp -> Timestamp,
+    }
+}
+
+diesel::table! {
+    use crate::persistence::sql_types::*;
+    use diesel_full_text_search::Tsvector;
+
+    read_markers (user_uuid, room_uuid) {
+        user_uuid -> Uuid,
+        room_uuid -> Uuid,
+        message_uuid -> Uuid,
+        timestamp -> Timestamp,
+        message_sequence -> Int8,
     }
 }
 
 diesel::table! {
+    use crate::persistence::sql_types::*;
+    use diesel_full_text_search::Tsvector;
+
     rooms (uuid) {
         uuid -> Uuid,
         #[max_length = 64]
         name -> Varchar,
+        message_ttl_seconds -> Nullable<Int4>,
+        last_sequence -> Int8,
     }
 }
 
 diesel::table! {
+    use crate::persistence::sql_types::*;
+    use diesel_full_text_search::Tsvector;
+
     rooms_users (room_uuid, user_uuid) {
         room_uuid -> Uuid,
         user_uuid -> Uuid,
@@ -26,6 +119,25 @@ diesel::table! {
 }
 
 diesel::table! {
+    use crate::persistence::sql_types::*;
+    use diesel_full_text_search::Tsvector;
+
+    scheduled_messages (uuid) {
+        uuid -> Uuid,
+        sender_uuid -> Uuid,
+        room_uuid -> Uuid,
+        text -> Text,
+        attachment_uuids -> Array<Uuid>,
+        deliver_at -> Timestamp,
+        created_at -> Timestamp,
+        ttl_seconds -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    use crate::persistence::sql_types::*;
+    use diesel_full_text_search::Tsvector;
+
     users (uuid) {
         uuid -> Uuid,
         #[max_length = 64]
@@ -34,12 +146,41 @@ diesel::table! {
         password -> Varchar,
         #[max_length = 32]
         auth_token -> Bpchar,
+        last_seen -> Nullable<Timestamp>,
+        canonical_username -> Text,
     }
 }
 
+diesel::joinable!(attachments -> rooms (room_uuid));
+diesel::joinable!(attachments -> users (uploader_uuid));
+diesel::joinable!(idempotency_keys -> users (sender_uuid));
+diesel::joinable!(mentions -> messages (message_uuid));
+diesel::joinable!(mentions -> users (user_uuid));
 diesel::joinable!(messages -> rooms (room_uuid));
 diesel::joinable!(messages -> users (sender_uuid));
+diesel::joinable!(messages_attachments -> attachments (attachment_uuid));
+diesel::joinable!(messages_attachments -> messages (message_uuid));
+diesel::joinable!(pins -> messages (message_uuid));
+diesel::joinable!(pins -> rooms (room_uuid));
+diesel::joinable!(pins -> users (pinner_uuid));
+diesel::joinable!(read_markers -> messages (message_uuid));
+diesel::joinable!(read_markers -> rooms (room_uuid));
+diesel::joinable!(read_markers -> users (user_uuid));
 diesel::joinable!(rooms_users -> rooms (room_uuid));
 diesel::joinable!(rooms_users -> users (user_uuid));
+diesel::joinable!(scheduled_messages -> rooms (room_uuid));
+diesel::joinable!(scheduled_messages -> users (sender_uuid));
 
-diesel::allow_tables_to_appear_in_same_query!(messages, rooms, rooms_users, users,);
+diesel::allow_tables_to_appear_in_same_query!(
+    attachments,
+    idempotency_keys,
+    mentions,
+    messages,
+    messages_attachments,
+    pins,
+    read_markers,
+    rooms,
+    rooms_users,
+    scheduled_messages,
+    users,
+);
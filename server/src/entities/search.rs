@@ -0,0 +1,115 @@
+use super::ConversionError;
+use crate::proto::MessageSearchRequest;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// A validated full-text search request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageSearch {
+    pub query: String,
+    pub room_uuid: Option<Uuid>,
+    pub sender_uuid: Option<Uuid>,
+    pub sent_after: Option<SystemTime>,
+    pub sent_before: Option<SystemTime>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+impl MessageSearch {
+    pub const DEFAULT_LIMIT: i64 = 20;
+    pub const MAX_LIMIT: i64 = 100;
+}
+
+impl TryFrom<MessageSearchRequest> for MessageSearch {
+    type Error = ConversionError;
+
+    fn try_from(request: MessageSearchRequest) -> Result<Self, Self::Error> {
+        let query = request.query.trim().to_string();
+        if query.is_empty() {
+            return Err(ConversionError::InvalidField("query"));
+        }
+
+        let room_uuid = request
+            .room_uuid
+            .map(Uuid::try_from)
+            .transpose()
+            .map_err(|_| ConversionError::InvalidField("room_uuid"))?;
+        let sender_uuid = request
+            .sender_uuid
+            .map(Uuid::try_from)
+            .transpose()
+            .map_err(|_| ConversionError::InvalidField("sender_uuid"))?;
+        let sent_after = request
+            .sent_after
+            .map(SystemTime::try_from)
+            .transpose()
+            .map_err(|_| ConversionError::InvalidField("sent_after"))?;
+        let sent_before = request
+            .sent_before
+            .map(SystemTime::try_from)
+            .transpose()
+            .map_err(|_| ConversionError::InvalidField("sent_before"))?;
+
+        if let (Some(after), Some(before)) = (sent_after, sent_before) {
+            if after > before {
+                return Err(ConversionError::InvalidField("sent_after"));
+            }
+        }
+
+        let limit = match i64::from(request.limit) {
+            0 => Self::DEFAULT_LIMIT,
+            limit => limit.min(Self::MAX_LIMIT),
+        };
+
+        Ok(Self {
+            query,
+            room_uuid,
+            sender_uuid,
+            sent_after,
+            sent_before,
+            limit,
+            offset: request.offset.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::MessageSearch;
+    use crate::proto::{self, MessageSearchRequest};
+    use rstest::rstest;
+    use std::time::{Duration, SystemTime};
+
+    #[rstest]
+    #[case::default(0, MessageSearch::DEFAULT_LIMIT)]
+    #[case::custom(5, 5)]
+    #[case::clamped(100_000, MessageSearch::MAX_LIMIT)]
+    fn limit(#[case] requested: u32, #[case] expected: i64) {
+        let request = MessageSearchRequest {
+            query: "deploy".into(),
+            limit: requested,
+            ..Default::default()
+        };
+
+        let search = MessageSearch::try_from(request).unwrap();
+        assert_eq!(search.limit, expected);
+    }
+
+    #[rstest]
+    #[case::empty_query(MessageSearchRequest { query: "  ".into(), ..Default::default() })]
+    #[case::malformed_room(MessageSearchRequest {
+        query: "deploy".into(),
+        room_uuid: Some(proto::Uuid { uuid: "nope".into() }),
+        ..Default::default()
+    })]
+    #[case::inverted_range(MessageSearchRequest {
+        query: "deploy".into(),
+        sent_after: Some(SystemTime::now().into()),
+        sent_before: Some((SystemTime::now() - Duration::from_secs(60)).into()),
+        ..Default::default()
+    })]
+    fn invalid(#[case] request: MessageSearchRequest) {
+        assert!(MessageSearch::try_from(request).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use super::{ConversionError, Message};
+use crate::persistence::sql_types::{SqlTime, SqlUuid};
+use crate::persistence::Connection;
+use crate::proto::room_subscription_request::ResumeAfter;
+use crate::proto::RoomSubscriptionRequest;
+use diesel::prelude::*;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// The point in a room's history a subscriber has seen everything up to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResumeCursor {
+    Message(Uuid),
+    Timestamp(SystemTime),
+    Sequence(i64),
+}
+
+/// A validated room subscription request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomSubscription {
+    pub room_uuid: Uuid,
+    pub resume_after: Option<ResumeCursor>,
+}
+
+impl RoomSubscription {
+    /// How many missed messages may be replayed before the client is told to list them instead.
+    pub const MAX_REPLAY: usize = 1000;
+
+    /// Load the messages the subscriber has missed, oldest first.
+    ///
+    /// At most one message more than [`Self::MAX_REPLAY`] is loaded, so that the
+    /// caller can tell whether there are too many of them.
+    ///
+    /// Returns [`diesel::result::Error::NotFound`] if the cursor points at a message
+    /// that's not in the room (anymore), since nothing can be replayed reliably then.
+    pub fn missed_messages(
+        &self,
+        now: SystemTime,
+        db_connection: &mut Connection,
+    ) -> QueryResult<Vec<Message>> {
+        use crate::entities::schema::messages;
+
+        let Some(cursor) = self.resume_after else {
+            return Ok(vec![]);
+        };
+
+        let mut query = messages::table
+            .filter(messages::room_uuid.eq(SqlUuid(self.room_uuid)))
+            .filter(
+                messages::expires_at
+                    .is_null()
+                    .or(messages::expires_at.gt(SqlTime(now))),
+            )
+            .into_boxed();
+        query = match cursor {
+            ResumeCursor::Message(message_uuid) => {
+                let seen_sequence: i64 = messages::table
+                    .find(SqlUuid(message_uuid))
+                    .filter(messages::room_uuid.eq(SqlUuid(self.room_uuid)))
+                    .select(messages::sequence)
+                    .first(db_connection)?;
+                query.filter(messages::sequence.gt(seen_sequence))
+            }
+            ResumeCursor::Timestamp(timestamp) => {
+                query.filter(messages::timestamp.gt(SqlTime(timestamp)))
+            }
+            ResumeCursor::Sequence(sequence) => query.filter(messages::sequence.gt(sequence)),
+        };
+
+        query
+            .order_by(messages::sequence)
+            .limit(Self::MAX_REPLAY as i64 + 1)
+            .select(Message::as_select())
+            .load(db_connection)
+    }
+}
+
+impl TryFrom<RoomSubscriptionRequest> for RoomSubscription {
+    type Error = ConversionError;
+
+    fn try_from(request: RoomSubscriptionRequest) -> Result<Self, Self::Error> {
+        let room_uuid = request
+            .room_uuid
+            .ok_or(ConversionError::MissingField)?
+            .try_into()
+            .map_err(|_| ConversionError::InvalidField("room_uuid"))?;
+
+        let resume_after = match request.resume_after {
+            None => None,
+            Some(ResumeAfter::MessageUuid(message_uuid)) => {
+                Some(ResumeCursor::Message(message_uuid.try_into().map_err(
+                    |_| ConversionError::InvalidField("message_uuid"),
+                )?))
+            }
+            Some(ResumeAfter::Timestamp(timestamp)) => Some(ResumeCursor::Timestamp(
+                timestamp
+                    .try_into()
+                    .map_err(|_| ConversionError::InvalidField("timestamp"))?,
+            )),
+            Some(ResumeAfter::Sequence(sequence)) => Some(ResumeCursor::Sequence(
+                sequence
+                    .try_into()
+                    .map_err(|_| ConversionError::InvalidField("sequence"))?,
+            )),
+        };
+
+        Ok(Self {
+            room_uuid,
+            resume_after,
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{ResumeCursor, RoomSubscription};
+    use crate::proto::room_subscription_request::ResumeAfter;
+    use crate::proto::{self, RoomSubscriptionRequest};
+    use rstest::rstest;
+    use std::time::{Duration, SystemTime};
+    use uuid::Uuid;
+
+    #[test]
+    fn cursors() {
+        let room_uuid = Uuid::new_v4();
+        let message_uuid = Uuid::new_v4();
+        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1_716_000_000);
+
+        let cases = [
+            (None, None),
+            (
+                Some(ResumeAfter::MessageUuid(message_uuid.into())),
+                Some(ResumeCursor::Message(message_uuid)),
+            ),
+            (
+                Some(ResumeAfter::Timestamp(timestamp.into())),
+                Some(ResumeCursor::Timestamp(timestamp)),
+            ),
+            (
+                Some(ResumeAfter::Sequence(42)),
+                Some(ResumeCursor::Sequence(42)),
+            ),
+        ];
+
+        for (resume_after, expected) in cases {
+            let request = RoomSubscriptionRequest {
+                room_uuid: Some(room_uuid.into()),
+                resume_after,
+            };
+            let subscription = RoomSubscription::try_from(request).unwrap();
+            assert_eq!(subscription.room_uuid, room_uuid);
+            assert_eq!(subscription.resume_after, expected);
+        }
+    }
+
+    #[rstest]
+    #[case::missing_room(None, None)]
+    #[case::invalid_room(Some(proto::Uuid { uuid: "nope".into() }), None)]
+    #[case::invalid_message(
+        Some(Uuid::new_v4().into()),
+        Some(ResumeAfter::MessageUuid(proto::Uuid { uuid: "nope".into() }))
+    )]
+    #[case::sequence_overflow(Some(Uuid::new_v4().into()), Some(ResumeAfter::Sequence(u64::MAX)))]
+    fn invalid(#[case] room_uuid: Option<proto::Uuid>, #[case] resume_after: Option<ResumeAfter>) {
+        let request = RoomSubscriptionRequest {
+            room_uuid,
+            resume_after,
+        };
+        assert!(RoomSubscription::try_from(request).is_err());
+    }
+}
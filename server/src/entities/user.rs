@@ -1,28 +1,38 @@
 use crate::entities::token::AuthToken;
+use crate::entities::username;
+use crate::persistence::sql_types::{SqlOptionalTime, SqlUuid};
 use crate::proto::{self, AuthPair};
 use diesel::prelude::*;
 use rand_chacha::ChaCha20Rng;
 use std::str::FromStr;
+use std::time::SystemTime;
 use uuid::Uuid;
 
 #[derive(Queryable, Selectable, Insertable, Identifiable, Clone, Debug)]
 #[diesel(table_name = crate::entities::schema::users)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(check_for_backend(crate::persistence::MultiBackend))]
 #[diesel(primary_key(uuid))]
 pub struct User {
+    #[diesel(serialize_as = SqlUuid)]
     pub uuid: Uuid,
     pub username: String,
     pub password: String,
     pub auth_token: String,
+    #[diesel(serialize_as = SqlOptionalTime)]
+    pub last_seen: Option<SystemTime>,
+    /// The form the username is compared in, see [`username::canonicalize`].
+    pub canonical_username: String,
 }
 
 impl User {
     pub fn new(username: String, password: String, rng: &mut ChaCha20Rng) -> Self {
         Self {
             uuid: Uuid::new_v4(),
+            canonical_username: username::canonicalize(&username),
             username,
             password,
             auth_token: AuthToken::new(rng).to_string(),
+            last_seen: None,
         }
     }
 
@@ -0,0 +1,149 @@
+//! # Usernames
+//!
+//! A username is stored the way it was typed (give or take [normalisation](normalize)), but
+//! compared in its [canonical](canonicalize) form, so that names which only differ in case,
+//! width or lookalike letters (i.e. a Latin `a` and a Cyrillic `а`) belong to the same user.
+//! The canonical form is stored alongside, under a unique index.
+//!
+//! Only the common Cyrillic and Greek lookalikes of Latin letters are replaced, rather than
+//! the whole Unicode confusables table, and ASCII characters are never replaced by each other,
+//! so `l` and `I` are still told apart.
+
+use unicode_normalization::UnicodeNormalization;
+
+/// The longest a username may be, in characters.
+pub const MAX_LENGTH: usize = 64;
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsernameError {
+    #[error("The username is empty")]
+    Empty,
+    #[error("The username is longer than {MAX_LENGTH} characters")]
+    TooLong,
+    #[error("The username contains an invisible or control character: {0:?}")]
+    InvalidCharacter(char),
+}
+
+/// Turn a username as typed into the form it's stored in: NFKC-normalised, without
+/// surrounding whitespace. Control and invisible characters aren't allowed.
+///
+/// # Errors
+///
+/// Fails if the normalised username is empty, too long, or contains a disallowed character.
+pub fn normalize(username: &str) -> Result<String, UsernameError> {
+    let normalized: String = username.nfkc().collect();
+    let normalized = normalized.trim();
+
+    if let Some(invalid) = normalized
+        .chars()
+        .find(|&c| c.is_control() || is_invisible(c))
+    {
+        return Err(UsernameError::InvalidCharacter(invalid));
+    }
+    match normalized.chars().count() {
+        0 => Err(UsernameError::Empty),
+        length if length > MAX_LENGTH => Err(UsernameError::TooLong),
+        _ => Ok(normalized.to_string()),
+    }
+}
+
+/// Get the form usernames are compared in: NFKC-normalised, case folded, with
+/// lookalikes of Latin letters replaced by them, and invisible characters dropped.
+#[must_use]
+pub fn canonicalize(username: &str) -> String {
+    let mut folded = String::with_capacity(username.len());
+    for c in username.trim().nfkc().filter(|&c| !is_invisible(c)) {
+        match c {
+            'ß' | 'ẞ' => folded.push_str("ss"),
+            'ς' => folded.push('σ'),
+            c => folded.extend(c.to_lowercase()),
+        }
+    }
+    folded.chars().map(latin_lookalike).collect()
+}
+
+/// Characters that render as nothing, so two names that only differ in them look the same.
+const fn is_invisible(c: char) -> bool {
+    matches!(c, '\u{AD}' | '\u{200B}'..='\u{200F}' | '\u{2060}'..='\u{2064}' | '\u{FEFF}')
+}
+
+/// Replace a lowercase Cyrillic or Greek letter with the Latin one it looks like.
+///
+/// Letters whose uppercase forms are the lookalikes (like `в` of `В`) are included,
+/// since names are compared after case folding.
+const fn latin_lookalike(c: char) -> char {
+    match c {
+        // Cyrillic.
+        'а' => 'a',
+        'в' => 'b',
+        'с' => 'c',
+        'ԁ' => 'd',
+        'е' => 'e',
+        'н' | 'һ' => 'h',
+        'і' => 'i',
+        'ј' => 'j',
+        'к' => 'k',
+        'м' => 'm',
+        'о' => 'o',
+        'р' => 'p',
+        'ԛ' => 'q',
+        'ѕ' => 's',
+        'т' => 't',
+        'ԝ' => 'w',
+        'х' => 'x',
+        'у' => 'y',
+        // Greek.
+        'α' => 'a',
+        'β' => 'b',
+        'ε' => 'e',
+        'η' => 'h',
+        'ι' => 'i',
+        'κ' => 'k',
+        'μ' => 'm',
+        'ν' => 'v',
+        'ο' => 'o',
+        'ρ' => 'p',
+        'τ' => 't',
+        'υ' => 'u',
+        'χ' => 'x',
+        'ζ' => 'z',
+        c => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{canonicalize, normalize, UsernameError};
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::case("Alice", "alice")]
+    #[case::fullwidth("ａｌｉｃｅ", "alice")]
+    #[case::cyrillic("аlicе", "alice")]
+    #[case::greek_uppercase("ΑLΙCΕ", "alice")]
+    #[case::sharp_s("Straße", "strasse")]
+    #[case::invisible("al\u{200B}ice", "alice")]
+    #[case::surrounding_whitespace(" alice ", "alice")]
+    fn confusable_names_are_the_same(#[case] username: &str, #[case] canonical: &str) {
+        assert_eq!(canonicalize(username), canonical);
+    }
+
+    #[test]
+    fn distinct_names_stay_distinct() {
+        assert_ne!(canonicalize("alice"), canonicalize("bob"));
+        assert_ne!(canonicalize("Il"), canonicalize("ll"));
+        assert_eq!(canonicalize("Маша"), "maшa");
+    }
+
+    #[rstest]
+    #[case::kept("Alice", Ok("Alice".into()))]
+    #[case::trimmed("  Alice\t", Ok("Alice".into()))]
+    #[case::fullwidth("Ａｌｉｃｅ", Ok("Alice".into()))]
+    #[case::empty("   ", Err(UsernameError::Empty))]
+    #[case::too_long(&"a".repeat(65), Err(UsernameError::TooLong))]
+    #[case::control("ali\nce", Err(UsernameError::InvalidCharacter('\n')))]
+    #[case::invisible("al\u{200B}ice", Err(UsernameError::InvalidCharacter('\u{200B}')))]
+    fn normalization(#[case] username: &str, #[case] expected: Result<String, UsernameError>) {
+        assert_eq!(normalize(username), expected);
+    }
+}
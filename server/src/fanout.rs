@@ -0,0 +1,282 @@
+//! # Room fan-out
+//!
+//! Room events only concern the subscribers of that room, so instead of a single server-wide
+//! broadcast channel that every subscriber has to filter, each room that's being watched gets
+//! a channel of its own in a [`RoomChannels`] registry. A channel is created when the first
+//! subscriber of a room arrives and is dropped together with the last one, so publishing an
+//! event costs as much as the room has subscribers, and events for rooms that nobody is
+//! watching are discarded right away.
+//!
+//! See `benches/fanout.rs` for how this compares to the single-channel approach.
+
+use hashbrown::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
+use uuid::Uuid;
+
+/// A registry of per-room broadcast channels.
+///
+/// Each channel counts the events it has carried that `tallied` picks out, so that a receiver
+/// that lags behind can tell how many of them it missed (see [`RoomRecvError::Lagged`]).
+#[derive(Debug)]
+pub struct RoomChannels<T> {
+    capacity: usize,
+    tallied: fn(&T) -> bool,
+    channels: Mutex<HashMap<Uuid, Channel<T>>>,
+}
+
+#[derive(Debug)]
+struct Channel<T> {
+    sender: broadcast::Sender<Tallied<T>>,
+    /// How many tallied events have been sent so far.
+    tally: u64,
+}
+
+/// An event, along with the tally of its channel after it was sent.
+#[derive(Debug, Clone)]
+struct Tallied<T> {
+    event: T,
+    tally: u64,
+    counted: bool,
+}
+
+impl<T: Clone> RoomChannels<T> {
+    /// Create an empty registry, where each channel holds up to `capacity` events.
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self::tallying(capacity, |_| false)
+    }
+
+    /// Create an empty registry like [`Self::new`], which tallies the events `tallied` picks out.
+    #[must_use]
+    pub fn tallying(capacity: usize, tallied: fn(&T) -> bool) -> Self {
+        Self {
+            capacity,
+            tallied,
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Start receiving events of a room, creating its channel if needed.
+    #[must_use]
+    pub fn subscribe(self: &Arc<Self>, room_uuid: Uuid) -> RoomReceiver<T> {
+        let mut channels = self
+            .channels
+            .lock()
+            .expect("Room channel mutex was poisoned");
+        let channel = channels.entry(room_uuid).or_insert_with(|| Channel {
+            sender: broadcast::channel(self.capacity).0,
+            tally: 0,
+        });
+
+        RoomReceiver {
+            receiver: channel.sender.subscribe(),
+            tally: channel.tally,
+            held_back: None,
+            _registration: Registration {
+                room_uuid,
+                channels: Arc::clone(self),
+            },
+        }
+    }
+
+    /// Publish an event to the subscribers of a room.
+    ///
+    /// Returns how many subscribers the event was sent to.
+    pub fn send(&self, room_uuid: Uuid, event: T) -> usize {
+        // NOTE: The event is sent with the lock held, so that tallies grow in the order
+        // receivers see the events in.
+        let mut channels = self
+            .channels
+            .lock()
+            .expect("Room channel mutex was poisoned");
+        let Some(channel) = channels.get_mut(&room_uuid) else {
+            return 0;
+        };
+
+        let counted = (self.tallied)(&event);
+        channel.tally += u64::from(counted);
+        let tallied = Tallied {
+            event,
+            tally: channel.tally,
+            counted,
+        };
+        channel.sender.send(tallied).unwrap_or(0)
+    }
+
+    /// How many rooms currently have at least one subscriber.
+    #[must_use]
+    pub fn room_count(&self) -> usize {
+        self.channels
+            .lock()
+            .expect("Room channel mutex was poisoned")
+            .len()
+    }
+}
+
+/// Why a [`RoomReceiver`] couldn't receive an event.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomRecvError {
+    #[error("The room channel was closed")]
+    Closed,
+    /// The receiver fell behind, and the `skipped` oldest events it hadn't seen were
+    /// dropped for it. Out of those, `tallied` were picked out by the channel's tally.
+    #[error("Lagged behind by {skipped} events, {tallied} of them tallied")]
+    Lagged { skipped: u64, tallied: u64 },
+}
+
+/// A subscription to the events of a single room.
+///
+/// Dropping the last receiver of a room drops its channel as well.
+#[derive(Debug)]
+pub struct RoomReceiver<T: Clone> {
+    // NOTE: Fields are dropped in declaration order, so by the time the
+    // registration is dropped, the receiver is no longer counted.
+    receiver: broadcast::Receiver<Tallied<T>>,
+    /// The tally of the channel as of the last event received.
+    tally: u64,
+    /// The first event after a lag, which is returned after the lag has been reported.
+    held_back: Option<T>,
+    _registration: Registration<T>,
+}
+
+impl<T: Clone> RoomReceiver<T> {
+    /// Wait for the next event, see [`broadcast::Receiver::recv`].
+    ///
+    /// A lag is only reported once the first event after it has arrived, since that's when
+    /// the skipped events can be tallied. That event is returned by the following call.
+    pub async fn recv(&mut self) -> Result<T, RoomRecvError> {
+        if let Some(event) = self.held_back.take() {
+            return Ok(event);
+        }
+
+        let mut skipped = 0;
+        loop {
+            match self.receiver.recv().await {
+                Ok(received) => {
+                    let tally_before = received.tally - u64::from(received.counted);
+                    let tallied = tally_before.saturating_sub(self.tally);
+                    self.tally = received.tally;
+                    if skipped == 0 {
+                        return Ok(received.event);
+                    }
+
+                    self.held_back = Some(received.event);
+                    return Err(RoomRecvError::Lagged { skipped, tallied });
+                }
+                // NOTE: The events that are still buffered can be received right away.
+                Err(RecvError::Lagged(count)) => skipped += count,
+                // Without a following event, every skipped one might have been tallied.
+                Err(RecvError::Closed) if skipped > 0 => {
+                    return Err(RoomRecvError::Lagged {
+                        skipped,
+                        tallied: skipped,
+                    });
+                }
+                Err(RecvError::Closed) => return Err(RoomRecvError::Closed),
+            }
+        }
+    }
+
+    /// Take the next event if there is one, see [`broadcast::Receiver::try_recv`].
+    ///
+    /// Lags are reported as they are, without tallying the skipped events.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(event) = self.held_back.take() {
+            return Ok(event);
+        }
+
+        let received = self.receiver.try_recv()?;
+        self.tally = received.tally;
+        Ok(received.event)
+    }
+}
+
+/// Removes the channel of a room from the registry once it has no receivers left.
+#[derive(Debug)]
+struct Registration<T> {
+    room_uuid: Uuid,
+    channels: Arc<RoomChannels<T>>,
+}
+
+impl<T> Drop for Registration<T> {
+    fn drop(&mut self) {
+        // NOTE: New receivers are only created with the lock held, so the
+        // count can't go up between the check and the removal.
+        let mut channels = match self.channels.channels.lock() {
+            Ok(channels) => channels,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if channels
+            .get(&self.room_uuid)
+            .is_some_and(|channel| channel.sender.receiver_count() == 0)
+        {
+            let _ = channels.remove(&self.room_uuid);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{RoomChannels, RoomRecvError};
+    use std::sync::Arc;
+    use tokio::sync::broadcast::error::TryRecvError;
+    use uuid::Uuid;
+
+    #[test]
+    fn channels_live_as_long_as_subscribers() {
+        let channels = Arc::new(RoomChannels::<u32>::new(4));
+        let room = Uuid::new_v4();
+        assert_eq!(channels.room_count(), 0);
+
+        let first = channels.subscribe(room);
+        let second = channels.subscribe(room);
+        assert_eq!(channels.room_count(), 1);
+
+        drop(first);
+        assert_eq!(channels.room_count(), 1);
+        drop(second);
+        assert_eq!(channels.room_count(), 0);
+    }
+
+    #[test]
+    fn events_stay_in_their_room() {
+        let channels = Arc::new(RoomChannels::new(4));
+        let (room, other_room) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut receiver = channels.subscribe(room);
+        let mut other_receiver = channels.subscribe(other_room);
+
+        assert_eq!(channels.send(room, 42), 1);
+        assert_eq!(channels.send(Uuid::new_v4(), 7), 0);
+
+        assert_eq!(receiver.try_recv().unwrap(), 42);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(other_receiver.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[tokio::test]
+    async fn lagging_receivers_learn_how_many_tallied_events_they_missed() {
+        let channels = Arc::new(RoomChannels::tallying(2, |event: &u32| event % 2 == 1));
+        let room = Uuid::new_v4();
+        let mut receiver = channels.subscribe(room);
+
+        for event in [1, 2, 3, 4, 6, 8] {
+            channels.send(room, event);
+        }
+
+        assert_eq!(
+            receiver.recv().await,
+            Err(RoomRecvError::Lagged {
+                skipped: 4,
+                tallied: 2
+            })
+        );
+        assert_eq!(receiver.recv().await, Ok(6));
+        assert_eq!(receiver.recv().await, Ok(8));
+
+        channels.send(room, 10);
+        assert_eq!(receiver.recv().await, Ok(10));
+    }
+}
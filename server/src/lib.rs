@@ -38,16 +38,33 @@
 
 pub mod auth;
 pub mod channel;
+pub mod command;
 pub mod entities;
+pub mod fanout;
+pub mod membership;
 pub mod persistence;
+pub mod presence;
+pub mod relay;
+pub mod repositories;
 pub mod services;
+pub mod storage;
+pub mod streaming;
+pub mod typing;
 
 use crate::auth::Authenticator;
-use crate::persistence::create_persistence_pool;
+use crate::command::MigrateCommand;
+use crate::persistence::migrations::{self, Migrations};
+use crate::persistence::{create_persistence_pool, Connection, MultiBackend, PoolConfig};
 use crate::proto::chat_server::ChatServer;
 use crate::proto::registry_server::RegistryServer;
+use crate::repositories::Repositories;
 use crate::services::{chat::Chat, registry::Registry};
+use crate::storage::LocalBlobStore;
+use crate::streaming::StreamingConfig;
+use diesel::migration::MigrationSource;
+use diesel_migrations::MigrationHarness;
 use std::env;
+use std::sync::Arc;
 use tonic::transport::{Identity, Server, ServerTlsConfig};
 use tracing_subscriber::fmt;
 
@@ -55,7 +72,10 @@ const CERT: &str = include_str!("../../tls/server.pem");
 const KEY: &str = include_str!("../../tls/server.key");
 
 #[derive(Debug, Default)]
-pub struct TCPChat {}
+pub struct TCPChat {
+    /// Apply pending migrations at startup, instead of refusing to start.
+    pub apply_migrations: bool,
+}
 
 impl TCPChat {
     pub fn preflight() {
@@ -75,15 +95,32 @@ impl TCPChat {
             .expect("Invalid gRPC listen address");
 
         // Set up needed external resources and an authenticator.
-        let persistence_pool = create_persistence_pool();
-        let interceptor = Authenticator::new(persistence_pool.clone());
+        let persistence_pool = create_persistence_pool(PoolConfig::from_env());
+        {
+            let mut db = persistence_pool
+                .get()
+                .expect("Could not connect to the database");
+            migrations::ensure_up_to_date(&mut db, self.apply_migrations)
+                .expect("The database schema should be up to date");
+        }
+        let repositories = Repositories::database(persistence_pool);
+        let authenticator = Authenticator::new(repositories.users.clone());
+        let attachment_dir = env::var("ATTACHMENT_DIR").unwrap_or_else(|_| "attachments".into());
+        let blob_store = Arc::new(LocalBlobStore::new(attachment_dir));
 
         // Set up gRPC services.
-        let chat = Chat::new(persistence_pool.clone())
-            .await
-            .expect("Could not initialize a chat instance");
-        let chat = ChatServer::with_interceptor(chat, interceptor.clone());
-        let registry = Registry::with_persistence_pool(persistence_pool.clone());
+        let chat = Chat::new(
+            repositories.clone(),
+            blob_store,
+            StreamingConfig::from_env(),
+        )
+        .await
+        .expect("Could not initialize a chat instance");
+        tokio::spawn(chat.clone().dispatch_scheduled_messages());
+        tokio::spawn(chat.clone().sweep_expired_messages());
+        tokio::spawn(chat.clone().relay_events());
+        let chat = authenticator.wrap(ChatServer::new(chat));
+        let registry = Registry::new(repositories.users);
         let registry = RegistryServer::new(registry);
 
         let identity = Identity::from_pem(CERT, KEY);
@@ -101,6 +138,50 @@ impl TCPChat {
     }
 }
 
+impl TCPChat {
+    /// Run a `server migrate` subcommand against the database `$DATABASE_URL` points at.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn migrate(command: MigrateCommand) {
+        let url = env::var("DATABASE_URL").expect("Could not read $DATABASE_URL");
+        let mut db = Connection::open(&url).expect("Could not connect to the database");
+        let source = Migrations::of(&db);
+
+        match command {
+            MigrateCommand::Up => {
+                let applied = migrations::run_pending(&mut db).unwrap_or_else(|error| {
+                    eprintln!("Could not apply migrations: {error}");
+                    std::process::exit(1);
+                });
+                println!("Applied {} migration(s)", applied.len());
+                for version in applied {
+                    println!("  {version}");
+                }
+            }
+            MigrateCommand::Down => {
+                let reverted = db
+                    .revert_last_migration(source)
+                    .expect("Could not revert the migration");
+                println!("Reverted {reverted}");
+            }
+            MigrateCommand::Status => {
+                let applied = db
+                    .applied_migrations()
+                    .expect("Could not read applied migrations");
+                let embedded = MigrationSource::<MultiBackend>::migrations(&source)
+                    .expect("Could not read embedded migrations");
+                for migration in embedded {
+                    let mark = if applied.contains(&migration.name().version()) {
+                        "x"
+                    } else {
+                        " "
+                    };
+                    println!("[{mark}] {}", migration.name());
+                }
+            }
+        }
+    }
+}
+
 pub mod proto {
     // HACK: The generated code produces some clippy warnings, which
     // are by nature impossible to fix for me, so just silence them.
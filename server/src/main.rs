@@ -1,8 +1,17 @@
+use std::{env, process};
+use tcp_chat::command::Command;
 use tcp_chat::TCPChat;
 
 #[tokio::main]
 async fn main() {
+    let command = Command::parse(env::args().skip(1)).unwrap_or_else(|error| {
+        eprintln!("{error}\n\n{}", Command::USAGE);
+        process::exit(2);
+    });
+
     TCPChat::preflight();
-    let chat = TCPChat::default();
-    chat.run().await;
+    match command {
+        Command::Serve { apply_migrations } => TCPChat { apply_migrations }.run().await,
+        Command::Migrate(command) => TCPChat::migrate(command),
+    }
 }
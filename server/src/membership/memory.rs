@@ -0,0 +1,145 @@
+//! # In-memory membership cache
+//!
+//! The rooms of each user are kept in a map that's split into [`SHARD_COUNT`] shards by
+//! user UUID, each behind its own lock, so that lookups for different users rarely contend.
+//!
+//! Every membership change goes through this process, so invalidations can't be lost and
+//! entries never expire. Unlike Redis, the cache keeps users without any rooms as well.
+
+use super::MembershipCache;
+use hashbrown::{HashMap, HashSet};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// How many independently locked parts the cache is split into.
+pub const SHARD_COUNT: usize = 16;
+
+type Shard = Mutex<HashMap<Uuid, HashSet<Uuid>>>;
+
+#[derive(Debug)]
+pub struct InMemoryMembershipCache {
+    shards: Box<[Shard]>,
+}
+
+impl InMemoryMembershipCache {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            shards: (0..SHARD_COUNT).map(|_| Shard::default()).collect(),
+        }
+    }
+
+    /// How many users have their rooms cached.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| Self::lock(shard).len())
+            .sum()
+    }
+
+    /// Whether no user has his rooms cached.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, user_uuid: &Uuid) -> &Shard {
+        // NOTE: The last byte of a v4 UUID is random, so users are spread evenly.
+        &self.shards[usize::from(user_uuid.as_bytes()[15]) % SHARD_COUNT]
+    }
+
+    fn lock(shard: &Shard) -> std::sync::MutexGuard<'_, HashMap<Uuid, HashSet<Uuid>>> {
+        // The maps are left consistent even if a holder panics, so poisoning is harmless.
+        match shard.lock() {
+            Ok(map) => map,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Default for InMemoryMembershipCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[tonic::async_trait]
+impl MembershipCache for InMemoryMembershipCache {
+    async fn contains(&self, user_uuid: &Uuid, room_uuid: &Uuid) -> Option<bool> {
+        Self::lock(self.shard(user_uuid))
+            .get(user_uuid)
+            .map(|rooms| rooms.contains(room_uuid))
+    }
+
+    async fn rooms(&self, user_uuid: &Uuid) -> Option<Vec<Uuid>> {
+        Self::lock(self.shard(user_uuid))
+            .get(user_uuid)
+            .map(|rooms| rooms.iter().copied().collect())
+    }
+
+    async fn store(&self, user_uuid: &Uuid, rooms: &[Uuid]) {
+        let rooms = rooms.iter().copied().collect();
+        let _ = Self::lock(self.shard(user_uuid)).insert(*user_uuid, rooms);
+        tracing::trace!(message = "Populated the membership cache", user = ?user_uuid);
+    }
+
+    async fn invalidate(&self, user_uuids: &[Uuid]) {
+        for user_uuid in user_uuids {
+            let _ = Self::lock(self.shard(user_uuid)).remove(user_uuid);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{InMemoryMembershipCache, MembershipCache};
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn rooms_are_cached_until_invalidated() {
+        let cache = InMemoryMembershipCache::new();
+        let (user, room, other_room) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(cache.contains(&user, &room).await, None);
+
+        cache.store(&user, &[room]).await;
+        assert_eq!(cache.contains(&user, &room).await, Some(true));
+        assert_eq!(cache.contains(&user, &other_room).await, Some(false));
+        assert_eq!(cache.rooms(&user).await, Some(vec![room]));
+
+        cache.invalidate(&[user]).await;
+        assert_eq!(cache.rooms(&user).await, None);
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn users_without_rooms_are_cached() {
+        let cache = InMemoryMembershipCache::new();
+        let user = Uuid::new_v4();
+
+        cache.store(&user, &[]).await;
+        assert_eq!(cache.rooms(&user).await, Some(vec![]));
+        assert_eq!(cache.contains(&user, &Uuid::new_v4()).await, Some(false));
+    }
+
+    #[tokio::test]
+    async fn users_are_spread_across_shards() {
+        let cache = InMemoryMembershipCache::new();
+        let users: Vec<Uuid> = (0..256).map(|_| Uuid::new_v4()).collect();
+        for user in &users {
+            cache.store(user, &[Uuid::new_v4()]).await;
+        }
+        assert_eq!(cache.len(), users.len());
+
+        let used_shards = cache
+            .shards
+            .iter()
+            .filter(|shard| !shard.lock().unwrap().is_empty())
+            .count();
+        assert!(used_shards > 1);
+
+        cache.invalidate(&users[..128]).await;
+        assert_eq!(cache.len(), 128);
+    }
+}
@@ -0,0 +1,54 @@
+//! # Membership cache
+//!
+//! Almost every RPC checks whether the caller is a member of some room, so the rooms
+//! of each user are cached in a [`MembershipCache`]. Two implementations are available:
+//!
+//! - [`RedisMembershipCache`] keeps the rooms in Redis, where they're shared by all instances.
+//!   This is the default, and the only choice when several instances are running.
+//! - [`InMemoryMembershipCache`] keeps them in the memory of the process, which is enough for
+//!   a single instance, so the server can run with just Postgres. It's used when `$KV_URL` is unset.
+//!
+//! ## Population
+//!
+//! The cache is populated lazily: the rooms of a user are loaded from the database the
+//! first time they're needed, and [stored](MembershipCache::store) in the cache.
+//!
+//! ## Invalidation
+//!
+//! Whenever the membership of a user changes, his rooms are [dropped](MembershipCache::invalidate)
+//! rather than updated, so that they're reloaded in full next time.
+//!
+//! ## Degradation
+//!
+//! The cache is only an optimisation: the `rooms_users` table is the source of truth.
+//! A cache may miss at any time (i.e. when it can't reach its backend), in which case
+//! callers fall back to the database.
+
+mod memory;
+mod redis;
+
+pub use self::redis::RedisMembershipCache;
+pub use memory::InMemoryMembershipCache;
+
+use std::fmt;
+use uuid::Uuid;
+
+/// A place to keep the rooms of each user in.
+#[tonic::async_trait]
+pub trait MembershipCache: fmt::Debug + Send + Sync {
+    /// Check whether a user is a member of a room.
+    ///
+    /// Returns `None` if the rooms of the user aren't cached.
+    async fn contains(&self, user_uuid: &Uuid, room_uuid: &Uuid) -> Option<bool>;
+
+    /// Get the rooms of a user.
+    ///
+    /// Returns `None` if they aren't cached.
+    async fn rooms(&self, user_uuid: &Uuid) -> Option<Vec<Uuid>>;
+
+    /// Cache the rooms of a user, as loaded from the database.
+    async fn store(&self, user_uuid: &Uuid, rooms: &[Uuid]);
+
+    /// Drop the cached rooms of users whose membership has changed.
+    async fn invalidate(&self, user_uuids: &[Uuid]);
+}
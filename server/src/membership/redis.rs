@@ -0,0 +1,258 @@
+//! # Redis membership cache
+//!
+//! The rooms of each user are kept as a set under a namespaced key (see
+//! [`RedisMembershipCache::key`]). Namespacing lets several deployments (or other
+//! applications) share a Redis instance.
+//!
+//! Sets expire after [`TTL`]. Since Redis doesn't store empty sets, users without
+//! any rooms are looked up in the database every time, which is cheap enough.
+//!
+//! On startup, only the keys of this namespace are dropped, in case the database has
+//! changed while the server was down. Once a cache operation fails, the cache is
+//! considered unhealthy and isn't used at all, so every lookup misses. Meanwhile,
+//! a watcher task (see [`RedisMembershipCache::watch`]) checks on Redis, and once it's
+//! back, clears the namespace (since invalidations may have been lost) before putting
+//! the cache back to use.
+
+use super::MembershipCache;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, RedisResult};
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// For how long a cached set lives before it's reloaded from the database.
+pub const TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How often an unhealthy cache is checked on.
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many keys are dropped at once when clearing the cache.
+const CLEAR_BATCH_SIZE: usize = 512;
+
+#[derive(Debug, Clone)]
+pub struct RedisMembershipCache {
+    client: redis::Client,
+    namespace: String,
+    healthy: Arc<AtomicBool>,
+}
+
+impl RedisMembershipCache {
+    /// Create an unhealthy cache, which is put to use by [`Self::watch`].
+    #[must_use]
+    pub fn new(client: redis::Client, namespace: String) -> Self {
+        Self {
+            client,
+            namespace,
+            healthy: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Create a cache with the namespace from `$KV_NAMESPACE` (or `default` if it's unset),
+    /// spawning a task that [watches](Self::watch) over it.
+    #[must_use]
+    pub fn start(client: redis::Client) -> Self {
+        let namespace = env::var("KV_NAMESPACE").unwrap_or_else(|_| "default".into());
+        let cache = Self::new(client, namespace);
+        tokio::spawn(cache.clone().watch());
+        cache
+    }
+
+    /// The key the rooms of a user are stored under.
+    #[must_use]
+    pub fn key(&self, user_uuid: &Uuid) -> String {
+        format!("tcpchat:{}:member:{user_uuid}", self.namespace)
+    }
+
+    /// Whether the cache is in use.
+    #[must_use]
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Put the cache to use while Redis is reachable, and clear it whenever it comes back. Never returns.
+    pub async fn watch(self) {
+        loop {
+            if !self.is_healthy() {
+                match self.clear().await {
+                    Ok(forgotten) => {
+                        self.healthy.store(true, Ordering::Relaxed);
+                        tracing::info!(message = "The membership cache is available", %forgotten);
+                    }
+                    Err(error) => {
+                        tracing::debug!(
+                            message = "The membership cache is still unavailable",
+                            ?error
+                        );
+                    }
+                }
+            }
+            tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Pass the result of a cache operation through, marking the cache unhealthy on failure.
+    fn check<T>(&self, result: RedisResult<T>) -> Option<T> {
+        result
+            .map_err(|error| {
+                if self.healthy.swap(false, Ordering::Relaxed) {
+                    tracing::error!(
+                        message =
+                            "The membership cache is unavailable, falling back to the database",
+                        ?error
+                    );
+                }
+            })
+            .ok()
+    }
+
+    /// Drop all cached memberships of this namespace, leaving other keys alone.
+    ///
+    /// Returns how many users were forgotten.
+    async fn clear(&self) -> RedisResult<usize> {
+        let pattern = format!("tcpchat:{}:member:*", self.namespace);
+        let mut cache = self.connection().await?;
+
+        let keys: Vec<String> = {
+            let mut keys = Vec::new();
+            let mut iter = cache.scan_match::<_, String>(&pattern).await?;
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+            keys
+        };
+
+        for batch in keys.chunks(CLEAR_BATCH_SIZE) {
+            let () = cache.del(batch).await?;
+        }
+        Ok(keys.len())
+    }
+
+    async fn connection(&self) -> RedisResult<MultiplexedConnection> {
+        self.client.get_multiplexed_async_connection().await
+    }
+}
+
+#[tonic::async_trait]
+impl MembershipCache for RedisMembershipCache {
+    /// Check whether a user is a member of a room.
+    ///
+    /// Returns `None` if the rooms of the user aren't cached, or the cache is unhealthy.
+    async fn contains(&self, user_uuid: &Uuid, room_uuid: &Uuid) -> Option<bool> {
+        if !self.is_healthy() {
+            return None;
+        }
+
+        let result: RedisResult<(bool, bool)> = async {
+            redis::pipe()
+                .sismember(self.key(user_uuid), room_uuid)
+                .exists(self.key(user_uuid))
+                .query_async(&mut self.connection().await?)
+                .await
+        }
+        .await;
+
+        let (is_member, is_cached) = self.check(result)?;
+        is_cached.then_some(is_member)
+    }
+
+    /// Get the rooms of a user.
+    ///
+    /// Returns `None` if they aren't cached, or the cache is unhealthy.
+    async fn rooms(&self, user_uuid: &Uuid) -> Option<Vec<Uuid>> {
+        if !self.is_healthy() {
+            return None;
+        }
+
+        let result: RedisResult<Vec<Uuid>> = async {
+            let mut cache = self.connection().await?;
+            cache.smembers(self.key(user_uuid)).await
+        }
+        .await;
+
+        let rooms = self.check(result)?;
+        (!rooms.is_empty()).then_some(rooms)
+    }
+
+    async fn store(&self, user_uuid: &Uuid, rooms: &[Uuid]) {
+        if rooms.is_empty() || !self.is_healthy() {
+            return;
+        }
+
+        let key = self.key(user_uuid);
+        let ttl = i64::try_from(TTL.as_secs()).unwrap_or(i64::MAX);
+        let result: RedisResult<()> = async {
+            redis::pipe()
+                .atomic()
+                .del(&key)
+                .ignore()
+                .sadd(&key, rooms)
+                .ignore()
+                .expire(&key, ttl)
+                .ignore()
+                .query_async(&mut self.connection().await?)
+                .await
+        }
+        .await;
+
+        if self.check(result).is_some() {
+            tracing::trace!(message = "Populated the membership cache", user = ?user_uuid);
+        }
+    }
+
+    /// Drop the cached rooms of users whose membership has changed.
+    ///
+    /// If that fails, the cache is cleared as a whole once it recovers.
+    async fn invalidate(&self, user_uuids: &[Uuid]) {
+        if user_uuids.is_empty() || !self.is_healthy() {
+            return;
+        }
+
+        let keys: Vec<String> = user_uuids.iter().map(|user| self.key(user)).collect();
+        let result: RedisResult<()> = async {
+            let mut cache = self.connection().await?;
+            cache.del(keys).await
+        }
+        .await;
+
+        let _ = self.check(result);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::RedisMembershipCache;
+    use redis::{ErrorKind, RedisError};
+    use std::sync::atomic::Ordering;
+    use uuid::Uuid;
+
+    fn cache() -> RedisMembershipCache {
+        let client = redis::Client::open("redis://localhost").unwrap();
+        RedisMembershipCache::new(client, "staging".into())
+    }
+
+    #[test]
+    fn keys_are_namespaced() {
+        let cache = cache();
+        let user = Uuid::new_v4();
+
+        assert_eq!(cache.key(&user), format!("tcpchat:staging:member:{user}"));
+    }
+
+    #[test]
+    fn failures_take_the_cache_out_of_use() {
+        let cache = cache();
+        assert!(!cache.is_healthy());
+
+        cache.healthy.store(true, Ordering::Relaxed);
+        assert_eq!(cache.check(Ok(42)), Some(42));
+        assert!(cache.is_healthy());
+
+        let error = RedisError::from((ErrorKind::IoError, "Connection refused"));
+        assert_eq!(cache.check::<()>(Err(error)), None);
+        assert!(!cache.is_healthy());
+    }
+}
@@ -0,0 +1,380 @@
+//! # Migrations
+//!
+//! The `migrations/` directory is embedded into the binary at build time (see [`MIGRATIONS`]),
+//! so that the server can bring the schema up to date by itself, or refuse to start on an
+//! outdated one. They're applied with `diesel_migrations`, which tracks them in the same table
+//! the `diesel` CLI uses, so databases set up by either one can be managed by the other.
+//!
+//! The migrations are shared by both backends. The few that use Postgres features have a
+//! version for SQLite in their `sqlite/` subdirectory, which [`Migrations`] runs instead
+//! on SQLite (see [`SQLITE_VERSIONS`]). A new migration only needs one if it doesn't
+//! run on SQLite as is. SQLite databases can't be migrated with the `diesel` CLI.
+//!
+//! Some data can't be migrated in SQL, because it's computed by the server (i.e. canonical
+//! usernames). The migration that needs it is preceded by a backfill in Rust (see
+//! [`BACKFILLS`]), within the same transaction, so it's tracked like any other. The `diesel`
+//! CLI only runs its SQL, which fails while there's data left to fill in.
+
+use super::sql_types::SqlUuid;
+use super::{Connection, MultiBackend};
+use crate::entities::username;
+use diesel::connection::BoxableConnection;
+use diesel::migration::{self, Migration, MigrationMetadata, MigrationName, MigrationSource};
+use diesel::prelude::*;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// All the migrations in the `migrations/` directory.
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("../migrations");
+
+/// Embed the SQLite version of a migration, see [`SQLITE_VERSIONS`].
+#[cfg(feature = "sqlite")]
+macro_rules! sqlite_version {
+    ($name:literal) => {
+        (
+            $name,
+            include_str!(concat!("../../../migrations/", $name, "/sqlite/up.sql")),
+            None,
+        )
+    };
+    ($name:literal, down) => {
+        (
+            $name,
+            include_str!(concat!("../../../migrations/", $name, "/sqlite/up.sql")),
+            Some(include_str!(concat!(
+                "../../../migrations/",
+                $name,
+                "/sqlite/down.sql"
+            ))),
+        )
+    };
+}
+
+/// The SQLite versions of migrations, as `(name, up.sql, down.sql)`. Migrations whose
+/// `down.sql` runs on SQLite as is don't have a version of it.
+#[cfg(feature = "sqlite")]
+pub const SQLITE_VERSIONS: &[(&str, &str, Option<&str>)] = &[
+    sqlite_version!("00000000000000_diesel_initial_setup", down),
+    sqlite_version!("2024-05-22-120000_add_messages_text_search", down),
+    sqlite_version!("2024-05-29-120000_add_message_sequences"),
+    sqlite_version!("2024-05-31-120000_add_read_markers_sequence"),
+    sqlite_version!("2024-06-01-120000_require_canonical_usernames", down),
+];
+
+/// Fills in data that a migration needs, returning how many rows were updated.
+pub type Backfill = fn(&mut Connection) -> Result<usize, MigrationError>;
+
+/// The migrations that need data filled in by the server before they run, as `(name, backfill)`.
+pub const BACKFILLS: &[(&str, Backfill)] = &[(
+    "2024-06-01-120000_require_canonical_usernames",
+    backfill_canonical_usernames,
+)];
+
+/// The error `diesel_migrations` reports failures with.
+pub type MigrationError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The embedded [`MIGRATIONS`], as they are run on a particular backend.
+#[derive(Debug, Clone, Copy)]
+pub struct Migrations {
+    /// Whether to run the [`SQLITE_VERSIONS`] instead of the originals.
+    sqlite: bool,
+}
+
+impl Migrations {
+    /// The migrations for the backend `db` is connected to.
+    #[must_use]
+    pub const fn of(db: &Connection) -> Self {
+        Self {
+            sqlite: !db.is_postgres(),
+        }
+    }
+}
+
+impl MigrationSource<MultiBackend> for Migrations {
+    fn migrations(&self) -> migration::Result<Vec<Box<dyn Migration<MultiBackend>>>> {
+        let migrations = MigrationSource::<MultiBackend>::migrations(&MIGRATIONS)?;
+        let migrations = if self.sqlite {
+            sqlite_versions(migrations)
+        } else {
+            migrations
+        };
+        Ok(migrations.into_iter().map(Backfilled::wrap).collect())
+    }
+}
+
+/// Replace the migrations that have a version in [`SQLITE_VERSIONS`] with it.
+#[cfg(feature = "sqlite")]
+fn sqlite_versions(
+    migrations: Vec<Box<dyn Migration<MultiBackend>>>,
+) -> Vec<Box<dyn Migration<MultiBackend>>> {
+    migrations
+        .into_iter()
+        .map(|migration| -> Box<dyn Migration<MultiBackend>> {
+            let name = migration.name().to_string();
+            match SQLITE_VERSIONS
+                .iter()
+                .find(|(version, ..)| *version == name)
+            {
+                Some(&(_, up, down)) => Box::new(SqliteVersion {
+                    original: migration,
+                    up,
+                    down,
+                }),
+                None => migration,
+            }
+        })
+        .collect()
+}
+
+/// Without SQLite support, there's no connection to run SQLite versions on.
+#[cfg(not(feature = "sqlite"))]
+const fn sqlite_versions(
+    migrations: Vec<Box<dyn Migration<MultiBackend>>>,
+) -> Vec<Box<dyn Migration<MultiBackend>>> {
+    migrations
+}
+
+/// A migration that's preceded by a backfill, see [`BACKFILLS`].
+struct Backfilled {
+    original: Box<dyn Migration<MultiBackend>>,
+    backfill: Backfill,
+}
+
+impl Backfilled {
+    /// Precede `migration` by its backfill, if it has one.
+    fn wrap(migration: Box<dyn Migration<MultiBackend>>) -> Box<dyn Migration<MultiBackend>> {
+        let name = migration.name().to_string();
+        match BACKFILLS.iter().find(|(backfilled, _)| *backfilled == name) {
+            Some(&(_, backfill)) => Box::new(Self {
+                original: migration,
+                backfill,
+            }),
+            None => migration,
+        }
+    }
+}
+
+impl Migration<MultiBackend> for Backfilled {
+    fn run(&self, db: &mut dyn BoxableConnection<MultiBackend>) -> migration::Result<()> {
+        let connection = db
+            .as_any_mut()
+            .downcast_mut::<Connection>()
+            .ok_or("Data can only be backfilled over a server connection")?;
+        (self.backfill)(connection)?;
+        self.original.run(db)
+    }
+
+    fn revert(&self, db: &mut dyn BoxableConnection<MultiBackend>) -> migration::Result<()> {
+        self.original.revert(db)
+    }
+
+    fn metadata(&self) -> &dyn MigrationMetadata {
+        self.original.metadata()
+    }
+
+    fn name(&self) -> &dyn MigrationName {
+        self.original.name()
+    }
+}
+
+/// A migration that runs different SQL on SQLite, see [`SQLITE_VERSIONS`].
+#[cfg(feature = "sqlite")]
+struct SqliteVersion {
+    original: Box<dyn Migration<MultiBackend>>,
+    up: &'static str,
+    down: Option<&'static str>,
+}
+
+#[cfg(feature = "sqlite")]
+impl Migration<MultiBackend> for SqliteVersion {
+    fn run(&self, db: &mut dyn BoxableConnection<MultiBackend>) -> migration::Result<()> {
+        Ok(db.batch_execute(self.up)?)
+    }
+
+    fn revert(&self, db: &mut dyn BoxableConnection<MultiBackend>) -> migration::Result<()> {
+        match self.down {
+            Some(down) => Ok(db.batch_execute(down)?),
+            None => self.original.revert(db),
+        }
+    }
+
+    fn metadata(&self) -> &dyn MigrationMetadata {
+        self.original.metadata()
+    }
+
+    fn name(&self) -> &dyn MigrationName {
+        self.original.name()
+    }
+}
+
+/// Usernames of existing users that share a canonical form, so only one of them could keep it.
+///
+/// Each group lists the users as `username (uuid)`. An operator has to rename all but one user
+/// of each group (or delete them) before migrating again.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Usernames collide in their canonical form, rename all but one of each group: {0:?}")]
+pub struct UsernameCollisions(pub Vec<Vec<String>>);
+
+/// Apply all pending migrations, returning the versions of the applied migrations.
+///
+/// # Errors
+///
+/// Fails if a migration fails, e.g. with [`UsernameCollisions`] if canonical usernames can't be
+/// filled in. Migrations that have been applied before that stay applied.
+pub fn run_pending(db: &mut Connection) -> Result<Vec<String>, MigrationError> {
+    Ok(db
+        .run_pending_migrations(Migrations::of(db))?
+        .iter()
+        .map(ToString::to_string)
+        .collect())
+}
+
+/// Make sure the schema is up to date, applying pending migrations only if `apply` is set.
+///
+/// # Errors
+///
+/// Fails if a migration fails, or if there are pending migrations that can't be applied.
+pub fn ensure_up_to_date(db: &mut Connection, apply: bool) -> Result<(), MigrationError> {
+    if apply {
+        let applied = run_pending(db)?;
+        tracing::info!(
+            message = "The database schema is up to date",
+            applied = applied.len()
+        );
+        return Ok(());
+    }
+
+    let pending = db.pending_migrations(Migrations::of(db))?;
+    if !pending.is_empty() {
+        let names: Vec<String> = pending
+            .iter()
+            .map(|migration| migration.name().to_string())
+            .collect();
+        return Err(
+            format!("The database schema is out of date, pending migrations: {names:?}").into(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Fill in the [canonical usernames](username::canonicalize) of the users that don't have one,
+/// before the column is made `NOT NULL`. Returns how many users were updated.
+///
+/// # Errors
+///
+/// Fails with [`UsernameCollisions`] without changing anything if the canonical forms of any
+/// usernames collide, either with each other or with the ones already filled in.
+pub fn backfill_canonical_usernames(db: &mut Connection) -> Result<usize, MigrationError> {
+    use crate::entities::schema::users;
+
+    db.write_transaction(|db| {
+        let missing: Vec<(Uuid, String)> = users::table
+            .filter(users::canonical_username.is_null())
+            .select((users::uuid, users::username))
+            .load(db)?;
+        if missing.is_empty() {
+            return Ok(0);
+        }
+
+        let existing: Vec<(Uuid, String, String)> = users::table
+            .filter(users::canonical_username.is_not_null())
+            .select((users::uuid, users::username, users::canonical_username))
+            .load(db)?;
+
+        let mut owners: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (uuid, username, canonical_username) in existing {
+            owners
+                .entry(canonical_username)
+                .or_default()
+                .push(format!("{username} ({uuid})"));
+        }
+        for (uuid, username) in &missing {
+            owners
+                .entry(username::canonicalize(username))
+                .or_default()
+                .push(format!("{username} ({uuid})"));
+        }
+
+        let collisions: Vec<Vec<String>> = owners
+            .into_values()
+            .filter(|owners| owners.len() > 1)
+            .collect();
+        if !collisions.is_empty() {
+            return Err(UsernameCollisions(collisions).into());
+        }
+
+        for (uuid, username) in &missing {
+            diesel::update(users::table.find(SqlUuid(*uuid)))
+                .set(users::canonical_username.eq(username::canonicalize(username)))
+                .execute(db)?;
+        }
+
+        tracing::info!(
+            message = "Filled in canonical usernames",
+            count = missing.len()
+        );
+        Ok(missing.len())
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{MultiBackend, MIGRATIONS};
+    use diesel::migration::MigrationSource;
+
+    #[test]
+    fn migrations_are_embedded() {
+        let migrations = MigrationSource::<MultiBackend>::migrations(&MIGRATIONS).unwrap();
+        assert_eq!(
+            migrations[0].name().to_string(),
+            "00000000000000_diesel_initial_setup"
+        );
+        assert!(migrations
+            .windows(2)
+            .all(|pair| pair[0].name().version() < pair[1].name().version()));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn canonical_usernames_are_filled_in_before_they_are_required() {
+        use super::{run_pending, Connection, Migrations, BACKFILLS};
+        use crate::entities::{schema::users, username};
+        use diesel::connection::SimpleConnection;
+        use diesel::migration::MigrationConnection;
+        use diesel::prelude::*;
+        use diesel_migrations::MigrationHarness;
+
+        let mut db = Connection::open(&format!("{}:memory:", Connection::SQLITE_SCHEME)).unwrap();
+        // Migrations run one at a time aren't tracked until the table for it has been set up.
+        db.setup().unwrap();
+        let migrations = Migrations::of(&db).migrations().unwrap();
+        let (required, _) = BACKFILLS[0];
+        for migration in migrations
+            .iter()
+            .take_while(|migration| migration.name().to_string() != required)
+        {
+            db.run_migration(migration.as_ref()).unwrap();
+        }
+        db.batch_execute(
+            "INSERT INTO users (uuid, username, password, auth_token) \
+             VALUES ('00000000-0000-0000-0000-000000000001', 'Alice', '', '')",
+        )
+        .unwrap();
+
+        assert_eq!(run_pending(&mut db).unwrap().len(), 1);
+        let canonical: Vec<Option<String>> = users::table
+            .select(users::canonical_username.nullable())
+            .load(&mut db)
+            .unwrap();
+        assert_eq!(canonical, [Some(username::canonicalize("Alice"))]);
+        assert!(db
+            .batch_execute(
+                "INSERT INTO users (uuid, username, password, auth_token) \
+                 VALUES ('00000000-0000-0000-0000-000000000002', 'Bob', '', '')",
+            )
+            .is_err());
+    }
+}
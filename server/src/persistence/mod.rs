@@ -1,17 +1,385 @@
-use diesel::r2d2::{ConnectionManager, Pool as R2D2Pool};
+//! # Persistence
+//!
+//! Diesel and r2d2 are synchronous, so a query blocks the thread it runs on until the database
+//! answers, and so does waiting for a free connection. Running either on a `tokio` worker
+//! would stall every other task scheduled on it, so all database work goes through [`run`],
+//! which checks a connection out and runs the work on the blocking thread pool instead.
+//!
+//! ## Configuration
+//!
+//! The pool is configured from the environment, see [`PoolConfig::from_env`].
+//! The schema is managed by the server itself, see [`migrations`].
+//!
+//! ## Backends
+//!
+//! Postgres is the default. With the `sqlite` feature, a `$DATABASE_URL` like `sqlite://chat.db`
+//! selects SQLite instead. Both go through the same [`Connection`], so queries, the schema and
+//! the migrations are shared, with a few exceptions:
+//!
+//! - UUIDs, timestamps and arrays are stored differently, see [`sql_types`].
+//! - Message search uses a full text index on Postgres, and matches every word with `LIKE`
+//!   on SQLite, without ranking the results.
+//! - A few migrations use Postgres features, and have their own version for SQLite,
+//!   see [`migrations`].
+//! - SQLite has a single writer, so transactions that write take the write lock upfront,
+//!   see [`Connection::write_transaction`]. Postgres locks rows instead.
+//!
+//! Diesel can't build a few queries for both backends at once: upserts are emulated with
+//! [`Connection::insert_or_ignore`], rows are inserted one at a time instead of in batches,
+//! and row locks are only taken on the inner Postgres connection (see
+//! [`Connection::as_postgres`]).
+
+pub mod migrations;
+pub mod sql_types;
+
+pub use backends::{AnyConnection as Connection, MultiBackend, MultiRawValue};
+
+#[cfg(feature = "sqlite")]
+use diesel::connection::{AnsiTransactionManager, SimpleConnection, TransactionManager};
+use diesel::r2d2::{self, ManageConnection, Pool as R2D2Pool, R2D2Connection};
+#[cfg(feature = "sqlite")]
+use diesel::result::ConnectionError;
+use diesel::result::{ConnectionResult, DatabaseErrorKind, Error as QueryError, QueryResult};
 use diesel::PgConnection;
+#[cfg(feature = "sqlite")]
+use diesel::SqliteConnection;
 use std::env;
+use std::time::Duration;
+
+// NOTE: The derive glob-imports both this module and `diesel::connection`,
+// so the enum can't be called `Connection` where it's defined.
+mod backends {
+    use diesel::PgConnection;
+    #[cfg(feature = "sqlite")]
+    use diesel::SqliteConnection;
+
+    /// A connection to either database backend, see the [module docs](super).
+    #[derive(diesel::MultiConnection)]
+    pub enum AnyConnection {
+        Postgres(PgConnection),
+        #[cfg(feature = "sqlite")]
+        Sqlite(SqliteConnection),
+    }
+}
+
+pub type ConnectionPool = R2D2Pool<ConnectionManager>;
+pub type PooledConnection = r2d2::PooledConnection<ConnectionManager>;
+
+impl Connection {
+    /// The URL scheme that selects SQLite.
+    #[cfg(feature = "sqlite")]
+    pub const SQLITE_SCHEME: &'static str = "sqlite://";
+
+    /// For how long a SQLite connection waits for another one to release the write lock.
+    #[cfg(feature = "sqlite")]
+    pub const SQLITE_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Connect to the database at `url`, which is a SQLite database if it starts
+    /// with [`Self::SQLITE_SCHEME`] (with the `sqlite` feature), or Postgres otherwise.
+    ///
+    /// SQLite connections enforce foreign keys, which SQLite doesn't do by default,
+    /// and wait for [`Self::SQLITE_BUSY_TIMEOUT`] when the database is locked.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the database can't be connected to.
+    pub fn open(url: &str) -> ConnectionResult<Self> {
+        use diesel::Connection as _;
+
+        #[cfg(feature = "sqlite")]
+        if let Some(path) = url.strip_prefix(Self::SQLITE_SCHEME) {
+            let mut db = SqliteConnection::establish(path)?;
+            db.batch_execute(&format!(
+                "PRAGMA foreign_keys = ON; PRAGMA busy_timeout = {}; PRAGMA journal_mode = WAL;",
+                Self::SQLITE_BUSY_TIMEOUT.as_millis()
+            ))
+            .map_err(ConnectionError::CouldntSetupConfiguration)?;
+            return Ok(Self::Sqlite(db));
+        }
+
+        PgConnection::establish(url).map(Self::Postgres)
+    }
+
+    /// Whether this is a connection to Postgres.
+    #[must_use]
+    pub const fn is_postgres(&self) -> bool {
+        matches!(self, Self::Postgres(_))
+    }
+
+    /// The inner Postgres connection, if this is one.
+    pub fn as_postgres(&mut self) -> Option<&mut PgConnection> {
+        match self {
+            Self::Postgres(db) => Some(db),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite(_) => None,
+        }
+    }
+
+    /// Run `work` in a transaction that's going to write.
+    ///
+    /// On SQLite, the transaction takes the database's write lock right away with
+    /// `BEGIN IMMEDIATE`. Otherwise, a transaction that reads first would fail when it
+    /// tries to write after another connection has, instead of waiting for its turn.
+    /// On Postgres, or inside another transaction, this is a plain (nested) transaction.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `work` does, or if the transaction can't be started or committed.
+    pub fn write_transaction<T, E, F>(&mut self, work: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut Self) -> Result<T, E>,
+        E: From<QueryError>,
+    {
+        use diesel::Connection as _;
+
+        #[cfg(feature = "sqlite")]
+        if let Self::Sqlite(db) = self {
+            let status = AnsiTransactionManager::transaction_manager_status_mut(db);
+            if status.transaction_depth()?.is_none() {
+                AnsiTransactionManager::begin_transaction_sql(db, "BEGIN IMMEDIATE")?;
+
+                // NOTE: This is what `Connection::transaction` does after beginning one.
+                return match work(self) {
+                    Ok(value) => {
+                        Self::commit_transaction(self)?;
+                        Ok(value)
+                    }
+                    Err(error) => match Self::rollback_transaction(self) {
+                        Ok(()) | Err(QueryError::BrokenTransactionManager) => Err(error),
+                        Err(rollback_error) => Err(rollback_error.into()),
+                    },
+                };
+            }
+        }
+
+        self.transaction(work)
+    }
+}
+
+impl Connection {
+    /// Run `insert`, treating a row that violates a unique constraint like
+    /// `ON CONFLICT DO NOTHING` would, by inserting nothing.
+    ///
+    /// The insert runs in a savepoint, so that the violation doesn't abort
+    /// the surrounding transaction on Postgres.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `insert` does for any other reason.
+    pub fn insert_or_ignore<F>(&mut self, insert: F) -> QueryResult<usize>
+    where
+        F: FnOnce(&mut Self) -> QueryResult<usize>,
+    {
+        use diesel::Connection as _;
+
+        match self.transaction(insert) {
+            Err(QueryError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Ok(0),
+            result => result,
+        }
+    }
+}
+
+/// Opens [`Connection`]s for the pool, see [`Connection::open`].
+#[derive(Debug, Clone)]
+pub struct ConnectionManager {
+    url: String,
+}
+
+impl ConnectionManager {
+    #[must_use]
+    pub fn new(url: impl Into<String>) -> Self {
+        Self { url: url.into() }
+    }
+}
+
+impl ManageConnection for ConnectionManager {
+    type Connection = Connection;
+    type Error = r2d2::Error;
+
+    fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        Connection::open(&self.url).map_err(r2d2::Error::ConnectionError)
+    }
+
+    fn is_valid(&self, db: &mut Self::Connection) -> Result<(), Self::Error> {
+        db.ping().map_err(r2d2::Error::QueryError)
+    }
+
+    fn has_broken(&self, db: &mut Self::Connection) -> bool {
+        std::thread::panicking() || db.is_broken()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PersistenceError {
+    #[error("Couldn't acquire a database connection: {0}")]
+    Pool(#[from] r2d2::PoolError),
+    #[error("Database work didn't finish: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+/// How the database connection pool is set up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// The most connections the pool keeps open.
+    pub max_size: u32,
+    /// How many idle connections the pool tries to keep around, or `max_size` if unset.
+    pub min_idle: Option<u32>,
+    /// For how long to wait for a free connection before failing a request.
+    pub connection_timeout: Duration,
+    /// For how long an idle connection is kept open, or forever if unset.
+    pub idle_timeout: Option<Duration>,
+    /// For how long a connection is kept open at all, or forever if unset.
+    pub max_lifetime: Option<Duration>,
+    /// Whether to check a connection is alive before handing it out.
+    pub test_on_check_out: bool,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 10,
+            min_idle: None,
+            connection_timeout: Duration::from_secs(30),
+            idle_timeout: Some(Duration::from_secs(10 * 60)),
+            max_lifetime: Some(Duration::from_secs(30 * 60)),
+            test_on_check_out: true,
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Read the configuration from `$DATABASE_POOL_SIZE`, `$DATABASE_MIN_IDLE`,
+    /// `$DATABASE_CONNECTION_TIMEOUT`, `$DATABASE_IDLE_TIMEOUT`, `$DATABASE_MAX_LIFETIME`
+    /// (all in seconds, where `0` means "never") and `$DATABASE_TEST_ON_CHECKOUT`.
+    ///
+    /// Unset or invalid values fall back to the defaults.
+    #[must_use]
+    pub fn from_env() -> Self {
+        Self::from_vars(|name| env::var(name).ok())
+    }
 
-pub type Connection = PgConnection;
-pub type ConnectionPool = R2D2Pool<ConnectionManager<Connection>>;
+    /// Start building a pool with this configuration.
+    #[must_use]
+    pub fn builder(&self) -> r2d2::Builder<ConnectionManager> {
+        R2D2Pool::builder()
+            .max_size(self.max_size)
+            .min_idle(self.min_idle)
+            .connection_timeout(self.connection_timeout)
+            .idle_timeout(self.idle_timeout)
+            .max_lifetime(self.max_lifetime)
+            .test_on_check_out(self.test_on_check_out)
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let default = Self::default();
+
+        let parse = |name: &str| -> Option<u64> {
+            let value = var(name)?;
+            value.trim().parse().map_or_else(
+                |_| {
+                    tracing::warn!(message = "Invalid database pool setting", name, ?value);
+                    None
+                },
+                Some,
+            )
+        };
+        let timeout = |name: &str, default: Option<Duration>| {
+            parse(name).map_or(default, |seconds| {
+                (seconds > 0).then(|| Duration::from_secs(seconds))
+            })
+        };
+
+        Self {
+            max_size: parse("DATABASE_POOL_SIZE")
+                .and_then(|size| u32::try_from(size).ok())
+                .filter(|&size| size > 0)
+                .unwrap_or(default.max_size),
+            min_idle: parse("DATABASE_MIN_IDLE")
+                .and_then(|size| u32::try_from(size).ok())
+                .or(default.min_idle),
+            connection_timeout: timeout("DATABASE_CONNECTION_TIMEOUT", None)
+                .unwrap_or(default.connection_timeout),
+            idle_timeout: timeout("DATABASE_IDLE_TIMEOUT", default.idle_timeout),
+            max_lifetime: timeout("DATABASE_MAX_LIFETIME", default.max_lifetime),
+            test_on_check_out: var("DATABASE_TEST_ON_CHECKOUT")
+                .map_or(default.test_on_check_out, |value| {
+                    !matches!(value.trim(), "0" | "false" | "no")
+                }),
+        }
+    }
+}
 
 #[tracing::instrument]
-pub fn create_persistence_pool() -> ConnectionPool {
+pub fn create_persistence_pool(config: PoolConfig) -> ConnectionPool {
     let url = env::var("DATABASE_URL").expect("Could not read $DATABASE_URL");
-    tracing::debug!(message = "Creating a PostgreSQL connection pool", ?url);
-    let manager = ConnectionManager::<Connection>::new(url);
-    ConnectionPool::builder()
-        .test_on_check_out(true)
+    tracing::debug!(message = "Creating a database connection pool", ?url);
+    let manager = ConnectionManager::new(url);
+    config
+        .builder()
         .build(manager)
         .expect("Could not build a connection pool")
 }
+
+/// Run blocking database work on a connection from the pool, on the blocking thread pool.
+///
+/// # Errors
+///
+/// Fails if no connection frees up in time, or if the work panics.
+pub async fn run<T, F>(pool: &ConnectionPool, work: F) -> Result<T, PersistenceError>
+where
+    F: FnOnce(&mut PooledConnection) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+        let mut db = pool.get()?;
+        Ok(work(&mut db))
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PoolConfig;
+    use hashbrown::HashMap;
+    use std::time::Duration;
+
+    fn config(vars: &[(&str, &str)]) -> PoolConfig {
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        PoolConfig::from_vars(|name| vars.get(name).map(ToString::to_string))
+    }
+
+    #[test]
+    fn unset_vars_fall_back_to_defaults() {
+        assert_eq!(config(&[]), PoolConfig::default());
+        assert_eq!(
+            config(&[("DATABASE_POOL_SIZE", "0"), ("DATABASE_MIN_IDLE", "many")]),
+            PoolConfig::default()
+        );
+    }
+
+    #[test]
+    fn vars_override_defaults() {
+        let config = config(&[
+            ("DATABASE_POOL_SIZE", "32"),
+            ("DATABASE_MIN_IDLE", "4"),
+            ("DATABASE_CONNECTION_TIMEOUT", "5"),
+            ("DATABASE_IDLE_TIMEOUT", "0"),
+            ("DATABASE_TEST_ON_CHECKOUT", "false"),
+        ]);
+
+        assert_eq!(
+            config,
+            PoolConfig {
+                max_size: 32,
+                min_idle: Some(4),
+                connection_timeout: Duration::from_secs(5),
+                idle_timeout: None,
+                test_on_check_out: false,
+                ..PoolConfig::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,329 @@
+//! # SQL types
+//!
+//! The schema is written against these types instead of the ones from [`diesel::sql_types`],
+//! which this module re-exports otherwise (see `diesel.toml`). Diesel only knows how to store
+//! UUIDs, [`SystemTime`]s and arrays in Postgres, so these types tell it how to store them in
+//! either backend the [`Connection`](super::Connection) may connect to:
+//!
+//! | Type            | Postgres    | SQLite                                  |
+//! |-----------------|-------------|-----------------------------------------|
+//! | [`Uuid`]        | `UUID`      | hyphenated text                         |
+//! | [`Timestamp`]   | `TIMESTAMP` | microseconds since the Unix epoch       |
+//! | [`Array<Uuid>`] | `UUID[]`    | comma-separated, hyphenated text        |
+//!
+//! Values of these types are loaded straight into [`uuid::Uuid`]s, [`SystemTime`]s and
+//! [`Vec`]s of UUIDs. Diesel can't take those as query parameters for types it doesn't know,
+//! though, so parameters are wrapped in [`SqlUuid`], [`SqlTime`], [`SqlOptionalTime`] or
+//! [`SqlUuids`] instead, and entities use them as their fields' `serialize_as` types.
+
+use super::MultiBackend;
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
+use diesel::expression::AsExpression;
+// NOTE: This is where `#[derive(AsExpression)]` gets `Bound` from as well.
+use diesel::internal::derives::as_expression::Bound;
+use diesel::pg::{Pg, PgValue};
+use diesel::query_builder::QueryId;
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types as diesel_types;
+use std::marker::PhantomData;
+use std::time::{Duration, SystemTime};
+
+pub use diesel::sql_types::*;
+
+/// A UUID, see the [module docs](self).
+#[derive(Debug, Clone, Copy, Default, QueryId, SqlType)]
+#[diesel(postgres_type(oid = 2950, array_oid = 2951))]
+#[cfg_attr(feature = "sqlite", diesel(sqlite_type(name = "Text")))]
+pub struct Uuid;
+
+/// A moment in time without a time zone, see the [module docs](self).
+#[derive(Debug, Clone, Copy, Default, QueryId, SqlType)]
+#[diesel(postgres_type(oid = 1114, array_oid = 1115))]
+#[cfg_attr(feature = "sqlite", diesel(sqlite_type(name = "Long")))]
+pub struct Timestamp;
+
+// NOTE: `table!` expects the same operators on timestamp columns as diesel's `Timestamp` has.
+impl diesel_types::ops::Add for Timestamp {
+    type Rhs = Interval;
+    type Output = Self;
+}
+
+impl diesel_types::ops::Sub for Timestamp {
+    type Rhs = Interval;
+    type Output = Self;
+}
+
+impl diesel_types::SqlOrd for Timestamp {}
+
+/// An array of values, see the [module docs](self). Only arrays of [`Uuid`]s can be stored.
+#[derive(Debug, Clone, Copy, Default, QueryId)]
+pub struct Array<ST: 'static>(PhantomData<ST>);
+
+impl<ST: 'static> diesel_types::SqlType for Array<ST> {
+    type IsNull = diesel_types::is_nullable::NotNull;
+}
+
+impl<ST: 'static> diesel_types::SingleValue for Array<ST> {}
+
+impl diesel_types::HasSqlType<Array<Uuid>> for Pg {
+    fn metadata(lookup: &mut Self::MetadataLookup) -> Self::TypeMetadata {
+        <Self as diesel_types::HasSqlType<diesel_types::Array<diesel_types::Uuid>>>::metadata(
+            lookup,
+        )
+    }
+}
+
+impl diesel_types::HasSqlType<Uuid> for MultiBackend {
+    fn metadata(lookup: &mut Self::MetadataLookup) -> Self::TypeMetadata {
+        Self::lookup_sql_type::<Uuid>(lookup)
+    }
+}
+
+impl diesel_types::HasSqlType<Timestamp> for MultiBackend {
+    fn metadata(lookup: &mut Self::MetadataLookup) -> Self::TypeMetadata {
+        Self::lookup_sql_type::<Timestamp>(lookup)
+    }
+}
+
+impl diesel_types::HasSqlType<Array<Uuid>> for MultiBackend {
+    fn metadata(lookup: &mut Self::MetadataLookup) -> Self::TypeMetadata {
+        Self::lookup_sql_type::<Array<Uuid>>(lookup)
+    }
+}
+
+/// A UUID as a query parameter, see the [module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, AsExpression)]
+#[diesel(sql_type = Uuid)]
+pub struct SqlUuid(pub uuid::Uuid);
+
+/// A moment as a query parameter, see the [module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsExpression)]
+#[diesel(sql_type = Timestamp)]
+pub struct SqlTime(pub SystemTime);
+
+/// A moment that may be missing as a query parameter, see the [module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SqlOptionalTime(pub Option<SystemTime>);
+
+/// UUIDs as a query parameter, see the [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq, AsExpression)]
+#[diesel(sql_type = Array<Uuid>)]
+pub struct SqlUuids(pub Vec<uuid::Uuid>);
+
+impl From<uuid::Uuid> for SqlUuid {
+    fn from(uuid: uuid::Uuid) -> Self {
+        Self(uuid)
+    }
+}
+
+impl From<SystemTime> for SqlTime {
+    fn from(time: SystemTime) -> Self {
+        Self(time)
+    }
+}
+
+impl From<Option<SystemTime>> for SqlOptionalTime {
+    fn from(time: Option<SystemTime>) -> Self {
+        Self(time)
+    }
+}
+
+impl From<Vec<uuid::Uuid>> for SqlUuids {
+    fn from(uuids: Vec<uuid::Uuid>) -> Self {
+        Self(uuids)
+    }
+}
+
+impl AsExpression<Nullable<Timestamp>> for SqlOptionalTime {
+    type Expression = Bound<Nullable<Timestamp>, Self>;
+
+    fn as_expression(self) -> Self::Expression {
+        Bound::new(self)
+    }
+}
+
+/// Serialize a query parameter like the value it wraps.
+macro_rules! parameter {
+    ($parameter:ty, $sql_type:ty, $rust_type:ty) => {
+        impl<DB: Backend> ToSql<$sql_type, DB> for $parameter
+        where
+            $rust_type: ToSql<$sql_type, DB>,
+        {
+            fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, DB>) -> serialize::Result {
+                self.0.to_sql(out)
+            }
+        }
+    };
+}
+
+parameter!(SqlUuid, Uuid, uuid::Uuid);
+parameter!(SqlTime, Timestamp, SystemTime);
+parameter!(SqlUuids, Array<Uuid>, Vec<uuid::Uuid>);
+
+impl<DB: Backend> ToSql<Nullable<Timestamp>, DB> for SqlOptionalTime
+where
+    SystemTime: ToSql<Timestamp, DB>,
+{
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, DB>) -> serialize::Result {
+        match &self.0 {
+            Some(time) => time.to_sql(out),
+            None => Ok(IsNull::Yes),
+        }
+    }
+}
+
+/// Implement [`ToSql`] and [`FromSql`] for the [`MultiBackend`],
+/// by handing the value to whichever backend the query runs on.
+macro_rules! multi_backend_value {
+    ($sql_type:ty, $rust_type:ty) => {
+        impl ToSql<$sql_type, MultiBackend> for $rust_type {
+            fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, MultiBackend>) -> serialize::Result {
+                out.set_value((<$sql_type>::default(), self));
+                Ok(IsNull::No)
+            }
+        }
+
+        impl FromSql<$sql_type, MultiBackend> for $rust_type {
+            fn from_sql(
+                bytes: <MultiBackend as Backend>::RawValue<'_>,
+            ) -> deserialize::Result<Self> {
+                bytes.from_sql::<Self, $sql_type>()
+            }
+        }
+    };
+}
+
+multi_backend_value!(Uuid, uuid::Uuid);
+multi_backend_value!(Timestamp, SystemTime);
+multi_backend_value!(Array<Uuid>, Vec<uuid::Uuid>);
+
+/// Implement [`ToSql`] and [`FromSql`] for Postgres, by storing the value
+/// exactly like the diesel type that stands for the same Postgres type.
+macro_rules! postgres_value {
+    ($sql_type:ty, $rust_type:ty, $diesel_type:ty) => {
+        impl ToSql<$sql_type, Pg> for $rust_type {
+            fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+                ToSql::<$diesel_type, Pg>::to_sql(self, out)
+            }
+        }
+
+        impl FromSql<$sql_type, Pg> for $rust_type {
+            fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+                FromSql::<$diesel_type, Pg>::from_sql(bytes)
+            }
+        }
+    };
+}
+
+postgres_value!(Uuid, uuid::Uuid, diesel_types::Uuid);
+postgres_value!(Timestamp, SystemTime, diesel_types::Timestamp);
+postgres_value!(
+    Array<Uuid>,
+    Vec<uuid::Uuid>,
+    diesel_types::Array<diesel_types::Uuid>
+);
+
+/// Convert a moment into microseconds since the Unix epoch, which may be negative.
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+fn to_micros(time: SystemTime) -> Option<i64> {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => i64::try_from(since.as_micros()).ok(),
+        Err(error) => i64::try_from(error.duration().as_micros())
+            .ok()
+            .map(|micros| -micros),
+    }
+}
+
+/// Convert microseconds since the Unix epoch back into a moment.
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+fn from_micros(micros: i64) -> Option<SystemTime> {
+    let since = Duration::from_micros(micros.unsigned_abs());
+    if micros < 0 {
+        SystemTime::UNIX_EPOCH.checked_sub(since)
+    } else {
+        SystemTime::UNIX_EPOCH.checked_add(since)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::{from_micros, to_micros, Array, Timestamp, Uuid};
+    use diesel::deserialize::{self, FromSql};
+    use diesel::serialize::{self, IsNull, Output, ToSql};
+    use diesel::sql_types::{BigInt, HasSqlType, Text};
+    use diesel::sqlite::{Sqlite, SqliteType, SqliteValue};
+    use std::time::SystemTime;
+
+    impl HasSqlType<Array<Uuid>> for Sqlite {
+        fn metadata(_: &mut Self::MetadataLookup) -> Self::TypeMetadata {
+            SqliteType::Text
+        }
+    }
+
+    impl ToSql<Uuid, Sqlite> for uuid::Uuid {
+        fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
+            out.set_value(self.hyphenated().to_string());
+            Ok(IsNull::No)
+        }
+    }
+
+    impl FromSql<Uuid, Sqlite> for uuid::Uuid {
+        fn from_sql(bytes: SqliteValue<'_, '_, '_>) -> deserialize::Result<Self> {
+            let text = <String as FromSql<Text, Sqlite>>::from_sql(bytes)?;
+            Ok(text.parse()?)
+        }
+    }
+
+    impl ToSql<Timestamp, Sqlite> for SystemTime {
+        fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
+            let micros = to_micros(*self).ok_or("The timestamp is too far from the epoch")?;
+            out.set_value(micros);
+            Ok(IsNull::No)
+        }
+    }
+
+    impl FromSql<Timestamp, Sqlite> for SystemTime {
+        fn from_sql(bytes: SqliteValue<'_, '_, '_>) -> deserialize::Result<Self> {
+            let micros = <i64 as FromSql<BigInt, Sqlite>>::from_sql(bytes)?;
+            Ok(from_micros(micros).ok_or("The timestamp is too far from the epoch")?)
+        }
+    }
+
+    impl ToSql<Array<Uuid>, Sqlite> for Vec<uuid::Uuid> {
+        fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
+            let uuids: Vec<String> = self
+                .iter()
+                .map(|uuid| uuid.hyphenated().to_string())
+                .collect();
+            out.set_value(uuids.join(","));
+            Ok(IsNull::No)
+        }
+    }
+
+    impl FromSql<Array<Uuid>, Sqlite> for Vec<uuid::Uuid> {
+        fn from_sql(bytes: SqliteValue<'_, '_, '_>) -> deserialize::Result<Self> {
+            let text = <String as FromSql<Text, Sqlite>>::from_sql(bytes)?;
+            Ok(text
+                .split(',')
+                .filter(|uuid| !uuid.is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_micros, to_micros};
+    use rstest::rstest;
+    use std::time::{Duration, SystemTime};
+
+    #[rstest]
+    #[case::epoch(SystemTime::UNIX_EPOCH)]
+    #[case::after_epoch(SystemTime::UNIX_EPOCH + Duration::from_micros(1_717_243_200_123_456))]
+    #[case::before_epoch(SystemTime::UNIX_EPOCH - Duration::from_secs(86_400))]
+    fn micros_round_trip(#[case] time: SystemTime) {
+        assert_eq!(to_micros(time).and_then(from_micros), Some(time));
+    }
+}
@@ -0,0 +1,229 @@
+//! # Presence
+//!
+//! A user is online for as long as at least one of his clients holds a `SubscribeToUser`
+//! stream (the [`DisconnectChannel`](crate::channel::DisconnectChannel) behind that stream
+//! tells us when a client goes away). Users may be connected from multiple devices at
+//! once, so the [`PresenceTracker`] counts connections instead of flipping a flag.
+//!
+//! On top of that, a user may choose an explicit status (away, do-not-disturb) with
+//! a custom text, which is only shown while he's online. Whenever the last connection
+//! drops, the user becomes offline and his last-seen timestamp is updated.
+//!
+//! Last-seen timestamps are persisted, so an offline user without an explicit status
+//! is forgotten by the tracker right away, and only users that are online or have
+//! chosen a status take up memory.
+
+use crate::proto::{self, PresenceStatus};
+use hashbrown::HashMap;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// The presence of a single user, as tracked by the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Presence {
+    pub user_uuid: Uuid,
+    pub connections: usize,
+    pub status: PresenceStatus,
+    pub status_text: String,
+    pub last_seen: Option<SystemTime>,
+}
+
+impl Presence {
+    #[must_use]
+    pub fn offline(user_uuid: Uuid, last_seen: Option<SystemTime>) -> Self {
+        Self {
+            user_uuid,
+            connections: 0,
+            status: PresenceStatus::Online,
+            status_text: String::new(),
+            last_seen,
+        }
+    }
+
+    #[must_use]
+    pub const fn is_online(&self) -> bool {
+        self.connections > 0
+    }
+
+    /// Whether the user has chosen a status other than the default one.
+    #[must_use]
+    pub fn has_explicit_status(&self) -> bool {
+        self.status != PresenceStatus::Online || !self.status_text.is_empty()
+    }
+
+    /// The status other users should see.
+    #[must_use]
+    pub const fn visible_status(&self) -> PresenceStatus {
+        match self.is_online() {
+            true => self.status,
+            false => PresenceStatus::Offline,
+        }
+    }
+}
+
+impl From<Presence> for proto::Presence {
+    fn from(presence: Presence) -> Self {
+        let visible_status = presence.visible_status();
+        Self {
+            user_uuid: Some(presence.user_uuid.into()),
+            status: visible_status.into(),
+            status_text: match visible_status {
+                PresenceStatus::Offline => String::new(),
+                _ => presence.status_text,
+            },
+            last_seen: presence.last_seen.map(Into::into),
+        }
+    }
+}
+
+/// Keeps track of connections and explicit statuses of all users.
+#[derive(Debug, Default)]
+pub struct PresenceTracker {
+    states: Mutex<HashMap<Uuid, Presence>>,
+}
+
+impl PresenceTracker {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a new connection of a user.
+    ///
+    /// Returns the new presence if the user has just come online.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn connect(&self, user: Uuid, now: SystemTime) -> Option<Presence> {
+        let mut states = self.states.lock().expect("Presence mutex was poisoned");
+        let presence = states
+            .entry(user)
+            .or_insert_with(|| Presence::offline(user, None));
+
+        presence.connections += 1;
+        presence.last_seen = Some(now);
+        (presence.connections == 1).then(|| presence.clone())
+    }
+
+    /// Unregister a connection of a user.
+    ///
+    /// Returns the new presence if the user has just gone offline.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn disconnect(&self, user: Uuid, now: SystemTime) -> Option<Presence> {
+        let mut states = self.states.lock().expect("Presence mutex was poisoned");
+        let presence = states.get_mut(&user)?;
+
+        presence.connections = presence.connections.saturating_sub(1);
+        presence.last_seen = Some(now);
+        if presence.is_online() {
+            return None;
+        }
+
+        let presence = presence.clone();
+        if !presence.has_explicit_status() {
+            let _ = states.remove(&user);
+        }
+        Some(presence)
+    }
+
+    /// Set an explicit status of a user.
+    ///
+    /// Returns the new presence if the change is visible to other users.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn set_status(
+        &self,
+        user: Uuid,
+        status: PresenceStatus,
+        status_text: String,
+    ) -> Option<Presence> {
+        let mut states = self.states.lock().expect("Presence mutex was poisoned");
+        let presence = states
+            .entry(user)
+            .or_insert_with(|| Presence::offline(user, None));
+
+        presence.status = status;
+        presence.status_text = status_text;
+        if presence.is_online() {
+            return Some(presence.clone());
+        }
+
+        if !presence.has_explicit_status() {
+            let _ = states.remove(&user);
+        }
+        None
+    }
+
+    /// Get the presence of a user, if he's online or has an explicit status.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn get(&self, user: &Uuid) -> Option<Presence> {
+        let states = self.states.lock().expect("Presence mutex was poisoned");
+        states.get(user).cloned()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::PresenceTracker;
+    use crate::proto::{self, PresenceStatus};
+    use std::time::SystemTime;
+    use uuid::Uuid;
+
+    #[test]
+    fn multiple_devices() {
+        let tracker = PresenceTracker::new();
+        let user = Uuid::new_v4();
+        let now = SystemTime::now();
+
+        assert!(tracker.connect(user, now).is_some());
+        assert!(tracker.connect(user, now).is_none());
+        assert!(tracker.disconnect(user, now).is_none());
+        assert!(tracker.get(&user).is_some_and(|p| p.is_online()));
+
+        let presence = tracker.disconnect(user, now).unwrap();
+        assert!(!presence.is_online());
+        assert_eq!(presence.last_seen, Some(now));
+    }
+
+    #[test]
+    fn explicit_status() {
+        let tracker = PresenceTracker::new();
+        let user = Uuid::new_v4();
+        let now = SystemTime::now();
+
+        // Nobody should be notified about status changes of offline users...
+        assert!(tracker
+            .set_status(user, PresenceStatus::Away, "lunch".into())
+            .is_none());
+
+        // ...but the status should be kept until he comes online.
+        let presence = tracker.connect(user, now).unwrap();
+        assert_eq!(presence.visible_status(), PresenceStatus::Away);
+
+        let presence = tracker.disconnect(user, now).unwrap();
+        let proto_presence = proto::Presence::from(presence);
+        assert_eq!(proto_presence.status(), PresenceStatus::Offline);
+        assert!(proto_presence.status_text.is_empty());
+    }
+
+    #[test]
+    fn offline_users_are_forgotten() {
+        let tracker = PresenceTracker::new();
+        let (plain, away) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = SystemTime::now();
+
+        tracker.connect(plain, now);
+        tracker.connect(away, now);
+        tracker.set_status(away, PresenceStatus::Away, String::new());
+        tracker.disconnect(plain, now).unwrap();
+        tracker.disconnect(away, now).unwrap();
+
+        assert!(tracker.get(&plain).is_none());
+        assert!(tracker.get(&away).is_some());
+
+        // Going back to the default status leaves nothing worth keeping.
+        assert!(tracker
+            .set_status(away, PresenceStatus::Online, String::new())
+            .is_none());
+        assert!(tracker.get(&away).is_none());
+    }
+}
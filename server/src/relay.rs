@@ -0,0 +1,248 @@
+//! # Cross-instance relay
+//!
+//! Events are delivered to local subscribers through in-process channels, which is enough
+//! for a single server. To let several instances run behind a load balancer, every event
+//! is also published to a Redis pub/sub [`CHANNEL`], and every instance relays the events
+//! published by the others to its own subscribers.
+//!
+//! ## Double delivery
+//!
+//! Each instance tags the events it publishes with a random instance UUID, and skips
+//! its own events when they come back from Redis, since they've been delivered already.
+//!
+//! ## Publishing
+//!
+//! Events are emitted from synchronous code too, so publishing goes through a bounded
+//! queue drained by a background task. Relaying is best-effort: while Redis is unreachable,
+//! the publisher reconnects with an exponential backoff, and events that don't fit into the
+//! queue in the meantime are dropped (and counted), just like events for a lagging subscriber are.
+//!
+//! ## Single instance
+//!
+//! Without Redis (when `$KV_URL` is unset), there's no relay at all, and events are
+//! only delivered locally.
+//!
+//! ## Limitations
+//!
+//! Only events are shared. Ephemeral state, such as the connection counts behind
+//! [presence](crate::presence), is still tracked by each instance on its own.
+
+use crate::proto::{relayed_event, RelayedEvent};
+use prost::Message;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, RedisResult};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use uuid::Uuid;
+
+/// The Redis pub/sub channel all instances exchange events through.
+pub const CHANNEL: &str = "tcp-chat:events";
+
+/// For how long to wait before reconnecting to Redis after a failure.
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The longest the publisher waits between attempts to reconnect to Redis.
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// How many events may wait to be published before new ones are dropped.
+pub const OUTBOX_CAPACITY: usize = 1024;
+
+/// The publishing side of the relay, shared by everything that emits events.
+#[derive(Debug, Clone)]
+pub struct Relay {
+    client: redis::Client,
+    instance_uuid: Uuid,
+    outbox: mpsc::Sender<RelayedEvent>,
+    /// How many events were dropped because the outbox was full.
+    dropped: Arc<AtomicU64>,
+}
+
+impl Relay {
+    /// Create a relay for a new instance, spawning a task that publishes its events.
+    #[must_use]
+    pub fn start(client: redis::Client) -> Self {
+        let (relay, outbox) = Self::new(client.clone());
+        tokio::spawn(publish(client, outbox));
+        relay
+    }
+
+    fn new(client: redis::Client) -> (Self, mpsc::Receiver<RelayedEvent>) {
+        let instance_uuid = Uuid::new_v4();
+        tracing::info!(message = "Relaying events across instances", instance = ?instance_uuid);
+        let (outbox, outbox_rx) = mpsc::channel(OUTBOX_CAPACITY);
+        let relay = Self {
+            client,
+            instance_uuid,
+            outbox,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        (relay, outbox_rx)
+    }
+
+    /// Queue an event of this instance for other instances, or drop it if the queue is full.
+    pub fn publish(&self, event: relayed_event::Event) {
+        let relayed = RelayedEvent {
+            instance_uuid: Some(self.instance_uuid.into()),
+            event: Some(event),
+        };
+        match self.outbox.try_send(relayed) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                // NOTE: Only warn every now and then, since this goes on for as long as Redis is down.
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped.is_power_of_two() {
+                    tracing::warn!(
+                        message = "The relay outbox is full, dropping events",
+                        %dropped
+                    );
+                }
+            }
+            Err(TrySendError::Closed(_)) => {
+                tracing::error!(message = "The relay publisher is gone, dropping an event");
+            }
+        }
+    }
+
+    /// How many events have been dropped so far because the outbox was full.
+    #[must_use]
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Decode an event received from Redis, unless it's been published by this instance.
+    #[must_use]
+    pub fn accept(&self, payload: &[u8]) -> Option<relayed_event::Event> {
+        let relayed = RelayedEvent::decode(payload)
+            .map_err(|error| tracing::warn!(message = "Couldn't decode a relayed event", ?error))
+            .ok()?;
+        let origin: Uuid = relayed.instance_uuid.and_then(|u| u.try_into().ok())?;
+
+        if origin == self.instance_uuid {
+            return None;
+        }
+        relayed.event
+    }
+
+    /// Start listening to the events published by all instances, see [`Self::accept`].
+    pub async fn subscribe(&self) -> RedisResult<redis::aio::PubSub> {
+        let mut pubsub = self.client.get_async_pubsub().await?;
+        pubsub.subscribe(CHANNEL).await?;
+        tracing::debug!(message = "Subscribed to relayed events");
+        Ok(pubsub)
+    }
+}
+
+/// Publish queued events to Redis until the relay is dropped, reconnecting as needed.
+///
+/// Failed connection attempts are retried after a delay that doubles up to
+/// [`MAX_RECONNECT_DELAY`]. Events keep queueing up in the meantime, see [`Relay::publish`].
+async fn publish(client: redis::Client, mut outbox: mpsc::Receiver<RelayedEvent>) {
+    let mut connection: Option<MultiplexedConnection> = None;
+    let mut reconnect_delay = RECONNECT_DELAY;
+
+    while let Some(event) = outbox.recv().await {
+        if connection.is_none() {
+            match client.get_multiplexed_async_connection().await {
+                Ok(established) => {
+                    connection = Some(established);
+                    reconnect_delay = RECONNECT_DELAY;
+                }
+                Err(error) => {
+                    tracing::error!(
+                        message = "Couldn't connect to Redis to relay events",
+                        ?error,
+                        retry_in = ?reconnect_delay
+                    );
+                    tokio::time::sleep(reconnect_delay).await;
+                    reconnect_delay = next_reconnect_delay(reconnect_delay);
+                    continue;
+                }
+            }
+        }
+        let Some(cache) = connection.as_mut() else {
+            continue;
+        };
+
+        let published: redis::RedisResult<usize> =
+            cache.publish(CHANNEL, event.encode_to_vec()).await;
+        if let Err(error) = published {
+            tracing::error!(message = "Couldn't publish a relayed event", ?error);
+            connection = None;
+        }
+    }
+}
+
+/// How long to wait before the next attempt to reconnect, after waiting for `delay`.
+#[must_use]
+pub fn next_reconnect_delay(delay: Duration) -> Duration {
+    (delay * 2).min(MAX_RECONNECT_DELAY)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{next_reconnect_delay, Relay};
+    use super::{MAX_RECONNECT_DELAY, OUTBOX_CAPACITY, RECONNECT_DELAY};
+    use crate::proto::{relayed_event, RelayedEvent, ServersideRoomEvent};
+    use prost::Message;
+    use uuid::Uuid;
+
+    fn relay() -> (Relay, tokio::sync::mpsc::Receiver<RelayedEvent>) {
+        Relay::new(redis::Client::open("redis://localhost").unwrap())
+    }
+
+    fn room_event() -> relayed_event::Event {
+        relayed_event::Event::RoomEvent(ServersideRoomEvent {
+            room_uuid: Some(Uuid::new_v4().into()),
+            event: None,
+        })
+    }
+
+    #[test]
+    fn own_events_are_skipped() {
+        let (relay, mut outbox) = relay();
+        relay.publish(room_event());
+
+        let published = outbox.try_recv().unwrap();
+        assert_eq!(relay.accept(&published.encode_to_vec()), None);
+    }
+
+    #[test]
+    fn foreign_events_are_accepted() {
+        let (relay, _outbox) = relay();
+        let event = room_event();
+        let foreign = RelayedEvent {
+            instance_uuid: Some(Uuid::new_v4().into()),
+            event: Some(event.clone()),
+        };
+
+        assert_eq!(relay.accept(&foreign.encode_to_vec()), Some(event));
+        assert_eq!(relay.accept(b"garbage"), None);
+    }
+
+    #[test]
+    fn events_are_dropped_once_the_outbox_is_full() {
+        let (relay, mut outbox) = relay();
+        for _ in 0..OUTBOX_CAPACITY + 3 {
+            relay.publish(room_event());
+        }
+        assert_eq!(relay.dropped_events(), 3);
+
+        outbox.try_recv().unwrap();
+        relay.publish(room_event());
+        assert_eq!(relay.dropped_events(), 3);
+    }
+
+    #[test]
+    fn reconnects_back_off_up_to_a_limit() {
+        let mut delay = RECONNECT_DELAY;
+        for _ in 0..10 {
+            let next = next_reconnect_delay(delay);
+            assert!(next >= delay);
+            delay = next;
+        }
+        assert_eq!(delay, MAX_RECONNECT_DELAY);
+    }
+}
@@ -0,0 +1,899 @@
+use super::ScheduledMessageRepository;
+use super::{AttachmentRepository, Deduplicated, PinOutcome, PinRepository};
+use super::{MessageRepository, RepositoryResult, RoomRepository, UnpinOutcome, UserRepository};
+use crate::entities::schema::messages;
+use crate::entities::{username, Attachment, IdempotencyKey, Mention, MessagePin, MessageSearch};
+use crate::entities::{HydratedMessage, Message, ReadMarker, Room, RoomSubscription, RoomUser};
+use crate::entities::{ScheduledMessage, User};
+use crate::persistence::sql_types::{SqlOptionalTime, SqlTime, SqlUuid};
+use crate::persistence::{self, ConnectionPool, PooledConnection};
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as QueryError};
+use diesel::PgConnection;
+use hashbrown::HashMap;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// Repositories backed by the database, either Postgres or SQLite (see [`persistence`]).
+#[derive(Debug, Clone)]
+pub struct DatabaseRepository {
+    pool: ConnectionPool,
+}
+
+impl DatabaseRepository {
+    #[must_use]
+    pub const fn new(pool: ConnectionPool) -> Self {
+        Self { pool }
+    }
+
+    /// Run queries on a pooled connection, see [`persistence::run`].
+    async fn run<T, F>(&self, work: F) -> RepositoryResult<T>
+    where
+        F: FnOnce(&mut PooledConnection) -> QueryResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        Ok(persistence::run(&self.pool, work).await??)
+    }
+}
+
+#[tonic::async_trait]
+impl UserRepository for DatabaseRepository {
+    async fn find(&self, user_uuid: Uuid) -> RepositoryResult<Option<User>> {
+        use crate::entities::schema::users;
+
+        self.run(move |db| {
+            users::table
+                .find(SqlUuid(user_uuid))
+                .select(User::as_select())
+                .first(db)
+                .optional()
+        })
+        .await
+    }
+
+    async fn find_by_username(&self, username: String) -> RepositoryResult<Option<User>> {
+        use crate::entities::schema::users;
+
+        let canonical_username = username::canonicalize(&username);
+        self.run(move |db| {
+            users::table
+                .filter(users::canonical_username.eq(canonical_username))
+                .select(User::as_select())
+                .first(db)
+                .optional()
+        })
+        .await
+    }
+
+    async fn find_by_credentials(
+        &self,
+        username: String,
+        password: String,
+    ) -> RepositoryResult<Option<User>> {
+        use crate::entities::schema::users;
+
+        let canonical_username = username::canonicalize(&username);
+        self.run(move |db| {
+            users::table
+                .filter(users::canonical_username.eq(canonical_username))
+                .filter(users::password.eq(password))
+                .select(User::as_select())
+                .first(db)
+                .optional()
+        })
+        .await
+    }
+
+    async fn create(&self, user: User) -> RepositoryResult<bool> {
+        use crate::entities::schema::users;
+
+        // NOTE: Checking for a duplicate first would race with concurrent
+        // registrations, so the unique index on usernames decides.
+        self.run(move |db| {
+            match diesel::insert_into(users::table)
+                .values(user.clone())
+                .execute(db)
+            {
+                Ok(_) => Ok(true),
+                Err(QueryError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Ok(false),
+                Err(error) => Err(error),
+            }
+        })
+        .await
+    }
+
+    async fn last_seen(
+        &self,
+        user_uuids: Vec<Uuid>,
+    ) -> RepositoryResult<Vec<(Uuid, Option<SystemTime>)>> {
+        use crate::entities::schema::users;
+
+        self.run(move |db| {
+            users::table
+                .filter(users::uuid.eq_any(user_uuids.into_iter().map(SqlUuid)))
+                .select((users::uuid, users::last_seen))
+                .load(db)
+        })
+        .await
+    }
+
+    async fn set_last_seen(
+        &self,
+        user_uuid: Uuid,
+        last_seen: Option<SystemTime>,
+    ) -> RepositoryResult<()> {
+        use crate::entities::schema::users;
+
+        self.run(move |db| {
+            diesel::update(users::table.find(SqlUuid(user_uuid)))
+                .set(users::last_seen.eq(SqlOptionalTime(last_seen)))
+                .execute(db)
+                .map(|_| ())
+        })
+        .await
+    }
+}
+
+#[tonic::async_trait]
+impl RoomRepository for DatabaseRepository {
+    async fn find(&self, room_uuid: Uuid) -> RepositoryResult<Option<Room>> {
+        use crate::entities::schema::rooms;
+
+        self.run(move |db| {
+            rooms::table
+                .find(SqlUuid(room_uuid))
+                .select(Room::as_select())
+                .first(db)
+                .optional()
+        })
+        .await
+    }
+
+    async fn find_many(&self, room_uuids: Vec<Uuid>) -> RepositoryResult<Vec<Room>> {
+        use crate::entities::schema::rooms;
+
+        self.run(move |db| {
+            rooms::table
+                .filter(rooms::uuid.eq_any(room_uuids.into_iter().map(SqlUuid)))
+                .select(Room::as_select())
+                .load(db)
+        })
+        .await
+    }
+
+    async fn create(&self, room: Room, member_uuids: Vec<Uuid>) -> RepositoryResult<Vec<Uuid>> {
+        use crate::entities::schema::{rooms, rooms_users, users};
+
+        self.run(move |db| {
+            db.write_transaction(|db| {
+                let registered: Vec<Uuid> = users::table
+                    .filter(users::uuid.eq_any(member_uuids.iter().copied().map(SqlUuid)))
+                    .select(users::uuid)
+                    .load(db)?;
+                let unknown: Vec<Uuid> = member_uuids
+                    .iter()
+                    .filter(|user_uuid| !registered.contains(user_uuid))
+                    .copied()
+                    .collect();
+                if !unknown.is_empty() {
+                    return Ok(unknown);
+                }
+
+                let members: Vec<RoomUser> = member_uuids
+                    .into_iter()
+                    .map(|user_uuid| RoomUser {
+                        room_uuid: room.uuid,
+                        user_uuid,
+                    })
+                    .collect();
+                let _ = diesel::insert_into(rooms::table)
+                    .values(room.clone())
+                    .execute(db)?;
+                // NOTE: Diesel can't batch inserts for both backends at once.
+                for member in &members {
+                    let _ = diesel::insert_into(rooms_users::table)
+                        .values(member.clone())
+                        .execute(db)?;
+                }
+                Ok(vec![])
+            })
+        })
+        .await
+    }
+
+    async fn set_message_ttl(
+        &self,
+        room_uuid: Uuid,
+        ttl: Option<Duration>,
+    ) -> RepositoryResult<()> {
+        use crate::entities::schema::rooms;
+
+        let ttl_seconds = ttl.and_then(|ttl| i32::try_from(ttl.as_secs()).ok());
+        self.run(move |db| {
+            diesel::update(rooms::table.find(SqlUuid(room_uuid)))
+                .set(rooms::message_ttl_seconds.eq(ttl_seconds))
+                .execute(db)
+                .map(|_| ())
+        })
+        .await
+    }
+
+    async fn members(&self, room_uuid: Uuid) -> RepositoryResult<Vec<Uuid>> {
+        use crate::entities::schema::rooms_users;
+
+        self.run(move |db| {
+            rooms_users::table
+                .filter(rooms_users::room_uuid.eq(SqlUuid(room_uuid)))
+                .select(rooms_users::user_uuid)
+                .load(db)
+        })
+        .await
+    }
+
+    async fn members_of_many(&self, room_uuids: Vec<Uuid>) -> RepositoryResult<Vec<Vec<Uuid>>> {
+        use crate::entities::schema::rooms_users;
+
+        self.run(move |db| {
+            let mut members: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+            for (room_uuid, user_uuid) in rooms_users::table
+                .filter(rooms_users::room_uuid.eq_any(room_uuids.iter().copied().map(SqlUuid)))
+                .select((rooms_users::room_uuid, rooms_users::user_uuid))
+                .load::<(Uuid, Uuid)>(db)?
+            {
+                members.entry(room_uuid).or_default().push(user_uuid);
+            }
+
+            Ok(room_uuids
+                .iter()
+                .map(|room_uuid| members.get(room_uuid).cloned().unwrap_or_default())
+                .collect())
+        })
+        .await
+    }
+
+    async fn rooms_of(&self, user_uuid: Uuid) -> RepositoryResult<Vec<Uuid>> {
+        self.run(move |db| RoomUser::rooms_of(&user_uuid, db)).await
+    }
+
+    async fn is_member(&self, room_uuid: Uuid, user_uuid: Uuid) -> RepositoryResult<bool> {
+        self.run(move |db| RoomUser::exists(&room_uuid, &user_uuid, db))
+            .await
+    }
+
+    async fn neighbours(&self, user_uuid: Uuid) -> RepositoryResult<Vec<Uuid>> {
+        use crate::entities::schema::rooms_users;
+
+        self.run(move |db| {
+            let shared_rooms: Vec<Uuid> = rooms_users::table
+                .filter(rooms_users::user_uuid.eq(SqlUuid(user_uuid)))
+                .select(rooms_users::room_uuid)
+                .load(db)?;
+
+            rooms_users::table
+                .filter(rooms_users::room_uuid.eq_any(shared_rooms.into_iter().map(SqlUuid)))
+                .filter(rooms_users::user_uuid.ne(SqlUuid(user_uuid)))
+                .select(rooms_users::user_uuid)
+                .distinct()
+                .load(db)
+        })
+        .await
+    }
+}
+
+#[tonic::async_trait]
+impl MessageRepository for DatabaseRepository {
+    async fn list(&self, room_uuid: Uuid, now: SystemTime) -> RepositoryResult<Vec<Message>> {
+        use crate::entities::schema::messages;
+
+        self.run(move |db| {
+            messages::table
+                .filter(messages::room_uuid.eq(SqlUuid(room_uuid)))
+                .filter(
+                    messages::expires_at
+                        .is_null()
+                        .or(messages::expires_at.gt(SqlTime(now))),
+                )
+                .order_by(messages::sequence)
+                .select(Message::as_select())
+                .load(db)
+        })
+        .await
+    }
+
+    async fn find_in_room(
+        &self,
+        room_uuid: Uuid,
+        message_uuid: Uuid,
+    ) -> RepositoryResult<Option<Message>> {
+        use crate::entities::schema::messages;
+
+        self.run(move |db| {
+            messages::table
+                .find(SqlUuid(message_uuid))
+                .filter(messages::room_uuid.eq(SqlUuid(room_uuid)))
+                .select(Message::as_select())
+                .first(db)
+                .optional()
+        })
+        .await
+    }
+
+    async fn store(
+        &self,
+        mut message: Message,
+        attachment_uuids: Vec<Uuid>,
+        idempotency_key: Option<IdempotencyKey>,
+    ) -> RepositoryResult<Deduplicated<(Message, Vec<Mention>)>> {
+        self.run(move |db| {
+            db.write_transaction(|db| {
+                if let Some(key) = &idempotency_key {
+                    if let Some(duplicate_of) = key.claim(db)? {
+                        return Ok(Deduplicated::Duplicate(duplicate_of));
+                    }
+                }
+
+                let mentions = store_message(db, &mut message, &attachment_uuids)?;
+                Ok(Deduplicated::Stored((message, mentions)))
+            })
+        })
+        .await
+    }
+
+    async fn hydrate(&self, messages: Vec<Message>) -> RepositoryResult<Vec<HydratedMessage>> {
+        self.run(move |db| Message::hydrate(messages, db)).await
+    }
+
+    async fn search(
+        &self,
+        searcher_uuid: Uuid,
+        search: MessageSearch,
+        now: SystemTime,
+    ) -> RepositoryResult<Vec<(Message, f32, String)>> {
+        // NOTE: Postgres searches its full text index, which can only be queried on its own connection.
+        self.run(move |db| match db.as_postgres() {
+            Some(db) => db.search_messages(
+                searched_messages!(searcher_uuid, &search, now),
+                &search.query,
+            ),
+            None => db.search_messages(
+                searched_messages!(searcher_uuid, &search, now),
+                &search.query,
+            ),
+        })
+        .await
+    }
+
+    async fn missed(
+        &self,
+        subscription: RoomSubscription,
+        now: SystemTime,
+    ) -> RepositoryResult<Option<Vec<Message>>> {
+        self.run(move |db| match subscription.missed_messages(now, db) {
+            Ok(messages) => Ok(Some(messages)),
+            Err(QueryError::NotFound) => Ok(None),
+            Err(error) => Err(error),
+        })
+        .await
+    }
+
+    async fn delete_expired(&self, now: SystemTime, limit: i64) -> RepositoryResult<Vec<Message>> {
+        self.run(move |db| Message::delete_expired(now, limit, db))
+            .await
+    }
+
+    async fn next_expiry(&self) -> RepositoryResult<Option<SystemTime>> {
+        use crate::entities::schema::messages;
+
+        self.run(|db| {
+            messages::table
+                .select(diesel::dsl::min(messages::expires_at))
+                .first(db)
+        })
+        .await
+    }
+
+    async fn prune_idempotency_keys(&self, now: SystemTime) -> RepositoryResult<usize> {
+        self.run(move |db| IdempotencyKey::prune(now, db)).await
+    }
+
+    async fn move_read_marker(&self, marker: ReadMarker) -> RepositoryResult<bool> {
+        use crate::entities::schema::read_markers;
+
+        // NOTE: The update is conditional, so that concurrent requests can't move the marker back.
+        self.run(move |db| {
+            db.write_transaction(|db| {
+                let inserted = db.insert_or_ignore(|db| {
+                    diesel::insert_into(read_markers::table)
+                        .values(marker.clone())
+                        .execute(db)
+                })?;
+                if inserted > 0 {
+                    return Ok(true);
+                }
+
+                diesel::update(
+                    read_markers::table
+                        .find((SqlUuid(marker.user_uuid), SqlUuid(marker.room_uuid))),
+                )
+                .filter(read_markers::message_sequence.lt(marker.message_sequence))
+                .set(marker.clone())
+                .execute(db)
+                .map(|updated| updated > 0)
+            })
+        })
+        .await
+    }
+
+    async fn unread_statuses(
+        &self,
+        room_uuids: Vec<Uuid>,
+        reader_uuid: Uuid,
+        now: SystemTime,
+    ) -> RepositoryResult<Vec<(Option<Uuid>, u64)>> {
+        use crate::entities::schema::{messages, read_markers};
+        use diesel::dsl::count_star;
+
+        self.run(move |db| {
+            let sql_room_uuids: Vec<SqlUuid> = room_uuids.iter().copied().map(SqlUuid).collect();
+            let markers: HashMap<Uuid, Uuid> = read_markers::table
+                .filter(read_markers::user_uuid.eq(SqlUuid(reader_uuid)))
+                .filter(read_markers::room_uuid.eq_any(&sql_room_uuids))
+                .select((read_markers::room_uuid, read_markers::message_uuid))
+                .load::<(Uuid, Uuid)>(db)?
+                .into_iter()
+                .collect();
+
+            // Messages count as unread in rooms without a marker, where the comparison is NULL.
+            let reader_marker = read_markers::room_uuid
+                .eq(messages::room_uuid)
+                .and(read_markers::user_uuid.eq(SqlUuid(reader_uuid)));
+            let unread_counts: HashMap<Uuid, i64> = messages::table
+                .left_join(read_markers::table.on(reader_marker))
+                .filter(messages::room_uuid.eq_any(&sql_room_uuids))
+                .filter(messages::sender_uuid.ne(SqlUuid(reader_uuid)))
+                .filter(
+                    messages::expires_at
+                        .is_null()
+                        .or(messages::expires_at.gt(SqlTime(now))),
+                )
+                .filter(
+                    read_markers::message_sequence
+                        .nullable()
+                        .is_null()
+                        .or(messages::sequence
+                            .gt(read_markers::message_sequence.nullable().assume_not_null())),
+                )
+                .group_by(messages::room_uuid)
+                .select((messages::room_uuid, count_star()))
+                .load::<(Uuid, i64)>(db)?
+                .into_iter()
+                .collect();
+
+            Ok(room_uuids
+                .iter()
+                .map(|room_uuid| {
+                    let unread_count = unread_counts.get(room_uuid).copied().unwrap_or_default();
+                    (
+                        markers.get(room_uuid).copied(),
+                        unread_count.try_into().unwrap_or_default(),
+                    )
+                })
+                .collect())
+        })
+        .await
+    }
+}
+
+#[tonic::async_trait]
+impl AttachmentRepository for DatabaseRepository {
+    async fn find(&self, attachment_uuid: Uuid) -> RepositoryResult<Option<Attachment>> {
+        use crate::entities::schema::attachments;
+
+        self.run(move |db| {
+            attachments::table
+                .find(SqlUuid(attachment_uuid))
+                .select(Attachment::as_select())
+                .first(db)
+                .optional()
+        })
+        .await
+    }
+
+    async fn find_in_room(
+        &self,
+        room_uuid: Uuid,
+        attachment_uuids: Vec<Uuid>,
+    ) -> RepositoryResult<Vec<Attachment>> {
+        use crate::entities::schema::attachments;
+
+        self.run(move |db| {
+            attachments::table
+                .filter(attachments::uuid.eq_any(attachment_uuids.into_iter().map(SqlUuid)))
+                .filter(attachments::room_uuid.eq(SqlUuid(room_uuid)))
+                .select(Attachment::as_select())
+                .load(db)
+        })
+        .await
+    }
+
+    async fn create(&self, attachment: Attachment) -> RepositoryResult<()> {
+        use crate::entities::schema::attachments;
+
+        self.run(move |db| {
+            diesel::insert_into(attachments::table)
+                .values(attachment)
+                .execute(db)
+                .map(|_| ())
+        })
+        .await
+    }
+}
+
+#[tonic::async_trait]
+impl PinRepository for DatabaseRepository {
+    async fn pin(&self, pin: MessagePin) -> RepositoryResult<PinOutcome> {
+        use crate::entities::schema::{pins, rooms};
+
+        self.run(move |db| {
+            db.write_transaction(|db| {
+                // Lock the room's row, so that concurrent pins can't both squeeze under the limit.
+                // SQLite has locked the whole database already.
+                if let Some(db) = db.as_postgres() {
+                    let _: Uuid = rooms::table
+                        .find(SqlUuid(pin.room_uuid))
+                        .select(rooms::uuid)
+                        .for_update()
+                        .first(db)?;
+                }
+
+                let pinned: Vec<Uuid> = pins::table
+                    .filter(pins::room_uuid.eq(SqlUuid(pin.room_uuid)))
+                    .select(pins::message_uuid)
+                    .load(db)?;
+                if pinned.contains(&pin.message_uuid) {
+                    return Ok(PinOutcome::AlreadyPinned);
+                }
+                if pinned.len() >= MessagePin::MAX_PINS_PER_ROOM {
+                    return Ok(PinOutcome::LimitReached);
+                }
+
+                let inserted = db.insert_or_ignore(|db| {
+                    diesel::insert_into(pins::table)
+                        .values(pin.clone())
+                        .execute(db)
+                })?;
+                Ok(if inserted == 0 {
+                    PinOutcome::AlreadyPinned
+                } else {
+                    PinOutcome::Pinned
+                })
+            })
+        })
+        .await
+    }
+
+    async fn unpin(
+        &self,
+        room_uuid: Uuid,
+        message_uuid: Uuid,
+        unpinner_uuid: Uuid,
+    ) -> RepositoryResult<UnpinOutcome> {
+        use crate::entities::schema::{messages, pins};
+
+        self.run(move |db| {
+            db.write_transaction(|db| {
+                let pinned: Option<(Uuid, Uuid)> = pins::table
+                    .inner_join(messages::table)
+                    .filter(pins::message_uuid.eq(SqlUuid(message_uuid)))
+                    .filter(pins::room_uuid.eq(SqlUuid(room_uuid)))
+                    .select((pins::pinner_uuid, messages::sender_uuid))
+                    .first(db)
+                    .optional()?;
+                let Some((pinner_uuid, author_uuid)) = pinned else {
+                    return Ok(UnpinOutcome::NotPinned);
+                };
+                if unpinner_uuid != pinner_uuid && unpinner_uuid != author_uuid {
+                    return Ok(UnpinOutcome::NotAllowed);
+                }
+
+                diesel::delete(pins::table.find(SqlUuid(message_uuid))).execute(db)?;
+                Ok(UnpinOutcome::Unpinned)
+            })
+        })
+        .await
+    }
+
+    async fn list(&self, room_uuid: Uuid) -> RepositoryResult<Vec<(MessagePin, Message)>> {
+        use crate::entities::schema::{messages, pins};
+
+        self.run(move |db| {
+            pins::table
+                .inner_join(messages::table)
+                .filter(pins::room_uuid.eq(SqlUuid(room_uuid)))
+                .order_by(pins::timestamp.desc())
+                .select((MessagePin::as_select(), Message::as_select()))
+                .load(db)
+        })
+        .await
+    }
+}
+
+#[tonic::async_trait]
+impl ScheduledMessageRepository for DatabaseRepository {
+    async fn schedule(
+        &self,
+        message: ScheduledMessage,
+        idempotency_key: Option<IdempotencyKey>,
+    ) -> RepositoryResult<Option<Deduplicated<ScheduledMessage>>> {
+        use crate::entities::schema::{scheduled_messages, users};
+
+        self.run(move |db| {
+            db.write_transaction(|db| {
+                // Lock the sender's row, so that concurrent messages can't both squeeze under the
+                // limit. SQLite has locked the whole database already.
+                if let Some(db) = db.as_postgres() {
+                    let _: Uuid = users::table
+                        .find(SqlUuid(message.sender_uuid))
+                        .select(users::uuid)
+                        .for_update()
+                        .first(db)?;
+                }
+
+                let pending: i64 = scheduled_messages::table
+                    .filter(scheduled_messages::sender_uuid.eq(SqlUuid(message.sender_uuid)))
+                    .count()
+                    .get_result(db)?;
+                if pending >= ScheduledMessage::MAX_PENDING_PER_USER {
+                    return Ok(None);
+                }
+
+                if let Some(key) = &idempotency_key {
+                    if let Some(duplicate_of) = key.claim(db)? {
+                        return Ok(Some(Deduplicated::Duplicate(duplicate_of)));
+                    }
+                }
+
+                diesel::insert_into(scheduled_messages::table)
+                    .values(message.clone())
+                    .execute(db)?;
+                Ok(Some(Deduplicated::Stored(message)))
+            })
+        })
+        .await
+    }
+
+    async fn list_pending(&self, sender_uuid: Uuid) -> RepositoryResult<Vec<ScheduledMessage>> {
+        use crate::entities::schema::scheduled_messages;
+
+        self.run(move |db| {
+            scheduled_messages::table
+                .filter(scheduled_messages::sender_uuid.eq(SqlUuid(sender_uuid)))
+                .order_by(scheduled_messages::deliver_at)
+                .select(ScheduledMessage::as_select())
+                .load(db)
+        })
+        .await
+    }
+
+    async fn cancel(&self, sender_uuid: Uuid, message_uuid: Uuid) -> RepositoryResult<bool> {
+        use crate::entities::schema::scheduled_messages;
+
+        self.run(move |db| {
+            diesel::delete(scheduled_messages::table.find(SqlUuid(message_uuid)))
+                .filter(scheduled_messages::sender_uuid.eq(SqlUuid(sender_uuid)))
+                .execute(db)
+                .map(|deleted| deleted > 0)
+        })
+        .await
+    }
+
+    async fn due(&self, now: SystemTime, limit: i64) -> RepositoryResult<Vec<ScheduledMessage>> {
+        use crate::entities::schema::scheduled_messages;
+
+        self.run(move |db| {
+            scheduled_messages::table
+                .filter(scheduled_messages::deliver_at.le(SqlTime(now)))
+                .order_by(scheduled_messages::deliver_at)
+                .limit(limit)
+                .select(ScheduledMessage::as_select())
+                .load(db)
+        })
+        .await
+    }
+
+    async fn next_delivery(&self) -> RepositoryResult<Option<SystemTime>> {
+        use crate::entities::schema::scheduled_messages;
+
+        self.run(|db| {
+            scheduled_messages::table
+                .select(diesel::dsl::min(scheduled_messages::deliver_at))
+                .first(db)
+        })
+        .await
+    }
+
+    async fn deliver(
+        &self,
+        mut message: Message,
+        attachment_uuids: Vec<Uuid>,
+    ) -> RepositoryResult<Option<(Message, Vec<Mention>)>> {
+        use crate::entities::schema::{rooms_users, scheduled_messages};
+
+        self.run(move |db| {
+            db.write_transaction(|db| {
+                let dequeued =
+                    diesel::delete(scheduled_messages::table.find(SqlUuid(message.uuid))).execute(db)?;
+                if dequeued == 0 {
+                    return Ok(None);
+                }
+
+                let is_member: bool = diesel::select(diesel::dsl::exists(
+                    rooms_users::table.find((SqlUuid(message.room_uuid), SqlUuid(message.sender_uuid))),
+                ))
+                .get_result(db)?;
+                if !is_member {
+                    tracing::warn!(
+                        message = "Dropping a scheduled message, its sender is no longer a room member",
+                        user = ?message.sender_uuid,
+                        room = ?message.room_uuid
+                    );
+                    return Ok(None);
+                }
+
+                let mentions = store_message(db, &mut message, &attachment_uuids)?;
+                Ok(Some((message, mentions)))
+            })
+        })
+        .await
+    }
+
+    async fn postpone(&self, message_uuid: Uuid, deliver_at: SystemTime) -> RepositoryResult<()> {
+        use crate::entities::schema::scheduled_messages;
+
+        self.run(move |db| {
+            diesel::update(scheduled_messages::table.find(SqlUuid(message_uuid)))
+                .set(scheduled_messages::deliver_at.eq(SqlTime(deliver_at)))
+                .execute(db)
+                .map(|_| ())
+        })
+        .await
+    }
+}
+
+/// Resolve mentions in a message, then store it along with them and links to its attachments.
+fn store_message(
+    db: &mut persistence::Connection,
+    message: &mut Message,
+    attachment_uuids: &[Uuid],
+) -> QueryResult<Vec<Mention>> {
+    let mentions = Mention::resolve(message, db)?;
+    message.store(attachment_uuids, &mentions, db)?;
+    Ok(mentions)
+}
+
+/// Select the messages that `$searcher_uuid` may find with `$search` at `$now`, without matching
+/// its text yet, as a boxed query for whichever backend it's run on.
+macro_rules! searched_messages {
+    ($searcher_uuid:expr, $search:expr, $now:expr) => {{
+        use crate::entities::schema::rooms_users;
+
+        let search: &MessageSearch = $search;
+        let member_rooms = rooms_users::table
+            .filter(rooms_users::user_uuid.eq(SqlUuid($searcher_uuid)))
+            .select(rooms_users::room_uuid);
+
+        let mut query = messages::table
+            .filter(messages::room_uuid.eq_any(member_rooms))
+            .filter(
+                messages::expires_at
+                    .is_null()
+                    .or(messages::expires_at.gt(SqlTime($now))),
+            )
+            .into_boxed();
+        if let Some(searched_room_uuid) = search.room_uuid {
+            query = query.filter(messages::room_uuid.eq(SqlUuid(searched_room_uuid)));
+        }
+        if let Some(searched_sender_uuid) = search.sender_uuid {
+            query = query.filter(messages::sender_uuid.eq(SqlUuid(searched_sender_uuid)));
+        }
+        if let Some(sent_after) = search.sent_after {
+            query = query.filter(messages::timestamp.ge(SqlTime(sent_after)));
+        }
+        if let Some(sent_before) = search.sent_before {
+            query = query.filter(messages::timestamp.le(SqlTime(sent_before)));
+        }
+        query.limit(search.limit).offset(search.offset)
+    }};
+}
+use searched_messages;
+
+/// How a backend matches messages against the text of a [`MessageSearch`], and ranks them.
+trait SearchMessages: diesel::Connection {
+    /// Load the messages `query` selects that match `text`, along with their ranks and
+    /// highlighted snippets, best first.
+    fn search_messages(
+        &mut self,
+        query: messages::BoxedQuery<'_, Self::Backend>,
+        text: &str,
+    ) -> QueryResult<Vec<(Message, f32, String)>>;
+}
+
+impl SearchMessages for PgConnection {
+    fn search_messages(
+        &mut self,
+        query: messages::BoxedQuery<'_, Self::Backend>,
+        text: &str,
+    ) -> QueryResult<Vec<(Message, f32, String)>> {
+        use diesel_full_text_search::configuration::TsConfiguration;
+        use diesel_full_text_search::{ts_headline_with_search_config, ts_rank};
+        use diesel_full_text_search::{
+            websearch_to_tsquery_with_search_config, TsVectorExtensions,
+        };
+
+        // NOTE: The configuration must match the one in the `text_search` column definition.
+        let tsquery =
+            || websearch_to_tsquery_with_search_config(TsConfiguration::SIMPLE, text.to_string());
+        let rank = || ts_rank(messages::text_search, tsquery());
+        let snippet =
+            ts_headline_with_search_config(TsConfiguration::SIMPLE, messages::text, tsquery());
+
+        query
+            .filter(messages::text_search.matches(tsquery()))
+            .select((Message::as_select(), rank(), snippet))
+            .order((rank().desc(), messages::timestamp.desc()))
+            .load(self)
+    }
+}
+
+/// SQLite has no full text search index, so every word has to appear in the text instead,
+/// and messages aren't ranked: the newest come first, with their whole text as the snippet.
+impl SearchMessages for persistence::Connection {
+    fn search_messages(
+        &mut self,
+        mut query: messages::BoxedQuery<'_, Self::Backend>,
+        text: &str,
+    ) -> QueryResult<Vec<(Message, f32, String)>> {
+        for word in text.split_whitespace() {
+            let pattern = format!("%{}%", escape_like(word));
+            query = query.filter(messages::text.like(pattern).escape('\\'));
+        }
+
+        let found: Vec<Message> = query
+            .select(Message::as_select())
+            .order(messages::timestamp.desc())
+            .load(self)?;
+        Ok(found
+            .into_iter()
+            .map(|message| {
+                let snippet = message.text.clone();
+                (message, 1.0, snippet)
+            })
+            .collect())
+    }
+}
+
+/// Escape the wildcards of `LIKE` in a word, with `\` as the escape character.
+fn escape_like(word: &str) -> String {
+    let mut escaped = String::with_capacity(word.len());
+    for c in word.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape_like;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::plain("hello", "hello")]
+    #[case::wildcards("100%_done", "100\\%\\_done")]
+    #[case::escape("a\\b", "a\\\\b")]
+    fn like_patterns(#[case] word: &str, #[case] expected: &str) {
+        assert_eq!(escape_like(word), expected);
+    }
+}
@@ -0,0 +1,933 @@
+//! # In-memory repositories
+//!
+//! Everything is kept in plain collections behind a single lock, and nothing is persisted.
+//! Messages are numbered and deduplicated like in the database, but searching is naive:
+//! a message matches if it contains all the words of the query, and every hit ranks the same.
+
+use super::ScheduledMessageRepository;
+use super::{AttachmentRepository, Deduplicated, PinOutcome, PinRepository};
+use super::{MessageRepository, RepositoryResult, RoomRepository, UnpinOutcome, UserRepository};
+use crate::entities::{username, Attachment, IdempotencyKey, Mention, MessagePin, MessageSearch};
+use crate::entities::{HydratedMessage, Message, ReadMarker, ResumeCursor, Room};
+use crate::entities::{RoomSubscription, ScheduledMessage, User};
+use hashbrown::{HashMap, HashSet};
+use itertools::Itertools;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+#[derive(Debug, Default)]
+struct State {
+    users: HashMap<Uuid, User>,
+    rooms: HashMap<Uuid, Room>,
+    /// Pairs of room and user UUIDs.
+    members: HashSet<(Uuid, Uuid)>,
+    messages: HashMap<Uuid, Message>,
+    /// Keyed by user and room UUID.
+    read_markers: HashMap<(Uuid, Uuid), ReadMarker>,
+    attachments: HashMap<Uuid, Attachment>,
+    /// Pairs of message and attachment UUIDs.
+    message_attachments: Vec<(Uuid, Uuid)>,
+    mentions: Vec<Mention>,
+    /// Keyed by message UUID.
+    pins: HashMap<Uuid, MessagePin>,
+    scheduled_messages: HashMap<Uuid, ScheduledMessage>,
+    /// Keyed by sender UUID and key.
+    idempotency_keys: HashMap<(Uuid, String), IdempotencyKey>,
+}
+
+impl State {
+    /// See [`IdempotencyKey::claim`].
+    fn claim(&mut self, key: IdempotencyKey) -> Option<Uuid> {
+        let id = (key.sender_uuid, key.key.clone());
+        if let Some(existing) = self.idempotency_keys.get(&id) {
+            if existing.is_fresh(key.created_at) {
+                return Some(existing.message_uuid);
+            }
+        }
+        let _ = self.idempotency_keys.insert(id, key);
+        None
+    }
+
+    /// See [`Message::store`] and [`Mention::resolve`].
+    fn store_message(
+        &mut self,
+        mut message: Message,
+        attachment_uuids: Vec<Uuid>,
+    ) -> RepositoryResult<(Message, Vec<Mention>)> {
+        let room = self
+            .rooms
+            .get_mut(&message.room_uuid)
+            .ok_or(diesel::result::Error::NotFound)?;
+        room.last_sequence += 1;
+        message.sequence = room.last_sequence;
+
+        let members: std::collections::HashMap<String, Uuid> = self
+            .members
+            .iter()
+            .filter(|(room, _)| *room == message.room_uuid)
+            .filter_map(|(_, user)| self.users.get(user))
+            .map(|user| (user.canonical_username.clone(), user.uuid))
+            .collect();
+        let mentions = Mention::resolve_among(&message, &members);
+
+        self.message_attachments.extend(
+            attachment_uuids
+                .into_iter()
+                .map(|attachment| (message.uuid, attachment)),
+        );
+        self.mentions.extend(mentions.iter().cloned());
+        let _ = self.messages.insert(message.uuid, message.clone());
+        Ok((message, mentions))
+    }
+
+    /// See [`MessageRepository::unread_statuses`].
+    fn unread_status(
+        &self,
+        room_uuid: Uuid,
+        reader_uuid: Uuid,
+        now: SystemTime,
+    ) -> (Option<Uuid>, u64) {
+        let marker = self.read_markers.get(&(reader_uuid, room_uuid));
+        let unread_count = self
+            .messages
+            .values()
+            .filter(|message| message.room_uuid == room_uuid)
+            .filter(|message| message.sender_uuid != reader_uuid)
+            .filter(|message| message.expires_at.map_or(true, |expiry| expiry > now))
+            .filter(|message| {
+                marker.map_or(true, |marked| message.sequence > marked.message_sequence)
+            })
+            .count();
+        (
+            marker.map(|marker| marker.message_uuid),
+            unread_count.try_into().unwrap_or_default(),
+        )
+    }
+
+    /// See [`Message::hydrate`].
+    fn hydrate(&self, message: Message) -> HydratedMessage {
+        let attachments = self
+            .message_attachments
+            .iter()
+            .filter(|(attached_to, _)| *attached_to == message.uuid)
+            .filter_map(|(_, attachment)| self.attachments.get(attachment).cloned())
+            .collect();
+        let mentions = self
+            .mentions
+            .iter()
+            .filter(|mention| mention.message_uuid == message.uuid)
+            .sorted_by_key(|mention| mention.byte_offset)
+            .cloned()
+            .collect();
+        HydratedMessage {
+            message,
+            attachments,
+            mentions,
+        }
+    }
+
+    /// Delete a message along with everything that points to it.
+    fn delete_message(&mut self, message_uuid: Uuid) {
+        let _ = self.messages.remove(&message_uuid);
+        let _ = self.pins.remove(&message_uuid);
+        self.mentions
+            .retain(|mention| mention.message_uuid != message_uuid);
+        self.message_attachments
+            .retain(|(message, _)| *message != message_uuid);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryRepository {
+    state: Mutex<State>,
+}
+
+impl InMemoryRepository {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store a message as is, e.g. to set up a test.
+    pub fn insert_message(&self, message: Message) {
+        let _ = self.lock().messages.insert(message.uuid, message);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // Nothing panics while holding the lock, so poisoning is harmless.
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl UserRepository for InMemoryRepository {
+    async fn find(&self, user_uuid: Uuid) -> RepositoryResult<Option<User>> {
+        Ok(self.lock().users.get(&user_uuid).cloned())
+    }
+
+    async fn find_by_username(&self, username: String) -> RepositoryResult<Option<User>> {
+        let canonical_username = username::canonicalize(&username);
+        Ok(self
+            .lock()
+            .users
+            .values()
+            .find(|user| user.canonical_username == canonical_username)
+            .cloned())
+    }
+
+    async fn find_by_credentials(
+        &self,
+        username: String,
+        password: String,
+    ) -> RepositoryResult<Option<User>> {
+        let canonical_username = username::canonicalize(&username);
+        Ok(self
+            .lock()
+            .users
+            .values()
+            .find(|user| user.canonical_username == canonical_username && user.password == password)
+            .cloned())
+    }
+
+    async fn create(&self, user: User) -> RepositoryResult<bool> {
+        let mut state = self.lock();
+        if state
+            .users
+            .values()
+            .any(|existing| existing.canonical_username == user.canonical_username)
+        {
+            return Ok(false);
+        }
+        let _ = state.users.insert(user.uuid, user);
+        Ok(true)
+    }
+
+    async fn last_seen(
+        &self,
+        user_uuids: Vec<Uuid>,
+    ) -> RepositoryResult<Vec<(Uuid, Option<SystemTime>)>> {
+        let state = self.lock();
+        Ok(user_uuids
+            .into_iter()
+            .filter_map(|uuid| state.users.get(&uuid).map(|user| (uuid, user.last_seen)))
+            .collect())
+    }
+
+    async fn set_last_seen(
+        &self,
+        user_uuid: Uuid,
+        last_seen: Option<SystemTime>,
+    ) -> RepositoryResult<()> {
+        if let Some(user) = self.lock().users.get_mut(&user_uuid) {
+            user.last_seen = last_seen;
+        }
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl RoomRepository for InMemoryRepository {
+    async fn find(&self, room_uuid: Uuid) -> RepositoryResult<Option<Room>> {
+        Ok(self.lock().rooms.get(&room_uuid).cloned())
+    }
+
+    async fn find_many(&self, room_uuids: Vec<Uuid>) -> RepositoryResult<Vec<Room>> {
+        let state = self.lock();
+        Ok(room_uuids
+            .iter()
+            .filter_map(|uuid| state.rooms.get(uuid).cloned())
+            .collect())
+    }
+
+    async fn create(&self, room: Room, member_uuids: Vec<Uuid>) -> RepositoryResult<Vec<Uuid>> {
+        let mut state = self.lock();
+        let unknown: Vec<Uuid> = member_uuids
+            .iter()
+            .filter(|user_uuid| !state.users.contains_key(*user_uuid))
+            .copied()
+            .collect();
+        if !unknown.is_empty() {
+            return Ok(unknown);
+        }
+
+        state
+            .members
+            .extend(member_uuids.into_iter().map(|user| (room.uuid, user)));
+        let _ = state.rooms.insert(room.uuid, room);
+        Ok(vec![])
+    }
+
+    async fn set_message_ttl(
+        &self,
+        room_uuid: Uuid,
+        ttl: Option<Duration>,
+    ) -> RepositoryResult<()> {
+        if let Some(room) = self.lock().rooms.get_mut(&room_uuid) {
+            room.message_ttl_seconds = ttl.and_then(|ttl| i32::try_from(ttl.as_secs()).ok());
+        }
+        Ok(())
+    }
+
+    async fn members(&self, room_uuid: Uuid) -> RepositoryResult<Vec<Uuid>> {
+        Ok(self
+            .lock()
+            .members
+            .iter()
+            .filter(|(room, _)| *room == room_uuid)
+            .map(|&(_, user)| user)
+            .collect())
+    }
+
+    async fn members_of_many(&self, room_uuids: Vec<Uuid>) -> RepositoryResult<Vec<Vec<Uuid>>> {
+        let mut members: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+        for &(room, user) in &self.lock().members {
+            members.entry(room).or_default().push(user);
+        }
+        Ok(room_uuids
+            .iter()
+            .map(|room| members.get(room).cloned().unwrap_or_default())
+            .collect())
+    }
+
+    async fn rooms_of(&self, user_uuid: Uuid) -> RepositoryResult<Vec<Uuid>> {
+        Ok(self
+            .lock()
+            .members
+            .iter()
+            .filter(|(_, user)| *user == user_uuid)
+            .map(|&(room, _)| room)
+            .collect())
+    }
+
+    async fn is_member(&self, room_uuid: Uuid, user_uuid: Uuid) -> RepositoryResult<bool> {
+        Ok(self.lock().members.contains(&(room_uuid, user_uuid)))
+    }
+
+    async fn neighbours(&self, user_uuid: Uuid) -> RepositoryResult<Vec<Uuid>> {
+        let state = self.lock();
+        let shared_rooms: HashSet<Uuid> = state
+            .members
+            .iter()
+            .filter(|(_, user)| *user == user_uuid)
+            .map(|&(room, _)| room)
+            .collect();
+        let neighbours: HashSet<Uuid> = state
+            .members
+            .iter()
+            .filter(|(room, user)| *user != user_uuid && shared_rooms.contains(room))
+            .map(|&(_, user)| user)
+            .collect();
+        Ok(neighbours.into_iter().collect())
+    }
+}
+
+#[tonic::async_trait]
+impl MessageRepository for InMemoryRepository {
+    async fn list(&self, room_uuid: Uuid, now: SystemTime) -> RepositoryResult<Vec<Message>> {
+        let mut messages: Vec<Message> = self
+            .lock()
+            .messages
+            .values()
+            .filter(|message| message.room_uuid == room_uuid)
+            .filter(|message| message.expires_at.map_or(true, |expiry| expiry > now))
+            .cloned()
+            .collect();
+        messages.sort_by_key(|message| message.sequence);
+        Ok(messages)
+    }
+
+    async fn find_in_room(
+        &self,
+        room_uuid: Uuid,
+        message_uuid: Uuid,
+    ) -> RepositoryResult<Option<Message>> {
+        Ok(self
+            .lock()
+            .messages
+            .get(&message_uuid)
+            .filter(|message| message.room_uuid == room_uuid)
+            .cloned())
+    }
+
+    async fn store(
+        &self,
+        message: Message,
+        attachment_uuids: Vec<Uuid>,
+        idempotency_key: Option<IdempotencyKey>,
+    ) -> RepositoryResult<Deduplicated<(Message, Vec<Mention>)>> {
+        let mut state = self.lock();
+        if let Some(duplicate_of) = idempotency_key.and_then(|key| state.claim(key)) {
+            return Ok(Deduplicated::Duplicate(duplicate_of));
+        }
+        state
+            .store_message(message, attachment_uuids)
+            .map(Deduplicated::Stored)
+    }
+
+    async fn hydrate(&self, messages: Vec<Message>) -> RepositoryResult<Vec<HydratedMessage>> {
+        let state = self.lock();
+        Ok(messages
+            .into_iter()
+            .map(|message| state.hydrate(message))
+            .collect())
+    }
+
+    async fn search(
+        &self,
+        searcher_uuid: Uuid,
+        search: MessageSearch,
+        now: SystemTime,
+    ) -> RepositoryResult<Vec<(Message, f32, String)>> {
+        let words: Vec<String> = search
+            .query
+            .split_whitespace()
+            .map(str::to_lowercase)
+            .collect();
+        let state = self.lock();
+        let found = state
+            .messages
+            .values()
+            .filter(|message| state.members.contains(&(message.room_uuid, searcher_uuid)))
+            .filter(|message| message.expires_at.map_or(true, |expiry| expiry > now))
+            .filter(|message| {
+                search
+                    .room_uuid
+                    .map_or(true, |room| message.room_uuid == room)
+            })
+            .filter(|message| {
+                search
+                    .sender_uuid
+                    .map_or(true, |sender| message.sender_uuid == sender)
+            })
+            .filter(|message| {
+                search
+                    .sent_after
+                    .map_or(true, |after| message.timestamp >= after)
+            })
+            .filter(|message| {
+                search
+                    .sent_before
+                    .map_or(true, |before| message.timestamp <= before)
+            })
+            .filter(|message| {
+                let text = message.text.to_lowercase();
+                words.iter().all(|word| text.contains(word.as_str()))
+            })
+            .sorted_by_key(|message| std::cmp::Reverse(message.timestamp))
+            .skip(search.offset.try_into().unwrap_or_default())
+            .take(search.limit.try_into().unwrap_or_default())
+            .map(|message| (message.clone(), 1.0, message.text.clone()))
+            .collect();
+        Ok(found)
+    }
+
+    async fn missed(
+        &self,
+        subscription: RoomSubscription,
+        now: SystemTime,
+    ) -> RepositoryResult<Option<Vec<Message>>> {
+        let Some(cursor) = subscription.resume_after else {
+            return Ok(Some(vec![]));
+        };
+
+        let state = self.lock();
+        let in_room = |message: &&Message| message.room_uuid == subscription.room_uuid;
+        let is_missed: Box<dyn Fn(&Message) -> bool> = match cursor {
+            ResumeCursor::Message(message_uuid) => {
+                let Some(seen) = state.messages.get(&message_uuid).filter(in_room) else {
+                    return Ok(None);
+                };
+                let seen_sequence = seen.sequence;
+                Box::new(move |message| message.sequence > seen_sequence)
+            }
+            ResumeCursor::Timestamp(timestamp) => {
+                Box::new(move |message| message.timestamp > timestamp)
+            }
+            ResumeCursor::Sequence(sequence) => {
+                Box::new(move |message| message.sequence > sequence)
+            }
+        };
+
+        let missed = state
+            .messages
+            .values()
+            .filter(in_room)
+            .filter(|message| message.expires_at.map_or(true, |expiry| expiry > now))
+            .filter(|message| is_missed(message))
+            .sorted_by_key(|message| message.sequence)
+            .take(RoomSubscription::MAX_REPLAY + 1)
+            .cloned()
+            .collect();
+        Ok(Some(missed))
+    }
+
+    async fn delete_expired(&self, now: SystemTime, limit: i64) -> RepositoryResult<Vec<Message>> {
+        let mut state = self.lock();
+        let expired: Vec<Message> = state
+            .messages
+            .values()
+            .filter(|message| message.expires_at.is_some_and(|expiry| expiry <= now))
+            .sorted_by_key(|message| message.expires_at)
+            .take(limit.try_into().unwrap_or_default())
+            .cloned()
+            .collect();
+        let expired_uuids: HashSet<Uuid> = expired.iter().map(|message| message.uuid).collect();
+
+        let stale_markers: Vec<ReadMarker> = state
+            .read_markers
+            .values()
+            .filter(|marker| expired_uuids.contains(&marker.message_uuid))
+            .cloned()
+            .collect();
+        for marker in stale_markers {
+            let replacement = state
+                .messages
+                .values()
+                .filter(|message| message.room_uuid == marker.room_uuid)
+                .filter(|message| !expired_uuids.contains(&message.uuid))
+                .filter(|message| message.sequence < marker.message_sequence)
+                .max_by_key(|message| message.sequence)
+                .map(|message| (message.uuid, message.sequence));
+            let key = (marker.user_uuid, marker.room_uuid);
+            match replacement {
+                Some((replacement_uuid, replacement_sequence)) => {
+                    if let Some(marker) = state.read_markers.get_mut(&key) {
+                        marker.message_uuid = replacement_uuid;
+                        marker.message_sequence = replacement_sequence;
+                    }
+                }
+                None => {
+                    let _ = state.read_markers.remove(&key);
+                }
+            }
+        }
+
+        for message_uuid in expired_uuids {
+            state.delete_message(message_uuid);
+        }
+        Ok(expired)
+    }
+
+    async fn next_expiry(&self) -> RepositoryResult<Option<SystemTime>> {
+        Ok(self
+            .lock()
+            .messages
+            .values()
+            .filter_map(|message| message.expires_at)
+            .min())
+    }
+
+    async fn prune_idempotency_keys(&self, now: SystemTime) -> RepositoryResult<usize> {
+        let mut state = self.lock();
+        let before = state.idempotency_keys.len();
+        state.idempotency_keys.retain(|_, key| key.is_fresh(now));
+        Ok(before - state.idempotency_keys.len())
+    }
+
+    async fn move_read_marker(&self, marker: ReadMarker) -> RepositoryResult<bool> {
+        let mut state = self.lock();
+        let key = (marker.user_uuid, marker.room_uuid);
+        if state
+            .read_markers
+            .get(&key)
+            .is_some_and(|stored| stored.message_sequence >= marker.message_sequence)
+        {
+            return Ok(false);
+        }
+        let _ = state.read_markers.insert(key, marker);
+        Ok(true)
+    }
+
+    async fn unread_statuses(
+        &self,
+        room_uuids: Vec<Uuid>,
+        reader_uuid: Uuid,
+        now: SystemTime,
+    ) -> RepositoryResult<Vec<(Option<Uuid>, u64)>> {
+        let state = self.lock();
+        Ok(room_uuids
+            .into_iter()
+            .map(|room_uuid| state.unread_status(room_uuid, reader_uuid, now))
+            .collect())
+    }
+}
+
+#[tonic::async_trait]
+impl AttachmentRepository for InMemoryRepository {
+    async fn find(&self, attachment_uuid: Uuid) -> RepositoryResult<Option<Attachment>> {
+        Ok(self.lock().attachments.get(&attachment_uuid).cloned())
+    }
+
+    async fn find_in_room(
+        &self,
+        room_uuid: Uuid,
+        attachment_uuids: Vec<Uuid>,
+    ) -> RepositoryResult<Vec<Attachment>> {
+        let state = self.lock();
+        Ok(attachment_uuids
+            .iter()
+            .filter_map(|uuid| state.attachments.get(uuid))
+            .filter(|attachment| attachment.room_uuid == room_uuid)
+            .cloned()
+            .collect())
+    }
+
+    async fn create(&self, attachment: Attachment) -> RepositoryResult<()> {
+        let _ = self.lock().attachments.insert(attachment.uuid, attachment);
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl PinRepository for InMemoryRepository {
+    async fn pin(&self, pin: MessagePin) -> RepositoryResult<PinOutcome> {
+        let mut state = self.lock();
+        if state.pins.contains_key(&pin.message_uuid) {
+            return Ok(PinOutcome::AlreadyPinned);
+        }
+        let pin_count = state
+            .pins
+            .values()
+            .filter(|pinned| pinned.room_uuid == pin.room_uuid)
+            .count();
+        if pin_count >= MessagePin::MAX_PINS_PER_ROOM {
+            return Ok(PinOutcome::LimitReached);
+        }
+        let _ = state.pins.insert(pin.message_uuid, pin);
+        Ok(PinOutcome::Pinned)
+    }
+
+    async fn unpin(
+        &self,
+        room_uuid: Uuid,
+        message_uuid: Uuid,
+        unpinner_uuid: Uuid,
+    ) -> RepositoryResult<UnpinOutcome> {
+        let mut state = self.lock();
+        let Some(pin) = state
+            .pins
+            .get(&message_uuid)
+            .filter(|pin| pin.room_uuid == room_uuid)
+        else {
+            return Ok(UnpinOutcome::NotPinned);
+        };
+        let author_uuid = state
+            .messages
+            .get(&message_uuid)
+            .map(|message| message.sender_uuid);
+        if unpinner_uuid != pin.pinner_uuid && Some(unpinner_uuid) != author_uuid {
+            return Ok(UnpinOutcome::NotAllowed);
+        }
+
+        let _ = state.pins.remove(&message_uuid);
+        Ok(UnpinOutcome::Unpinned)
+    }
+
+    async fn list(&self, room_uuid: Uuid) -> RepositoryResult<Vec<(MessagePin, Message)>> {
+        let state = self.lock();
+        Ok(state
+            .pins
+            .values()
+            .filter(|pin| pin.room_uuid == room_uuid)
+            .filter_map(|pin| Some((pin.clone(), state.messages.get(&pin.message_uuid)?.clone())))
+            .sorted_by_key(|(pin, _)| std::cmp::Reverse(pin.timestamp))
+            .collect())
+    }
+}
+
+#[tonic::async_trait]
+impl ScheduledMessageRepository for InMemoryRepository {
+    async fn schedule(
+        &self,
+        message: ScheduledMessage,
+        idempotency_key: Option<IdempotencyKey>,
+    ) -> RepositoryResult<Option<Deduplicated<ScheduledMessage>>> {
+        let mut state = self.lock();
+        let pending = state
+            .scheduled_messages
+            .values()
+            .filter(|pending| pending.sender_uuid == message.sender_uuid)
+            .count();
+        if i64::try_from(pending).unwrap_or(i64::MAX) >= ScheduledMessage::MAX_PENDING_PER_USER {
+            return Ok(None);
+        }
+        if let Some(duplicate_of) = idempotency_key.and_then(|key| state.claim(key)) {
+            return Ok(Some(Deduplicated::Duplicate(duplicate_of)));
+        }
+        let _ = state
+            .scheduled_messages
+            .insert(message.uuid, message.clone());
+        Ok(Some(Deduplicated::Stored(message)))
+    }
+
+    async fn list_pending(&self, sender_uuid: Uuid) -> RepositoryResult<Vec<ScheduledMessage>> {
+        Ok(self
+            .lock()
+            .scheduled_messages
+            .values()
+            .filter(|message| message.sender_uuid == sender_uuid)
+            .sorted_by_key(|message| message.deliver_at)
+            .cloned()
+            .collect())
+    }
+
+    async fn cancel(&self, sender_uuid: Uuid, message_uuid: Uuid) -> RepositoryResult<bool> {
+        let mut state = self.lock();
+        let sent_by_them = state
+            .scheduled_messages
+            .get(&message_uuid)
+            .is_some_and(|message| message.sender_uuid == sender_uuid);
+        if sent_by_them {
+            let _ = state.scheduled_messages.remove(&message_uuid);
+        }
+        Ok(sent_by_them)
+    }
+
+    async fn due(&self, now: SystemTime, limit: i64) -> RepositoryResult<Vec<ScheduledMessage>> {
+        Ok(self
+            .lock()
+            .scheduled_messages
+            .values()
+            .filter(|message| message.deliver_at <= now)
+            .sorted_by_key(|message| message.deliver_at)
+            .take(limit.try_into().unwrap_or_default())
+            .cloned()
+            .collect())
+    }
+
+    async fn next_delivery(&self) -> RepositoryResult<Option<SystemTime>> {
+        Ok(self
+            .lock()
+            .scheduled_messages
+            .values()
+            .map(|message| message.deliver_at)
+            .min())
+    }
+
+    async fn deliver(
+        &self,
+        message: Message,
+        attachment_uuids: Vec<Uuid>,
+    ) -> RepositoryResult<Option<(Message, Vec<Mention>)>> {
+        let mut state = self.lock();
+        if state.scheduled_messages.remove(&message.uuid).is_none() {
+            return Ok(None);
+        }
+        if !state
+            .members
+            .contains(&(message.room_uuid, message.sender_uuid))
+        {
+            tracing::warn!(
+                message = "Dropping a scheduled message, its sender is no longer a room member",
+                user = ?message.sender_uuid,
+                room = ?message.room_uuid
+            );
+            return Ok(None);
+        }
+        state.store_message(message, attachment_uuids).map(Some)
+    }
+
+    async fn postpone(&self, message_uuid: Uuid, deliver_at: SystemTime) -> RepositoryResult<()> {
+        if let Some(message) = self.lock().scheduled_messages.get_mut(&message_uuid) {
+            message.deliver_at = deliver_at;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{InMemoryRepository, MessageRepository, RoomRepository, UserRepository};
+    use super::{PinOutcome, PinRepository};
+    use crate::entities::{Message, MessagePin, ReadMarker, Room, User};
+    use rand_chacha::ChaCha20Rng;
+    use rand_core::SeedableRng;
+    use std::time::{Duration, SystemTime};
+    use uuid::Uuid;
+
+    fn user(username: &str) -> User {
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        User::new(username.to_string(), "hash".to_string(), &mut rng)
+    }
+
+    async fn register(repository: &InMemoryRepository, username: &str) -> Uuid {
+        let user = user(username);
+        assert!(UserRepository::create(repository, user.clone())
+            .await
+            .unwrap());
+        user.uuid
+    }
+
+    #[tokio::test]
+    async fn usernames_are_unique() {
+        let repository = InMemoryRepository::new();
+        let alice = user("alice");
+
+        assert!(UserRepository::create(&repository, alice.clone())
+            .await
+            .unwrap());
+        assert!(!UserRepository::create(&repository, user("Аlice"))
+            .await
+            .unwrap());
+        let found = repository
+            .find_by_credentials("ALICE".to_string(), "hash".to_string())
+            .await
+            .unwrap();
+        assert_eq!(found.map(|user| user.uuid), Some(alice.uuid));
+    }
+
+    #[tokio::test]
+    async fn neighbours_share_a_room() {
+        let repository = InMemoryRepository::new();
+        let (alice, bob, carol, dave) = (
+            register(&repository, "alice").await,
+            register(&repository, "bob").await,
+            register(&repository, "carol").await,
+            register(&repository, "dave").await,
+        );
+        RoomRepository::create(&repository, Room::new("a"), vec![alice, bob])
+            .await
+            .unwrap();
+        RoomRepository::create(&repository, Room::new("b"), vec![alice, bob, carol])
+            .await
+            .unwrap();
+        RoomRepository::create(&repository, Room::new("c"), vec![dave])
+            .await
+            .unwrap();
+
+        let mut neighbours = repository.neighbours(alice).await.unwrap();
+        neighbours.sort();
+        let mut expected = vec![bob, carol];
+        expected.sort();
+        assert_eq!(neighbours, expected);
+        assert!(repository.neighbours(dave).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rooms_with_unknown_members_are_not_created() {
+        let repository = InMemoryRepository::new();
+        let (alice, stranger) = (register(&repository, "alice").await, Uuid::new_v4());
+        let room = Room::new("a");
+
+        let unknown = RoomRepository::create(&repository, room.clone(), vec![alice, stranger])
+            .await
+            .unwrap();
+        assert_eq!(unknown, vec![stranger]);
+        assert!(RoomRepository::find(&repository, room.uuid)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(repository.rooms_of(alice).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn read_markers_reset_unread_counts() {
+        let repository = InMemoryRepository::new();
+        let (reader, sender, room) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let now = SystemTime::now();
+        let messages: Vec<Message> = (1..=3)
+            .map(|i| Message {
+                timestamp: now + Duration::from_secs(i),
+                sequence: i.try_into().unwrap(),
+                ..Message::new("hi", sender, room)
+            })
+            .collect();
+        for message in &messages {
+            repository.insert_message(message.clone());
+        }
+        repository.insert_message(Message::new("mine", reader, room));
+        repository.insert_message(Message {
+            expires_at: Some(SystemTime::UNIX_EPOCH),
+            sequence: 4,
+            ..Message::new("expired, but not swept yet", sender, room)
+        });
+
+        assert_eq!(
+            repository
+                .unread_statuses(vec![room], reader, now)
+                .await
+                .unwrap(),
+            [(None, 3)]
+        );
+
+        assert!(repository
+            .move_read_marker(ReadMarker::new(reader, &messages[1]))
+            .await
+            .unwrap());
+        assert_eq!(
+            repository
+                .unread_statuses(vec![room], reader, now)
+                .await
+                .unwrap(),
+            [(Some(messages[1].uuid), 1)]
+        );
+
+        // Markers are compared by sequence, so an earlier message doesn't move them back.
+        assert!(!repository
+            .move_read_marker(ReadMarker::new(reader, &messages[0]))
+            .await
+            .unwrap());
+        assert_eq!(
+            repository
+                .unread_statuses(vec![room], reader, now)
+                .await
+                .unwrap(),
+            [(Some(messages[1].uuid), 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_messages_are_not_listed() {
+        let repository = InMemoryRepository::new();
+        let (sender, room) = (Uuid::new_v4(), Uuid::new_v4());
+        let kept = Message::new("kept", sender, room);
+        let expired = Message {
+            expires_at: Some(SystemTime::UNIX_EPOCH),
+            ..Message::new("expired", sender, room)
+        };
+        repository.insert_message(kept.clone());
+        repository.insert_message(expired);
+
+        let listed = MessageRepository::list(&repository, room, SystemTime::now())
+            .await
+            .unwrap();
+        assert_eq!(
+            listed
+                .iter()
+                .map(|message| message.uuid)
+                .collect::<Vec<_>>(),
+            vec![kept.uuid]
+        );
+    }
+
+    #[tokio::test]
+    async fn pins_are_limited_per_room() {
+        let repository = InMemoryRepository::new();
+        let (pinner, room) = (Uuid::new_v4(), Uuid::new_v4());
+        let pin = |message: &Message| MessagePin::new(pinner, message);
+
+        for _ in 0..MessagePin::MAX_PINS_PER_ROOM {
+            let message = Message::new("pin me", pinner, room);
+            assert_eq!(
+                repository.pin(pin(&message)).await.unwrap(),
+                PinOutcome::Pinned
+            );
+        }
+
+        let message = Message::new("one too many", pinner, room);
+        assert_eq!(
+            repository.pin(pin(&message)).await.unwrap(),
+            PinOutcome::LimitReached
+        );
+        let elsewhere = Message::new("another room", pinner, Uuid::new_v4());
+        assert_eq!(
+            repository.pin(pin(&elsewhere)).await.unwrap(),
+            PinOutcome::Pinned
+        );
+    }
+}
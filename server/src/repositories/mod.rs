@@ -0,0 +1,315 @@
+//! # Repositories
+//!
+//! Handlers and background tasks reach the stored state through the repository traits
+//! (users, rooms, messages, attachments, pins and scheduled messages) rather than through
+//! diesel, so that their logic can be tested in-process. Two implementations are available:
+//!
+//! - [`DatabaseRepository`] is the real thing, running queries on the blocking thread pool
+//!   (see [`persistence::run`](crate::persistence::run)) against Postgres or SQLite.
+//! - [`InMemoryRepository`] keeps everything in the memory of the process, and is meant for tests.
+//!
+//! A single value implements all the traits, and [`Repositories`] hands it out as each of them.
+
+mod database;
+mod memory;
+
+pub use database::DatabaseRepository;
+pub use memory::InMemoryRepository;
+
+use crate::entities::{Attachment, HydratedMessage, IdempotencyKey, Mention, MessagePin};
+use crate::entities::{Message, MessageSearch, ReadMarker, Room, RoomSubscription};
+use crate::entities::{ScheduledMessage, User};
+use crate::persistence::{ConnectionPool, PersistenceError};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+#[derive(thiserror::Error, Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub enum RepositoryError {
+    #[error("Database query failed: {0}")]
+    Query(#[from] diesel::result::Error),
+    #[error(transparent)]
+    Persistence(#[from] PersistenceError),
+}
+
+#[allow(clippy::module_name_repetitions)]
+pub type RepositoryResult<T> = Result<T, RepositoryError>;
+
+/// What became of something sent along with an [`IdempotencyKey`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Deduplicated<T> {
+    /// It's new, and has been stored.
+    Stored(T),
+    /// It's a retry of the message with this UUID, so nothing has been stored.
+    Duplicate(Uuid),
+}
+
+/// What became of a pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinOutcome {
+    Pinned,
+    AlreadyPinned,
+    /// The room already has [`MessagePin::MAX_PINS_PER_ROOM`] pins.
+    LimitReached,
+}
+
+/// What became of an unpin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnpinOutcome {
+    Unpinned,
+    /// The message isn't pinned in the room.
+    NotPinned,
+    /// Only the member who pinned the message and its author may unpin it.
+    NotAllowed,
+}
+
+/// Registered users.
+#[tonic::async_trait]
+pub trait UserRepository: fmt::Debug + Send + Sync {
+    async fn find(&self, user_uuid: Uuid) -> RepositoryResult<Option<User>>;
+
+    /// Find a user by any username with the same [canonical form](crate::entities::username).
+    async fn find_by_username(&self, username: String) -> RepositoryResult<Option<User>>;
+
+    /// Find the user with a username (compared like in [`Self::find_by_username`])
+    /// and (hashed) password.
+    async fn find_by_credentials(
+        &self,
+        username: String,
+        password: String,
+    ) -> RepositoryResult<Option<User>>;
+
+    /// Store a new user. Returns `false` if a user with the same canonical username exists.
+    async fn create(&self, user: User) -> RepositoryResult<bool>;
+
+    /// Look up when users were last seen. Unknown users are skipped.
+    async fn last_seen(
+        &self,
+        user_uuids: Vec<Uuid>,
+    ) -> RepositoryResult<Vec<(Uuid, Option<SystemTime>)>>;
+
+    async fn set_last_seen(
+        &self,
+        user_uuid: Uuid,
+        last_seen: Option<SystemTime>,
+    ) -> RepositoryResult<()>;
+}
+
+/// Rooms and their members.
+#[tonic::async_trait]
+pub trait RoomRepository: fmt::Debug + Send + Sync {
+    async fn find(&self, room_uuid: Uuid) -> RepositoryResult<Option<Room>>;
+
+    /// Find several rooms at once. Unknown rooms are skipped.
+    async fn find_many(&self, room_uuids: Vec<Uuid>) -> RepositoryResult<Vec<Room>>;
+
+    /// Store a new room along with its members, all at once.
+    ///
+    /// Returns the members that aren't registered users, in which case nothing is stored.
+    async fn create(&self, room: Room, member_uuids: Vec<Uuid>) -> RepositoryResult<Vec<Uuid>>;
+
+    async fn set_message_ttl(&self, room_uuid: Uuid, ttl: Option<Duration>)
+        -> RepositoryResult<()>;
+
+    async fn members(&self, room_uuid: Uuid) -> RepositoryResult<Vec<Uuid>>;
+
+    /// List the members of several rooms at once, in the same order as `room_uuids`.
+    /// Unknown rooms have no members.
+    async fn members_of_many(&self, room_uuids: Vec<Uuid>) -> RepositoryResult<Vec<Vec<Uuid>>>;
+
+    /// List the rooms a user is a member of.
+    async fn rooms_of(&self, user_uuid: Uuid) -> RepositoryResult<Vec<Uuid>>;
+
+    async fn is_member(&self, room_uuid: Uuid, user_uuid: Uuid) -> RepositoryResult<bool>;
+
+    /// List everyone who shares at least one room with a user, except for the user himself.
+    async fn neighbours(&self, user_uuid: Uuid) -> RepositoryResult<Vec<Uuid>>;
+}
+
+/// Messages and read markers.
+#[tonic::async_trait]
+pub trait MessageRepository: fmt::Debug + Send + Sync {
+    /// List the messages of a room that haven't expired by `now`, oldest first.
+    async fn list(&self, room_uuid: Uuid, now: SystemTime) -> RepositoryResult<Vec<Message>>;
+
+    async fn find_in_room(
+        &self,
+        room_uuid: Uuid,
+        message_uuid: Uuid,
+    ) -> RepositoryResult<Option<Message>>;
+
+    /// Resolve the mentions of a message, then store it along with them and links to its
+    /// attachments, unless its idempotency key has been claimed by another message.
+    ///
+    /// The message gets the next sequence number in its room.
+    async fn store(
+        &self,
+        message: Message,
+        attachment_uuids: Vec<Uuid>,
+        idempotency_key: Option<IdempotencyKey>,
+    ) -> RepositoryResult<Deduplicated<(Message, Vec<Mention>)>>;
+
+    /// Load the attachments and mentions of messages, see [`Message::hydrate`].
+    async fn hydrate(&self, messages: Vec<Message>) -> RepositoryResult<Vec<HydratedMessage>>;
+
+    /// Search the unexpired messages of the rooms a user is a member of,
+    /// returning them along with their ranks and highlighted snippets, best first.
+    async fn search(
+        &self,
+        searcher_uuid: Uuid,
+        search: MessageSearch,
+        now: SystemTime,
+    ) -> RepositoryResult<Vec<(Message, f32, String)>>;
+
+    /// Load the messages a room subscriber has missed, see [`RoomSubscription::missed_messages`].
+    ///
+    /// Returns `None` if the cursor points at a message that's not in the room (anymore).
+    async fn missed(
+        &self,
+        subscription: RoomSubscription,
+        now: SystemTime,
+    ) -> RepositoryResult<Option<Vec<Message>>>;
+
+    /// Delete a batch of messages that have expired by `now`, returning them.
+    ///
+    /// Read markers that point at deleted messages are moved back to the latest
+    /// message left in the room before them (or removed).
+    async fn delete_expired(&self, now: SystemTime, limit: i64) -> RepositoryResult<Vec<Message>>;
+
+    /// Get the time the next message expires at.
+    async fn next_expiry(&self) -> RepositoryResult<Option<SystemTime>>;
+
+    /// Forget idempotency keys that have outlived their window, returning how many there were.
+    async fn prune_idempotency_keys(&self, now: SystemTime) -> RepositoryResult<usize>;
+
+    /// Store a read marker, unless its user's marker in its room already points at
+    /// a message with the same or a later sequence number. Returns whether it was stored.
+    async fn move_read_marker(&self, marker: ReadMarker) -> RepositoryResult<bool>;
+
+    /// Get the last message a user has read in each of many rooms, along with the amount of
+    /// messages from other members that arrived after it and haven't expired by `now`,
+    /// in the same order as `room_uuids`.
+    async fn unread_statuses(
+        &self,
+        room_uuids: Vec<Uuid>,
+        reader_uuid: Uuid,
+        now: SystemTime,
+    ) -> RepositoryResult<Vec<(Option<Uuid>, u64)>>;
+}
+
+/// Metadata of uploaded attachments. The contents live in a [`BlobStore`](crate::storage::BlobStore).
+#[tonic::async_trait]
+pub trait AttachmentRepository: fmt::Debug + Send + Sync {
+    async fn find(&self, attachment_uuid: Uuid) -> RepositoryResult<Option<Attachment>>;
+
+    /// Find the attachments uploaded to a room among the provided ones.
+    async fn find_in_room(
+        &self,
+        room_uuid: Uuid,
+        attachment_uuids: Vec<Uuid>,
+    ) -> RepositoryResult<Vec<Attachment>>;
+
+    async fn create(&self, attachment: Attachment) -> RepositoryResult<()>;
+}
+
+/// Pinned messages.
+#[tonic::async_trait]
+pub trait PinRepository: fmt::Debug + Send + Sync {
+    /// Pin a message, unless it's pinned already or its room has run out of pins.
+    async fn pin(&self, pin: MessagePin) -> RepositoryResult<PinOutcome>;
+
+    /// Unpin a message on behalf of a room member, if they pinned or wrote it.
+    async fn unpin(
+        &self,
+        room_uuid: Uuid,
+        message_uuid: Uuid,
+        unpinner_uuid: Uuid,
+    ) -> RepositoryResult<UnpinOutcome>;
+
+    /// List the pins of a room along with the messages they point to, latest first.
+    async fn list(&self, room_uuid: Uuid) -> RepositoryResult<Vec<(MessagePin, Message)>>;
+}
+
+/// Messages waiting for their delivery time.
+#[tonic::async_trait]
+pub trait ScheduledMessageRepository: fmt::Debug + Send + Sync {
+    /// Queue a message, unless its idempotency key has been claimed by another message.
+    ///
+    /// Returns `None` without queueing anything if the sender already has
+    /// [`ScheduledMessage::MAX_PENDING_PER_USER`] messages waiting for delivery.
+    async fn schedule(
+        &self,
+        message: ScheduledMessage,
+        idempotency_key: Option<IdempotencyKey>,
+    ) -> RepositoryResult<Option<Deduplicated<ScheduledMessage>>>;
+
+    /// List the messages a user has waiting for delivery, earliest first.
+    async fn list_pending(&self, sender_uuid: Uuid) -> RepositoryResult<Vec<ScheduledMessage>>;
+
+    /// Cancel a message of a user. Returns `false` if the user has no such message.
+    async fn cancel(&self, sender_uuid: Uuid, message_uuid: Uuid) -> RepositoryResult<bool>;
+
+    /// List a batch of messages that are due by `now`, earliest first.
+    async fn due(&self, now: SystemTime, limit: i64) -> RepositoryResult<Vec<ScheduledMessage>>;
+
+    /// Get the time the next message is due at.
+    async fn next_delivery(&self) -> RepositoryResult<Option<SystemTime>>;
+
+    /// Take a message off the queue and store it like [`MessageRepository::store`] does, all at once.
+    ///
+    /// Returns `None` if the message has been cancelled in the meantime, or if
+    /// its sender is no longer a member of its room, in which case it's dropped.
+    async fn deliver(
+        &self,
+        message: Message,
+        attachment_uuids: Vec<Uuid>,
+    ) -> RepositoryResult<Option<(Message, Vec<Mention>)>>;
+
+    /// Move the delivery time of a message, i.e. to retry a failed delivery later.
+    async fn postpone(&self, message_uuid: Uuid, deliver_at: SystemTime) -> RepositoryResult<()>;
+}
+
+/// The repositories a service works with.
+#[derive(Debug, Clone)]
+pub struct Repositories {
+    pub users: Arc<dyn UserRepository>,
+    pub rooms: Arc<dyn RoomRepository>,
+    pub messages: Arc<dyn MessageRepository>,
+    pub attachments: Arc<dyn AttachmentRepository>,
+    pub pins: Arc<dyn PinRepository>,
+    pub scheduled_messages: Arc<dyn ScheduledMessageRepository>,
+}
+
+impl Repositories {
+    #[must_use]
+    pub fn database(pool: ConnectionPool) -> Self {
+        Self::from_shared(Arc::new(DatabaseRepository::new(pool)))
+    }
+
+    #[must_use]
+    pub fn in_memory() -> Self {
+        Self::from_shared(Arc::new(InMemoryRepository::new()))
+    }
+
+    /// Hand out a single value that implements all the repositories.
+    pub fn from_shared<R>(repository: Arc<R>) -> Self
+    where
+        R: UserRepository + RoomRepository + MessageRepository + 'static,
+        R: AttachmentRepository + PinRepository + ScheduledMessageRepository,
+    {
+        Self {
+            users: repository.clone(),
+            rooms: repository.clone(),
+            messages: repository.clone(),
+            attachments: repository.clone(),
+            pins: repository.clone(),
+            scheduled_messages: repository,
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests;
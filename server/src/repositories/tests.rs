@@ -0,0 +1,1136 @@
+//! The behaviour every implementation of the repositories has to share, checked through
+//! [`Repositories`] against each backend:
+//!
+//! - The [`InMemoryRepository`](super::InMemoryRepository), always.
+//! - The [`DatabaseRepository`](super::DatabaseRepository) on SQLite with the `sqlite` feature,
+//!   in a fresh database file per test.
+//! - The [`DatabaseRepository`](super::DatabaseRepository) on Postgres if `$TEST_DATABASE_URL`
+//!   is set, in a fresh database per test (so the user it connects as must be allowed to create
+//!   them).
+//!
+//! Tests that can't reach their backend pass without checking anything, except on CI (when
+//! `$CI` is set), where Postgres has to be available and a missing `$TEST_DATABASE_URL` fails
+//! them instead.
+
+use super::{Deduplicated, PinOutcome, Repositories, UnpinOutcome};
+use crate::entities::{Attachment, IdempotencyKey, Message, MessagePin, MessageSearch};
+use crate::entities::{ReadMarker, ResumeCursor, Room, RoomSubscription, ScheduledMessage, User};
+use crate::persistence::{migrations, Connection, ConnectionManager, PoolConfig};
+use diesel::connection::SimpleConnection;
+use rand_chacha::ChaCha20Rng;
+use rand_core::SeedableRng;
+use rstest::rstest;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy)]
+enum Backend {
+    Memory,
+    Sqlite,
+    Postgres,
+}
+
+/// Repositories on a database that's only used by a single test, and is removed after it.
+struct TestDatabase {
+    repositories: Repositories,
+    _cleanup: Option<Cleanup>,
+}
+
+enum Cleanup {
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+    Sqlite(PathBuf),
+    Postgres {
+        admin_url: String,
+        name: String,
+    },
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        match self {
+            Self::Sqlite(path) => {
+                for suffix in ["", "-wal", "-shm"] {
+                    let mut file = path.clone().into_os_string();
+                    file.push(suffix);
+                    let _ = std::fs::remove_file(file);
+                }
+            }
+            Self::Postgres { admin_url, name } => {
+                if let Ok(mut db) = Connection::open(admin_url) {
+                    let _ = db.batch_execute(&format!("DROP DATABASE \"{name}\" WITH (FORCE)"));
+                }
+            }
+        }
+    }
+}
+
+impl TestDatabase {
+    /// Set up a fresh database, or return `None` if the backend isn't available.
+    ///
+    /// # Panics
+    ///
+    /// On CI, if `$TEST_DATABASE_URL` isn't set.
+    #[allow(clippy::unwrap_used)]
+    fn connect(backend: Backend) -> Option<Self> {
+        match backend {
+            Backend::Memory => Some(Self {
+                repositories: Repositories::in_memory(),
+                _cleanup: None,
+            }),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite => {
+                let path = std::env::temp_dir().join(format!("tcp-chat-{}.sqlite", Uuid::new_v4()));
+                let cleanup = Cleanup::Sqlite(path.clone());
+                let url = format!("{}{}", Connection::SQLITE_SCHEME, path.display());
+                Some(Self::migrated(url, cleanup))
+            }
+            #[cfg(not(feature = "sqlite"))]
+            Backend::Sqlite => None,
+            Backend::Postgres => {
+                let Ok(admin_url) = std::env::var("TEST_DATABASE_URL") else {
+                    assert!(
+                        std::env::var_os("CI").is_none(),
+                        "$TEST_DATABASE_URL has to be set on CI, or Postgres would go untested"
+                    );
+                    return None;
+                };
+                let name = format!("tcp_chat_test_{}", Uuid::new_v4().simple());
+                let mut admin = Connection::open(&admin_url).unwrap();
+                admin
+                    .batch_execute(&format!("CREATE DATABASE \"{name}\""))
+                    .unwrap();
+                let cleanup = Cleanup::Postgres {
+                    admin_url: admin_url.clone(),
+                    name: name.clone(),
+                };
+
+                let (server_url, _) = admin_url.rsplit_once('/').unwrap();
+                Some(Self::migrated(format!("{server_url}/{name}"), cleanup))
+            }
+        }
+    }
+
+    /// Bring the fresh database at `url` up to date, and wrap it in repositories.
+    #[allow(clippy::unwrap_used)]
+    fn migrated(url: String, cleanup: Cleanup) -> Self {
+        let config = PoolConfig {
+            max_size: 2,
+            ..PoolConfig::default()
+        };
+        let pool = config.builder().build(ConnectionManager::new(url)).unwrap();
+        migrations::run_pending(&mut pool.get().unwrap()).unwrap();
+        Self {
+            repositories: Repositories::database(pool),
+            _cleanup: Some(cleanup),
+        }
+    }
+}
+
+/// Some point in time, so that tests don't depend on the clock.
+fn at(seconds: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(1_716_000_000 + seconds)
+}
+
+fn user(username: &str) -> User {
+    let mut rng = ChaCha20Rng::seed_from_u64(0);
+    User::new(username.to_string(), "hash".to_string(), &mut rng)
+}
+
+#[allow(clippy::unwrap_used)]
+async fn register(repositories: &Repositories, username: &str) -> Uuid {
+    let user = user(username);
+    assert!(repositories.users.create(user.clone()).await.unwrap());
+    user.uuid
+}
+
+#[allow(clippy::unwrap_used)]
+async fn create_room(repositories: &Repositories, member_uuids: &[Uuid]) -> Uuid {
+    let room = Room::new("room");
+    let unknown = repositories
+        .rooms
+        .create(room.clone(), member_uuids.to_vec())
+        .await
+        .unwrap();
+    assert!(unknown.is_empty());
+    room.uuid
+}
+
+#[allow(clippy::unwrap_used)]
+async fn store(repositories: &Repositories, message: Message) -> Message {
+    match repositories
+        .messages
+        .store(message, vec![], None)
+        .await
+        .unwrap()
+    {
+        Deduplicated::Stored((message, _)) => message,
+        Deduplicated::Duplicate(_) => panic!("The message isn't a duplicate"),
+    }
+}
+
+/// Store a message sent at [`at`] `seconds`.
+async fn send(
+    repositories: &Repositories,
+    text: &str,
+    sender_uuid: Uuid,
+    room_uuid: Uuid,
+    seconds: u64,
+) -> Message {
+    let message = Message {
+        timestamp: at(seconds),
+        ..Message::new(text, sender_uuid, room_uuid)
+    };
+    store(repositories, message).await
+}
+
+/// Search the messages at [`at`] 60 seconds, returning the UUIDs of the found ones.
+#[allow(clippy::unwrap_used)]
+async fn search(
+    repositories: &Repositories,
+    searcher_uuid: Uuid,
+    query: &str,
+    tweak: &dyn Fn(&mut MessageSearch),
+) -> Vec<Uuid> {
+    let mut search = MessageSearch {
+        query: query.to_string(),
+        room_uuid: None,
+        sender_uuid: None,
+        sent_after: None,
+        sent_before: None,
+        limit: MessageSearch::DEFAULT_LIMIT,
+        offset: 0,
+    };
+    tweak(&mut search);
+    repositories
+        .messages
+        .search(searcher_uuid, search, at(60))
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(message, _, _)| message.uuid)
+        .collect()
+}
+
+fn uuids(messages: &[Message]) -> Vec<Uuid> {
+    messages.iter().map(|message| message.uuid).collect()
+}
+
+fn sorted(mut uuids: Vec<Uuid>) -> Vec<Uuid> {
+    uuids.sort();
+    uuids
+}
+
+#[rstest]
+#[tokio::test]
+#[allow(clippy::unwrap_used)]
+async fn users(#[values(Backend::Memory, Backend::Sqlite, Backend::Postgres)] backend: Backend) {
+    let Some(db) = TestDatabase::connect(backend) else {
+        return;
+    };
+    let users = &db.repositories.users;
+    let alice = register(&db.repositories, "alice").await;
+
+    assert!(!users.create(user("alice")).await.unwrap());
+    assert!(!users.create(user("ALICE")).await.unwrap());
+    assert!(!users.create(user("Аlice")).await.unwrap());
+    let found = users.find_by_username("ALICE".to_string()).await.unwrap();
+    assert_eq!(found.map(|user| user.uuid), Some(alice));
+    let found = users
+        .find_by_credentials("alice".to_string(), "hash".to_string())
+        .await
+        .unwrap();
+    assert_eq!(found.map(|user| user.uuid), Some(alice));
+    assert!(users
+        .find_by_credentials("alice".to_string(), "wrong".to_string())
+        .await
+        .unwrap()
+        .is_none());
+    let found = users.find(alice).await.unwrap().unwrap();
+    assert_eq!(found.username, "alice");
+    assert!(users.find(Uuid::new_v4()).await.unwrap().is_none());
+
+    assert_eq!(
+        users.last_seen(vec![alice]).await.unwrap(),
+        vec![(alice, None)]
+    );
+    users.set_last_seen(alice, Some(at(1))).await.unwrap();
+    assert_eq!(
+        users.last_seen(vec![alice, Uuid::new_v4()]).await.unwrap(),
+        vec![(alice, Some(at(1)))]
+    );
+}
+
+#[rstest]
+#[tokio::test]
+#[allow(clippy::unwrap_used)]
+async fn rooms(#[values(Backend::Memory, Backend::Sqlite, Backend::Postgres)] backend: Backend) {
+    let Some(db) = TestDatabase::connect(backend) else {
+        return;
+    };
+    let rooms = &db.repositories.rooms;
+    let (alice, bob, carol, dave) = (
+        register(&db.repositories, "alice").await,
+        register(&db.repositories, "bob").await,
+        register(&db.repositories, "carol").await,
+        register(&db.repositories, "dave").await,
+    );
+
+    let stranger = Uuid::new_v4();
+    let room = Room::new("strangers");
+    let unknown = rooms
+        .create(room.clone(), vec![alice, stranger])
+        .await
+        .unwrap();
+    assert_eq!(unknown, vec![stranger]);
+    assert!(rooms.find(room.uuid).await.unwrap().is_none());
+    assert!(rooms.rooms_of(alice).await.unwrap().is_empty());
+
+    let a = create_room(&db.repositories, &[alice, bob]).await;
+    let b = create_room(&db.repositories, &[alice, bob, carol]).await;
+    let c = create_room(&db.repositories, &[dave]).await;
+
+    let found = rooms
+        .find_many(vec![a, b, Uuid::new_v4()])
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|room| room.uuid)
+        .collect();
+    assert_eq!(sorted(found), sorted(vec![a, b]));
+    assert_eq!(
+        sorted(rooms.members(b).await.unwrap()),
+        sorted(vec![alice, bob, carol])
+    );
+    let members: Vec<Vec<Uuid>> = rooms
+        .members_of_many(vec![c, Uuid::new_v4(), b])
+        .await
+        .unwrap()
+        .into_iter()
+        .map(sorted)
+        .collect();
+    assert_eq!(
+        members,
+        [vec![dave], vec![], sorted(vec![alice, bob, carol])]
+    );
+    assert_eq!(
+        sorted(rooms.rooms_of(alice).await.unwrap()),
+        sorted(vec![a, b])
+    );
+    assert!(rooms.is_member(c, dave).await.unwrap());
+    assert!(!rooms.is_member(c, alice).await.unwrap());
+    assert_eq!(
+        sorted(rooms.neighbours(alice).await.unwrap()),
+        sorted(vec![bob, carol])
+    );
+    assert!(rooms.neighbours(dave).await.unwrap().is_empty());
+
+    rooms
+        .set_message_ttl(a, Some(Duration::from_secs(60)))
+        .await
+        .unwrap();
+    let room = rooms.find(a).await.unwrap().unwrap();
+    assert_eq!(room.message_ttl_seconds, Some(60));
+    rooms.set_message_ttl(a, None).await.unwrap();
+    let room = rooms.find(a).await.unwrap().unwrap();
+    assert_eq!(room.message_ttl_seconds, None);
+}
+
+#[rstest]
+#[tokio::test]
+#[allow(clippy::unwrap_used)]
+async fn messages_are_numbered_per_room(
+    #[values(Backend::Memory, Backend::Sqlite, Backend::Postgres)] backend: Backend,
+) {
+    let Some(db) = TestDatabase::connect(backend) else {
+        return;
+    };
+    let messages = &db.repositories.messages;
+    let alice = register(&db.repositories, "alice").await;
+    let (a, b) = (
+        create_room(&db.repositories, &[alice]).await,
+        create_room(&db.repositories, &[alice]).await,
+    );
+
+    let mut sent = vec![];
+    for seconds in 1..=3 {
+        sent.push(send(&db.repositories, "hi", alice, a, seconds).await);
+    }
+    let elsewhere = send(&db.repositories, "hi", alice, b, 4).await;
+
+    let sequences: Vec<i64> = sent.iter().map(|message| message.sequence).collect();
+    assert_eq!(sequences, [1, 2, 3]);
+    assert_eq!(elsewhere.sequence, 1);
+    let room = db.repositories.rooms.find(a).await.unwrap().unwrap();
+    assert_eq!(room.last_sequence, 3);
+
+    let listed = messages.list(a, at(10)).await.unwrap();
+    assert_eq!(uuids(&listed), uuids(&sent));
+    let found = messages.find_in_room(a, sent[0].uuid).await.unwrap();
+    assert_eq!(found.map(|message| message.sequence), Some(1));
+    assert!(messages
+        .find_in_room(a, elsewhere.uuid)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[rstest]
+#[tokio::test]
+#[allow(clippy::unwrap_used)]
+async fn mentions_and_attachments_are_hydrated(
+    #[values(Backend::Memory, Backend::Sqlite, Backend::Postgres)] backend: Backend,
+) {
+    let Some(db) = TestDatabase::connect(backend) else {
+        return;
+    };
+    let (alice, bob, maria, carol) = (
+        register(&db.repositories, "alice").await,
+        register(&db.repositories, "bob").await,
+        register(&db.repositories, "Мария").await,
+        register(&db.repositories, "carol").await,
+    );
+    let room = create_room(&db.repositories, &[alice, bob, maria]).await;
+    let other_room = create_room(&db.repositories, &[alice]).await;
+
+    let attachment = Attachment::new(
+        room,
+        alice,
+        "notes.txt".to_string(),
+        "text/plain".to_string(),
+        blake3::hash(b"notes"),
+        5,
+    );
+    let attachments = &db.repositories.attachments;
+    attachments.create(attachment.clone()).await.unwrap();
+    let found = attachments.find(attachment.uuid).await.unwrap().unwrap();
+    assert_eq!(found.hash, attachment.hash);
+    let found = attachments
+        .find_in_room(room, vec![attachment.uuid, Uuid::new_v4()])
+        .await
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    assert!(attachments
+        .find_in_room(other_room, vec![attachment.uuid])
+        .await
+        .unwrap()
+        .is_empty());
+
+    let message = Message::new("@BOB, @мария and @carol", alice, room);
+    let Deduplicated::Stored((message, mentions)) = db
+        .repositories
+        .messages
+        .store(message, vec![attachment.uuid], None)
+        .await
+        .unwrap()
+    else {
+        panic!("The message isn't a duplicate");
+    };
+    let mentioned: Vec<Uuid> = mentions.iter().map(|mention| mention.user_uuid).collect();
+    assert_eq!(mentioned, [bob, maria]);
+    assert!(!mentioned.contains(&carol));
+
+    let plain = send(&db.repositories, "no attachments", alice, room, 1).await;
+    let hydrated = db
+        .repositories
+        .messages
+        .hydrate(vec![message, plain])
+        .await
+        .unwrap();
+    assert_eq!(hydrated.len(), 2);
+    assert_eq!(hydrated[0].attachments.len(), 1);
+    assert_eq!(hydrated[0].attachments[0].uuid, attachment.uuid);
+    let offsets: Vec<i32> = hydrated[0]
+        .mentions
+        .iter()
+        .map(|mention| mention.byte_offset)
+        .collect();
+    assert_eq!(offsets, [0, 6]);
+    assert!(hydrated[1].attachments.is_empty());
+    assert!(hydrated[1].mentions.is_empty());
+}
+
+#[rstest]
+#[tokio::test]
+#[allow(clippy::unwrap_used)]
+async fn idempotency_keys_deduplicate_messages(
+    #[values(Backend::Memory, Backend::Sqlite, Backend::Postgres)] backend: Backend,
+) {
+    let Some(db) = TestDatabase::connect(backend) else {
+        return;
+    };
+    let messages = &db.repositories.messages;
+    let alice = register(&db.repositories, "alice").await;
+    let room = create_room(&db.repositories, &[alice]).await;
+    let keyed = |message: &Message, created_at: SystemTime| IdempotencyKey {
+        created_at,
+        ..IdempotencyKey::new(alice, "retry-me".to_string(), message.uuid)
+    };
+
+    let first = Message::new("once", alice, room);
+    let stored = messages
+        .store(first.clone(), vec![], Some(keyed(&first, at(0))))
+        .await
+        .unwrap();
+    assert!(matches!(stored, Deduplicated::Stored(_)));
+
+    let retry = Message::new("once", alice, room);
+    let stored = messages
+        .store(retry.clone(), vec![], Some(keyed(&retry, at(1))))
+        .await
+        .unwrap();
+    assert!(matches!(stored, Deduplicated::Duplicate(uuid) if uuid == first.uuid));
+
+    // Once the window has passed, the key can be claimed again.
+    let late = Message::new("twice", alice, room);
+    let reclaimed_at = at(0) + IdempotencyKey::WINDOW;
+    let stored = messages
+        .store(late.clone(), vec![], Some(keyed(&late, reclaimed_at)))
+        .await
+        .unwrap();
+    assert!(matches!(stored, Deduplicated::Stored(_)));
+    assert_eq!(messages.list(room, at(0)).await.unwrap().len(), 2);
+
+    assert_eq!(messages.prune_idempotency_keys(at(0)).await.unwrap(), 0);
+    let pruned = messages
+        .prune_idempotency_keys(reclaimed_at + IdempotencyKey::WINDOW + Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert_eq!(pruned, 1);
+}
+
+#[rstest]
+#[tokio::test]
+#[allow(clippy::unwrap_used)]
+async fn missed_messages_follow_the_cursor(
+    #[values(Backend::Memory, Backend::Sqlite, Backend::Postgres)] backend: Backend,
+) {
+    let Some(db) = TestDatabase::connect(backend) else {
+        return;
+    };
+    let messages = &db.repositories.messages;
+    let alice = register(&db.repositories, "alice").await;
+    let (room, other_room) = (
+        create_room(&db.repositories, &[alice]).await,
+        create_room(&db.repositories, &[alice]).await,
+    );
+    let mut sent = vec![];
+    for seconds in 1..=3 {
+        sent.push(send(&db.repositories, "hi", alice, room, seconds).await);
+    }
+    let elsewhere = send(&db.repositories, "hi", alice, other_room, 4).await;
+
+    let missed = |resume_after| {
+        messages.missed(
+            RoomSubscription {
+                room_uuid: room,
+                resume_after,
+            },
+            at(10),
+        )
+    };
+    assert_eq!(missed(None).await.unwrap().map(|m| uuids(&m)), Some(vec![]));
+    assert_eq!(
+        missed(Some(ResumeCursor::Message(sent[0].uuid)))
+            .await
+            .unwrap()
+            .map(|m| uuids(&m)),
+        Some(uuids(&sent[1..]))
+    );
+    assert_eq!(
+        missed(Some(ResumeCursor::Sequence(2)))
+            .await
+            .unwrap()
+            .map(|m| uuids(&m)),
+        Some(uuids(&sent[2..]))
+    );
+    assert_eq!(
+        missed(Some(ResumeCursor::Timestamp(at(1))))
+            .await
+            .unwrap()
+            .map(|m| uuids(&m)),
+        Some(uuids(&sent[1..]))
+    );
+    assert!(missed(Some(ResumeCursor::Message(Uuid::new_v4())))
+        .await
+        .unwrap()
+        .is_none());
+    assert!(missed(Some(ResumeCursor::Message(elsewhere.uuid)))
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[rstest]
+#[tokio::test]
+#[allow(clippy::unwrap_used)]
+async fn read_markers_only_move_forward(
+    #[values(Backend::Memory, Backend::Sqlite, Backend::Postgres)] backend: Backend,
+) {
+    let Some(db) = TestDatabase::connect(backend) else {
+        return;
+    };
+    let messages = &db.repositories.messages;
+    let (alice, bob) = (
+        register(&db.repositories, "alice").await,
+        register(&db.repositories, "bob").await,
+    );
+    let room = create_room(&db.repositories, &[alice, bob]).await;
+    let mut sent = vec![];
+    for seconds in 1..=3 {
+        sent.push(send(&db.repositories, "hi", alice, room, seconds).await);
+    }
+    send(&db.repositories, "mine", bob, room, 4).await;
+
+    assert_eq!(
+        messages
+            .unread_statuses(vec![room], bob, at(10))
+            .await
+            .unwrap(),
+        [(None, 3)]
+    );
+    assert_eq!(
+        messages
+            .unread_statuses(vec![room], alice, at(10))
+            .await
+            .unwrap(),
+        [(None, 1)]
+    );
+
+    assert!(messages
+        .move_read_marker(ReadMarker::new(bob, &sent[1]))
+        .await
+        .unwrap());
+    assert_eq!(
+        messages
+            .unread_statuses(vec![room], bob, at(10))
+            .await
+            .unwrap(),
+        [(Some(sent[1].uuid), 1)]
+    );
+
+    for earlier in &sent[..2] {
+        assert!(!messages
+            .move_read_marker(ReadMarker::new(bob, earlier))
+            .await
+            .unwrap());
+    }
+    assert_eq!(
+        messages
+            .unread_statuses(vec![room], bob, at(10))
+            .await
+            .unwrap(),
+        [(Some(sent[1].uuid), 1)]
+    );
+
+    assert!(messages
+        .move_read_marker(ReadMarker::new(bob, &sent[2]))
+        .await
+        .unwrap());
+    assert_eq!(
+        messages
+            .unread_statuses(vec![room], bob, at(10))
+            .await
+            .unwrap(),
+        [(Some(sent[2].uuid), 0)]
+    );
+}
+
+#[rstest]
+#[tokio::test]
+#[allow(clippy::unwrap_used)]
+async fn unread_statuses_are_listed_for_many_rooms_at_once(
+    #[values(Backend::Memory, Backend::Sqlite, Backend::Postgres)] backend: Backend,
+) {
+    let Some(db) = TestDatabase::connect(backend) else {
+        return;
+    };
+    let messages = &db.repositories.messages;
+    let (alice, bob) = (
+        register(&db.repositories, "alice").await,
+        register(&db.repositories, "bob").await,
+    );
+    let (marked, unmarked, empty) = (
+        create_room(&db.repositories, &[alice, bob]).await,
+        create_room(&db.repositories, &[alice, bob]).await,
+        create_room(&db.repositories, &[alice, bob]).await,
+    );
+    let read = send(&db.repositories, "read", alice, marked, 1).await;
+    for seconds in 2..=3 {
+        send(&db.repositories, "unread", alice, marked, seconds).await;
+        send(&db.repositories, "unread", alice, unmarked, seconds).await;
+    }
+    send(&db.repositories, "mine", bob, unmarked, 4).await;
+    let expired = Message {
+        timestamp: at(5),
+        expires_at: Some(at(6)),
+        ..Message::new("expired", alice, unmarked)
+    };
+    store(&db.repositories, expired).await;
+    assert!(messages
+        .move_read_marker(ReadMarker::new(bob, &read))
+        .await
+        .unwrap());
+
+    let rooms = vec![empty, unmarked, marked];
+    let statuses = messages
+        .unread_statuses(rooms.clone(), bob, at(10))
+        .await
+        .unwrap();
+    assert_eq!(statuses, [(None, 0), (None, 2), (Some(read.uuid), 2)]);
+    for (room, status) in rooms.into_iter().zip(statuses) {
+        assert_eq!(
+            messages
+                .unread_statuses(vec![room], bob, at(10))
+                .await
+                .unwrap(),
+            [status]
+        );
+    }
+    assert!(messages
+        .unread_statuses(vec![], bob, at(10))
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[rstest]
+#[tokio::test]
+#[allow(clippy::unwrap_used)]
+async fn expired_messages_are_hidden_then_deleted(
+    #[values(Backend::Memory, Backend::Sqlite, Backend::Postgres)] backend: Backend,
+) {
+    let Some(db) = TestDatabase::connect(backend) else {
+        return;
+    };
+    let messages = &db.repositories.messages;
+    let (alice, bob) = (
+        register(&db.repositories, "alice").await,
+        register(&db.repositories, "bob").await,
+    );
+    let (room, other_room) = (
+        create_room(&db.repositories, &[alice, bob]).await,
+        create_room(&db.repositories, &[alice, bob]).await,
+    );
+    let expiring = |text: &str, room_uuid: Uuid, seconds: u64, expires_at: u64| Message {
+        timestamp: at(seconds),
+        expires_at: Some(at(expires_at)),
+        ..Message::new(text, alice, room_uuid)
+    };
+
+    let kept = send(&db.repositories, "kept", alice, room, 1).await;
+    let first = store(&db.repositories, expiring("first", room, 2, 10)).await;
+    let second = store(&db.repositories, expiring("second", room, 3, 20)).await;
+    let last = send(&db.repositories, "last", alice, room, 4).await;
+    let lonely = store(&db.repositories, expiring("lonely", other_room, 5, 15)).await;
+    let now = at(60);
+
+    let listed = messages.list(room, now).await.unwrap();
+    assert_eq!(uuids(&listed), [kept.uuid, last.uuid]);
+    let listed = messages.list(room, at(0)).await.unwrap();
+    assert_eq!(listed.len(), 4);
+    assert_eq!(messages.next_expiry().await.unwrap(), Some(at(10)));
+
+    for read in [&second, &lonely] {
+        assert!(messages
+            .move_read_marker(ReadMarker::new(bob, read))
+            .await
+            .unwrap());
+    }
+    assert_eq!(
+        messages
+            .unread_statuses(vec![room], bob, now)
+            .await
+            .unwrap(),
+        [(Some(second.uuid), 1)]
+    );
+
+    let deleted = messages.delete_expired(now, 1).await.unwrap();
+    assert_eq!(uuids(&deleted), [first.uuid]);
+    let deleted = messages.delete_expired(now, 10).await.unwrap();
+    assert_eq!(uuids(&deleted), [lonely.uuid, second.uuid]);
+    assert!(messages.delete_expired(now, 10).await.unwrap().is_empty());
+    assert_eq!(messages.next_expiry().await.unwrap(), None);
+
+    // Markers move back to the latest message left before them, or disappear.
+    assert_eq!(
+        messages
+            .unread_statuses(vec![room], bob, now)
+            .await
+            .unwrap(),
+        [(Some(kept.uuid), 1)]
+    );
+    assert_eq!(
+        messages
+            .unread_statuses(vec![other_room], bob, now)
+            .await
+            .unwrap(),
+        [(None, 0)]
+    );
+}
+
+#[rstest]
+#[tokio::test]
+#[allow(clippy::unwrap_used)]
+async fn search_covers_the_rooms_of_the_searcher(
+    #[values(Backend::Memory, Backend::Sqlite, Backend::Postgres)] backend: Backend,
+) {
+    let Some(db) = TestDatabase::connect(backend) else {
+        return;
+    };
+    let (alice, bob, carol) = (
+        register(&db.repositories, "alice").await,
+        register(&db.repositories, "bob").await,
+        register(&db.repositories, "carol").await,
+    );
+    let (a, b) = (
+        create_room(&db.repositories, &[alice, bob]).await,
+        create_room(&db.repositories, &[alice, carol]).await,
+    );
+    let hello_world = send(&db.repositories, "hello world", alice, a, 1).await;
+    let hello_there = send(&db.repositories, "Hello there", bob, a, 2).await;
+    send(&db.repositories, "goodbye world", alice, a, 3).await;
+    let hello_carol = send(&db.repositories, "hello carol", carol, b, 4).await;
+    store(
+        &db.repositories,
+        Message {
+            timestamp: at(5),
+            expires_at: Some(at(6)),
+            ..Message::new("hello expired", alice, a)
+        },
+    )
+    .await;
+
+    assert_eq!(
+        search(&db.repositories, bob, "hello", &|_| ()).await,
+        [hello_there.uuid, hello_world.uuid]
+    );
+    assert_eq!(
+        search(&db.repositories, alice, "HELLO", &|_| ()).await,
+        [hello_carol.uuid, hello_there.uuid, hello_world.uuid]
+    );
+    assert_eq!(
+        search(&db.repositories, alice, "hello world", &|_| ()).await,
+        [hello_world.uuid]
+    );
+    assert!(search(&db.repositories, alice, "farewell", &|_| ())
+        .await
+        .is_empty());
+    assert_eq!(
+        search(&db.repositories, alice, "hello", &|search| search
+            .room_uuid =
+            Some(b))
+        .await,
+        [hello_carol.uuid]
+    );
+    assert_eq!(
+        search(&db.repositories, alice, "hello", &|search| search
+            .sender_uuid =
+            Some(bob))
+        .await,
+        [hello_there.uuid]
+    );
+    assert_eq!(
+        search(&db.repositories, alice, "hello", &|search| search
+            .sent_after =
+            Some(at(2)))
+        .await,
+        [hello_carol.uuid, hello_there.uuid]
+    );
+    assert_eq!(
+        search(&db.repositories, alice, "hello", &|search| search
+            .sent_before =
+            Some(at(1)))
+        .await,
+        [hello_world.uuid]
+    );
+    assert_eq!(
+        search(&db.repositories, alice, "hello", &|search| {
+            search.limit = 1;
+            search.offset = 1;
+        })
+        .await,
+        [hello_there.uuid]
+    );
+}
+
+#[rstest]
+#[tokio::test]
+#[allow(clippy::unwrap_used)]
+async fn pins_are_limited_per_room(
+    #[values(Backend::Memory, Backend::Sqlite, Backend::Postgres)] backend: Backend,
+) {
+    let Some(db) = TestDatabase::connect(backend) else {
+        return;
+    };
+    let pins = &db.repositories.pins;
+    let alice = register(&db.repositories, "alice").await;
+    let (room, other_room) = (
+        create_room(&db.repositories, &[alice]).await,
+        create_room(&db.repositories, &[alice]).await,
+    );
+    let pin = |message: &Message, seconds| MessagePin {
+        timestamp: at(seconds),
+        ..MessagePin::new(alice, message)
+    };
+
+    let mut pinned = vec![];
+    for seconds in 0..u64::try_from(MessagePin::MAX_PINS_PER_ROOM).unwrap() {
+        let message = send(&db.repositories, "pin me", alice, room, seconds).await;
+        assert_eq!(
+            pins.pin(pin(&message, seconds)).await.unwrap(),
+            PinOutcome::Pinned
+        );
+        pinned.push(message);
+    }
+    assert_eq!(
+        pins.pin(pin(&pinned[0], 100)).await.unwrap(),
+        PinOutcome::AlreadyPinned
+    );
+
+    let listed: Vec<Uuid> = pins
+        .list(room)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(pin, message)| {
+            assert_eq!(pin.message_uuid, message.uuid);
+            message.uuid
+        })
+        .collect();
+    assert_eq!(listed, uuids(&pinned).into_iter().rev().collect::<Vec<_>>());
+
+    let one_too_many = send(&db.repositories, "one too many", alice, room, 100).await;
+    assert_eq!(
+        pins.pin(pin(&one_too_many, 100)).await.unwrap(),
+        PinOutcome::LimitReached
+    );
+    let elsewhere = send(&db.repositories, "another room", alice, other_room, 100).await;
+    assert_eq!(
+        pins.pin(pin(&elsewhere, 100)).await.unwrap(),
+        PinOutcome::Pinned
+    );
+
+    let unpin = |room_uuid, message: &Message| pins.unpin(room_uuid, message.uuid, alice);
+    assert_eq!(
+        unpin(other_room, &pinned[0]).await.unwrap(),
+        UnpinOutcome::NotPinned
+    );
+    assert_eq!(
+        unpin(room, &pinned[0]).await.unwrap(),
+        UnpinOutcome::Unpinned
+    );
+    assert_eq!(
+        unpin(room, &pinned[0]).await.unwrap(),
+        UnpinOutcome::NotPinned
+    );
+    assert_eq!(
+        pins.pin(pin(&one_too_many, 101)).await.unwrap(),
+        PinOutcome::Pinned
+    );
+}
+
+#[rstest]
+#[tokio::test]
+#[allow(clippy::unwrap_used)]
+async fn pins_are_removed_by_their_pinner_or_the_author(
+    #[values(Backend::Memory, Backend::Sqlite, Backend::Postgres)] backend: Backend,
+) {
+    let Some(db) = TestDatabase::connect(backend) else {
+        return;
+    };
+    let pins = &db.repositories.pins;
+    let (alice, bob, carol) = (
+        register(&db.repositories, "alice").await,
+        register(&db.repositories, "bob").await,
+        register(&db.repositories, "carol").await,
+    );
+    let room = create_room(&db.repositories, &[alice, bob, carol]).await;
+
+    // Bob pins Alice's messages.
+    for seconds in 0..2 {
+        let message = send(&db.repositories, "pin me", alice, room, seconds).await;
+        let pin = MessagePin::new(bob, &message);
+        assert_eq!(pins.pin(pin).await.unwrap(), PinOutcome::Pinned);
+    }
+    let pinned: Vec<Uuid> = pins
+        .list(room)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(pin, _)| pin.message_uuid)
+        .collect();
+
+    assert_eq!(
+        pins.unpin(room, pinned[0], carol).await.unwrap(),
+        UnpinOutcome::NotAllowed
+    );
+    assert_eq!(
+        pins.unpin(room, pinned[0], bob).await.unwrap(),
+        UnpinOutcome::Unpinned
+    );
+    assert_eq!(
+        pins.unpin(room, pinned[1], alice).await.unwrap(),
+        UnpinOutcome::Unpinned
+    );
+    assert!(pins.list(room).await.unwrap().is_empty());
+}
+
+#[rstest]
+#[tokio::test]
+#[allow(clippy::unwrap_used)]
+async fn scheduled_messages_are_delivered_once(
+    #[values(Backend::Memory, Backend::Sqlite, Backend::Postgres)] backend: Backend,
+) {
+    let Some(db) = TestDatabase::connect(backend) else {
+        return;
+    };
+    let scheduled = &db.repositories.scheduled_messages;
+    let (alice, carol) = (
+        register(&db.repositories, "alice").await,
+        register(&db.repositories, "carol").await,
+    );
+    let room = create_room(&db.repositories, &[alice]).await;
+    let attachment = Attachment::new(
+        room,
+        alice,
+        "notes.txt".to_string(),
+        "text/plain".to_string(),
+        blake3::hash(b"notes"),
+        5,
+    );
+    db.repositories
+        .attachments
+        .create(attachment.clone())
+        .await
+        .unwrap();
+    let schedule = |sender_uuid, attachment_uuids, deliver_at| {
+        let message = Message::new("later", sender_uuid, room);
+        ScheduledMessage::new(message, attachment_uuids, deliver_at, None)
+    };
+
+    let later = schedule(alice, vec![], at(20));
+    let key = |message: &ScheduledMessage| {
+        IdempotencyKey::new(alice, "retry-me".to_string(), message.uuid)
+    };
+    let stored = scheduled
+        .schedule(later.clone(), Some(key(&later)))
+        .await
+        .unwrap();
+    assert!(matches!(stored, Some(Deduplicated::Stored(_))));
+    let retry = schedule(alice, vec![], at(20));
+    let stored = scheduled
+        .schedule(retry.clone(), Some(key(&retry)))
+        .await
+        .unwrap();
+    assert!(matches!(stored, Some(Deduplicated::Duplicate(uuid)) if uuid == later.uuid));
+    let sooner = schedule(alice, vec![attachment.uuid], at(10));
+    scheduled.schedule(sooner.clone(), None).await.unwrap();
+
+    assert_eq!(scheduled.list_pending(alice).await.unwrap().len(), 2);
+    let pending = scheduled.list_pending(alice).await.unwrap();
+    let pending_uuids: Vec<Uuid> = pending.iter().map(|message| message.uuid).collect();
+    assert_eq!(pending_uuids, [sooner.uuid, later.uuid]);
+    assert_eq!(pending[0].attachment_uuids, [attachment.uuid]);
+    assert_eq!(scheduled.next_delivery().await.unwrap(), Some(at(10)));
+    let due = scheduled.due(at(15), 10).await.unwrap();
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].uuid, sooner.uuid);
+
+    scheduled.postpone(sooner.uuid, at(30)).await.unwrap();
+    assert!(scheduled.due(at(15), 10).await.unwrap().is_empty());
+    assert_eq!(scheduled.next_delivery().await.unwrap(), Some(at(20)));
+
+    assert!(!scheduled.cancel(carol, later.uuid).await.unwrap());
+    assert!(scheduled.cancel(alice, later.uuid).await.unwrap());
+    assert_eq!(scheduled.list_pending(alice).await.unwrap().len(), 1);
+
+    let (message, attachment_uuids) = sooner.into_message(at(30));
+    let delivered = scheduled
+        .deliver(message.clone(), attachment_uuids.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        delivered.map(|(message, _)| (message.uuid, message.sequence)),
+        Some((message.uuid, 1))
+    );
+    assert!(scheduled
+        .deliver(message.clone(), attachment_uuids)
+        .await
+        .unwrap()
+        .is_none());
+    let hydrated = db
+        .repositories
+        .messages
+        .hydrate(db.repositories.messages.list(room, at(30)).await.unwrap())
+        .await
+        .unwrap();
+    assert_eq!(hydrated.len(), 1);
+    assert_eq!(hydrated[0].attachments.len(), 1);
+
+    // Only room members get their messages delivered.
+    let outsider = schedule(carol, vec![], at(40));
+    scheduled.schedule(outsider.clone(), None).await.unwrap();
+    let (message, attachment_uuids) = outsider.into_message(at(40));
+    assert!(scheduled
+        .deliver(message, attachment_uuids)
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(scheduled.list_pending(carol).await.unwrap().len(), 0);
+    assert_eq!(
+        db.repositories
+            .messages
+            .list(room, at(40))
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[rstest]
+#[tokio::test]
+#[allow(clippy::unwrap_used)]
+async fn scheduled_messages_are_capped_per_sender(
+    #[values(Backend::Memory, Backend::Sqlite, Backend::Postgres)] backend: Backend,
+) {
+    let Some(db) = TestDatabase::connect(backend) else {
+        return;
+    };
+    let scheduled = &db.repositories.scheduled_messages;
+    let alice = register(&db.repositories, "alice").await;
+    let room = create_room(&db.repositories, &[alice]).await;
+    let schedule = || {
+        let message = Message::new("later", alice, room);
+        ScheduledMessage::new(message, vec![], at(20), None)
+    };
+
+    let headroom = 5;
+    for _ in headroom..ScheduledMessage::MAX_PENDING_PER_USER {
+        assert!(scheduled
+            .schedule(schedule(), None)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    // Messages scheduled at the same time can't squeeze under the limit together.
+    let attempts = (0..headroom * 2).map(|_| scheduled.schedule(schedule(), None));
+    let queued = futures::future::join_all(attempts)
+        .await
+        .into_iter()
+        .filter(|outcome| outcome.as_ref().unwrap().is_some())
+        .count();
+    assert_eq!(i64::try_from(queued).unwrap(), headroom);
+    assert!(scheduled
+        .schedule(schedule(), None)
+        .await
+        .unwrap()
+        .is_none());
+    let pending = scheduled.list_pending(alice).await.unwrap();
+    assert_eq!(
+        i64::try_from(pending.len()).unwrap(),
+        ScheduledMessage::MAX_PENDING_PER_USER
+    );
+}
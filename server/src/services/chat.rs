@@ -1,36 +1,112 @@
 use crate::auth::AuthenticatedRequest;
 use crate::channel::DisconnectChannel;
-use crate::entities::{Message, Room, RoomUser, User};
+use crate::entities::{Attachment, IdempotencyKey, Mention, ScheduledMessage};
+use crate::entities::{ConversionError, ResumeCursor, RoomSubscription};
+use crate::entities::{Message, MessagePin, MessageSearch, ReadMarker, Room, User};
+use crate::fanout::{RoomChannels, RoomRecvError};
+use crate::membership::{InMemoryMembershipCache, MembershipCache, RedisMembershipCache};
+use crate::presence::{Presence, PresenceTracker};
+use crate::proto::attachment_chunk::Content;
+use crate::proto::relayed_event;
 use crate::proto::serverside_user_event::Event;
 use crate::proto::user_lookup_request::Identifier;
-use crate::proto::{ClientsideMessage, ClientsideRoom, MessageList, RoomList};
+use crate::proto::{AttachmentChunk, AttachmentMetadata, ClientsideMessage, ClientsideRoom};
+use crate::proto::{MessageList, MessageUnpinned, PinRequest, PinnedMessageList, RoomList};
+use crate::proto::{MessageSearchHit, MessageSearchRequest, MessageSearchResults};
+use crate::proto::{PresenceList, PresenceRequest, PresenceStatus, PresenceUpdateRequest};
+use crate::proto::{ReadMarkerRequest, TypingRequest};
+use crate::proto::{RoomMessageTtlRequest, RoomSubscriptionRequest, ScheduledMessageList};
 use crate::proto::{RoomWithUserCreationRequest, UserLookupRequest};
 use crate::proto::{ServersideMessage, ServersideRoom, ServersideRoomEvent, ServersideUserEvent};
-use crate::{channel, persistence, proto};
-use diesel::r2d2::{ConnectionManager, PooledConnection};
-use diesel::PgConnection;
+use crate::proto::{SessionCommand, SessionEvent};
+use crate::relay::{self, Relay};
+use crate::repositories::{Deduplicated, PinOutcome, Repositories, UnpinOutcome};
+use crate::services::repository_error_status;
+use crate::storage::{self, BlobStore, BlobStoreError};
+use crate::streaming::{DeliveredSequences, SlowConsumerPolicy, StreamingConfig};
+use crate::typing::{Tick, Transition, TypingTracker};
+use crate::{channel, proto};
+use futures::{Future, Stream, StreamExt};
 use itertools::Itertools;
 use ollama_rs::generation::completion::request::GenerationRequest;
 use ollama_rs::Ollama;
-use redis::aio::MultiplexedConnection;
-use redis::{AsyncCommands, Client, RedisResult};
+use redis::{Client, RedisResult};
 use std::collections::HashMap;
 use std::env;
-use tokio::sync::{broadcast, mpsc, oneshot};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, oneshot, Notify};
+use tokio::time::Instant;
+use tokio_util::io::ReaderStream;
 use tokio_util::sync::CancellationToken;
-use tonic::{Request, Response, Status};
+use tonic::metadata::MetadataMap;
+use tonic::{Request, Response, Status, Streaming};
 use tracing::instrument;
 use uuid::Uuid;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Chat {
     // Connections to external services.
-    persistence_pool: persistence::ConnectionPool,
-    cache_client: redis::Client,
+    repositories: Repositories,
+    membership: Arc<dyn MembershipCache>,
+    blob_store: Arc<dyn BlobStore>,
+
+    // Message passing channels.
+    room_channels: Arc<RoomChannels<ServersideRoomEvent>>,
+    user_event_tx: broadcast::Sender<UserEvent>,
+    relay: Option<Relay>,
+    slow_consumer_policy: SlowConsumerPolicy,
+
+    // Ephemeral state.
+    typing_tracker: Arc<TypingTracker>,
+    presence_tracker: Arc<PresenceTracker>,
+
+    // Wake the background tasks up when there's new work for them.
+    scheduler_wakeup: Arc<Notify>,
+    sweeper_wakeup: Arc<Notify>,
+}
+
+/// A personal event, addressed to any number of users at once.
+///
+/// Turns into a [`ServersideUserEvent`] for each of the recipients with a running
+/// `SubscribeToUser` handle, so that an event that concerns a lot of users (i.e.
+/// a presence change) only takes up a single slot in the internal channel.
+#[derive(Debug, Clone)]
+pub struct UserEvent {
+    pub recipients: Vec<Uuid>,
+    pub event: Event,
+}
+
+impl From<UserEvent> for proto::RelayedUserEvent {
+    fn from(event: UserEvent) -> Self {
+        Self {
+            recipients: event.recipients.into_iter().map(Into::into).collect(),
+            event: Some(ServersideUserEvent {
+                user_uuid: None,
+                event: Some(event.event),
+            }),
+        }
+    }
+}
+
+impl TryFrom<proto::RelayedUserEvent> for UserEvent {
+    type Error = ConversionError;
 
-    // Message passing channel.
-    message_tx: broadcast::Sender<Message>,
-    user_event_tx: broadcast::Sender<ServersideUserEvent>,
+    fn try_from(event: proto::RelayedUserEvent) -> Result<Self, Self::Error> {
+        let recipients = event
+            .recipients
+            .into_iter()
+            .map(Uuid::try_from)
+            .collect::<Result<_, _>>()
+            .map_err(|_| ConversionError::InvalidField("recipients"))?;
+        let event = event
+            .event
+            .and_then(|event| event.event)
+            .ok_or(ConversionError::MissingField)?;
+        Ok(Self { recipients, event })
+    }
 }
 
 #[tonic::async_trait]
@@ -48,28 +124,17 @@ impl proto::chat_server::Chat for Chat {
                     "Can't lookup user without an identifier",
                 ))?;
 
-        let mut connection = self.acquire_database_connection().await?;
-
-        // Import some traits and methods to interact with the ORM.
-        use crate::entities::schema::users::dsl::*;
-        use diesel::prelude::*;
-
         let found_user: Option<User> = match identifier.clone() {
             Identifier::Uuid(proto_uuid) => {
-                let proto_uuid = Uuid::try_from(proto_uuid)
+                let user_uuid = Uuid::try_from(proto_uuid)
                     .map_err(|_| Status::invalid_argument("The provided UUID is invalid"))?;
-                users
-                    .filter(uuid.eq::<Uuid>(proto_uuid))
-                    .select(User::as_select())
-                    .first(&mut connection)
+                self.repositories.users.find(user_uuid).await
+            }
+            Identifier::Username(username) => {
+                self.repositories.users.find_by_username(username).await
             }
-            Identifier::Username(proto_uname) => users
-                .filter(username.eq(proto_uname))
-                .select(User::as_select())
-                .first(&mut connection),
         }
-        .optional()
-        .map_err(|err| Status::internal(err.to_string()))?;
+        .map_err(repository_error_status)?;
 
         match found_user {
             Some(user) => {
@@ -97,91 +162,135 @@ impl proto::chat_server::Chat for Chat {
             .try_into()
             .map_err(|_| Status::invalid_argument("Invalid room UUID"))?;
 
-        let mut db = self.acquire_database_connection().await?;
-
-        use crate::entities::schema::rooms::dsl::*;
-        use diesel::prelude::*;
-
-        let db_room: Room = rooms
+        let db_room: Room = self
+            .repositories
+            .rooms
             .find(requested_room)
-            .select(Room::as_select())
-            .first(&mut db)
-            .map_err(|error| {
-                let msg = "Couldn't fetch rooms from database";
-                tracing::error!(message = msg, ?error);
-                Status::internal(msg)
-            })?;
-
-        let members: Vec<proto::Uuid> = db_room
-            .get_members(&mut db)
             .await
+            .map_err(repository_error_status)?
+            .ok_or(Status::not_found("No such room"))?;
+        let members: Vec<proto::Uuid> = self
+            .repositories
+            .rooms
+            .members(requested_room)
+            .await
+            .map_err(repository_error_status)?
             .into_iter()
-            .map(|u| u.into())
+            .map(Into::into)
             .collect();
 
         let serverside_room = ServersideRoom {
             uuid: Some(db_room.uuid.into()),
+            message_ttl_seconds: ttl_to_seconds(db_room.message_ttl()),
             name: db_room.name,
             members,
+            ..Default::default()
         };
 
         Ok(Response::new(serverside_room))
     }
 
     #[instrument(skip_all)]
-    async fn list_rooms(&self, request: Request<()>) -> Result<Response<RoomList>, Status> {
-        let originator = request
+    async fn set_room_message_ttl(
+        &self,
+        request: Request<RoomMessageTtlRequest>,
+    ) -> Result<Response<()>, Status> {
+        let originator_uuid = request
             .get_originator_uuid()
             .expect("The authenticator should not let anonymous requests through");
 
-        let mut db = self.acquire_database_connection().await?;
-        let mut cache = self.acquire_cache_connection().await?;
+        let request = request.into_inner();
+        let requested_room_uuid: Uuid = request
+            .room_uuid
+            .and_then(|u| u.try_into().ok())
+            .ok_or(Status::invalid_argument("Invalid room UUID"))?;
+
+        let ttl = ttl_from_seconds(request.ttl_seconds);
+        if ttl.is_some_and(|ttl| ttl > Message::MAX_TTL) {
+            return Err(Status::invalid_argument("The TTL is too long"));
+        }
+
+        // Ensure the user is a member of the room he's configuring.
+        if !self
+            .check_room_membership(&originator_uuid, &requested_room_uuid)
+            .await?
+        {
+            tracing::warn!(
+                message = "User tried to change the message TTL of a room he's not a member of",
+                user = ?originator_uuid,
+                room = ?requested_room_uuid
+            );
+            return Err(Status::permission_denied(
+                "You are not a member of this room",
+            ));
+        }
 
-        let room_uuids: Vec<Uuid> = cache
-            .lrange(originator, 0, -1)
+        self.repositories
+            .rooms
+            .set_message_ttl(requested_room_uuid, ttl)
             .await
-            .unwrap_or_else(|error| {
-                tracing::error!(message = "Couldn't get membership from cache", ?error);
-                vec![]
-            });
+            .map_err(|error| {
+                let msg = "Could not store the message TTL";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?;
+
+        tracing::info!(message = "Changed the message TTL of a room", user = ?originator_uuid, room = ?requested_room_uuid, ?ttl);
+        Ok(Response::new(()))
+    }
 
-        use crate::entities::schema::rooms::dsl::*;
-        use diesel::prelude::*;
+    #[instrument(skip_all)]
+    async fn list_rooms(&self, request: Request<()>) -> Result<Response<RoomList>, Status> {
+        let originator = request
+            .get_originator_uuid()
+            .expect("The authenticator should not let anonymous requests through");
 
-        let db_rooms: Vec<Room> = rooms
-            .filter(uuid.eq_any(room_uuids))
-            .load::<Room>(&mut db)
+        let room_uuids = self.member_rooms(&originator).await?;
+        let db_rooms: Vec<Room> = self
+            .repositories
+            .rooms
+            .find_many(room_uuids)
+            .await
             .map_err(|error| {
                 let msg = "Couldn't load rooms from the database";
                 tracing::error!(message = msg, ?error);
                 Status::internal(msg)
             })?;
 
-        let serverside_rooms_future: Vec<_> = db_rooms
-            .into_iter()
-            .map(|db_room| async move {
-                let mut db = self
-                    .acquire_database_connection()
-                    .await
-                    .expect("Couldn't acquire a database connection");
+        let unread_statuses = self
+            .repositories
+            .messages
+            .unread_statuses(
+                db_rooms.iter().map(|room| room.uuid).collect(),
+                originator,
+                SystemTime::now(),
+            )
+            .await
+            .map_err(repository_error_status)?;
 
-                let members: Vec<proto::Uuid> = db_room
-                    .get_members(&mut db)
-                    .await
-                    .iter()
-                    .map(|u| proto::Uuid::from(*u))
-                    .collect();
+        let members = self
+            .repositories
+            .rooms
+            .members_of_many(db_rooms.iter().map(|room| room.uuid).collect())
+            .await
+            .map_err(repository_error_status)?;
 
-                ServersideRoom {
+        let serverside_rooms: Vec<ServersideRoom> = db_rooms
+            .into_iter()
+            .zip(members)
+            .zip(unread_statuses)
+            .map(
+                |((db_room, members), (last_read_message, unread_count))| ServersideRoom {
                     uuid: Some(db_room.uuid.into()),
+                    message_ttl_seconds: ttl_to_seconds(db_room.message_ttl()),
                     name: db_room.name,
-                    members,
-                }
-            })
+                    members: members.into_iter().map(Into::into).collect(),
+                    unread_count,
+                    last_read_message: last_read_message.map(Into::into),
+                },
+            )
             .collect();
 
-        let serverside_rooms = futures::future::join_all(serverside_rooms_future).await;
-
         tracing::info!(message = "Sending a list of rooms", user = ?originator, count = %serverside_rooms.len());
 
         Ok(Response::new(RoomList {
@@ -218,22 +327,31 @@ impl proto::chat_server::Chat for Chat {
             ));
         }
 
-        let mut db = self.acquire_database_connection().await?;
-
-        use crate::entities::schema::messages::dsl::*;
-        use diesel::prelude::*;
-
-        let room_messages: Vec<Message> = messages
-            .filter(room_uuid.eq(requested_room_uuid))
-            .load::<Message>(&mut db)
+        // Expired messages might not have been swept yet.
+        let room_messages: Vec<Message> = self
+            .repositories
+            .messages
+            .list(requested_room_uuid, SystemTime::now())
+            .await
             .map_err(|error| {
                 let msg = "Couldn't fetch messages from database";
                 tracing::error!(message = msg, ?error);
                 Status::internal(msg)
             })?;
 
-        let serverside_messages: Vec<ServersideMessage> =
-            room_messages.into_iter().map(|m| m.into()).collect();
+        let serverside_messages: Vec<ServersideMessage> = self
+            .repositories
+            .messages
+            .hydrate(room_messages)
+            .await
+            .map_err(|error| {
+                let msg = "Couldn't fetch attachments from database";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?
+            .into_iter()
+            .map(Into::into)
+            .collect();
 
         tracing::info!(message = "Sending a list of messages", user = ?originator_uuid, count = %serverside_messages.len());
 
@@ -242,12 +360,122 @@ impl proto::chat_server::Chat for Chat {
         }))
     }
 
+    #[instrument(skip_all)]
+    async fn search_messages(
+        &self,
+        request: Request<MessageSearchRequest>,
+    ) -> Result<Response<MessageSearchResults>, Status> {
+        let originator_uuid = request
+            .get_originator_uuid()
+            .expect("The authenticator should not let anonymous requests through");
+
+        let search = MessageSearch::try_from(request.into_inner())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+
+        // Searching a room one is not a member of is an error rather than an empty result.
+        if let Some(searched_room_uuid) = search.room_uuid {
+            if !self
+                .check_room_membership(&originator_uuid, &searched_room_uuid)
+                .await?
+            {
+                tracing::warn!(
+                    message = "User tried to search a room he's not a member of",
+                    user = ?originator_uuid,
+                    room = ?searched_room_uuid
+                );
+                return Err(Status::permission_denied(
+                    "You are not a member of this room",
+                ));
+            }
+        }
+
+        let found = self
+            .repositories
+            .messages
+            .search(originator_uuid, search, SystemTime::now())
+            .await
+            .map_err(|error| {
+                let msg = "Couldn't search messages in database";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?;
+
+        tracing::info!(message = "Sending search results", user = ?originator_uuid, count = %found.len());
+
+        let (found_messages, ranks_and_snippets): (Vec<Message>, Vec<(f32, String)>) = found
+            .into_iter()
+            .map(|(message, rank, snippet)| (message, (rank, snippet)))
+            .unzip();
+        let hydrated_messages = self
+            .repositories
+            .messages
+            .hydrate(found_messages)
+            .await
+            .map_err(|error| {
+                let msg = "Couldn't fetch attachments from database";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?;
+
+        let hits = hydrated_messages
+            .into_iter()
+            .zip(ranks_and_snippets)
+            .map(|(message, (rank, snippet))| MessageSearchHit {
+                message: Some(message.into()),
+                rank,
+                snippet,
+            })
+            .collect();
+
+        Ok(Response::new(MessageSearchResults { hits }))
+    }
+
     #[instrument(skip_all)]
     async fn send_message(
         &self,
         request: Request<ClientsideMessage>,
-    ) -> Result<Response<()>, Status> {
+    ) -> Result<Response<proto::Uuid>, Status> {
+        let attachment_uuids: Vec<Uuid> = request
+            .get_ref()
+            .attachment_uuids
+            .iter()
+            .cloned()
+            .map(Uuid::try_from)
+            .unique()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| Status::invalid_argument("Some attachment UUIDs are invalid"))?;
+
+        if attachment_uuids.len() > Self::MAX_ATTACHMENTS_PER_MESSAGE {
+            return Err(Status::invalid_argument(format!(
+                "Can't attach more than {} files to a message",
+                Self::MAX_ATTACHMENTS_PER_MESSAGE
+            )));
+        }
+
+        let deliver_at: Option<SystemTime> = request
+            .get_ref()
+            .deliver_at
+            .clone()
+            .map(SystemTime::try_from)
+            .transpose()
+            .map_err(|_| Status::invalid_argument("Invalid delivery time"))?;
+
+        let requested_ttl = ttl_from_seconds(request.get_ref().ttl_seconds);
+        if requested_ttl.is_some_and(|ttl| ttl > Message::MAX_TTL) {
+            return Err(Status::invalid_argument("The TTL is too long"));
+        }
+
+        let idempotency_key = request.get_ref().idempotency_key.clone();
+        if idempotency_key.chars().count() > IdempotencyKey::MAX_LENGTH {
+            return Err(Status::invalid_argument(format!(
+                "The idempotency key can't be longer than {} characters",
+                IdempotencyKey::MAX_LENGTH
+            )));
+        }
+
         let message = Message::try_from(request)?;
+        let idempotency_key = (!idempotency_key.is_empty())
+            .then(|| IdempotencyKey::new(message.sender_uuid, idempotency_key, message.uuid));
 
         // Ensure the user isn't sending a message to a room he's not a member of.
         if !self
@@ -266,574 +494,3062 @@ impl proto::chat_server::Chat for Chat {
 
         tracing::info!(message = "Received new message", sender = ?message.sender_uuid, room = ?message.room_uuid);
 
-        // Store the message in the database and mirror it to all receivers.
-        {
-            use crate::entities::schema::messages::dsl::*;
-            use diesel::prelude::*;
+        // Ensure all attachments were uploaded to the room the message is sent to.
+        let message_attachments = self
+            .repositories
+            .attachments
+            .find_in_room(message.room_uuid, attachment_uuids.clone())
+            .await
+            .map_err(|error| {
+                let msg = "Couldn't fetch attachments from database";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?;
+
+        if message_attachments.len() != attachment_uuids.len() {
+            return Err(Status::not_found(
+                "Some attachments were not uploaded to this room",
+            ));
+        }
+
+        // Messages without a TTL of their own get the room's default one.
+        let ttl = match requested_ttl {
+            Some(ttl) => Some(ttl),
+            None => self
+                .repositories
+                .rooms
+                .find(message.room_uuid)
+                .await
+                .map_err(|error| {
+                    let msg = "Couldn't fetch the room from database";
+                    tracing::error!(message = msg, ?error);
+                    Status::internal(msg)
+                })?
+                .and_then(|room| room.message_ttl()),
+        };
+
+        // Hold the message back if it's meant to be delivered later.
+        if let Some(deliver_at) = deliver_at.filter(|at| *at > SystemTime::now()) {
+            if deliver_at > SystemTime::now() + ScheduledMessage::MAX_DELAY {
+                return Err(Status::invalid_argument(
+                    "The delivery time is too far in the future",
+                ));
+            }
 
-            let mut conn = self.acquire_database_connection().await?;
-            let _ = diesel::insert_into(messages)
-                .values(&message)
-                .execute(&mut conn)
+            let scheduled_message =
+                ScheduledMessage::new(message, attachment_uuids, deliver_at, ttl);
+            let scheduled = self
+                .repositories
+                .scheduled_messages
+                .schedule(scheduled_message, idempotency_key)
+                .await
                 .map_err(|error| {
-                    tracing::error!(message = "Could not store message!", ?error);
-                    Status::internal("Could not send the message due to an internal error")
+                    tracing::error!(message = "Could not store scheduled message!", ?error);
+                    Status::internal("Could not schedule the message due to an internal error")
                 })?;
 
-            match self.message_tx.send(message) {
-                Ok(recv_count) => tracing::trace!(message = "Broadcasting room event", ?recv_count),
-                Err(error) => {
-                    if self.message_tx.receiver_count() > 0 {
-                        tracing::error!(message = "Could not broadcast room event", ?error);
-                    } else {
-                        tracing::trace!(message = "No subscribers for room event");
-                    }
+            return match scheduled {
+                None => Err(Status::resource_exhausted(format!(
+                    "Can't have more than {} scheduled messages",
+                    ScheduledMessage::MAX_PENDING_PER_USER
+                ))),
+                Some(Deduplicated::Stored(scheduled_message)) => {
+                    tracing::debug!(message = "Scheduled a message", uuid = ?scheduled_message.uuid, ?deliver_at);
+                    self.scheduler_wakeup.notify_one();
+                    Ok(Response::new(scheduled_message.uuid.into()))
                 }
-            }
+                Some(Deduplicated::Duplicate(duplicate_of)) => {
+                    tracing::debug!(message = "Deduplicated a retried message", original = ?duplicate_of);
+                    Ok(Response::new(duplicate_of.into()))
+                }
+            };
         }
 
-        Ok(Response::new(()))
+        // Store the message in the database, unless it's a
+        // retry of a message that has already been stored.
+        let stored = self
+            .repositories
+            .messages
+            .store(message.with_ttl(ttl), attachment_uuids, idempotency_key)
+            .await
+            .map_err(|error| {
+                tracing::error!(message = "Could not store message!", ?error);
+                Status::internal("Could not send the message due to an internal error")
+            })?;
+
+        // Mirror stored messages to all receivers.
+        match stored {
+            Deduplicated::Stored((message, message_mentions)) => {
+                let message_uuid = message.uuid;
+                self.broadcast_new_message(message, message_attachments, message_mentions);
+                Ok(Response::new(message_uuid.into()))
+            }
+            Deduplicated::Duplicate(duplicate_of) => {
+                tracing::debug!(message = "Deduplicated a retried message", original = ?duplicate_of);
+                Ok(Response::new(duplicate_of.into()))
+            }
+        }
     }
 
     #[instrument(skip_all)]
-    async fn create_room(
+    async fn list_scheduled_messages(
         &self,
-        request: Request<ClientsideRoom>,
-    ) -> Result<Response<proto::Uuid>, Status> {
-        let room = request.into_inner();
-        let room_uuid = self.create_room(room).await?;
+        request: Request<()>,
+    ) -> Result<Response<ScheduledMessageList>, Status> {
+        let originator_uuid = request
+            .get_originator_uuid()
+            .expect("The authenticator should not let anonymous requests through");
 
-        Ok(Response::new(room_uuid.into()))
+        let pending_messages: Vec<proto::ScheduledMessage> = self
+            .repositories
+            .scheduled_messages
+            .list_pending(originator_uuid)
+            .await
+            .map_err(|error| {
+                let msg = "Couldn't fetch scheduled messages from database";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?
+            .into_iter()
+            .map(Into::into)
+            .collect();
+
+        Ok(Response::new(ScheduledMessageList {
+            messages: pending_messages,
+        }))
     }
 
     #[instrument(skip_all)]
-    async fn create_room_with_user(
+    async fn cancel_scheduled_message(
         &self,
-        request: Request<RoomWithUserCreationRequest>,
-    ) -> Result<Response<proto::Uuid>, Status> {
+        request: Request<proto::Uuid>,
+    ) -> Result<Response<()>, Status> {
         let originator_uuid = request
             .get_originator_uuid()
-            .map_err(|err| Status::internal(err.to_string()))?;
+            .expect("The authenticator should not let anonymous requests through");
 
-        let possible_interlocutor_uuid = request
+        let requested_message_uuid: Uuid = request
             .into_inner()
-            .user_uuid
-            .and_then(|u| Uuid::try_from(u).ok())
-            .ok_or(Status::invalid_argument("Invalid interlocutor UUID"))?;
-
-        let mut db = self.acquire_database_connection().await?;
-
-        // Import some traits and methods to interact with the ORM.
-        use crate::entities::schema::users::dsl::*;
-        use diesel::prelude::*;
-
-        let interlocutor = users
-            .find(possible_interlocutor_uuid)
-            .select(User::as_select())
-            .first(&mut db)
-            .optional()
-            .map_err(|err| Status::internal(err.to_string()))?
-            .ok_or(Status::internal("No such user"))?;
+            .try_into()
+            .map_err(|_| Status::invalid_argument("Invalid message UUID"))?;
 
-        let originator = users
-            .find(originator_uuid)
-            .select(User::as_select())
-            .first(&mut db)
-            .optional()
-            .map_err(|err| Status::internal(err.to_string()))?
-            .ok_or(Status::internal("No such user"))?;
+        // Other users' messages are indistinguishable from missing ones.
+        let cancelled = self
+            .repositories
+            .scheduled_messages
+            .cancel(originator_uuid, requested_message_uuid)
+            .await
+            .map_err(|error| {
+                let msg = "Could not cancel the scheduled message";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?;
 
-        let room_name = format!(
-            "Private chat between {} and {}",
-            originator.username, interlocutor.username
-        );
-        let private_room_uuid = self
-            .create_room(ClientsideRoom {
-                name: room_name,
-                members: vec![interlocutor.uuid.into(), originator_uuid.into()],
-            })
-            .await?;
+        if !cancelled {
+            return Err(Status::not_found("No such scheduled message"));
+        }
 
-        Ok(Response::new(private_room_uuid.into()))
+        tracing::info!(message = "Cancelled a scheduled message", user = ?originator_uuid, uuid = ?requested_message_uuid);
+        Ok(Response::new(()))
     }
 
-    type SubscribeToRoomStream = DisconnectChannel<Result<ServersideRoomEvent, Status>>;
-
     #[instrument(skip_all)]
-    async fn subscribe_to_room(
-        &self,
-        request: Request<proto::Uuid>,
-    ) -> Result<Response<Self::SubscribeToRoomStream>, Status> {
-        let subscriber: Uuid = request
+    async fn mark_read(&self, request: Request<ReadMarkerRequest>) -> Result<Response<()>, Status> {
+        let reader_uuid = request
             .get_originator_uuid()
             .expect("The authenticator should not let anonymous requests through");
 
-        let subscribed_room: Uuid = request.into_inner().try_into().map_err(|error| {
-            let msg = "The room UUID is invalid";
-            tracing::trace!(message = msg, ?error);
-            Status::invalid_argument(msg)
-        })?;
-
-        // Ensure the user is a member of the room he's subscribing to.
+        let request = request.into_inner();
+        let requested_room_uuid: Uuid = request
+            .room_uuid
+            .and_then(|u| u.try_into().ok())
+            .ok_or(Status::invalid_argument("Invalid room UUID"))?;
+        let requested_message_uuid: Uuid = request
+            .message_uuid
+            .and_then(|u| u.try_into().ok())
+            .ok_or(Status::invalid_argument("Invalid message UUID"))?;
+
+        // Ensure the user is a member of the room he's reading messages in.
         if !self
-            .check_room_membership(&subscriber, &subscribed_room)
+            .check_room_membership(&reader_uuid, &requested_room_uuid)
             .await?
         {
             tracing::warn!(
-                message = "User tried to subscribe to a room he's not a member of",
-                ?subscriber,
-                room = ?subscribed_room
+                message = "User tried to mark messages as read in a room he's not a member of",
+                user = ?reader_uuid,
+                room = ?requested_room_uuid
             );
             return Err(Status::permission_denied(
                 "You are not a member of this room",
             ));
         }
 
-        // The 'streamer' thread (see below) needs a cache connection.
-        let mut cache = self.acquire_cache_connection().await?;
+        let read_message: Message = self
+            .repositories
+            .messages
+            .find_in_room(requested_room_uuid, requested_message_uuid)
+            .await
+            .map_err(|error| {
+                let msg = "Couldn't fetch the message from database";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?
+            .ok_or(Status::not_found("No such message in this room"))?;
+
+        // The read marker only moves forward, so it's left alone if it's already past this message.
+        let marker = ReadMarker::new(reader_uuid, &read_message);
+        let moved = self
+            .repositories
+            .messages
+            .move_read_marker(marker.clone())
+            .await
+            .map_err(|error| {
+                let msg = "Could not store the read marker";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?;
 
-        // NOTE: Read this.
-        //
-        // There are a total of 3 channels involved in this whole streaming thing:
-        // - An internal `broadcast` channel that transfers messages from `SendMessage` RPC calls;
-        // - A `DisconnectChannel`, which holds another 2 channels inside:
-        //   - A `mpsc` Tokio channel, which performs gRPC streaming;
-        //   - A `oneshot` Tokio channel, which fires when the client disconnects.
+        if !moved {
+            tracing::trace!(message = "Read marker is already past this message", user = ?reader_uuid);
+            return Ok(Response::new(()));
+        }
 
-        let (grpc_tx, grpc_rx) = mpsc::channel(4);
-        let (disconnect_tx, disconnect_rx) = oneshot::channel();
-        let disconnect_channel = channel::DisconnectChannel {
-            disconnect_tx: Some(disconnect_tx),
-            grpc_rx,
-        };
+        tracing::debug!(message = "Moved read marker", user = ?reader_uuid, room = ?requested_room_uuid);
 
-        let mut message_rx = self.message_tx.subscribe();
-        tracing::info!(message = "New room subscriber", room = ?subscribed_room);
+        use proto::serverside_room_event::Event;
+        self.broadcast_room_event(ServersideRoomEvent {
+            room_uuid: Some(requested_room_uuid.into()),
+            event: Some(Event::ReadReceipt(marker.into())),
+        });
 
-        // The logic for the streaming thread, extracted into a variable to help rustfmt.
-        let streaming_closure = async move {
-            while let Ok(msg) = message_rx.recv().await {
-                let message_room = msg.room_uuid;
-                let subscriber_rooms: Vec<Uuid> = cache
-                    .lrange(subscriber, 0, -1)
-                    .await
-                    .unwrap_or_else(|error| {
-                        tracing::error!(
-                            message = "Could not retrieve membership from cache",
-                            ?subscriber,
-                            ?error
-                        );
-                        vec![]
-                    });
+        Ok(Response::new(()))
+    }
 
-                // Check that the user is a member of the room and that he's subscribed to the rooms the message is from.
-                if subscriber_rooms.contains(&message_room) && subscribed_room == message_room {
-                    use proto::serverside_room_event::Event;
-                    let new_message = Event::NewMessage(msg.into());
-                    let event = ServersideRoomEvent {
-                        room_uuid: Some(subscribed_room.into()),
-                        event: Some(new_message),
-                    };
+    #[instrument(skip_all)]
+    async fn pin_message(&self, request: Request<PinRequest>) -> Result<Response<()>, Status> {
+        let pinner_uuid = request
+            .get_originator_uuid()
+            .expect("The authenticator should not let anonymous requests through");
 
-                    let send_result = grpc_tx.send(Ok(event)).await;
-                    if send_result.is_err() {
-                        tracing::warn!(
-                            message = "A message was sent, but nobody is subscribed to the channel"
-                        )
-                    }
-                }
+        let request = request.into_inner();
+        let requested_room_uuid: Uuid = request
+            .room_uuid
+            .and_then(|u| u.try_into().ok())
+            .ok_or(Status::invalid_argument("Invalid room UUID"))?;
+        let requested_message_uuid: Uuid = request
+            .message_uuid
+            .and_then(|u| u.try_into().ok())
+            .ok_or(Status::invalid_argument("Invalid message UUID"))?;
+
+        // Ensure the user is a member of the room he's pinning messages in.
+        if !self
+            .check_room_membership(&pinner_uuid, &requested_room_uuid)
+            .await?
+        {
+            tracing::warn!(
+                message = "User tried to pin a message in a room he's not a member of",
+                user = ?pinner_uuid,
+                room = ?requested_room_uuid
+            );
+            return Err(Status::permission_denied(
+                "You are not a member of this room",
+            ));
+        }
+
+        let pinned_message: Message = self
+            .repositories
+            .messages
+            .find_in_room(requested_room_uuid, requested_message_uuid)
+            .await
+            .map_err(|error| {
+                let msg = "Couldn't fetch the message from database";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?
+            .ok_or(Status::not_found("No such message in this room"))?;
+
+        let pin = MessagePin::new(pinner_uuid, &pinned_message);
+        let outcome = self
+            .repositories
+            .pins
+            .pin(pin.clone())
+            .await
+            .map_err(|error| {
+                let msg = "Could not store the pin";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?;
+
+        match outcome {
+            PinOutcome::Pinned => {}
+            // Pinning an already pinned message changes nothing, so nothing is announced then.
+            PinOutcome::AlreadyPinned => {
+                tracing::trace!(message = "Message is already pinned", message_uuid = ?requested_message_uuid);
+                return Ok(Response::new(()));
             }
-        };
+            PinOutcome::LimitReached => {
+                return Err(Status::resource_exhausted(format!(
+                    "Can't pin more than {} messages in a room",
+                    MessagePin::MAX_PINS_PER_ROOM
+                )));
+            }
+        }
 
-        // The 'canceller' thread will cancel this token when the client disconnects.
-        let token = CancellationToken::new();
-        let token_clone = token.clone();
+        let hydrated_message = self
+            .repositories
+            .messages
+            .hydrate(vec![pinned_message])
+            .await
+            .map_err(|error| {
+                let msg = "Couldn't fetch attachments from database";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?
+            .pop()
+            .expect("A message should be hydrated into exactly one hydrated message");
 
-        // This is the 'canceller' thread.
-        //
-        // This task will cancel the token when the client disconnects, which will shutdown
-        // the streaming thread (see below) and cause the broadcast::Receiver to drop.
-        tokio::spawn(async move {
-            let _ = disconnect_rx.await;
-            tracing::debug!(message = "Client disconnected, stopping message streaming");
-            token.cancel();
+        tracing::info!(message = "Pinned a message", user = ?pinner_uuid, room = ?requested_room_uuid);
+
+        use proto::serverside_room_event::Event;
+        self.broadcast_room_event(ServersideRoomEvent {
+            room_uuid: Some(requested_room_uuid.into()),
+            event: Some(Event::MessagePinned(
+                pin.into_pinned_message(hydrated_message.into()),
+            )),
         });
 
-        // This is the 'streamer' thread.
-        //
-        // This thread will receive all messages sent via the `SendMessage` RPC call, and
-        // mirror them to all subsribers. Without a canceller thread, a cancellation token
-        // and a hacky DisconnectChannel, this thread would never terminate, meaning there
-        // would soon be a thousand of hanging broadcast::Receivers with no real client.
-        tokio::spawn(async move {
-            tokio::select! {
-                _ = token_clone.cancelled() => {}
-                _ = streaming_closure => {}
+        Ok(Response::new(()))
+    }
+
+    #[instrument(skip_all)]
+    async fn unpin_message(&self, request: Request<PinRequest>) -> Result<Response<()>, Status> {
+        let unpinner_uuid = request
+            .get_originator_uuid()
+            .expect("The authenticator should not let anonymous requests through");
+
+        let request = request.into_inner();
+        let requested_room_uuid: Uuid = request
+            .room_uuid
+            .and_then(|u| u.try_into().ok())
+            .ok_or(Status::invalid_argument("Invalid room UUID"))?;
+        let requested_message_uuid: Uuid = request
+            .message_uuid
+            .and_then(|u| u.try_into().ok())
+            .ok_or(Status::invalid_argument("Invalid message UUID"))?;
+
+        // Ensure the user is a member of the room he's unpinning messages in.
+        if !self
+            .check_room_membership(&unpinner_uuid, &requested_room_uuid)
+            .await?
+        {
+            tracing::warn!(
+                message = "User tried to unpin a message in a room he's not a member of",
+                user = ?unpinner_uuid,
+                room = ?requested_room_uuid
+            );
+            return Err(Status::permission_denied(
+                "You are not a member of this room",
+            ));
+        }
+
+        let outcome = self
+            .repositories
+            .pins
+            .unpin(requested_room_uuid, requested_message_uuid, unpinner_uuid)
+            .await
+            .map_err(|error| {
+                let msg = "Could not remove the pin";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?;
+
+        match outcome {
+            UnpinOutcome::Unpinned => {}
+            UnpinOutcome::NotPinned => {
+                return Err(Status::not_found("This message is not pinned in this room"));
             }
+            UnpinOutcome::NotAllowed => {
+                return Err(Status::permission_denied(
+                    "Only the member who pinned this message and its author can unpin it",
+                ));
+            }
+        }
+
+        tracing::info!(message = "Unpinned a message", user = ?unpinner_uuid, room = ?requested_room_uuid);
+
+        use proto::serverside_room_event::Event;
+        self.broadcast_room_event(ServersideRoomEvent {
+            room_uuid: Some(requested_room_uuid.into()),
+            event: Some(Event::MessageUnpinned(MessageUnpinned {
+                user_uuid: Some(unpinner_uuid.into()),
+                message_uuid: Some(requested_message_uuid.into()),
+            })),
         });
 
-        Ok(Response::new(disconnect_channel))
+        Ok(Response::new(()))
     }
 
-    type SubscribeToUserStream = DisconnectChannel<Result<ServersideUserEvent, Status>>;
+    #[instrument(skip_all)]
+    async fn list_pinned_messages(
+        &self,
+        request: Request<proto::Uuid>,
+    ) -> Result<Response<PinnedMessageList>, Status> {
+        let originator_uuid = request
+            .get_originator_uuid()
+            .expect("The authenticator should not let anonymous requests through");
+
+        let requested_room_uuid: Uuid = request
+            .into_inner()
+            .try_into()
+            .map_err(|_| Status::invalid_argument("Invalid room UUID"))?;
+
+        // Ensure the user is a member of the room he's fetching pins from.
+        if !self
+            .check_room_membership(&originator_uuid, &requested_room_uuid)
+            .await?
+        {
+            tracing::warn!(
+                message = "User tried to fetch pins from a room he's not a member of",
+                user = ?originator_uuid,
+                room = ?requested_room_uuid
+            );
+            return Err(Status::permission_denied(
+                "You are not a member of this room",
+            ));
+        }
+
+        let (room_pins, pinned_messages): (Vec<MessagePin>, Vec<Message>) = self
+            .repositories
+            .pins
+            .list(requested_room_uuid)
+            .await
+            .map_err(|error| {
+                let msg = "Couldn't fetch pins from database";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?
+            .into_iter()
+            .unzip();
+
+        let hydrated_messages = self
+            .repositories
+            .messages
+            .hydrate(pinned_messages)
+            .await
+            .map_err(|error| {
+                let msg = "Couldn't fetch attachments from database";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?;
+
+        let pinned_messages = room_pins
+            .into_iter()
+            .zip(hydrated_messages)
+            .map(|(pin, message)| pin.into_pinned_message(message.into()))
+            .collect();
+
+        Ok(Response::new(PinnedMessageList {
+            pins: pinned_messages,
+        }))
+    }
 
     #[instrument(skip_all)]
-    async fn subscribe_to_user(
+    async fn set_typing(&self, request: Request<TypingRequest>) -> Result<Response<()>, Status> {
+        let typist_uuid = request
+            .get_originator_uuid()
+            .expect("The authenticator should not let anonymous requests through");
+
+        let request = request.into_inner();
+        let typing_room_uuid: Uuid = request
+            .room_uuid
+            .and_then(|u| u.try_into().ok())
+            .ok_or(Status::invalid_argument("Invalid room UUID"))?;
+
+        // Ensure the user isn't typing in a room he's not a member of.
+        if !self
+            .check_room_membership(&typist_uuid, &typing_room_uuid)
+            .await?
+        {
+            tracing::warn!(
+                message = "User tried to type in a room he's not a member of",
+                user = ?typist_uuid,
+                room = ?typing_room_uuid
+            );
+            return Err(Status::permission_denied(
+                "You're not a member of this room",
+            ));
+        }
+
+        let (transition, watcher) = self.typing_tracker.update(
+            typing_room_uuid,
+            typist_uuid,
+            request.typing,
+            Instant::now(),
+        );
+
+        if let Transition::Announce(typing) = transition {
+            self.broadcast_room_event(typing_event(typing_room_uuid, typist_uuid, typing));
+        }
+
+        // This is the 'watcher' thread.
+        //
+        // It expires the typing indicator after a few seconds of silence and announces
+        // changes that were held back by the rate limiter. There's exactly one watcher
+        // per user per room, and it terminates once the user stops typing.
+        if let Some(postponed) = watcher {
+            let chat = self.clone();
+            tokio::spawn(async move {
+                loop {
+                    match chat
+                        .typing_tracker
+                        .tick(typing_room_uuid, typist_uuid, Instant::now())
+                    {
+                        Tick::Announce(typing) => {
+                            chat.broadcast_room_event(typing_event(
+                                typing_room_uuid,
+                                typist_uuid,
+                                typing,
+                            ));
+                        }
+                        Tick::WakeAt(instant) => {
+                            tokio::select! {
+                                () = tokio::time::sleep_until(instant) => {}
+                                () = postponed.notified() => {}
+                            }
+                        }
+                        Tick::Finished => break,
+                    }
+                }
+            });
+        }
+
+        Ok(Response::new(()))
+    }
+
+    #[instrument(skip_all)]
+    async fn upload_attachment(
         &self,
-        request: Request<()>,
-    ) -> Result<Response<Self::SubscribeToUserStream>, Status> {
-        let user_uuid: Uuid = request
+        request: Request<Streaming<AttachmentChunk>>,
+    ) -> Result<Response<proto::Attachment>, Status> {
+        let uploader_uuid = request
             .get_originator_uuid()
             .expect("The authenticator should not let anonymous requests through");
 
-        let (grpc_tx, grpc_rx) = mpsc::channel(4);
-        let (disconnect_tx, disconnect_rx) = oneshot::channel();
-        let disconnect_channel = DisconnectChannel {
-            disconnect_tx: Some(disconnect_tx),
-            grpc_rx,
+        let mut chunks = request.into_inner();
+        let metadata: AttachmentMetadata = match chunks.message().await? {
+            Some(AttachmentChunk {
+                content: Some(Content::Metadata(metadata)),
+            }) => metadata,
+            _ => {
+                return Err(Status::invalid_argument(
+                    "The first chunk must carry metadata",
+                ))
+            }
         };
 
-        let mut user_event_rx = self.user_event_tx.subscribe();
-        let streaming_closure = async move {
-            while let Ok(event) = user_event_rx.recv().await {
-                if event
-                    .user_uuid
-                    .clone()
-                    .is_some_and(|event_user_uuid| user_uuid == event_user_uuid)
-                {
-                    let send_result = grpc_tx.send(Ok(event)).await;
-                    if send_result.is_err() {
-                        tracing::trace!(message = "A user event occurred, but nobody is subscribed")
+        let upload_room_uuid: Uuid = metadata
+            .room_uuid
+            .and_then(|u| u.try_into().ok())
+            .ok_or(Status::invalid_argument("Invalid room UUID"))?;
+
+        if metadata.filename.is_empty()
+            || metadata.filename.chars().count() > Attachment::MAX_FILENAME_LENGTH
+        {
+            return Err(Status::invalid_argument("Invalid filename"));
+        }
+
+        // Ensure the user isn't uploading to a room he's not a member of.
+        if !self
+            .check_room_membership(&uploader_uuid, &upload_room_uuid)
+            .await?
+        {
+            tracing::warn!(
+                message = "User tried to upload to a room he's not a member of",
+                user = ?uploader_uuid,
+                room = ?upload_room_uuid
+            );
+            return Err(Status::permission_denied(
+                "You're not a member of this room",
+            ));
+        }
+
+        // Hash and store the contents as they arrive, keeping only the head
+        // around for MIME type sniffing. Bailing out drops the writer, which
+        // discards whatever was written.
+        let mut writer = self.blob_store.writer().await.map_err(|error| {
+            let msg = "Could not store the attachment";
+            tracing::error!(message = msg, ?error);
+            Status::internal(msg)
+        })?;
+        let mut head: Vec<u8> = vec![];
+        let mut size: usize = 0;
+        while let Some(chunk) = chunks.message().await? {
+            match chunk.content {
+                Some(Content::Data(data)) => {
+                    size += data.len();
+                    if size > storage::MAX_BLOB_SIZE {
+                        tracing::warn!(message = "Attachment is too large", user = ?uploader_uuid);
+                        return Err(Status::resource_exhausted("The attachment is too large"));
                     }
+                    let missing = storage::SNIFF_LENGTH.saturating_sub(head.len());
+                    head.extend_from_slice(&data[..missing.min(data.len())]);
+                    writer.write(&data).await.map_err(|error| {
+                        let msg = "Could not store the attachment";
+                        tracing::error!(message = msg, ?error);
+                        Status::internal(msg)
+                    })?;
                 }
+                _ => {
+                    return Err(Status::invalid_argument(
+                        "Only the first chunk may carry metadata",
+                    ))
+                }
+            }
+        }
+
+        if size == 0 {
+            return Err(Status::invalid_argument("The attachment is empty"));
+        }
+
+        let hash = writer.finish().await.map_err(|error| {
+            let msg = "Could not store the attachment";
+            tracing::error!(message = msg, ?error);
+            Status::internal(msg)
+        })?;
+
+        let declared_mime_type = Some(metadata.mime_type.as_str());
+        let attachment = Attachment::new(
+            upload_room_uuid,
+            uploader_uuid,
+            metadata.filename,
+            storage::sniff_mime_type(&head, declared_mime_type),
+            hash,
+            size,
+        );
+
+        self.repositories
+            .attachments
+            .create(attachment.clone())
+            .await
+            .map_err(|error| {
+                let msg = "Could not save the attachment in the database";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?;
+
+        tracing::info!(
+            message = "Uploaded new attachment",
+            uploader = ?uploader_uuid,
+            room = ?upload_room_uuid,
+            size = %attachment.size,
+            mime_type = %attachment.mime_type
+        );
+
+        Ok(Response::new(attachment.into()))
+    }
+
+    type DownloadAttachmentStream =
+        Pin<Box<dyn Stream<Item = Result<AttachmentChunk, Status>> + Send>>;
+
+    #[instrument(skip_all)]
+    async fn download_attachment(
+        &self,
+        request: Request<proto::Uuid>,
+    ) -> Result<Response<Self::DownloadAttachmentStream>, Status> {
+        let downloader_uuid = request
+            .get_originator_uuid()
+            .expect("The authenticator should not let anonymous requests through");
+
+        let requested_attachment_uuid: Uuid = request
+            .into_inner()
+            .try_into()
+            .map_err(|_| Status::invalid_argument("Invalid attachment UUID"))?;
+
+        let attachment: Attachment = self
+            .repositories
+            .attachments
+            .find(requested_attachment_uuid)
+            .await
+            .map_err(|error| {
+                let msg = "Couldn't fetch the attachment from database";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?
+            .ok_or(Status::not_found("No such attachment"))?;
+
+        // Ensure the user is a member of the room the file was uploaded to.
+        if !self
+            .check_room_membership(&downloader_uuid, &attachment.room_uuid)
+            .await?
+        {
+            tracing::warn!(
+                message = "User tried to download an attachment from a room he's not a member of",
+                user = ?downloader_uuid,
+                room = ?attachment.room_uuid
+            );
+            return Err(Status::permission_denied(
+                "You are not a member of this room",
+            ));
+        }
+
+        let hash = attachment.blake3_hash().map_err(|error| {
+            let msg = "The attachment has a malformed hash";
+            tracing::error!(message = msg, ?error);
+            Status::internal(msg)
+        })?;
+
+        let reader = self.blob_store.reader(&hash).await.map_err(|error| {
+            let msg = "Could not read the attachment";
+            tracing::error!(message = msg, ?error);
+            match error {
+                BlobStoreError::NotFound => Status::data_loss(msg),
+                BlobStoreError::Io(_) => Status::internal(msg),
             }
+        })?;
+
+        tracing::info!(message = "Sending an attachment", user = ?downloader_uuid, size = %attachment.size);
+
+        let metadata = AttachmentChunk {
+            content: Some(Content::Metadata(AttachmentMetadata {
+                room_uuid: Some(attachment.room_uuid.into()),
+                filename: attachment.filename,
+                mime_type: attachment.mime_type,
+            })),
+        };
+        let data = ReaderStream::with_capacity(reader, Self::ATTACHMENT_CHUNK_SIZE).map(|data| {
+            data.map(|data| AttachmentChunk {
+                content: Some(Content::Data(data.to_vec())),
+            })
+            .map_err(|error| {
+                let msg = "Could not read the attachment";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })
+        });
+
+        Ok(Response::new(Box::pin(
+            futures::stream::once(async { Ok(metadata) }).chain(data),
+        )))
+    }
+
+    #[instrument(skip_all)]
+    async fn create_room(
+        &self,
+        request: Request<ClientsideRoom>,
+    ) -> Result<Response<proto::Uuid>, Status> {
+        let room = request.into_inner();
+        let room_uuid = self.create_room(room).await?;
+
+        Ok(Response::new(room_uuid.into()))
+    }
+
+    #[instrument(skip_all)]
+    async fn create_room_with_user(
+        &self,
+        request: Request<RoomWithUserCreationRequest>,
+    ) -> Result<Response<proto::Uuid>, Status> {
+        let originator_uuid = request
+            .get_originator_uuid()
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        let possible_interlocutor_uuid = request
+            .into_inner()
+            .user_uuid
+            .and_then(|u| Uuid::try_from(u).ok())
+            .ok_or(Status::invalid_argument("Invalid interlocutor UUID"))?;
+
+        let find_user = |user_uuid: Uuid| async move {
+            self.repositories
+                .users
+                .find(user_uuid)
+                .await
+                .map_err(|err| Status::internal(err.to_string()))?
+                .ok_or(Status::not_found("No such user"))
+        };
+        let interlocutor = find_user(possible_interlocutor_uuid).await?;
+        let originator = find_user(originator_uuid).await?;
+
+        let room_name = format!(
+            "Private chat between {} and {}",
+            originator.username, interlocutor.username
+        );
+        let private_room_uuid = self
+            .create_room(ClientsideRoom {
+                name: room_name,
+                members: vec![interlocutor.uuid.into(), originator_uuid.into()],
+            })
+            .await?;
+
+        Ok(Response::new(private_room_uuid.into()))
+    }
+
+    type SubscribeToRoomStream = DisconnectChannel<Result<ServersideRoomEvent, Status>>;
+
+    #[instrument(skip_all)]
+    async fn subscribe_to_room(
+        &self,
+        request: Request<proto::Uuid>,
+    ) -> Result<Response<Self::SubscribeToRoomStream>, Status> {
+        let subscriber: Uuid = request
+            .get_originator_uuid()
+            .expect("The authenticator should not let anonymous requests through");
+
+        let room_uuid: Uuid = request.into_inner().try_into().map_err(|error| {
+            let msg = "The room UUID is invalid";
+            tracing::trace!(message = msg, ?error);
+            Status::invalid_argument(msg)
+        })?;
+
+        let subscription = RoomSubscription {
+            room_uuid,
+            resume_after: None,
+        };
+        Ok(Response::new(
+            self.subscribe_room(subscriber, subscription).await?,
+        ))
+    }
+
+    type ResumeRoomSubscriptionStream = DisconnectChannel<Result<ServersideRoomEvent, Status>>;
+
+    #[instrument(skip_all)]
+    async fn resume_room_subscription(
+        &self,
+        request: Request<RoomSubscriptionRequest>,
+    ) -> Result<Response<Self::ResumeRoomSubscriptionStream>, Status> {
+        let subscriber: Uuid = request
+            .get_originator_uuid()
+            .expect("The authenticator should not let anonymous requests through");
+
+        let subscription = RoomSubscription::try_from(request.into_inner()).map_err(|error| {
+            tracing::trace!(message = "Invalid subscription request", ?error);
+            Status::invalid_argument(error.to_string())
+        })?;
+
+        Ok(Response::new(
+            self.subscribe_room(subscriber, subscription).await?,
+        ))
+    }
+
+    type SubscribeToUserStream = DisconnectChannel<Result<ServersideUserEvent, Status>>;
+
+    #[instrument(skip_all)]
+    async fn subscribe_to_user(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<Self::SubscribeToUserStream>, Status> {
+        let user_uuid: Uuid = request
+            .get_originator_uuid()
+            .expect("The authenticator should not let anonymous requests through");
+
+        let (grpc_tx, grpc_rx) = mpsc::channel(4);
+        let (disconnect_tx, disconnect_rx) = oneshot::channel();
+        let disconnect_channel = DisconnectChannel {
+            disconnect_tx: Some(disconnect_tx),
+            grpc_rx,
+        };
+
+        let streaming_closure = self.stream_user(user_uuid, grpc_tx, std::convert::identity);
+
+        // Holding this stream is what makes the user appear online.
+        self.connect_presence(user_uuid).await;
+
+        let token = CancellationToken::new();
+        let token_clone = token.clone();
+        let chat = self.clone();
+
+        // Spawn the "canceller" thread.
+        //
+        // Apart from stopping the streamer, it also takes the user offline once his last client is gone.
+        tokio::spawn(async move {
+            let _ = disconnect_rx.await;
+            tracing::debug!(message = "Client disconnected, stopping user event streaming");
+            token.cancel();
+            chat.disconnect_presence(user_uuid).await;
+        });
+
+        // Spawn the "streamer" thread.
+        spawn_streamer(token_clone, streaming_closure);
+
+        Ok(Response::new(disconnect_channel))
+    }
+
+    type ConnectStream = DisconnectChannel<Result<SessionEvent, Status>>;
+
+    #[instrument(skip_all)]
+    async fn connect(
+        &self,
+        request: Request<Streaming<SessionCommand>>,
+    ) -> Result<Response<Self::ConnectStream>, Status> {
+        let user_uuid: Uuid = request
+            .get_originator_uuid()
+            .expect("The authenticator should not let anonymous requests through");
+
+        // Some commands are handled by the regular RPC handlers, which need the auth metadata.
+        let metadata = request.metadata().clone();
+        let session = self
+            .session(user_uuid, metadata, request.into_inner())
+            .await;
+
+        tracing::info!(message = "New session", user = ?user_uuid);
+        Ok(Response::new(session))
+    }
+
+    #[instrument(skip_all)]
+    async fn set_presence(
+        &self,
+        request: Request<PresenceUpdateRequest>,
+    ) -> Result<Response<()>, Status> {
+        let user_uuid = request
+            .get_originator_uuid()
+            .expect("The authenticator should not let anonymous requests through");
+
+        let request = request.into_inner();
+        let status = match request.status() {
+            PresenceStatus::Offline => {
+                return Err(Status::invalid_argument(
+                    "The offline status can't be set explicitly",
+                ))
+            }
+            status => status,
+        };
+
+        if request.status_text.chars().count() > Self::MAX_STATUS_TEXT_LENGTH {
+            return Err(Status::invalid_argument("The status text is too long"));
+        }
+
+        tracing::debug!(message = "User changed presence", user = ?user_uuid, ?status);
+
+        let visible_change =
+            self.presence_tracker
+                .set_status(user_uuid, status, request.status_text);
+        if let Some(presence) = visible_change {
+            self.announce_presence(presence).await;
+        }
+
+        Ok(Response::new(()))
+    }
+
+    #[instrument(skip_all)]
+    async fn get_presence(
+        &self,
+        request: Request<PresenceRequest>,
+    ) -> Result<Response<PresenceList>, Status> {
+        let requested_uuids: Vec<Uuid> = request
+            .into_inner()
+            .user_uuids
+            .into_iter()
+            .map(Uuid::try_from)
+            .unique()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| Status::invalid_argument("Some user UUIDs are invalid"))?;
+
+        if requested_uuids.len() > Self::MAX_PRESENCE_LOOKUP {
+            return Err(Status::invalid_argument(format!(
+                "Can't look up presence of more than {} users at once",
+                Self::MAX_PRESENCE_LOOKUP
+            )));
+        }
+
+        // Users that haven't been online since the server started are offline,
+        // but their last-seen timestamp can still be found in the database.
+        let (mut presences, unseen_uuids): (Vec<Presence>, Vec<Uuid>) = requested_uuids
+            .into_iter()
+            .partition_map(|user| match self.presence_tracker.get(&user) {
+                Some(presence) => itertools::Either::Left(presence),
+                None => itertools::Either::Right(user),
+            });
+
+        if !unseen_uuids.is_empty() {
+            let unseen_users: Vec<(Uuid, Option<SystemTime>)> = self
+                .repositories
+                .users
+                .last_seen(unseen_uuids)
+                .await
+                .map_err(|error| {
+                    let msg = "Couldn't fetch users from database";
+                    tracing::error!(message = msg, ?error);
+                    Status::internal(msg)
+                })?;
+
+            presences.extend(
+                unseen_users
+                    .into_iter()
+                    .map(|(user, seen)| Presence::offline(user, seen)),
+            );
+        }
+
+        Ok(Response::new(PresenceList {
+            presences: presences.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    #[instrument(skip_all, fields(user_uuid, room_uuid))]
+    async fn analyze_room(
+        &self,
+        request: Request<proto::Uuid>,
+    ) -> Result<Response<proto::RoomAnalysisResponse>, Status> {
+        let req_originator: Uuid = request
+            .get_originator_uuid()
+            .expect("The authenticator should not let anonymous requests through");
+        tracing::Span::current().record("user_uuid", req_originator.to_string());
+
+        let req_room_uuid: Uuid = request
+            .into_inner()
+            .try_into()
+            .map_err(|_| Status::invalid_argument("Invalid room UUID"))?;
+        tracing::Span::current().record("room_uuid", req_room_uuid.to_string());
+
+        if !self
+            .check_room_membership(&req_originator, &req_room_uuid)
+            .await?
+        {
+            tracing::warn!(message = "User tried to LLM-analyze a room he's not a member of");
+            return Err(Status::permission_denied(
+                "You're not a member of this room",
+            ));
+        }
+
+        tracing::trace!(message = "Collecting messages for an LLM analysis");
+
+        let messages: Vec<Message> = self
+            .repositories
+            .messages
+            .list(req_room_uuid, SystemTime::now())
+            .await
+            .map_err(|error| {
+                let message = "Couldn't load messages from the database";
+                tracing::error!(message = message, ?error);
+                Status::internal(message)
+            })?;
+
+        let sender_uuids: Vec<Uuid> = messages
+            .iter()
+            .map(|message| message.sender_uuid)
+            .unique()
+            .collect();
+        let mut usernames: HashMap<Uuid, String> = HashMap::new();
+        for sender_uuid in sender_uuids {
+            let sender = self
+                .repositories
+                .users
+                .find(sender_uuid)
+                .await
+                .map_err(|error| {
+                    let message = "Couldn't load users from the database";
+                    tracing::error!(message = message, ?error);
+                    Status::internal(message)
+                })?;
+            if let Some(sender) = sender {
+                let _ = usernames.insert(sender_uuid, sender.username);
+            }
+        }
+
+        let formatted_messages: Vec<String> = messages
+            .into_iter()
+            .map(|message| {
+                let username = usernames
+                    .get(&message.sender_uuid)
+                    .cloned()
+                    .unwrap_or_default();
+                format!("\n{username}: {}", message.text)
+            })
+            .collect();
+
+        // Connect to the running LLM.
+        let expect_message = "$LLM_HOST and $LLM_PORT should point to an LLM instance!";
+        let host = format!("http://{}", env::var("LLM_HOST").expect(expect_message));
+        let port = env::var("LLM_PORT")
+            .expect(expect_message)
+            .parse::<u16>()
+            .expect(expect_message);
+        let llm = Ollama::new(host, port);
+
+        tracing::trace!(message = "Connected to LLM, forming a prompt");
+
+        let model = "llama3".to_string();
+        let mut prompt = r#"
+            Hey. Here are a bunch of messages from a chat room, along with the user's names.
+            I want you to analyze the messages and figure out what topics were discussed, as
+            well as look for pattterns in the messages and provide insignt into the users'
+            mood and intentions. Provide additional information about the same things the users
+            were talking about. Your response will be used as a mean of improving the users'
+            experience, and also be a part of a real-time behavioral analysis. Stay concise,
+            go straight to the point, no introductions. Here are the messages:
+        "#
+        .to_string();
+
+        for message in formatted_messages.iter() {
+            prompt.push_str(message);
+        }
+
+        tracing::trace!(message = "Awaiting LLM response...");
+
+        let response = llm
+            .generate(GenerationRequest::new(model, prompt))
+            .await
+            .map_err(|error| {
+                let message = "Local LLM returned an error";
+                tracing::error!(message = message, ?error);
+                Status::internal(message)
+            })?
+            .response;
+
+        tracing::info!(message = "LLM analysis complete");
+
+        Ok(Response::new(proto::RoomAnalysisResponse { response }))
+    }
+}
+
+impl Chat {
+    /// Start streaming the events of a room to a subscriber, until he disconnects.
+    ///
+    /// Shared by the `SubscribeToRoom` and `ResumeRoomSubscription` RPCs.
+    async fn subscribe_room(
+        &self,
+        subscriber: Uuid,
+        subscription: RoomSubscription,
+    ) -> Result<DisconnectChannel<Result<ServersideRoomEvent, Status>>, Status> {
+        // NOTE: Read this.
+        //
+        // There are a total of 3 channels involved in this whole streaming thing:
+        // - An internal, per-room `broadcast` channel that transfers room events (i.e. from `SendMessage` RPC calls);
+        // - A `DisconnectChannel`, which holds another 2 channels inside:
+        //   - A `mpsc` Tokio channel, which performs gRPC streaming;
+        //   - A `oneshot` Tokio channel, which fires when the client disconnects.
+
+        let (grpc_tx, grpc_rx) = mpsc::channel(4);
+        let (disconnect_tx, disconnect_rx) = oneshot::channel();
+        let disconnect_channel = channel::DisconnectChannel {
+            disconnect_tx: Some(disconnect_tx),
+            grpc_rx,
+        };
+
+        let streaming_closure = self
+            .stream_room(subscriber, subscription, grpc_tx, std::convert::identity)
+            .await?;
+
+        // The 'canceller' thread will cancel this token when the client disconnects.
+        let token = CancellationToken::new();
+        let token_clone = token.clone();
+
+        // This is the 'canceller' thread.
+        //
+        // This task will cancel the token when the client disconnects, which will shutdown
+        // the streaming thread (see below) and cause the room's receiver to drop.
+        tokio::spawn(async move {
+            let _ = disconnect_rx.await;
+            tracing::debug!(message = "Client disconnected, stopping message streaming");
+            token.cancel();
+        });
+
+        // This is the 'streamer' thread.
+        //
+        // This thread will receive all room events (i.e. messages sent via the `SendMessage` RPC
+        // call), and mirror them to all subsribers. Without a canceller thread, a cancellation token
+        // and a hacky DisconnectChannel, this thread would never terminate, meaning there
+        // would soon be a thousand of hanging broadcast::Receivers with no real client.
+        spawn_streamer(token_clone, streaming_closure);
+
+        Ok(disconnect_channel)
+    }
+
+    const MAX_STATUS_TEXT_LENGTH: usize = 128;
+    const MAX_PRESENCE_LOOKUP: usize = 256;
+    const MAX_ATTACHMENTS_PER_MESSAGE: usize = 10;
+    const ATTACHMENT_CHUNK_SIZE: usize = 64 * 1024;
+    const SCHEDULER_POLL_INTERVAL: Duration = Duration::from_secs(60);
+    const SCHEDULER_RETRY_DELAY: Duration = Duration::from_secs(30);
+    const SCHEDULER_BATCH_SIZE: i64 = 64;
+    const SWEEPER_POLL_INTERVAL: Duration = Duration::from_secs(60);
+    const SWEEPER_BATCH_SIZE: i64 = 256;
+
+    pub async fn new(
+        repositories: Repositories,
+        blob_store: Arc<dyn BlobStore>,
+        streaming_config: StreamingConfig,
+    ) -> RedisResult<Self> {
+        // Redis is only needed to share state between instances, so a single instance can do without.
+        let cache_client = env::var("KV_URL").ok().map(Client::open).transpose()?;
+        Ok(Self::with_cache_client(
+            repositories,
+            blob_store,
+            streaming_config,
+            cache_client,
+        ))
+    }
+
+    fn with_cache_client(
+        repositories: Repositories,
+        blob_store: Arc<dyn BlobStore>,
+        streaming_config: StreamingConfig,
+        cache_client: Option<Client>,
+    ) -> Self {
+        let membership: Arc<dyn MembershipCache> = match &cache_client {
+            Some(client) => Arc::new(RedisMembershipCache::start(client.clone())),
+            None => {
+                tracing::info!(message = "$KV_URL is unset, running as a single instance");
+                Arc::new(InMemoryMembershipCache::new())
+            }
+        };
+
+        let (user_event_tx, _) = broadcast::channel(streaming_config.channel_capacity);
+
+        Self {
+            repositories,
+            membership,
+            blob_store,
+            room_channels: Arc::new(RoomChannels::tallying(
+                streaming_config.channel_capacity,
+                is_unrecoverable,
+            )),
+            user_event_tx,
+            relay: cache_client.map(Relay::start),
+            slow_consumer_policy: streaming_config.slow_consumer_policy,
+            typing_tracker: Arc::new(TypingTracker::new()),
+            presence_tracker: Arc::new(PresenceTracker::new()),
+            scheduler_wakeup: Arc::new(Notify::new()),
+            sweeper_wakeup: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Load and hydrate the messages a room subscriber has missed, oldest first.
+    async fn load_missed_messages(
+        repositories: &Repositories,
+        subscription: RoomSubscription,
+    ) -> Result<Vec<ServersideMessage>, Status> {
+        let missed_messages = repositories
+            .messages
+            .missed(subscription, SystemTime::now())
+            .await
+            .map_err(|error| {
+                let msg = "Couldn't fetch missed messages from database";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?
+            .ok_or(Status::not_found(
+                "The message to resume after is not in this room",
+            ))?;
+
+        if missed_messages.len() > RoomSubscription::MAX_REPLAY {
+            return Err(Status::out_of_range(
+                "Too many messages were missed, list them instead",
+            ));
+        }
+
+        repositories
+            .messages
+            .hydrate(missed_messages)
+            .await
+            .map(|messages| messages.into_iter().map(Into::into).collect())
+            .map_err(|error| {
+                let msg = "Couldn't fetch attachments from database";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })
+    }
+
+    /// Open a session for a user, executing `commands` on the user's behalf with the auth
+    /// `metadata` until they end. Events are pushed until the returned channel is dropped.
+    async fn session(
+        &self,
+        user_uuid: Uuid,
+        metadata: MetadataMap,
+        commands: impl Stream<Item = Result<SessionCommand, Status>> + Send + 'static,
+    ) -> DisconnectChannel<Result<SessionEvent, Status>> {
+        // Every room the session subscribes to gets a streamer of its own, but
+        // they all share the same gRPC stream and are stopped by the same token.
+        let (grpc_tx, grpc_rx) = mpsc::channel(16);
+        let (disconnect_tx, disconnect_rx) = oneshot::channel();
+        let disconnect_channel = DisconnectChannel {
+            disconnect_tx: Some(disconnect_tx),
+            grpc_rx,
+        };
+        let token = CancellationToken::new();
+
+        let user_streamer = self.stream_user(user_uuid, grpc_tx.clone(), |event| SessionEvent {
+            event: Some(proto::session_event::Event::UserEvent(event)),
+        });
+
+        // Holding a session is what makes the user appear online.
+        self.connect_presence(user_uuid).await;
+
+        // Spawn the "canceller" thread.
+        //
+        // Cancelling the session token stops all of the session's streamers at once.
+        let canceller_token = token.clone();
+        let chat = self.clone();
+        tokio::spawn(async move {
+            let _ = disconnect_rx.await;
+            tracing::debug!(message = "Client disconnected, closing the session");
+            canceller_token.cancel();
+            chat.disconnect_presence(user_uuid).await;
+        });
+
+        spawn_streamer(token.clone(), user_streamer);
+
+        // Spawn the "command reader" thread.
+        //
+        // It stops reading once the client closes its half of the stream, but
+        // events keep coming until the client disconnects completely.
+        let mut session = Session {
+            chat: self.clone(),
+            user_uuid,
+            metadata,
+            grpc_tx,
+            token: token.clone(),
+            rooms: HashMap::new(),
+        };
+        spawn_streamer(token, async move {
+            let mut commands = std::pin::pin!(commands);
+            loop {
+                match commands.next().await {
+                    Some(Ok(SessionCommand {
+                        command: Some(command),
+                    })) => session.handle(command).await,
+                    Some(Ok(SessionCommand { command: None })) => {
+                        let status = Status::invalid_argument("Empty command");
+                        session.reject(None, &status).await;
+                    }
+                    None => break,
+                    Some(Err(error)) => {
+                        tracing::debug!(message = "Couldn't read a session command", ?error);
+                        break;
+                    }
+                }
+            }
+        });
+
+        disconnect_channel
+    }
+
+    /// Start streaming the events of a room to a subscriber, starting with the messages he's missed.
+    ///
+    /// Membership is checked and missed messages are loaded right away, so that the caller can
+    /// report errors. The returned streamer runs until `grpc_tx` closes and should be cancelled
+    /// when the client goes away. Each event is wrapped with `wrap` before being sent.
+    async fn stream_room<T: Send + 'static>(
+        &self,
+        subscriber: Uuid,
+        subscription: RoomSubscription,
+        grpc_tx: mpsc::Sender<Result<T, Status>>,
+        wrap: fn(ServersideRoomEvent) -> T,
+    ) -> Result<impl Future<Output = ()> + Send + 'static, Status> {
+        let subscribed_room = subscription.room_uuid;
+
+        // Ensure the user is a member of the room he's subscribing to.
+        if !self
+            .check_room_membership(&subscriber, &subscribed_room)
+            .await?
+        {
+            tracing::warn!(
+                message = "User tried to subscribe to a room he's not a member of",
+                ?subscriber,
+                room = ?subscribed_room
+            );
+            return Err(Status::permission_denied(
+                "You are not a member of this room",
+            ));
+        }
+
+        // NOTE: Read the room's history *before* subscribing to live events, and load missed
+        // messages after. Every message stored later on has a higher sequence number, so it's
+        // either published after subscribing or already stored when missed messages are loaded
+        // (and skipped when it arrives live). Without a cursor, the history isn't replayed, but
+        // whatever's been stored since reading it is.
+        let repositories = self.repositories.clone();
+        let history = Self::last_sequence(&repositories, subscribed_room).await?;
+        let mut room_event_rx = self.room_channels.subscribe(subscribed_room);
+        tracing::info!(message = "New room subscriber", room = ?subscribed_room);
+
+        let subscription = RoomSubscription {
+            resume_after: subscription.resume_after.or_else(|| {
+                // Sequence numbers originate from an `i64` column, so this always fits.
+                let after = i64::try_from(history).unwrap_or(i64::MAX);
+                Some(ResumeCursor::Sequence(after))
+            }),
+            ..subscription
+        };
+        let replay = Self::load_missed_messages(&repositories, subscription).await?;
+        tracing::debug!(message = "Replaying missed messages", count = %replay.len());
+
+        let slow_consumer_policy = self.slow_consumer_policy;
+
+        Ok(async move {
+            use proto::serverside_room_event::Event;
+
+            // NOTE: This can't be a "highest sequence sent" watermark. Messages are published
+            // after they're committed, so live messages may arrive out of order, and recovering
+            // after the highest one would lose those that arrive late.
+            let mut delivered = DeliveredSequences::after(history);
+            let new_message = |message| ServersideRoomEvent {
+                room_uuid: Some(subscribed_room.into()),
+                event: Some(Event::NewMessage(message)),
+            };
+            let resync_required = |skipped_events| ServersideRoomEvent {
+                room_uuid: Some(subscribed_room.into()),
+                event: Some(Event::ResyncRequired(proto::ResyncRequired {
+                    skipped_events,
+                })),
+            };
+
+            // Everything after the cursor has been loaded, so nothing up to the last one is owed.
+            let replayed_up_to = replay.last().map(|message| message.sequence);
+            for message in replay {
+                delivered.insert(message.sequence);
+                if grpc_tx.send(Ok(wrap(new_message(message)))).await.is_err() {
+                    return;
+                }
+            }
+            delivered.settle(replayed_up_to.unwrap_or_default());
+
+            loop {
+                let event = match room_event_rx.recv().await {
+                    Ok(event) => event,
+                    Err(RoomRecvError::Closed) => break,
+                    Err(RoomRecvError::Lagged { skipped, tallied }) => {
+                        tracing::warn!(
+                            message = "Room subscriber is lagging behind",
+                            ?subscriber,
+                            room = ?subscribed_room,
+                            %skipped,
+                            %tallied
+                        );
+
+                        let recovered = match slow_consumer_policy {
+                            SlowConsumerPolicy::Recover => {
+                                // Sequence numbers originate from an `i64` column, so this always fits.
+                                let after =
+                                    i64::try_from(delivered.low_water()).unwrap_or(i64::MAX);
+                                let subscription = RoomSubscription {
+                                    room_uuid: subscribed_room,
+                                    resume_after: Some(ResumeCursor::Sequence(after)),
+                                };
+                                Self::load_missed_messages(&repositories, subscription)
+                                    .await
+                                    .ok()
+                            }
+                            SlowConsumerPolicy::Resync => None,
+                            SlowConsumerPolicy::Disconnect => {
+                                let status = Status::data_loss(
+                                    "Fell too far behind on room events, resubscribe to continue",
+                                );
+                                let _ = grpc_tx.send(Err(status)).await;
+                                break;
+                            }
+                        };
+
+                        let events = match recovered {
+                            Some(messages) => {
+                                tracing::debug!(
+                                    message = "Recovered skipped messages",
+                                    count = %messages.len()
+                                );
+                                let recovered_up_to = messages.last().map(|m| m.sequence);
+                                let mut events: Vec<_> = messages
+                                    .into_iter()
+                                    .filter(|message| delivered.insert(message.sequence))
+                                    .map(new_message)
+                                    .collect();
+                                delivered.settle(recovered_up_to.unwrap_or_default());
+
+                                // Other events aren't stored, so they can't be recovered.
+                                if tallied > 0 {
+                                    events.push(resync_required(tallied));
+                                }
+                                events
+                            }
+                            None => {
+                                // The client re-fetches the messages, so none of them are owed.
+                                match Self::last_sequence(&repositories, subscribed_room).await {
+                                    Ok(last_sequence) => delivered.settle(last_sequence),
+                                    Err(_) => delivered.settle(delivered.highest()),
+                                }
+                                vec![resync_required(skipped)]
+                            }
+                        };
+                        for event in events {
+                            if grpc_tx.send(Ok(wrap(event))).await.is_err() {
+                                return;
+                            }
+                        }
+                        continue;
+                    }
+                };
+
+                // Messages that have already been replayed or recovered shouldn't be delivered twice.
+                if let Some(Event::NewMessage(message)) = &event.event {
+                    if !delivered.insert(message.sequence) {
+                        continue;
+                    }
+                }
+
+                // Typists don't need to be told that they're typing.
+                if let Some(Event::Typing(indicator)) = &event.event {
+                    if indicator.user_uuid.clone().is_some_and(|u| subscriber == u) {
+                        continue;
+                    }
+                }
+
+                // The channel only carries events of the subscribed room, and membership
+                // has been checked above, so there's nothing left to filter.
+                let send_result = grpc_tx.send(Ok(wrap(event))).await;
+                if send_result.is_err() {
+                    tracing::warn!(
+                        message = "A message was sent, but nobody is subscribed to the channel"
+                    )
+                }
+            }
+        })
+    }
+
+    /// Get the sequence number of the last message stored in a room.
+    async fn last_sequence(repositories: &Repositories, room_uuid: Uuid) -> Result<u64, Status> {
+        let room = repositories
+            .rooms
+            .find(room_uuid)
+            .await
+            .map_err(repository_error_status)?
+            .ok_or_else(|| Status::not_found("The room doesn't exist"))?;
+
+        Ok(room.last_sequence.try_into().unwrap_or_default())
+    }
+
+    /// Start streaming the personal events of a user, see [`Self::stream_room`].
+    fn stream_user<T: Send + 'static>(
+        &self,
+        user_uuid: Uuid,
+        grpc_tx: mpsc::Sender<Result<T, Status>>,
+        wrap: fn(ServersideUserEvent) -> T,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        let mut user_event_rx = self.user_event_tx.subscribe();
+        let slow_consumer_policy = self.slow_consumer_policy;
+
+        async move {
+            loop {
+                let event = match user_event_rx.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!(
+                            message = "User subscriber is lagging behind",
+                            ?user_uuid,
+                            %skipped
+                        );
+
+                        // User events aren't persisted, so there's nothing to recover them from.
+                        let response = match slow_consumer_policy {
+                            SlowConsumerPolicy::Recover | SlowConsumerPolicy::Resync => {
+                                Ok(wrap(ServersideUserEvent {
+                                    user_uuid: Some(user_uuid.into()),
+                                    event: Some(Event::ResyncRequired(proto::ResyncRequired {
+                                        skipped_events: skipped,
+                                    })),
+                                }))
+                            }
+                            SlowConsumerPolicy::Disconnect => Err(Status::data_loss(
+                                "Fell too far behind on user events, resubscribe to continue",
+                            )),
+                        };
+                        let disconnect = response.is_err();
+                        if grpc_tx.send(response).await.is_err() || disconnect {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                if event.recipients.contains(&user_uuid) {
+                    let event = ServersideUserEvent {
+                        user_uuid: Some(user_uuid.into()),
+                        event: Some(event.event),
+                    };
+                    let send_result = grpc_tx.send(Ok(wrap(event))).await;
+                    if send_result.is_err() {
+                        tracing::trace!(message = "A user event occurred, but nobody is subscribed")
+                    }
+                }
+            }
+        }
+    }
+
+    /// Count a new connection of a user, announcing that he's online if it's the first one.
+    async fn connect_presence(&self, user_uuid: Uuid) {
+        if let Some(presence) = self.presence_tracker.connect(user_uuid, SystemTime::now()) {
+            self.store_last_seen(&presence).await;
+            self.announce_presence(presence).await;
+        }
+    }
+
+    /// Forget a connection of a user, announcing that he's offline if it was the last one.
+    async fn disconnect_presence(&self, user_uuid: Uuid) {
+        if let Some(presence) = self
+            .presence_tracker
+            .disconnect(user_uuid, SystemTime::now())
+        {
+            self.store_last_seen(&presence).await;
+            self.announce_presence(presence).await;
+        }
+    }
+
+    #[instrument]
+    async fn check_room_membership(&self, user: &Uuid, room: &Uuid) -> Result<bool, Status> {
+        match self.membership.contains(user, room).await {
+            Some(true) => Ok(true),
+            Some(false) => {
+                // Users never leave rooms, so a positive answer is always right, but a negative
+                // one might come from a set that's been cached right before the user joined.
+                let is_member = self
+                    .repositories
+                    .rooms
+                    .is_member(*room, *user)
+                    .await
+                    .map_err(|error| {
+                        let msg = "Couldn't check membership in the database";
+                        tracing::error!(message = msg, ?error);
+                        Status::internal(msg)
+                    })?;
+                if is_member {
+                    tracing::debug!(message = "The membership cache was stale", ?user, ?room);
+                    self.membership.invalidate(&[*user]).await;
+                }
+                Ok(is_member)
+            }
+            None => Ok(self.member_rooms(user).await?.contains(room)),
+        }
+    }
+
+    /// List the rooms a user is a member of, populating the membership cache if needed.
+    #[instrument]
+    async fn member_rooms(&self, user: &Uuid) -> Result<Vec<Uuid>, Status> {
+        if let Some(rooms) = self.membership.rooms(user).await {
+            return Ok(rooms);
+        }
+
+        let rooms = self
+            .repositories
+            .rooms
+            .rooms_of(*user)
+            .await
+            .map_err(|error| {
+                let msg = "Couldn't load memberships from the database";
+                tracing::error!(message = msg, ?error);
+                Status::internal(msg)
+            })?;
+
+        self.membership.store(user, &rooms).await;
+        Ok(rooms)
+    }
+
+    #[instrument(skip_all)]
+    async fn create_room(&self, clientside_room: ClientsideRoom) -> Result<Uuid, Status> {
+        let user_uuids: Vec<Uuid> = clientside_room
+            .members
+            .into_iter()
+            .map(Uuid::try_from)
+            .unique() // Ensure we don't operate on the same user twice!
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|error| {
+                let error = error.to_string();
+                let message = format!("Some member UUIDs could not be converted: {error}");
+                Status::invalid_argument(message)
+            })?;
+
+        // Store the room and members in the database, all or nothing.
+        let room = Room::new(clientside_room.name);
+        let unknown_members = self
+            .repositories
+            .rooms
+            .create(room.clone(), user_uuids.clone())
+            .await
+            .map_err(|error| {
+                let message = "Could not save the room in the database";
+                tracing::error!(message = message, ?error);
+                Status::internal(message)
+            })?;
+        if !unknown_members.is_empty() {
+            tracing::warn!(
+                message = "Tried to create a room with unknown members",
+                ?unknown_members
+            );
+            let unknown_members = unknown_members.iter().join(", ");
+            return Err(Status::not_found(format!(
+                "Some members are not registered users: {unknown_members}"
+            )));
+        }
+        tracing::info!(message = "Created new room", members = ?user_uuids, uuid = ?room.uuid);
+
+        // Update the membership cache, now that the room is committed.
+        self.membership.invalidate(&user_uuids).await;
+
+        self.broadcast_user_event(UserEvent {
+            recipients: user_uuids,
+            event: Event::AddedToRoom(room.uuid.into()),
+        });
+
+        tracing::info!(message = "Updated membership cache", room = ?room.uuid);
+
+        Ok(room.uuid)
+    }
+
+    /// Push a presence change to everyone who shares a room with the user.
+    async fn announce_presence(&self, presence: Presence) {
+        let recipients = self
+            .repositories
+            .rooms
+            .neighbours(presence.user_uuid)
+            .await
+            .unwrap_or_else(|error| {
+                tracing::error!(
+                    message = "Couldn't fetch room neighbours from database",
+                    ?error
+                );
+                vec![]
+            });
+
+        if recipients.is_empty() {
+            return;
+        }
+
+        self.broadcast_user_event(UserEvent {
+            recipients,
+            event: Event::PresenceChanged(presence.into()),
+        });
+    }
+
+    async fn store_last_seen(&self, presence: &Presence) {
+        let _ = self
+            .repositories
+            .users
+            .set_last_seen(presence.user_uuid, presence.last_seen)
+            .await
+            .map_err(|error| {
+                tracing::error!(message = "Couldn't store last-seen timestamp", ?error);
+            });
+    }
+
+    /// Mirror a freshly stored message to the room, and notify everyone it mentions.
+    fn broadcast_new_message(
+        &self,
+        message: Message,
+        attachments: Vec<Attachment>,
+        mentions: Vec<Mention>,
+    ) {
+        if message.expires_at.is_some() {
+            self.sweeper_wakeup.notify_one();
+        }
+
+        let room_uuid = message.room_uuid;
+        let sender_uuid = message.sender_uuid;
+        let mut serverside_message = ServersideMessage::from(message);
+        serverside_message.attachments = attachments.into_iter().map(Into::into).collect();
+
+        // Nobody needs to be notified about mentioning themselves.
+        let mentioned: Vec<Uuid> = mentions
+            .iter()
+            .map(|m| m.user_uuid)
+            .filter(|user_uuid| *user_uuid != sender_uuid)
+            .unique()
+            .collect();
+        serverside_message.mentions = mentions.into_iter().map(Into::into).collect();
+
+        if !mentioned.is_empty() {
+            self.broadcast_user_event(UserEvent {
+                recipients: mentioned,
+                event: proto::serverside_user_event::Event::Mentioned(serverside_message.clone()),
+            });
+        }
+
+        use proto::serverside_room_event::Event;
+        self.broadcast_room_event(ServersideRoomEvent {
+            room_uuid: Some(room_uuid.into()),
+            event: Some(Event::NewMessage(serverside_message)),
+        });
+    }
+
+    /// Deliver scheduled messages once their time comes. Never returns.
+    ///
+    /// The queue lives in the database, so messages that came due while the
+    /// server was down get delivered as soon as it's back up.
+    pub async fn dispatch_scheduled_messages(self) {
+        tracing::info!(message = "Starting scheduled message dispatcher");
+        loop {
+            let next_delivery = self.deliver_due_messages().await;
+
+            let sleep_for = time_until(next_delivery, Self::SCHEDULER_POLL_INTERVAL);
+            tokio::select! {
+                () = tokio::time::sleep(sleep_for) => {}
+                () = self.scheduler_wakeup.notified() => {}
+            }
+        }
+    }
+
+    /// Deliver events published by other instances to local subscribers.
+    ///
+    /// Never returns, unless there's no relay (i.e. when running without Redis).
+    pub async fn relay_events(self) {
+        let Some(relay) = self.relay.clone() else {
+            return;
+        };
+
+        let mut reconnect_delay = relay::RECONNECT_DELAY;
+        loop {
+            match relay.subscribe().await {
+                Ok(mut pubsub) => {
+                    let mut messages = pubsub.on_message();
+                    while let Some(message) = messages.next().await {
+                        match relay.accept(message.get_payload_bytes()) {
+                            Some(relayed_event::Event::RoomEvent(event)) => {
+                                self.deliver_room_event(event);
+                            }
+                            Some(relayed_event::Event::UserEvent(event)) => {
+                                match UserEvent::try_from(event) {
+                                    Ok(event) => self.deliver_user_event(event),
+                                    Err(error) => {
+                                        tracing::warn!(message = "Invalid relayed event", ?error);
+                                    }
+                                }
+                            }
+                            None => {}
+                        }
+                    }
+                    tracing::warn!(message = "Lost the relay subscription");
+                }
+                Err(error) => {
+                    tracing::error!(
+                        message = "Couldn't subscribe to relayed events",
+                        ?error,
+                        retry_in = ?reconnect_delay
+                    );
+                    tokio::time::sleep(reconnect_delay).await;
+                    reconnect_delay = relay::next_reconnect_delay(reconnect_delay);
+                    continue;
+                }
+            }
+            reconnect_delay = relay::RECONNECT_DELAY;
+            tokio::time::sleep(reconnect_delay).await;
+        }
+    }
+
+    /// Delete messages for good once they expire, and forget stale idempotency keys. Never returns.
+    pub async fn sweep_expired_messages(self) {
+        tracing::info!(message = "Starting expired message sweeper");
+        loop {
+            self.prune_idempotency_keys().await;
+            let next_expiry = self.delete_expired_messages().await;
+
+            let sleep_for = time_until(next_expiry, Self::SWEEPER_POLL_INTERVAL);
+            tokio::select! {
+                () = tokio::time::sleep(sleep_for) => {}
+                () = self.sweeper_wakeup.notified() => {}
+            }
+        }
+    }
+
+    async fn prune_idempotency_keys(&self) {
+        match self
+            .repositories
+            .messages
+            .prune_idempotency_keys(SystemTime::now())
+            .await
+        {
+            Ok(0) => {}
+            Ok(count) => tracing::debug!(message = "Pruned stale idempotency keys", %count),
+            Err(error) => tracing::error!(message = "Couldn't prune idempotency keys", ?error),
+        }
+    }
+
+    /// Delete a batch of expired messages, returning the time of the next expiry.
+    async fn delete_expired_messages(&self) -> Option<SystemTime> {
+        let expired = self
+            .repositories
+            .messages
+            .delete_expired(SystemTime::now(), Self::SWEEPER_BATCH_SIZE)
+            .await
+            .map_err(|error| {
+                tracing::error!(message = "Couldn't delete expired messages", ?error);
+            })
+            .ok()?;
+
+        if !expired.is_empty() {
+            tracing::info!(message = "Deleted expired messages", count = %expired.len());
+        }
+
+        for message in expired {
+            use proto::serverside_room_event::Event;
+            self.broadcast_room_event(ServersideRoomEvent {
+                room_uuid: Some(message.room_uuid.into()),
+                event: Some(Event::MessageDeleted(proto::MessageDeleted {
+                    message_uuid: Some(message.uuid.into()),
+                })),
+            });
+        }
+
+        self.repositories
+            .messages
+            .next_expiry()
+            .await
+            .map_err(|error| {
+                tracing::error!(message = "Couldn't fetch the next expiry time", ?error);
+            })
+            .ok()
+            .flatten()
+    }
+
+    /// Deliver a batch of scheduled messages that are due, returning the time of the next delivery.
+    async fn deliver_due_messages(&self) -> Option<SystemTime> {
+        let due_messages = self
+            .repositories
+            .scheduled_messages
+            .due(SystemTime::now(), Self::SCHEDULER_BATCH_SIZE)
+            .await
+            .map_err(|error| {
+                tracing::error!(message = "Couldn't fetch due scheduled messages", ?error);
+            })
+            .ok()?;
+
+        for scheduled_message in due_messages {
+            self.deliver_scheduled_message(scheduled_message).await;
+        }
+
+        self.repositories
+            .scheduled_messages
+            .next_delivery()
+            .await
+            .map_err(|error| {
+                tracing::error!(message = "Couldn't fetch the next delivery time", ?error);
+            })
+            .ok()
+            .flatten()
+    }
+
+    async fn deliver_scheduled_message(&self, scheduled_message: ScheduledMessage) {
+        let (message, attachment_uuids) = scheduled_message.into_message(SystemTime::now());
+        let message_uuid = message.uuid;
+        let delivered = self
+            .repositories
+            .scheduled_messages
+            .deliver(message, attachment_uuids.clone())
+            .await;
+
+        match delivered {
+            Ok(Some((message, mentions))) => {
+                tracing::info!(message = "Delivered a scheduled message", sender = ?message.sender_uuid, room = ?message.room_uuid);
+                let message_attachments = self
+                    .repositories
+                    .attachments
+                    .find_in_room(message.room_uuid, attachment_uuids)
+                    .await
+                    .unwrap_or_else(|error| {
+                        tracing::error!(
+                            message = "Couldn't fetch attachments from database",
+                            ?error
+                        );
+                        vec![]
+                    });
+                self.broadcast_new_message(message, message_attachments, mentions);
+            }
+            // The message was cancelled in the meantime, or its sender has left the room.
+            Ok(None) => {}
+            Err(error) => {
+                // Try again later instead of spinning on a message that can't be delivered.
+                tracing::error!(message = "Could not deliver a scheduled message", uuid = ?message_uuid, ?error);
+                let retry_at = SystemTime::now() + Self::SCHEDULER_RETRY_DELAY;
+                let _ = self
+                    .repositories
+                    .scheduled_messages
+                    .postpone(message_uuid, retry_at)
+                    .await
+                    .map_err(|error| {
+                        tracing::error!(message = "Couldn't postpone a scheduled message", ?error);
+                    });
+            }
+        }
+    }
+
+    /// Mirror an event to the running `SubscribeToRoom` handles of its room, on all instances.
+    fn broadcast_room_event(&self, event: ServersideRoomEvent) {
+        if let Some(relay) = &self.relay {
+            relay.publish(relayed_event::Event::RoomEvent(event.clone()));
+        }
+        self.deliver_room_event(event);
+    }
+
+    /// Mirror an event to all running `SubscribeToUser` handles, on all instances.
+    fn broadcast_user_event(&self, event: UserEvent) {
+        if let Some(relay) = &self.relay {
+            relay.publish(relayed_event::Event::UserEvent(event.clone().into()));
+        }
+        self.deliver_user_event(event);
+    }
+
+    /// Mirror an event to the running `SubscribeToRoom` handles of its room on this instance.
+    fn deliver_room_event(&self, event: ServersideRoomEvent) {
+        let Some(room_uuid) = event.room_uuid.clone().and_then(|u| Uuid::try_from(u).ok()) else {
+            tracing::error!(
+                message = "Tried to broadcast a room event without a room",
+                ?event
+            );
+            return;
+        };
+
+        match self.room_channels.send(room_uuid, event) {
+            0 => tracing::trace!(message = "No subscribers for room event"),
+            recv_count => tracing::trace!(message = "Broadcasting room event", ?recv_count),
+        }
+    }
+
+    /// Mirror an event to all running `SubscribeToUser` handles on this instance.
+    fn deliver_user_event(&self, event: UserEvent) {
+        match self.user_event_tx.send(event) {
+            Ok(recv_count) => tracing::trace!(message = "Broadcasting user event", ?recv_count),
+            Err(error) => {
+                if self.user_event_tx.receiver_count() > 0 {
+                    tracing::error!(message = "Could not broadcast user event", ?error);
+                } else {
+                    tracing::trace!(message = "No subscribers for user event");
+                }
+            }
+        }
+    }
+}
+
+/// The state of a session opened with `Connect`, owned by its command reader.
+struct Session {
+    chat: Chat,
+    user_uuid: Uuid,
+    metadata: MetadataMap,
+    grpc_tx: mpsc::Sender<Result<SessionEvent, Status>>,
+    token: CancellationToken,
+    /// Stops the streamer of each subscribed room.
+    rooms: HashMap<Uuid, CancellationToken>,
+}
+
+impl Session {
+    async fn handle(&mut self, command: proto::session_command::Command) {
+        use proto::session_command::Command;
+
+        let (room_uuid, result) = match command {
+            Command::Subscribe(request) => {
+                (request.room_uuid.clone(), self.subscribe(request).await)
+            }
+            Command::Unsubscribe(room_uuid) => {
+                (Some(room_uuid.clone()), self.unsubscribe(room_uuid))
+            }
+            Command::Typing(request) => (
+                request.room_uuid.clone(),
+                proto::chat_server::Chat::set_typing(&self.chat, self.request(request))
+                    .await
+                    .map(Response::into_inner),
+            ),
+            Command::Ack(request) => (
+                request.room_uuid.clone(),
+                proto::chat_server::Chat::mark_read(&self.chat, self.request(request))
+                    .await
+                    .map(Response::into_inner),
+            ),
+        };
+
+        if let Err(status) = result {
+            self.reject(room_uuid, &status).await;
+        }
+    }
+
+    async fn subscribe(&mut self, request: RoomSubscriptionRequest) -> Result<(), Status> {
+        let subscription = RoomSubscription::try_from(request).map_err(|error| {
+            tracing::trace!(message = "Invalid subscription request", ?error);
+            Status::invalid_argument(error.to_string())
+        })?;
+        let room_uuid = subscription.room_uuid;
+
+        let streamer = self
+            .chat
+            .stream_room(
+                self.user_uuid,
+                subscription,
+                self.grpc_tx.clone(),
+                |event| SessionEvent {
+                    event: Some(proto::session_event::Event::RoomEvent(event)),
+                },
+            )
+            .await?;
+
+        let token = self.token.child_token();
+        if let Some(previous) = self.rooms.insert(room_uuid, token.clone()) {
+            previous.cancel();
+        }
+        spawn_streamer(token, streamer);
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, room_uuid: proto::Uuid) -> Result<(), Status> {
+        let room_uuid: Uuid = room_uuid
+            .try_into()
+            .map_err(|_| Status::invalid_argument("Invalid room UUID"))?;
+
+        if let Some(token) = self.rooms.remove(&room_uuid) {
+            token.cancel();
+            tracing::debug!(message = "Unsubscribed from a room", room = ?room_uuid);
+        }
+        Ok(())
+    }
+
+    /// Tell the client that a command has failed, without closing the session.
+    async fn reject(&self, room_uuid: Option<proto::Uuid>, status: &Status) {
+        tracing::debug!(message = "Session command rejected", user = ?self.user_uuid, ?status);
+        let event = SessionEvent {
+            event: Some(proto::session_event::Event::CommandRejected(
+                proto::CommandRejected {
+                    room_uuid,
+                    code: status.code().into(),
+                    message: status.message().to_string(),
+                },
+            )),
+        };
+        let _ = self.grpc_tx.send(Ok(event)).await;
+    }
+
+    /// Make a request on behalf of the session's user, for a regular RPC handler.
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        *request.metadata_mut() = self.metadata.clone();
+        request
+    }
+}
+
+/// Run a streamer in the background until it finishes or the token is cancelled.
+fn spawn_streamer(token: CancellationToken, streamer: impl Future<Output = ()> + Send + 'static) {
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = token.cancelled() => {}
+            _ = streamer => {}
+        }
+    });
+}
+
+/// For how long to sleep until the next piece of background work, checking back at least every `max`.
+fn time_until(next: Option<SystemTime>, max: Duration) -> Duration {
+    next.map_or(max, |at| {
+        at.duration_since(SystemTime::now())
+            .unwrap_or_default()
+            .min(max)
+    })
+}
+
+/// Zero seconds mean "no TTL" in the protocol.
+fn ttl_from_seconds(seconds: u32) -> Option<Duration> {
+    (seconds > 0).then(|| Duration::from_secs(seconds.into()))
+}
+
+fn ttl_to_seconds(ttl: Option<Duration>) -> u32 {
+    ttl.and_then(|ttl| ttl.as_secs().try_into().ok())
+        .unwrap_or_default()
+}
+
+/// Whether a room event is lost for good if a subscriber misses it. Messages are stored,
+/// and typing indicators are soon out of date anyway.
+const fn is_unrecoverable(event: &ServersideRoomEvent) -> bool {
+    use proto::serverside_room_event::Event;
+
+    !matches!(event.event, Some(Event::NewMessage(_) | Event::Typing(_)))
+}
+
+fn typing_event(room_uuid: Uuid, user_uuid: Uuid, typing: bool) -> ServersideRoomEvent {
+    use proto::serverside_room_event::Event;
+    ServersideRoomEvent {
+        room_uuid: Some(room_uuid.into()),
+        event: Some(Event::Typing(proto::TypingIndicator {
+            user_uuid: Some(user_uuid.into()),
+            typing,
+        })),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{typing_event, Chat};
+    use crate::auth::Authenticator;
+    use crate::channel::DisconnectChannel;
+    use crate::entities::{Message, Room, User};
+    use crate::entities::{ResumeCursor, RoomSubscription};
+    use crate::proto::chat_server::Chat as ChatService;
+    use crate::proto::serverside_room_event::Event;
+    use crate::proto::session_command::Command;
+    use crate::proto::session_event::Event as SessionEventKind;
+    use crate::proto::{self, user_lookup_request::Identifier};
+    use crate::proto::{ClientsideMessage, ClientsideRoom, MessageSearchRequest, PinRequest};
+    use crate::proto::{ReadMarkerRequest, RoomMessageTtlRequest};
+    use crate::proto::{SessionCommand, SessionEvent, UserLookupRequest};
+    use crate::repositories::{Deduplicated, InMemoryRepository, Repositories, RepositoryResult};
+    use crate::repositories::{MessageRepository, RoomRepository};
+    use crate::repositories::{ScheduledMessageRepository, UserRepository};
+    use crate::storage::LocalBlobStore;
+    use crate::streaming::{SlowConsumerPolicy, StreamingConfig};
+    use crate::typing::ANNOUNCEMENT_INTERVAL;
+    use futures::StreamExt;
+    use rand_chacha::ChaCha20Rng;
+    use rand_core::SeedableRng;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    use tonic::{Code, Request, Status};
+    use uuid::Uuid;
+
+    /// A chat that keeps everything in memory.
+    fn chat(repository: &Arc<InMemoryRepository>) -> Chat {
+        chat_with(repository, StreamingConfig::default())
+    }
+
+    fn chat_with(repository: &Arc<InMemoryRepository>, streaming_config: StreamingConfig) -> Chat {
+        Chat::with_cache_client(
+            Repositories::from_shared(repository.clone()),
+            Arc::new(LocalBlobStore::new(std::env::temp_dir())),
+            streaming_config,
+            None,
+        )
+    }
+
+    async fn register(repository: &InMemoryRepository, username: &str) -> Uuid {
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        let user = User::new(username.into(), "hash".into(), &mut rng);
+        assert!(repository.create(user.clone()).await.unwrap());
+        user.uuid
+    }
+
+    fn request<T>(user_uuid: Uuid, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        let _ = request.metadata_mut().insert(
+            Authenticator::USER_UUID_KEY,
+            user_uuid.to_string().parse().unwrap(),
+        );
+        request
+    }
+
+    async fn create_room(chat: &Chat, creator: Uuid, members: &[Uuid]) -> Uuid {
+        let room = ClientsideRoom {
+            name: "room".into(),
+            members: members.iter().copied().map(Into::into).collect(),
+        };
+        ChatService::create_room(chat, request(creator, room))
+            .await
+            .unwrap()
+            .into_inner()
+            .try_into()
+            .unwrap()
+    }
+
+    fn clientside_message(room_uuid: Uuid, text: &str) -> ClientsideMessage {
+        ClientsideMessage {
+            room_uuid: Some(room_uuid.into()),
+            text: text.into(),
+            attachment_uuids: vec![],
+            deliver_at: None,
+            ttl_seconds: 0,
+            idempotency_key: String::new(),
+        }
+    }
+
+    async fn send(chat: &Chat, sender: Uuid, message: ClientsideMessage) -> Uuid {
+        chat.send_message(request(sender, message))
+            .await
+            .unwrap()
+            .into_inner()
+            .try_into()
+            .unwrap()
+    }
+
+    async fn list_messages(
+        chat: &Chat,
+        user: Uuid,
+        room_uuid: Uuid,
+    ) -> Vec<proto::ServersideMessage> {
+        chat.list_messages(request(user, proto::Uuid::from(room_uuid)))
+            .await
+            .unwrap()
+            .into_inner()
+            .messages
+    }
+
+    #[tokio::test]
+    async fn created_rooms_are_listed_for_members() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+        let (alice, bob, carol) = (
+            register(&repository, "alice").await,
+            register(&repository, "bob").await,
+            register(&repository, "carol").await,
+        );
+        let room_uuid = create_room(&chat, alice, &[alice, bob]).await;
+
+        let rooms = chat
+            .list_rooms(request(bob, ()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(rooms.rooms.len(), 1);
+        assert_eq!(rooms.rooms[0].uuid, Some(room_uuid.into()));
+        assert_eq!(rooms.rooms[0].members.len(), 2);
+
+        let rooms = chat.list_rooms(request(carol, ())).await.unwrap();
+        assert!(rooms.into_inner().rooms.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rooms_with_unknown_members_are_not_created() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+        let alice = register(&repository, "alice").await;
+
+        let room = ClientsideRoom {
+            name: "room".into(),
+            members: vec![alice.into(), Uuid::new_v4().into()],
+        };
+        let status = ChatService::create_room(&chat, request(alice, room))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let rooms = chat.list_rooms(request(alice, ())).await.unwrap();
+        assert!(rooms.into_inner().rooms.is_empty());
+    }
+
+    #[tokio::test]
+    async fn outsiders_cant_configure_rooms() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+        let (alice, mallory) = (
+            register(&repository, "alice").await,
+            register(&repository, "mallory").await,
+        );
+        let room_uuid = create_room(&chat, alice, &[alice]).await;
+
+        let ttl_request = RoomMessageTtlRequest {
+            room_uuid: Some(room_uuid.into()),
+            ttl_seconds: 60,
+        };
+        let status = chat
+            .set_room_message_ttl(request(mallory, ttl_request.clone()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+
+        chat.set_room_message_ttl(request(alice, ttl_request))
+            .await
+            .unwrap();
+        let room = chat
+            .lookup_room(request(alice, proto::Uuid::from(room_uuid)))
+            .await
+            .unwrap();
+        assert_eq!(room.into_inner().message_ttl_seconds, 60);
+    }
+
+    #[tokio::test]
+    async fn read_markers_only_move_forward() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+        let (alice, bob) = (
+            register(&repository, "alice").await,
+            register(&repository, "bob").await,
+        );
+        let room_uuid = create_room(&chat, alice, &[alice, bob]).await;
+
+        let older = Message::new("first", alice, room_uuid);
+        let newer = Message {
+            timestamp: older.timestamp + Duration::from_secs(1),
+            sequence: 1,
+            ..Message::new("second", alice, room_uuid)
+        };
+        repository.insert_message(older.clone());
+        repository.insert_message(newer.clone());
+
+        let mark = |message: &Message| ReadMarkerRequest {
+            room_uuid: Some(room_uuid.into()),
+            message_uuid: Some(message.uuid.into()),
+        };
+        chat.mark_read(request(bob, mark(&newer))).await.unwrap();
+        chat.mark_read(request(bob, mark(&older))).await.unwrap();
+
+        let rooms = chat
+            .list_rooms(request(bob, ()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(rooms.rooms[0].last_read_message, Some(newer.uuid.into()));
+        assert_eq!(rooms.rooms[0].unread_count, 0);
+    }
+
+    #[tokio::test]
+    async fn users_are_looked_up_by_canonical_username() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+        let alice = register(&repository, "alice").await;
+
+        let lookup = UserLookupRequest {
+            identifier: Some(Identifier::Username("ALICE".into())),
+        };
+        let user = chat
+            .lookup_user(request(alice, lookup))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(user.uuid, Some(alice.into()));
+        assert_eq!(user.username, "alice");
+    }
+
+    #[tokio::test]
+    async fn unknown_users_are_not_found() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+
+        let lookup = UserLookupRequest {
+            identifier: Some(Identifier::Username("nobody".into())),
+        };
+        let status = chat
+            .lookup_user(request(Uuid::new_v4(), lookup))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn retried_messages_are_stored_once() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+        let (alice, bob) = (
+            register(&repository, "alice").await,
+            register(&repository, "bob").await,
+        );
+        let room_uuid = create_room(&chat, alice, &[alice, bob]).await;
+
+        let message = ClientsideMessage {
+            idempotency_key: "retry-me".into(),
+            ..clientside_message(room_uuid, "hi @bob")
+        };
+        let first = send(&chat, alice, message.clone()).await;
+        let retry = send(&chat, alice, message).await;
+        assert_eq!(first, retry);
+
+        let messages = list_messages(&chat, bob, room_uuid).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].uuid, Some(first.into()));
+        assert_eq!(messages[0].sequence, 1);
+        assert_eq!(messages[0].mentions.len(), 1);
+        assert_eq!(messages[0].mentions[0].user_uuid, Some(bob.into()));
+    }
+
+    #[tokio::test]
+    async fn pinned_messages_are_listed_until_unpinned() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+        let alice = register(&repository, "alice").await;
+        let room_uuid = create_room(&chat, alice, &[alice]).await;
+        let message_uuid = send(&chat, alice, clientside_message(room_uuid, "pin me")).await;
+
+        let pin = PinRequest {
+            room_uuid: Some(room_uuid.into()),
+            message_uuid: Some(message_uuid.into()),
         };
+        chat.pin_message(request(alice, pin.clone())).await.unwrap();
+        chat.pin_message(request(alice, pin.clone())).await.unwrap();
+
+        let list = || chat.list_pinned_messages(request(alice, proto::Uuid::from(room_uuid)));
+        let pins = list().await.unwrap().into_inner().pins;
+        assert_eq!(pins.len(), 1);
+        assert_eq!(
+            pins[0].message.as_ref().and_then(|m| m.uuid.clone()),
+            Some(message_uuid.into())
+        );
 
-        let token = CancellationToken::new();
-        let token_clone = token.clone();
+        chat.unpin_message(request(alice, pin.clone()))
+            .await
+            .unwrap();
+        assert!(list().await.unwrap().into_inner().pins.is_empty());
+        let status = chat.unpin_message(request(alice, pin)).await.unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
 
-        // Spawn the "canceller" thread.
-        tokio::spawn(async move {
-            let _ = disconnect_rx.await;
-            tracing::debug!(message = "Client disconnected, stopping user event streaming");
-            token.cancel();
-        });
+    #[tokio::test]
+    async fn only_the_pinner_and_the_author_can_unpin() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+        let (alice, bob, carol, mallory) = (
+            register(&repository, "alice").await,
+            register(&repository, "bob").await,
+            register(&repository, "carol").await,
+            register(&repository, "mallory").await,
+        );
+        let room_uuid = create_room(&chat, alice, &[alice, bob, carol]).await;
+        let pin = |message_uuid: Uuid| PinRequest {
+            room_uuid: Some(room_uuid.into()),
+            message_uuid: Some(message_uuid.into()),
+        };
 
-        // Spawn the "streamer" thread.
-        tokio::spawn(async move {
-            tokio::select! {
-                _ = token_clone.cancelled() => {}
-                _ = streaming_closure => {}
-            }
-        });
+        // Bob pins two of Alice's messages.
+        let mut pinned = vec![];
+        for text in ["first", "second"] {
+            let message_uuid = send(&chat, alice, clientside_message(room_uuid, text)).await;
+            chat.pin_message(request(bob, pin(message_uuid)))
+                .await
+                .unwrap();
+            pinned.push(message_uuid);
+        }
 
-        Ok(Response::new(disconnect_channel))
+        for outsider in [carol, mallory] {
+            let status = chat
+                .unpin_message(request(outsider, pin(pinned[0])))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), Code::PermissionDenied);
+        }
+
+        chat.unpin_message(request(bob, pin(pinned[0])))
+            .await
+            .unwrap();
+        chat.unpin_message(request(alice, pin(pinned[1])))
+            .await
+            .unwrap();
+        let pins = chat
+            .list_pinned_messages(request(alice, proto::Uuid::from(room_uuid)))
+            .await
+            .unwrap();
+        assert!(pins.into_inner().pins.is_empty());
     }
 
-    #[instrument(skip_all, fields(user_uuid, room_uuid))]
-    async fn analyze_room(
-        &self,
-        request: Request<proto::Uuid>,
-    ) -> Result<Response<proto::RoomAnalysisResponse>, Status> {
-        let req_originator: Uuid = request
-            .get_originator_uuid()
-            .expect("The authenticator should not let anonymous requests through");
-        tracing::Span::current().record("user_uuid", req_originator.to_string());
+    #[tokio::test]
+    async fn scheduled_messages_are_delivered_once_due() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+        let alice = register(&repository, "alice").await;
+        let room_uuid = create_room(&chat, alice, &[alice]).await;
 
-        let req_room_uuid: Uuid = request
+        let later = |text| ClientsideMessage {
+            deliver_at: Some((SystemTime::now() + Duration::from_secs(60)).into()),
+            ..clientside_message(room_uuid, text)
+        };
+        let delivered = send(&chat, alice, later("deliver me")).await;
+        let cancelled = send(&chat, alice, later("cancel me")).await;
+
+        let pending = chat
+            .list_scheduled_messages(request(alice, ()))
+            .await
+            .unwrap()
             .into_inner()
-            .try_into()
-            .map_err(|_| Status::invalid_argument("Invalid room UUID"))?;
-        tracing::Span::current().record("room_uuid", req_room_uuid.to_string());
+            .messages;
+        assert_eq!(pending.len(), 2);
+        assert!(list_messages(&chat, alice, room_uuid).await.is_empty());
 
-        if !self
-            .check_room_membership(&req_originator, &req_room_uuid)
-            .await?
-        {
-            tracing::warn!(message = "User tried to LLM-analyze a room he's not a member of");
-            return Err(Status::permission_denied(
-                "You're not a member of this room",
-            ));
-        }
+        chat.cancel_scheduled_message(request(alice, proto::Uuid::from(cancelled)))
+            .await
+            .unwrap();
+        repository
+            .postpone(delivered, SystemTime::UNIX_EPOCH)
+            .await
+            .unwrap();
+        assert_eq!(chat.deliver_due_messages().await, None);
 
-        tracing::trace!(message = "Collecting messages for an LLM analysis");
+        let messages = list_messages(&chat, alice, room_uuid).await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].uuid, Some(delivered.into()));
+    }
+
+    #[tokio::test]
+    async fn searches_only_cover_member_rooms() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+        let (alice, bob) = (
+            register(&repository, "alice").await,
+            register(&repository, "bob").await,
+        );
+        let shared = create_room(&chat, alice, &[alice, bob]).await;
+        let private = create_room(&chat, alice, &[alice]).await;
+        let visible = send(&chat, alice, clientside_message(shared, "Deploy on Friday")).await;
+        send(&chat, alice, clientside_message(private, "deploy secrets")).await;
+        send(&chat, alice, clientside_message(shared, "lunch?")).await;
+
+        let search = MessageSearchRequest {
+            query: "deploy".into(),
+            ..Default::default()
+        };
+        let hits = chat
+            .search_messages(request(bob, search))
+            .await
+            .unwrap()
+            .into_inner()
+            .hits;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(
+            hits[0].message.as_ref().and_then(|m| m.uuid.clone()),
+            Some(visible.into())
+        );
+    }
 
-        let mut db = self.acquire_database_connection().await?;
+    #[tokio::test]
+    async fn missed_messages_are_replayed_after_the_cursor() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+        let alice = register(&repository, "alice").await;
+        let room_uuid = create_room(&chat, alice, &[alice]).await;
+        let seen = send(&chat, alice, clientside_message(room_uuid, "seen")).await;
+        let missed = send(&chat, alice, clientside_message(room_uuid, "missed")).await;
+
+        let replay = |resume_after| {
+            let subscription = RoomSubscription {
+                room_uuid,
+                resume_after: Some(resume_after),
+            };
+            Chat::load_missed_messages(&chat.repositories, subscription)
+        };
+        let replayed = replay(ResumeCursor::Message(seen)).await.unwrap();
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(replayed[0].uuid, Some(missed.into()));
+        assert_eq!(replay(ResumeCursor::Sequence(0)).await.unwrap().len(), 2);
 
-        let messages: Vec<Message> = {
-            use crate::entities::schema::messages::dsl::*;
-            use diesel::prelude::*;
+        let status = replay(ResumeCursor::Message(Uuid::new_v4()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
 
-            messages
-                .filter(room_uuid.eq(room_uuid))
-                .select(Message::as_select())
-                .load::<Message>(&mut db)
-                .map_err(|error| {
-                    let message = "Couldn't load messages from the database";
-                    tracing::error!(message = message, ?error);
-                    Status::internal(message)
-                })?
+    #[tokio::test]
+    async fn live_messages_are_streamed_out_of_order_but_once() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+        let alice = register(&repository, "alice").await;
+        let room_uuid = create_room(&chat, alice, &[alice]).await;
+        send(&chat, alice, clientside_message(room_uuid, "first")).await;
+        send(&chat, alice, clientside_message(room_uuid, "second")).await;
+
+        let (grpc_tx, mut grpc_rx) = tokio::sync::mpsc::channel(8);
+        let subscription = RoomSubscription {
+            room_uuid,
+            resume_after: Some(ResumeCursor::Sequence(0)),
         };
+        let streamer = chat
+            .stream_room(alice, subscription, grpc_tx, std::convert::identity)
+            .await
+            .unwrap();
+        tokio::spawn(streamer);
+
+        // The replayed second message arrives live too, then two messages committed out of order.
+        for sequence in [2, 4, 3] {
+            chat.deliver_room_event(proto::ServersideRoomEvent {
+                room_uuid: Some(room_uuid.into()),
+                event: Some(Event::NewMessage(proto::ServersideMessage {
+                    sequence,
+                    ..Default::default()
+                })),
+            });
+        }
 
-        let usernames: HashMap<Uuid, String> = messages
-            .iter()
-            .map(|message| message.sender_uuid)
-            .unique()
-            .map(|user_uuid| {
-                use crate::entities::schema::users::dsl::*;
-                use diesel::prelude::*;
-
-                (
-                    user_uuid,
-                    users
-                        .find(user_uuid)
-                        .select(username)
-                        .first(&mut db)
-                        .expect("Foreign key can't point to a non-existing primary key"),
-                )
-            })
-            .collect();
+        let mut sequences = vec![];
+        for _ in 0..4 {
+            match grpc_rx.recv().await.unwrap().unwrap().event {
+                Some(Event::NewMessage(message)) => sequences.push(message.sequence),
+                event => panic!("Unexpected event: {event:?}"),
+            }
+        }
+        assert_eq!(sequences, [1, 2, 4, 3]);
+    }
 
-        let formatted_messages: Vec<String> = messages
-            .into_iter()
-            .map(|message| {
-                let username = usernames
-                    .get(&message.sender_uuid)
-                    .cloned()
-                    .unwrap_or_default();
-                format!("\n{username}: {}", message.text)
-            })
-            .collect();
+    /// Rooms that store a message right after one of them is looked up, once.
+    #[derive(Debug)]
+    struct StoreAfterFind {
+        repository: Arc<InMemoryRepository>,
+        message: std::sync::Mutex<Option<Message>>,
+    }
 
-        // Connect to the running LLM.
-        let expect_message = "$LLM_HOST and $LLM_PORT should point to an LLM instance!";
-        let host = format!("http://{}", env::var("LLM_HOST").expect(expect_message));
-        let port = env::var("LLM_PORT")
-            .expect(expect_message)
-            .parse::<u16>()
-            .expect(expect_message);
-        let llm = Ollama::new(host, port);
+    #[tonic::async_trait]
+    impl RoomRepository for StoreAfterFind {
+        async fn find(&self, room_uuid: Uuid) -> RepositoryResult<Option<Room>> {
+            let room = RoomRepository::find(&*self.repository, room_uuid).await?;
+            let message = self.message.lock().unwrap().take();
+            if let Some(message) = message {
+                self.repository.store(message, vec![], None).await?;
+            }
+            Ok(room)
+        }
 
-        tracing::trace!(message = "Connected to LLM, forming a prompt");
+        async fn find_many(&self, room_uuids: Vec<Uuid>) -> RepositoryResult<Vec<Room>> {
+            self.repository.find_many(room_uuids).await
+        }
 
-        let model = "llama3".to_string();
-        let mut prompt = r#"
-            Hey. Here are a bunch of messages from a chat room, along with the user's names.
-            I want you to analyze the messages and figure out what topics were discussed, as
-            well as look for pattterns in the messages and provide insignt into the users'
-            mood and intentions. Provide additional information about the same things the users
-            were talking about. Your response will be used as a mean of improving the users'
-            experience, and also be a part of a real-time behavioral analysis. Stay concise,
-            go straight to the point, no introductions. Here are the messages:
-        "#
-        .to_string();
+        async fn create(&self, room: Room, member_uuids: Vec<Uuid>) -> RepositoryResult<Vec<Uuid>> {
+            RoomRepository::create(&*self.repository, room, member_uuids).await
+        }
 
-        for message in formatted_messages.iter() {
-            prompt.push_str(message);
+        async fn set_message_ttl(
+            &self,
+            room_uuid: Uuid,
+            ttl: Option<Duration>,
+        ) -> RepositoryResult<()> {
+            self.repository.set_message_ttl(room_uuid, ttl).await
         }
 
-        tracing::trace!(message = "Awaiting LLM response...");
+        async fn members(&self, room_uuid: Uuid) -> RepositoryResult<Vec<Uuid>> {
+            self.repository.members(room_uuid).await
+        }
 
-        let response = llm
-            .generate(GenerationRequest::new(model, prompt))
-            .await
-            .map_err(|error| {
-                let message = "Local LLM returned an error";
-                tracing::error!(message = message, ?error);
-                Status::internal(message)
-            })?
-            .response;
+        async fn members_of_many(&self, room_uuids: Vec<Uuid>) -> RepositoryResult<Vec<Vec<Uuid>>> {
+            self.repository.members_of_many(room_uuids).await
+        }
 
-        tracing::info!(message = "LLM analysis complete");
+        async fn rooms_of(&self, user_uuid: Uuid) -> RepositoryResult<Vec<Uuid>> {
+            self.repository.rooms_of(user_uuid).await
+        }
 
-        Ok(Response::new(proto::RoomAnalysisResponse { response }))
-    }
-}
+        async fn is_member(&self, room_uuid: Uuid, user_uuid: Uuid) -> RepositoryResult<bool> {
+            self.repository.is_member(room_uuid, user_uuid).await
+        }
 
-impl Chat {
-    const INTERNAL_CHANNEL_CAPACITY: usize = 16;
+        async fn neighbours(&self, user_uuid: Uuid) -> RepositoryResult<Vec<Uuid>> {
+            self.repository.neighbours(user_uuid).await
+        }
+    }
 
-    #[expect(
-        dependency_on_unit_never_type_fallback,
-        reason = "What on earth is this warning"
-    )]
-    pub async fn new(persistence_pool: persistence::ConnectionPool) -> RedisResult<Self> {
-        let cache_client = Client::open(env::var("KV_URL").expect("Could not read $KV_URL"))?;
-        let mut cache = cache_client.get_multiplexed_async_connection().await?;
+    #[tokio::test]
+    async fn messages_stored_while_subscribing_are_streamed() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let alice = register(&repository, "alice").await;
+        let room_uuid = create_room(&chat(&repository), alice, &[alice]).await;
+        let rooms = Arc::new(StoreAfterFind {
+            repository: repository.clone(),
+            message: std::sync::Mutex::new(Some(Message::new("racing", alice, room_uuid))),
+        });
+        let chat = Chat::with_cache_client(
+            Repositories {
+                rooms,
+                ..Repositories::from_shared(repository.clone())
+            },
+            Arc::new(LocalBlobStore::new(std::env::temp_dir())),
+            StreamingConfig::default(),
+            None,
+        );
 
-        // Flush the membership cache.
-        let _: () = redis::cmd("FLUSHALL")
-            .query_async(&mut cache)
+        let (grpc_tx, mut grpc_rx) = tokio::sync::mpsc::channel(8);
+        let subscription = RoomSubscription {
+            room_uuid,
+            resume_after: None,
+        };
+        let streamer = chat
+            .stream_room(alice, subscription, grpc_tx, std::convert::identity)
             .await
-            .map_err(|error| {
-                tracing::error!(message = "Could not flush the membership cache", ?error);
-                error
-            })?;
-
-        // Acquire a connection to the database.
-        let mut db = persistence_pool
-            .get()
-            .expect("Couldn't acquire a database connection");
-
-        use crate::entities::schema::rooms_users::dsl::*;
-        use crate::entities::schema::users::dsl::*;
-        use diesel::prelude::*;
-
-        // Get a list of registered users.
-        let user_uuids = users
-            .select(uuid)
-            .load::<Uuid>(&mut db)
-            .unwrap_or_else(|err| {
-                tracing::error!(message = "Could not get a list of users from the DB", ?err);
-                vec![]
+            .unwrap();
+        tokio::spawn(streamer);
+
+        // The message is stored right after the room's history is read, and published late.
+        for sequence in [1, 2] {
+            chat.deliver_room_event(proto::ServersideRoomEvent {
+                room_uuid: Some(room_uuid.into()),
+                event: Some(Event::NewMessage(proto::ServersideMessage {
+                    sequence,
+                    ..Default::default()
+                })),
             });
+        }
 
-        // Set up the membership cache.
-        for user in user_uuids.iter() {
-            tracing::debug!(message = "Setting up membership cache", ?user);
-            let rooms: Vec<Uuid> = rooms_users
-                .filter(user_uuid.eq(user))
-                .select(room_uuid)
-                .load::<Uuid>(&mut db)
-                .unwrap_or_default();
-            for room in rooms.iter() {
-                cache.rpush(user, room).await?;
+        let mut sequences = vec![];
+        for _ in 0..2 {
+            match grpc_rx.recv().await.unwrap().unwrap().event {
+                Some(Event::NewMessage(message)) => sequences.push(message.sequence),
+                event => panic!("Unexpected event: {event:?}"),
             }
         }
+        assert_eq!(sequences, [1, 2]);
+    }
 
-        let (message_tx, _) = broadcast::channel(Self::INTERNAL_CHANNEL_CAPACITY);
-        let (user_event_tx, _) = broadcast::channel(Self::INTERNAL_CHANNEL_CAPACITY);
+    type SessionCommands = futures::channel::mpsc::UnboundedSender<Result<SessionCommand, Status>>;
+    type SessionEvents = DisconnectChannel<Result<SessionEvent, Status>>;
 
-        Ok(Self {
-            persistence_pool,
-            cache_client,
-            message_tx,
-            user_event_tx,
-        })
+    /// Open a session for `user`, returning the sender of its commands and its events.
+    async fn connect(chat: &Chat, user: Uuid) -> (SessionCommands, SessionEvents) {
+        let (commands_tx, commands_rx) = futures::channel::mpsc::unbounded();
+        let metadata = request(user, ()).metadata().clone();
+        let events = chat.session(user, metadata, commands_rx).await;
+        (commands_tx, events)
     }
 
-    #[instrument(skip_all)]
-    async fn acquire_database_connection(
-        &self,
-    ) -> Result<PooledConnection<ConnectionManager<PgConnection>>, Status> {
-        let db_connection = self.persistence_pool.get().map_err(|error| {
-            let msg = "Couldn't acquire a database connection";
-            tracing::error!(message = msg, ?error);
-            Status::internal(msg)
-        })?;
+    fn command(commands_tx: &SessionCommands, command: Command) {
+        commands_tx
+            .unbounded_send(Ok(SessionCommand {
+                command: Some(command),
+            }))
+            .unwrap();
+    }
 
-        Ok(db_connection)
+    fn subscription(room_uuid: Uuid) -> Command {
+        Command::Subscribe(proto::RoomSubscriptionRequest {
+            room_uuid: Some(room_uuid.into()),
+            resume_after: None,
+        })
     }
 
-    #[instrument(skip_all)]
-    async fn acquire_cache_connection(&self) -> Result<MultiplexedConnection, Status> {
-        let cache_connection = self
-            .cache_client
-            .get_multiplexed_async_connection()
-            .await
-            .map_err(|error| {
-                let msg = "Couldn't acquire a cache connection";
-                tracing::error!(message = msg, ?error);
-                Status::internal(msg)
-            })?;
+    fn typing(room_uuid: Option<Uuid>) -> Command {
+        Command::Typing(proto::TypingRequest {
+            room_uuid: room_uuid.map(Into::into),
+            typing: true,
+        })
+    }
 
-        Ok(cache_connection)
+    /// Wait until every command sent before has been handled, by sending one that fails.
+    async fn handled(commands_tx: &SessionCommands, events: &mut SessionEvents) {
+        command(commands_tx, typing(None));
+        match next_session_event(events).await {
+            SessionEventKind::CommandRejected(rejected) => {
+                assert_eq!(rejected.code, i32::from(Code::InvalidArgument));
+            }
+            event => panic!("Unexpected event: {event:?}"),
+        }
     }
 
-    #[instrument]
-    async fn check_room_membership(&self, user: &Uuid, room: &Uuid) -> Result<bool, Status> {
-        let mut cache = self.acquire_cache_connection().await?;
-        let allowed_rooms: Vec<Uuid> = cache.lrange(user, 0, -1).await.unwrap_or_else(|error| {
-            tracing::error!(message = "Couldn't get membership from cache", ?error);
-            vec![]
-        });
+    /// The next event of a session that isn't a personal one.
+    async fn next_session_event(events: &mut SessionEvents) -> SessionEventKind {
+        loop {
+            let event = events.next().await.unwrap().unwrap().event.unwrap();
+            if !matches!(event, SessionEventKind::UserEvent(_)) {
+                return event;
+            }
+        }
+    }
 
-        Ok(allowed_rooms.contains(room))
+    /// The next room event of a session, with the room it's tagged with.
+    async fn next_room_event(events: &mut SessionEvents) -> (Uuid, Event) {
+        match next_session_event(events).await {
+            SessionEventKind::RoomEvent(event) => (
+                event.room_uuid.unwrap().try_into().unwrap(),
+                event.event.unwrap(),
+            ),
+            event => panic!("Unexpected event: {event:?}"),
+        }
     }
 
-    #[instrument(skip_all)]
-    async fn create_room(&self, clientside_room: ClientsideRoom) -> Result<Uuid, Status> {
-        let mut db_connection = self.acquire_database_connection().await?;
-        let mut cache_connection = self.acquire_cache_connection().await?;
+    #[tokio::test]
+    async fn sessions_push_events_of_subscribed_rooms_only() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+        let (alice, bob) = (
+            register(&repository, "alice").await,
+            register(&repository, "bob").await,
+        );
+        let first_room = create_room(&chat, alice, &[alice, bob]).await;
+        let second_room = create_room(&chat, alice, &[alice, bob]).await;
+        let (commands_tx, mut events) = connect(&chat, alice).await;
+        command(&commands_tx, subscription(first_room));
+        handled(&commands_tx, &mut events).await;
+
+        send(&chat, bob, clientside_message(second_room, "elsewhere")).await;
+        let message_uuid = send(&chat, bob, clientside_message(first_room, "here")).await;
+        match next_room_event(&mut events).await {
+            (room_uuid, Event::NewMessage(message)) => {
+                assert_eq!(room_uuid, first_room);
+                assert_eq!(message.text, "here");
+            }
+            event => panic!("Unexpected event: {event:?}"),
+        }
 
-        let user_uuids: Vec<Uuid> = clientside_room
-            .members
-            .into_iter()
-            .map(Uuid::try_from)
-            .unique() // Ensure we don't operate on the same user twice!
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|error| {
-                let error = error.to_string();
-                let message = format!("Some member UUIDs could not be converted: {error}");
-                Status::invalid_argument(message)
-            })?;
+        command(
+            &commands_tx,
+            Command::Ack(ReadMarkerRequest {
+                room_uuid: Some(first_room.into()),
+                message_uuid: Some(message_uuid.into()),
+            }),
+        );
+        match next_room_event(&mut events).await {
+            (room_uuid, Event::ReadReceipt(receipt)) => {
+                assert_eq!(room_uuid, first_room);
+                assert_eq!(receipt.user_uuid, Some(alice.into()));
+            }
+            event => panic!("Unexpected event: {event:?}"),
+        }
 
-        let room = Room::new(clientside_room.name);
-        let members: Vec<RoomUser> = user_uuids
-            .iter()
-            .map(|user_uuid| RoomUser {
-                room_uuid: room.uuid,
-                user_uuid: *user_uuid,
-            })
-            .collect();
+        command(&commands_tx, Command::Unsubscribe(first_room.into()));
+        command(&commands_tx, subscription(second_room));
+        handled(&commands_tx, &mut events).await;
 
-        // Store the room and members in the database.
-        {
-            use crate::entities::schema::rooms::dsl::*;
-            use crate::entities::schema::rooms_users::dsl::*;
-            use diesel::{insert_into, RunQueryDsl};
+        send(&chat, bob, clientside_message(first_room, "unheard")).await;
+        send(&chat, bob, clientside_message(second_room, "heard")).await;
+        match next_room_event(&mut events).await {
+            (room_uuid, Event::NewMessage(message)) => {
+                assert_eq!(room_uuid, second_room);
+                assert_eq!(message.text, "heard");
+            }
+            event => panic!("Unexpected event: {event:?}"),
+        }
+    }
 
-            let _ = insert_into(rooms)
-                .values(&room)
-                .execute(&mut db_connection)
-                .map_err(|error| {
-                    let message = "Could not save the room in the database";
-                    tracing::error!(message = message, ?error);
-                    Status::internal(message)
-                })?;
+    #[tokio::test]
+    async fn typing_over_a_session_is_pushed_to_other_sessions() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+        let (alice, bob) = (
+            register(&repository, "alice").await,
+            register(&repository, "bob").await,
+        );
+        let room_uuid = create_room(&chat, alice, &[alice, bob]).await;
+        let (alice_commands, _alice_events) = connect(&chat, alice).await;
+        let (bob_commands, mut bob_events) = connect(&chat, bob).await;
+        command(&bob_commands, subscription(room_uuid));
+        handled(&bob_commands, &mut bob_events).await;
+
+        command(&alice_commands, typing(Some(room_uuid)));
+        match next_room_event(&mut bob_events).await {
+            (typing_room_uuid, Event::Typing(indicator)) => {
+                assert_eq!(typing_room_uuid, room_uuid);
+                assert_eq!(indicator.user_uuid, Some(alice.into()));
+                assert!(indicator.typing);
+            }
+            event => panic!("Unexpected event: {event:?}"),
+        }
+    }
 
-            let _ = insert_into(rooms_users)
-                .values(&members)
-                .execute(&mut db_connection)
-                .map_err(|error| {
-                    let message = "Could not save the room's members in the database";
-                    tracing::error!(message = message, ?error);
-                    Status::internal(message)
-                })?;
+    #[tokio::test]
+    async fn failed_session_commands_dont_close_the_session() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+        let (alice, bob) = (
+            register(&repository, "alice").await,
+            register(&repository, "bob").await,
+        );
+        let others_room = create_room(&chat, bob, &[bob]).await;
+        let own_room = create_room(&chat, alice, &[alice]).await;
+        let (commands_tx, mut events) = connect(&chat, alice).await;
+
+        command(&commands_tx, subscription(others_room));
+        match next_session_event(&mut events).await {
+            SessionEventKind::CommandRejected(rejected) => {
+                assert_eq!(rejected.room_uuid, Some(others_room.into()));
+                assert_eq!(rejected.code, i32::from(Code::PermissionDenied));
+            }
+            event => panic!("Unexpected event: {event:?}"),
+        }
 
-            tracing::info!(message = "Created new room", members = ?user_uuids, uuid = ?room.uuid);
+        command(&commands_tx, subscription(own_room));
+        handled(&commands_tx, &mut events).await;
+        send(&chat, alice, clientside_message(own_room, "still here")).await;
+        match next_room_event(&mut events).await {
+            (room_uuid, Event::NewMessage(message)) => {
+                assert_eq!(room_uuid, own_room);
+                assert_eq!(message.text, "still here");
+            }
+            event => panic!("Unexpected event: {event:?}"),
         }
+    }
 
-        // Update the membership cache.
-        for user_uuid in user_uuids.into_iter() {
-            let _: () = cache_connection
-                .rpush(user_uuid, room.uuid)
-                .await
-                .map_err(|error| {
-                    let message = "Could not update membership cache";
-                    tracing::error!(message = message, ?error);
-                    Status::internal(message)
-                })?;
+    #[tokio::test]
+    async fn stopping_right_after_starting_to_type_is_announced_after_the_interval() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let chat = chat(&repository);
+        let (alice, bob) = (
+            register(&repository, "alice").await,
+            register(&repository, "bob").await,
+        );
+        let room_uuid = create_room(&chat, alice, &[alice, bob]).await;
+
+        let (grpc_tx, mut grpc_rx) = tokio::sync::mpsc::channel(8);
+        let subscription = RoomSubscription {
+            room_uuid,
+            resume_after: None,
+        };
+        let streamer = chat
+            .stream_room(bob, subscription, grpc_tx, std::convert::identity)
+            .await
+            .unwrap();
+        tokio::spawn(streamer);
 
-            let event = ServersideUserEvent {
-                user_uuid: Some(user_uuid.into()),
-                event: Some(Event::AddedToRoom(room.uuid.into())),
+        for typing in [true, false] {
+            let typing_request = proto::TypingRequest {
+                room_uuid: Some(room_uuid.into()),
+                typing,
             };
+            chat.set_typing(request(alice, typing_request))
+                .await
+                .unwrap();
+        }
 
-            match self.user_event_tx.send(event) {
-                Ok(recv_count) => tracing::trace!(message = "Broadcasting user event", ?recv_count),
-                Err(error) => {
-                    if self.user_event_tx.receiver_count() > 0 {
-                        tracing::error!(message = "Could not broadcast user event", ?error);
-                    } else {
-                        tracing::trace!(message = "No subscribers for user event");
-                    }
-                }
+        // The change is postponed by the rate limit, but not until the indicator would expire.
+        for typing in [true, false] {
+            let event = tokio::time::timeout(ANNOUNCEMENT_INTERVAL * 2, grpc_rx.recv())
+                .await
+                .expect("The change should be announced once the interval passes");
+            match event.unwrap().unwrap().event {
+                Some(Event::Typing(indicator)) => assert_eq!(indicator.typing, typing),
+                event => panic!("Unexpected event: {event:?}"),
             }
         }
+    }
 
-        tracing::info!(message = "Updated membership cache", room = ?room.uuid);
+    #[tokio::test]
+    async fn lagging_subscribers_recover_late_messages_and_resync_the_rest() {
+        let repository = Arc::new(InMemoryRepository::new());
+        let config = StreamingConfig {
+            channel_capacity: 2,
+            slow_consumer_policy: SlowConsumerPolicy::Recover,
+        };
+        let chat = chat_with(&repository, config);
+        let (alice, bob) = (
+            register(&repository, "alice").await,
+            register(&repository, "bob").await,
+        );
+        let room_uuid = create_room(&chat, alice, &[alice, bob]).await;
 
-        Ok(room.uuid)
+        let (grpc_tx, mut grpc_rx) = tokio::sync::mpsc::channel(8);
+        let subscription = RoomSubscription {
+            room_uuid,
+            resume_after: None,
+        };
+        let streamer = chat
+            .stream_room(alice, subscription, grpc_tx, std::convert::identity)
+            .await
+            .unwrap();
+        tokio::spawn(streamer);
+
+        // Both messages are stored, but only the later one is published before the lag.
+        let mut stored = vec![];
+        for text in ["late", "early"] {
+            let message = Message::new(text, bob, room_uuid);
+            match chat
+                .repositories
+                .messages
+                .store(message, vec![], None)
+                .await
+            {
+                Ok(Deduplicated::Stored((message, _))) => stored.push(message),
+                _ => panic!("The message should be stored"),
+            }
+        }
+        let live = |event| proto::ServersideRoomEvent {
+            room_uuid: Some(room_uuid.into()),
+            event: Some(event),
+        };
+        chat.deliver_room_event(live(Event::NewMessage(proto::ServersideMessage {
+            sequence: 2,
+            ..Default::default()
+        })));
+        match grpc_rx.recv().await.unwrap().unwrap().event {
+            Some(Event::NewMessage(message)) => assert_eq!(message.sequence, 2),
+            event => panic!("Unexpected event: {event:?}"),
+        }
+
+        // The pin and the first typing indicator are skipped.
+        chat.deliver_room_event(live(Event::MessagePinned(Default::default())));
+        for typing in [true, false, true] {
+            chat.deliver_room_event(typing_event(room_uuid, bob, typing));
+        }
+
+        match grpc_rx.recv().await.unwrap().unwrap().event {
+            Some(Event::NewMessage(message)) => {
+                assert_eq!(message.uuid, Some(stored[0].uuid.into()));
+                assert_eq!(message.sequence, 1);
+            }
+            event => panic!("Unexpected event: {event:?}"),
+        }
+        match grpc_rx.recv().await.unwrap().unwrap().event {
+            Some(Event::ResyncRequired(resync)) => assert_eq!(resync.skipped_events, 1),
+            event => panic!("Unexpected event: {event:?}"),
+        }
+        for typing in [false, true] {
+            match grpc_rx.recv().await.unwrap().unwrap().event {
+                Some(Event::Typing(indicator)) => assert_eq!(indicator.typing, typing),
+                event => panic!("Unexpected event: {event:?}"),
+            }
+        }
     }
 }
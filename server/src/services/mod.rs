@@ -1,6 +1,7 @@
 pub mod chat;
 pub mod registry;
 
+use crate::repositories::RepositoryError;
 use std::error::Error;
 
 pub fn acquire_connection_error_status<E: Error>(error: E) -> tonic::Status {
@@ -8,3 +9,14 @@ pub fn acquire_connection_error_status<E: Error>(error: E) -> tonic::Status {
     tracing::error!(message = message, ?error);
     tonic::Status::internal(message)
 }
+
+pub fn repository_error_status(error: RepositoryError) -> tonic::Status {
+    match error {
+        RepositoryError::Persistence(error) => acquire_connection_error_status(error),
+        RepositoryError::Query(error) => {
+            let message = "Database query failed";
+            tracing::error!(message = message, ?error);
+            tonic::Status::internal(message)
+        }
+    }
+}
@@ -1,6 +1,7 @@
-use crate::entities::User;
-use crate::persistence::ConnectionPool;
+use crate::entities::{username, User};
 use crate::proto::{self, AuthPair, UserCredentials};
+use crate::repositories::UserRepository;
+use crate::services::repository_error_status;
 use rand_chacha::ChaCha20Rng;
 use rand_core::{OsRng, RngCore, SeedableRng};
 use std::sync::Arc;
@@ -10,15 +11,15 @@ use tracing::instrument;
 
 #[derive(Debug)]
 pub struct Registry {
-    persistence_pool: ConnectionPool,
+    users: Arc<dyn UserRepository>,
     rng: Arc<Mutex<ChaCha20Rng>>,
 }
 
 impl Registry {
-    pub fn with_persistence_pool(persistence_pool: ConnectionPool) -> Self {
+    pub fn new(users: Arc<dyn UserRepository>) -> Self {
         let rng = ChaCha20Rng::seed_from_u64(OsRng.next_u64());
         Self {
-            persistence_pool,
+            users,
             rng: Arc::new(Mutex::new(rng)),
         }
     }
@@ -31,61 +32,48 @@ impl proto::registry_server::Registry for Registry {
         &self,
         request: Request<UserCredentials>,
     ) -> Result<Response<()>, Status> {
-        let mut connection = self
-            .persistence_pool
-            .get()
-            .map_err(|_| Status::internal("Database pool error"))?;
+        let mut credentials = request.into_inner();
+        credentials.username = username::normalize(&credentials.username).map_err(|error| {
+            tracing::warn!(message = "Invalid username", username = ?credentials.username, %error);
+            Status::invalid_argument(error.to_string())
+        })?;
 
-        // Import some traits and methods to interact with the ORM.
-        use crate::entities::schema::users::dsl::*;
-        use diesel::prelude::*;
+        // Hash the password using Blake3 hash function.
+        #[cfg(not(feature = "streebog"))]
+        {
+            credentials.password = blake3::hash(credentials.password.as_bytes()).to_string();
+        }
 
-        let mut credentials = request.into_inner();
-        let duplicate_user = users
-            .filter(username.eq(&credentials.username))
-            .select(User::as_select())
-            .first(&mut connection)
-            .optional()
-            .map_err(|err| Status::internal(err.to_string()))?;
-
-        match duplicate_user {
-            // No duplicate usernames found, registering a new account.
-            None => {
-                // Hash the password using Blake3 hash function.
-                #[cfg(not(feature = "streebog"))]
-                {
-                    credentials.password =
-                        blake3::hash(credentials.password.as_bytes()).to_string();
-                }
-
-                // Hash the password using GOST 34.11-2012 hash function.
-                //
-                // - Reference:      https://en.wikipedia.org/wiki/Streebog
-                // - Implementation: https://docs.rs/streebog/latest/streebog/index.html
-                #[cfg(feature = "streebog")]
-                {
-                    use streebog::{Digest, Streebog256};
-                    let mut hasher = Streebog256::new();
-                    hasher.update(credentials.password.as_str());
-                    credentials.password = hex::encode(hasher.finalize());
-                }
-
-                let mut rng = self.rng.lock().await;
-                let user = User::new(credentials.username.clone(), credentials.password, &mut rng);
-                let _ = diesel::insert_into(users)
-                    .values(&user)
-                    .execute(&mut connection)
-                    .map_err(|err| Status::internal(err.to_string()))?;
-                tracing::info!(message = "Registered new user", username = ?credentials.username);
-                Ok(Response::new(()))
-            }
+        // Hash the password using GOST 34.11-2012 hash function.
+        //
+        // - Reference:      https://en.wikipedia.org/wiki/Streebog
+        // - Implementation: https://docs.rs/streebog/latest/streebog/index.html
+        #[cfg(feature = "streebog")]
+        {
+            use streebog::{Digest, Streebog256};
+            let mut hasher = Streebog256::new();
+            hasher.update(credentials.password.as_str());
+            credentials.password = hex::encode(hasher.finalize());
+        }
 
-            // A user with a matching username was found, refuse to register.
-            Some(_) => {
-                let msg = "Such user already exists";
-                tracing::warn!(message = msg, username = ?credentials.username);
-                Err(Status::already_exists(msg))
-            }
+        let user = {
+            let mut rng = self.rng.lock().await;
+            User::new(credentials.username.clone(), credentials.password, &mut rng)
+        };
+
+        let registered = self
+            .users
+            .create(user)
+            .await
+            .map_err(repository_error_status)?;
+
+        if registered {
+            tracing::info!(message = "Registered new user", username = ?credentials.username);
+            Ok(Response::new(()))
+        } else {
+            let msg = "Such user already exists";
+            tracing::warn!(message = msg, username = ?credentials.username);
+            Err(Status::already_exists(msg))
         }
     }
 
@@ -94,16 +82,6 @@ impl proto::registry_server::Registry for Registry {
         &self,
         request: Request<UserCredentials>,
     ) -> Result<Response<AuthPair>, Status> {
-        let mut connection = self
-            .persistence_pool
-            .get()
-            .map_err(|_| Status::internal("Database pool error"))?;
-
-        // Import some traits and methods to interact with the ORM.
-        use crate::entities::schema::users::dsl::*;
-        use diesel::query_dsl::methods::{FilterDsl, SelectDsl};
-        use diesel::{ExpressionMethods, OptionalExtension, RunQueryDsl, SelectableHelper};
-
         let mut credentials = request.into_inner();
 
         // Hash the password using Blake3 hash function.
@@ -124,13 +102,11 @@ impl proto::registry_server::Registry for Registry {
             credentials.password = hex::encode(hasher.finalize());
         }
 
-        let candidate_user = users
-            .filter(username.eq(&credentials.username))
-            .filter(password.eq(&credentials.password))
-            .select(User::as_select())
-            .first(&mut connection)
-            .optional()
-            .map_err(|err| Status::internal(err.to_string()))?;
+        let candidate_user = self
+            .users
+            .find_by_credentials(credentials.username.clone(), credentials.password)
+            .await
+            .map_err(repository_error_status)?;
 
         match candidate_user {
             // A an account with matching credentials exist, returns its UUID and token.
@@ -148,3 +124,95 @@ impl proto::registry_server::Registry for Registry {
         }
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::Registry;
+    use crate::proto::registry_server::Registry as _;
+    use crate::proto::UserCredentials;
+    use crate::repositories::InMemoryRepository;
+    use std::sync::Arc;
+    use tonic::{Code, Request};
+
+    fn credentials(username: &str, password: &str) -> Request<UserCredentials> {
+        Request::new(UserCredentials {
+            username: username.into(),
+            password: password.into(),
+        })
+    }
+
+    #[tokio::test]
+    async fn usernames_can_only_be_registered_once() {
+        let registry = Registry::new(Arc::new(InMemoryRepository::new()));
+
+        registry
+            .register_new_user(credentials("alice", "secret"))
+            .await
+            .unwrap();
+        let status = registry
+            .register_new_user(credentials("alice", "other"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+    }
+
+    #[tokio::test]
+    async fn lookalike_usernames_are_taken() {
+        let registry = Registry::new(Arc::new(InMemoryRepository::new()));
+        registry
+            .register_new_user(credentials("alice", "secret"))
+            .await
+            .unwrap();
+
+        for lookalike in ["Alice", " ALICE ", "аlice", "ａｌｉｃｅ"] {
+            let status = registry
+                .register_new_user(credentials(lookalike, "secret"))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), Code::AlreadyExists, "{lookalike}");
+        }
+
+        let auth_pair = registry
+            .login_as_user(credentials("ALICE", "secret"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(auth_pair.user_uuid.is_some());
+    }
+
+    #[tokio::test]
+    async fn invalid_usernames_are_rejected() {
+        let registry = Registry::new(Arc::new(InMemoryRepository::new()));
+
+        for invalid in ["", "   ", "ali\u{200B}ce", &"a".repeat(65)] {
+            let status = registry
+                .register_new_user(credentials(invalid, "secret"))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument, "{invalid:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn login_requires_the_right_password() {
+        let registry = Registry::new(Arc::new(InMemoryRepository::new()));
+        registry
+            .register_new_user(credentials("alice", "secret"))
+            .await
+            .unwrap();
+
+        let auth_pair = registry
+            .login_as_user(credentials("alice", "secret"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(auth_pair.user_uuid.is_some());
+
+        let status = registry
+            .login_as_user(credentials("alice", "wrong"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+    }
+}
@@ -0,0 +1,155 @@
+use super::{BlobReader, BlobStore, BlobStoreError, BlobWriter};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+/// A [`BlobStore`] that keeps blobs as files in a local directory.
+///
+/// Blobs are sharded into subdirectories by the first two characters of their
+/// hash, so that no single directory ends up with millions of entries.
+#[derive(Debug, Clone)]
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn blob_path(&self, hash: &blake3::Hash) -> PathBuf {
+        let hex = hash.to_hex();
+        self.root.join(&hex[..2]).join(hex.as_str())
+    }
+}
+
+#[tonic::async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn writer(&self) -> Result<Box<dyn BlobWriter>, BlobStoreError> {
+        fs::create_dir_all(&self.root).await?;
+
+        // Write into a temporary file first, so that a half-written blob
+        // never ends up under its hash if the server dies mid-upload.
+        let temporary_path = self.root.join(format!("{}.tmp", Uuid::new_v4()));
+        let file = fs::File::create(&temporary_path).await?;
+
+        Ok(Box::new(LocalBlobWriter {
+            store: self.clone(),
+            file,
+            temporary_path,
+            hasher: blake3::Hasher::new(),
+            finished: false,
+        }))
+    }
+
+    async fn reader(&self, hash: &blake3::Hash) -> Result<BlobReader, BlobStoreError> {
+        match fs::File::open(self.blob_path(hash)).await {
+            Ok(file) => Ok(Box::pin(file)),
+            Err(error) if error.kind() == ErrorKind::NotFound => Err(BlobStoreError::NotFound),
+            Err(error) => Err(BlobStoreError::Io(error)),
+        }
+    }
+}
+
+/// A [`BlobWriter`] for a [`LocalBlobStore`].
+struct LocalBlobWriter {
+    store: LocalBlobStore,
+    file: fs::File,
+    temporary_path: PathBuf,
+    hasher: blake3::Hasher,
+    finished: bool,
+}
+
+#[tonic::async_trait]
+impl BlobWriter for LocalBlobWriter {
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), BlobStoreError> {
+        self.hasher.update(chunk);
+        self.file.write_all(chunk).await?;
+        Ok(())
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<blake3::Hash, BlobStoreError> {
+        self.file.flush().await?;
+
+        let hash = self.hasher.finalize();
+        let path = self.store.blob_path(&hash);
+        if fs::try_exists(&path).await? {
+            tracing::trace!(message = "Blob is already stored", %hash);
+            fs::remove_file(&self.temporary_path).await?;
+        } else {
+            if let Some(shard) = path.parent() {
+                fs::create_dir_all(shard).await?;
+            }
+            fs::rename(&self.temporary_path, &path).await?;
+            tracing::debug!(message = "Stored new blob", %hash, size = self.hasher.count());
+        }
+
+        self.finished = true;
+        Ok(hash)
+    }
+}
+
+impl Drop for LocalBlobWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            // Abandoned mid-upload, nothing refers to this file.
+            let _ = std::fs::remove_file(&self.temporary_path);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::LocalBlobStore;
+    use crate::storage::{BlobStore, BlobStoreError};
+    use tokio::io::AsyncReadExt;
+    use uuid::Uuid;
+
+    async fn write_blob(store: &LocalBlobStore, chunks: &[&[u8]]) -> blake3::Hash {
+        let mut writer = store.writer().await.unwrap();
+        for chunk in chunks {
+            writer.write(chunk).await.unwrap();
+        }
+        writer.finish().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let root = std::env::temp_dir().join(format!("tcp-chat-blobs-{}", Uuid::new_v4()));
+        let store = LocalBlobStore::new(&root);
+        let content = b"deploy checklist";
+        let hash = blake3::hash(content);
+
+        assert!(matches!(
+            store.reader(&hash).await,
+            Err(BlobStoreError::NotFound)
+        ));
+        assert_eq!(write_blob(&store, &[b"deploy ", b"checklist"]).await, hash);
+        assert_eq!(write_blob(&store, &[content]).await, hash); // Deduplicated.
+
+        let mut stored = vec![];
+        let mut reader = store.reader(&hash).await.unwrap();
+        reader.read_to_end(&mut stored).await.unwrap();
+        assert_eq!(stored, content);
+
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn abandoned_writes_leave_nothing_behind() {
+        let root = std::env::temp_dir().join(format!("tcp-chat-blobs-{}", Uuid::new_v4()));
+        let store = LocalBlobStore::new(&root);
+
+        let mut writer = store.writer().await.unwrap();
+        writer.write(b"half of a").await.unwrap();
+        drop(writer);
+
+        let mut entries = tokio::fs::read_dir(&root).await.unwrap();
+        assert!(entries.next_entry().await.unwrap().is_none());
+
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+//! # Blob storage
+//!
+//! Attachment contents are kept outside of the database, in a [`BlobStore`].
+//! Blobs are content-addressed by their Blake3 hash, so uploading the same
+//! file twice (even to different rooms) only stores its contents once. The
+//! metadata (who uploaded what, where and under which name) lives in Postgres.
+//!
+//! Blobs are streamed in and out rather than held in memory whole: a
+//! [`BlobWriter`] hashes every chunk as it is written and only files the blob
+//! under its hash once it's complete, and reads hand out a [`BlobReader`].
+
+mod local;
+
+pub use local::LocalBlobStore;
+
+use infer::MatcherType;
+use std::fmt;
+use std::pin::Pin;
+use tokio::io::AsyncRead;
+
+/// The largest attachment the server accepts.
+pub const MAX_BLOB_SIZE: usize = 16 * 1024 * 1024;
+
+/// How much of the beginning of a blob [`sniff_mime_type`] needs to look at.
+pub const SNIFF_LENGTH: usize = 64 * 1024;
+
+/// MIME types that a client may declare for contents that don't look like any
+/// known format. Anything else (most importantly, `text/html` and friends,
+/// which browsers would happily render) is served as an opaque blob instead.
+const SAFE_DECLARED_MIME_TYPES: &[&str] = &[
+    "text/plain",
+    "text/csv",
+    "text/markdown",
+    "text/tab-separated-values",
+    "application/json",
+];
+
+#[derive(thiserror::Error, Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub enum BlobStoreError {
+    #[error("No blob with such hash")]
+    NotFound,
+    #[error("Blob storage I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// The contents of a stored blob, read incrementally.
+pub type BlobReader = Pin<Box<dyn AsyncRead + Send>>;
+
+/// A place to keep attachment contents in.
+#[tonic::async_trait]
+pub trait BlobStore: fmt::Debug + Send + Sync {
+    /// Start writing a new blob. Its hash is only known once it's finished.
+    async fn writer(&self) -> Result<Box<dyn BlobWriter>, BlobStoreError>;
+
+    /// Read a blob by its hash.
+    async fn reader(&self, hash: &blake3::Hash) -> Result<BlobReader, BlobStoreError>;
+}
+
+/// A blob that is being written into a [`BlobStore`].
+///
+/// Dropping the writer without [finishing](BlobWriter::finish) it discards
+/// everything written so far.
+#[tonic::async_trait]
+pub trait BlobWriter: Send {
+    /// Append a chunk to the blob.
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), BlobStoreError>;
+
+    /// Store the blob under its hash and return the hash. Does not store
+    /// anything new if such a blob is already stored.
+    async fn finish(self: Box<Self>) -> Result<blake3::Hash, BlobStoreError>;
+}
+
+/// Figure out the MIME type of a blob by looking at the first
+/// [`SNIFF_LENGTH`] bytes of its contents.
+///
+/// Clients may provide a MIME type when uploading, but that can't be trusted,
+/// so it's only used when the contents don't look like any known format, and
+/// only if it's one of the few types that are safe to serve as declared.
+#[must_use]
+pub fn sniff_mime_type(head: &[u8], declared: Option<&str>) -> String {
+    // Markup (HTML, XML, scripts) is just text to us, for the same reason
+    // `text/html` isn't accepted from clients.
+    if let Some(kind) = infer::get(head).filter(|kind| kind.matcher_type() != MatcherType::Text) {
+        return kind.mime_type().to_string();
+    }
+
+    // The head may well cut a multibyte character in half.
+    let is_text = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(error) => error.error_len().is_none(),
+    };
+
+    match declared {
+        Some(declared) if SAFE_DECLARED_MIME_TYPES.contains(&declared) => declared.to_string(),
+        _ if is_text => "text/plain".to_string(),
+        _ => "application/octet-stream".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sniff_mime_type;
+    use rstest::rstest;
+
+    const PNG_HEADER: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+    #[rstest]
+    #[case::known_format(PNG_HEADER, Some("text/plain"), "image/png")]
+    #[case::declared(b"col1,col2", Some("text/csv"), "text/csv")]
+    #[case::markup(b"<html><body></body></html>", None, "text/plain")]
+    #[case::unsafe_declared(b"<script>alert(1)</script>", Some("text/html"), "text/plain")]
+    #[case::unsafe_declared_binary(&[0xFF, 0xFE, 0x00, 0x80], Some("image/svg+xml"), "application/octet-stream")]
+    #[case::text(b"hello", None, "text/plain")]
+    #[case::truncated_text("naïve".as_bytes().split_at(3).0, None, "text/plain")]
+    #[case::binary(&[0xFF, 0xFE, 0x00, 0x80], None, "application/octet-stream")]
+    fn sniffing(#[case] content: &[u8], #[case] declared: Option<&str>, #[case] expected: &str) {
+        assert_eq!(sniff_mime_type(content, declared), expected);
+    }
+}
@@ -0,0 +1,257 @@
+//! # Event streaming
+//!
+//! Room and user events reach the `SubscribeTo*` streams through internal `broadcast`
+//! channels with a fixed capacity. A subscriber whose client reads slower than events
+//! arrive eventually falls more than that capacity behind, and the oldest events it
+//! hasn't seen yet are dropped for it (see [`tokio::sync::broadcast::error::RecvError::Lagged`]).
+//!
+//! ## Slow consumers
+//!
+//! What happens to such a subscriber is decided by the [`SlowConsumerPolicy`]:
+//!
+//! - [`Recover`](SlowConsumerPolicy::Recover) reloads the skipped messages from the
+//!   database. Only messages are persisted, so this is only possible for room streams;
+//!   if recovery fails (or there's nothing to recover from), the client is asked to resync.
+//!   Other room events (pins, deletions, read receipts) can't be reloaded, so if any of
+//!   them were skipped, the client is asked to resync after the messages are recovered.
+//! - [`Resync`](SlowConsumerPolicy::Resync) sends a typed `ResyncRequired` event and
+//!   leaves re-fetching the state to the client.
+//! - [`Disconnect`](SlowConsumerPolicy::Disconnect) ends the stream with an error.
+//!
+//! ## Configuration
+//!
+//! Both the capacity and the policy are read from the environment, see [`StreamingConfig::from_env`].
+//!
+//! ## Message bookkeeping
+//!
+//! Messages reach room subscribers live, replayed after a cursor or recovered after a lag,
+//! and the same message may arrive in more than one way. [`DeliveredSequences`] keeps track
+//! of which ones have been sent, so that each is sent once and recovery starts at the first
+//! one that hasn't been.
+
+use std::collections::BTreeSet;
+use std::env;
+use std::str::FromStr;
+
+/// What to do with a subscriber that has fallen too far behind.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// Reload skipped messages from the database, falling back to [`Self::Resync`].
+    #[default]
+    Recover,
+    /// Tell the client that it has missed events and should re-fetch its state.
+    Resync,
+    /// End the stream with an error.
+    Disconnect,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("Unknown slow consumer policy: {0:?}")]
+pub struct UnknownPolicy(String);
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = UnknownPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "recover" => Ok(Self::Recover),
+            "resync" => Ok(Self::Resync),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err(UnknownPolicy(s.to_string())),
+        }
+    }
+}
+
+/// How events are streamed to subscribers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamingConfig {
+    /// How many events the internal channels hold before slow subscribers start lagging.
+    pub channel_capacity: usize,
+    pub slow_consumer_policy: SlowConsumerPolicy,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self {
+            channel_capacity: 16,
+            slow_consumer_policy: SlowConsumerPolicy::default(),
+        }
+    }
+}
+
+impl StreamingConfig {
+    /// Read the configuration from `$EVENT_CHANNEL_CAPACITY` and `$SLOW_CONSUMER_POLICY`.
+    ///
+    /// Unset or invalid values fall back to the defaults.
+    #[must_use]
+    pub fn from_env() -> Self {
+        Self::from_vars(
+            env::var("EVENT_CHANNEL_CAPACITY").ok().as_deref(),
+            env::var("SLOW_CONSUMER_POLICY").ok().as_deref(),
+        )
+    }
+
+    fn from_vars(capacity: Option<&str>, policy: Option<&str>) -> Self {
+        let default = Self::default();
+
+        let channel_capacity = capacity.map_or(default.channel_capacity, |capacity| {
+            let parsed = capacity.trim().parse::<usize>().ok();
+            parsed.filter(|&parsed| parsed > 0).unwrap_or_else(|| {
+                tracing::warn!(message = "Invalid event channel capacity", ?capacity);
+                default.channel_capacity
+            })
+        });
+
+        let slow_consumer_policy = policy.map_or(default.slow_consumer_policy, |policy| {
+            policy.parse().unwrap_or_else(|error| {
+                tracing::warn!(message = "Invalid slow consumer policy", ?error);
+                default.slow_consumer_policy
+            })
+        });
+
+        Self {
+            channel_capacity,
+            slow_consumer_policy,
+        }
+    }
+}
+
+/// The sequence numbers of the messages that have been sent to a room subscriber.
+///
+/// Messages are committed in sequence order (storing one locks its room until it's committed),
+/// but they are published afterwards, so live messages may arrive out of order. Everything up
+/// to a low-water mark has been sent (or is gone, i.e. expired), and only the sequence numbers
+/// sent above it are remembered.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeliveredSequences {
+    low_water: u64,
+    above: BTreeSet<u64>,
+}
+
+impl DeliveredSequences {
+    /// How many messages may be sent above a missing one before it's assumed to be lost,
+    /// i.e. because the instance that stored it couldn't relay it.
+    pub const MAX_OUT_OF_ORDER: usize = 1024;
+
+    /// Start with every message up to `low_water` taken care of.
+    #[must_use]
+    pub const fn after(low_water: u64) -> Self {
+        Self {
+            low_water,
+            above: BTreeSet::new(),
+        }
+    }
+
+    /// The sequence number of the last message before the first one that hasn't been sent.
+    #[must_use]
+    pub const fn low_water(&self) -> u64 {
+        self.low_water
+    }
+
+    /// The sequence number of the last message that has been sent.
+    #[must_use]
+    pub fn highest(&self) -> u64 {
+        self.above.last().copied().unwrap_or(self.low_water)
+    }
+
+    /// How many sequence numbers above the low-water mark are remembered.
+    #[must_use]
+    pub fn pending(&self) -> usize {
+        self.above.len()
+    }
+
+    /// Record that a message is being sent. Returns `false` if it has been sent already.
+    pub fn insert(&mut self, sequence: u64) -> bool {
+        if sequence <= self.low_water || !self.above.insert(sequence) {
+            return false;
+        }
+
+        if self.above.len() > Self::MAX_OUT_OF_ORDER {
+            if let Some(&lowest) = self.above.first() {
+                self.low_water = lowest - 1;
+            }
+        }
+        self.advance();
+        true
+    }
+
+    /// Record that every message up to `sequence` has been sent or is gone,
+    /// i.e. after everything up to it has been reloaded from the database.
+    pub fn settle(&mut self, sequence: u64) {
+        if sequence > self.low_water {
+            self.low_water = sequence;
+            self.above = self.above.split_off(&(sequence + 1));
+        }
+        self.advance();
+    }
+
+    fn advance(&mut self) {
+        while self.above.remove(&(self.low_water + 1)) {
+            self.low_water += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeliveredSequences, SlowConsumerPolicy, StreamingConfig};
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::recover("recover", Some(SlowConsumerPolicy::Recover))]
+    #[case::resync("resync", Some(SlowConsumerPolicy::Resync))]
+    #[case::disconnect(" Disconnect ", Some(SlowConsumerPolicy::Disconnect))]
+    #[case::unknown("drop", None)]
+    fn policy(#[case] input: &str, #[case] expected: Option<SlowConsumerPolicy>) {
+        assert_eq!(input.parse().ok(), expected);
+    }
+
+    #[rstest]
+    #[case::unset(None, None, StreamingConfig::default())]
+    #[case::set(Some("256"), Some("resync"), StreamingConfig {
+        channel_capacity: 256,
+        slow_consumer_policy: SlowConsumerPolicy::Resync,
+    })]
+    #[case::zero_capacity(Some("0"), None, StreamingConfig::default())]
+    #[case::garbage(Some("lots"), Some("drop"), StreamingConfig::default())]
+    fn config(
+        #[case] capacity: Option<&str>,
+        #[case] policy: Option<&str>,
+        #[case] expected: StreamingConfig,
+    ) {
+        assert_eq!(StreamingConfig::from_vars(capacity, policy), expected);
+    }
+
+    #[test]
+    fn delivered_sequences_are_pruned_below_the_low_water_mark() {
+        let mut delivered = DeliveredSequences::after(2);
+        assert!(!delivered.insert(2));
+
+        assert!(delivered.insert(4));
+        assert!(delivered.insert(5));
+        assert!(!delivered.insert(4));
+        assert_eq!((delivered.low_water(), delivered.pending()), (2, 2));
+
+        assert!(delivered.insert(3));
+        assert_eq!((delivered.low_water(), delivered.pending()), (5, 0));
+
+        assert!(delivered.insert(8));
+        delivered.settle(7);
+        assert_eq!((delivered.low_water(), delivered.pending()), (8, 0));
+        assert!(!delivered.insert(6));
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn missing_sequences_are_given_up_on_eventually() {
+        let mut delivered = DeliveredSequences::after(0);
+        let max = u64::try_from(DeliveredSequences::MAX_OUT_OF_ORDER).unwrap();
+        for sequence in 2..=max + 1 {
+            assert!(delivered.insert(sequence));
+        }
+        assert_eq!(delivered.low_water(), 0);
+
+        assert!(delivered.insert(max + 2));
+        assert_eq!((delivered.low_water(), delivered.pending()), (max + 2, 0));
+    }
+}
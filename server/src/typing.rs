@@ -0,0 +1,258 @@
+//! # Typing indicators
+//!
+//! Typing indicators are ephemeral: nothing is persisted, and the state only lives
+//! in a [`TypingTracker`] for as long as a user keeps calling the `SetTyping` RPC.
+//!
+//! ## Expiry
+//!
+//! A user is considered to be typing for [`TYPING_TIMEOUT`] after the last call.
+//! Clients are expected to repeat the call while the user keeps typing, and a
+//! watcher task (see [`TypingTracker::tick`]) turns the indicator off once the
+//! user goes silent, even if the client never sends an explicit "stopped typing".
+//!
+//! ## Rate limiting
+//!
+//! Every change of the announced state produces an event on the internal broadcast
+//! channel, so announcements for a single user in a single room are spaced at least
+//! [`ANNOUNCEMENT_INTERVAL`] apart. Changes that arrive too early are not dropped,
+//! but postponed: the watcher task announces the latest state once the interval passes.
+//! Since the watcher may be sleeping until the indicator expires, it's woken up through a
+//! [`Notify`] whenever a change is postponed.
+
+use hashbrown::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// For how long a user is considered to be typing after the last `SetTyping` call.
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The minimal interval between two announcements for the same user in the same room.
+pub const ANNOUNCEMENT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What should be done after the typing state of a user changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// The change should be announced to other room members.
+    Announce(bool),
+    /// Nothing should be announced (yet).
+    Quiet,
+}
+
+/// What a watcher task should do next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tick {
+    /// The change should be announced to other room members, then the watcher should tick again.
+    Announce(bool),
+    /// Nothing to do until the provided instant.
+    WakeAt(Instant),
+    /// The user is no longer typing and the state was forgotten, the watcher should stop.
+    Finished,
+}
+
+#[derive(Debug)]
+struct TypingState {
+    typing_until: Option<Instant>,
+    announced: bool,
+    announced_at: Option<Instant>,
+    /// Wakes up the watcher when a change is postponed.
+    postponed: Arc<Notify>,
+}
+
+impl TypingState {
+    fn is_typing(&self, now: Instant) -> bool {
+        self.typing_until.is_some_and(|until| until > now)
+    }
+
+    fn quiet_until(&self) -> Option<Instant> {
+        self.announced_at.map(|at| at + ANNOUNCEMENT_INTERVAL)
+    }
+
+    /// Announce the current state if it differs from the announced one and the rate limit allows it.
+    fn settle(&mut self, now: Instant) -> Option<bool> {
+        let typing = self.is_typing(now);
+        let quiet = self.quiet_until().is_some_and(|until| until > now);
+        if typing != self.announced && !quiet {
+            self.announced = typing;
+            self.announced_at = Some(now);
+            return Some(typing);
+        }
+
+        None
+    }
+}
+
+/// Keeps track of who is typing where.
+#[derive(Debug, Default)]
+pub struct TypingTracker {
+    // Keyed by (room, user).
+    states: Mutex<HashMap<(Uuid, Uuid), TypingState>>,
+}
+
+impl TypingTracker {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that a user has started or stopped typing in a room.
+    ///
+    /// The second returned value is set if the user was not tracked in this room before,
+    /// meaning that the caller should spawn a watcher task that calls [`Self::tick`]. The watcher
+    /// should also tick whenever it's notified, which happens when a change is postponed.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn update(
+        &self,
+        room: Uuid,
+        user: Uuid,
+        typing: bool,
+        now: Instant,
+    ) -> (Transition, Option<Arc<Notify>>) {
+        let mut states = self.states.lock().expect("Typing state mutex was poisoned");
+        let mut watcher = None;
+        let state = states.entry((room, user)).or_insert_with(|| {
+            let postponed = Arc::new(Notify::new());
+            watcher = Some(postponed.clone());
+            TypingState {
+                typing_until: None,
+                announced: false,
+                announced_at: None,
+                postponed,
+            }
+        });
+
+        state.typing_until = typing.then_some(now + TYPING_TIMEOUT);
+        let transition = match state.settle(now) {
+            Some(typing) => Transition::Announce(typing),
+            None => {
+                if state.is_typing(now) != state.announced {
+                    state.postponed.notify_one();
+                }
+                Transition::Quiet
+            }
+        };
+
+        (transition, watcher)
+    }
+
+    /// Advance the typing state of a user in a room, expiring it or announcing postponed changes.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn tick(&self, room: Uuid, user: Uuid, now: Instant) -> Tick {
+        let mut states = self.states.lock().expect("Typing state mutex was poisoned");
+        let Some(state) = states.get_mut(&(room, user)) else {
+            return Tick::Finished;
+        };
+
+        if let Some(typing) = state.settle(now) {
+            return Tick::Announce(typing);
+        }
+
+        let pending_change = state.is_typing(now) != state.announced;
+        let wake_at = match (state.typing_until, state.quiet_until()) {
+            (Some(until), _) if until > now && !pending_change => Some(until),
+            (_, Some(quiet_until)) if quiet_until > now => Some(quiet_until),
+            _ => None,
+        };
+
+        match wake_at {
+            Some(instant) => Tick::WakeAt(instant),
+            None => {
+                let _ = states.remove(&(room, user));
+                Tick::Finished
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{Tick, Transition, TypingTracker, ANNOUNCEMENT_INTERVAL, TYPING_TIMEOUT};
+    use futures::FutureExt;
+    use std::time::Duration;
+    use tokio::time::Instant;
+    use uuid::Uuid;
+
+    #[test]
+    fn expiry() {
+        let tracker = TypingTracker::new();
+        let (room, user) = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+
+        let (transition, watcher) = tracker.update(room, user, true, start);
+        assert_eq!(transition, Transition::Announce(true));
+        assert!(watcher.is_some());
+
+        let deadline = start + TYPING_TIMEOUT;
+        assert_eq!(tracker.tick(room, user, start), Tick::WakeAt(deadline));
+        assert_eq!(tracker.tick(room, user, deadline), Tick::Announce(false));
+        let quiet_until = deadline + ANNOUNCEMENT_INTERVAL;
+        assert_eq!(
+            tracker.tick(room, user, deadline),
+            Tick::WakeAt(quiet_until)
+        );
+        assert_eq!(tracker.tick(room, user, quiet_until), Tick::Finished);
+    }
+
+    #[test]
+    fn refresh() {
+        let tracker = TypingTracker::new();
+        let (room, user) = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+
+        let _ = tracker.update(room, user, true, start);
+        let later = start + Duration::from_secs(2);
+        let (transition, watcher) = tracker.update(room, user, true, later);
+        assert_eq!(transition, Transition::Quiet);
+        assert!(watcher.is_none());
+        assert_eq!(
+            tracker.tick(room, user, start + TYPING_TIMEOUT),
+            Tick::WakeAt(later + TYPING_TIMEOUT)
+        );
+    }
+
+    #[test]
+    fn rate_limit() {
+        let tracker = TypingTracker::new();
+        let (room, user) = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+
+        let _ = tracker.update(room, user, true, start);
+
+        // Flip-flopping within the interval should not produce any announcements...
+        let soon = start + ANNOUNCEMENT_INTERVAL / 4;
+        assert_eq!(tracker.update(room, user, false, soon).0, Transition::Quiet);
+        assert_eq!(tracker.update(room, user, true, soon).0, Transition::Quiet);
+        assert_eq!(tracker.update(room, user, false, soon).0, Transition::Quiet);
+
+        // ...but the latest state should be announced after it.
+        let quiet_until = start + ANNOUNCEMENT_INTERVAL;
+        assert_eq!(tracker.tick(room, user, soon), Tick::WakeAt(quiet_until));
+        assert_eq!(tracker.tick(room, user, quiet_until), Tick::Announce(false));
+    }
+
+    #[test]
+    fn postponed_changes_wake_the_watcher() {
+        let tracker = TypingTracker::new();
+        let (room, user) = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+
+        let (_, watcher) = tracker.update(room, user, true, start);
+        let watcher = watcher.unwrap();
+        let deadline = start + TYPING_TIMEOUT;
+        assert_eq!(tracker.tick(room, user, start), Tick::WakeAt(deadline));
+        assert!(watcher.notified().now_or_never().is_none());
+
+        // The watcher sleeps until the indicator expires, so stopping has to wake it up...
+        let soon = start + ANNOUNCEMENT_INTERVAL / 4;
+        assert_eq!(tracker.update(room, user, false, soon).0, Transition::Quiet);
+        assert!(watcher.notified().now_or_never().is_some());
+
+        // ...to announce it as soon as the interval passes, rather than at the deadline.
+        let quiet_until = start + ANNOUNCEMENT_INTERVAL;
+        assert_eq!(tracker.tick(room, user, soon), Tick::WakeAt(quiet_until));
+        assert_eq!(tracker.tick(room, user, quiet_until), Tick::Announce(false));
+    }
+}